@@ -1,5 +1,5 @@
 use thiserror::Error;
-use tungstenite::Error as WSError;
+use tungstenite::{http::StatusCode, Error as WSError};
 
 #[derive(Error, Debug)]
 pub enum BinanceError {
@@ -17,3 +17,67 @@ pub enum BinanceError {
     #[error("unknown data store error")]
     Unknown,
 }
+
+/// What an error means for the stream it came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The connection failed or dropped; connecting again should sort it out
+    Transient,
+    /// Connecting again won't help, eg. because binance doesn't know the instrument
+    Fatal,
+    /// binance sent something we couldn't make sense of. The connection itself is fine
+    DataQuality,
+}
+
+/// A bad url or a refused handshake won't get any better by trying again. Anything else might,
+/// including being told to slow down
+fn connect_kind(error: &WSError) -> ErrorKind {
+    match error {
+        WSError::Url(_) => ErrorKind::Fatal,
+        WSError::Http(response)
+            if response.status().is_client_error()
+                && response.status() != StatusCode::TOO_MANY_REQUESTS =>
+        {
+            ErrorKind::Fatal
+        }
+        _ => ErrorKind::Transient,
+    }
+}
+
+impl BinanceError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            BinanceError::Connect { error, .. } => connect_kind(error),
+            BinanceError::MessageError(_) => ErrorKind::Transient,
+            BinanceError::Json { .. } | BinanceError::InvalidHeader { .. } => {
+                ErrorKind::DataQuality
+            }
+            BinanceError::Unknown => ErrorKind::Fatal,
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use tungstenite::{http::Response, Error as WSError};
+
+    use super::{BinanceError, ErrorKind};
+
+    #[test]
+    fn test_kind() {
+        let connect = |status| BinanceError::Connect {
+            url: "wss://stream.binance.com:9443/ws/nope@depth20@100ms".to_string(),
+            error: WSError::Http(Response::builder().status(status).body(None).unwrap()),
+        };
+        assert_eq!(connect(400).kind(), ErrorKind::Fatal);
+        assert_eq!(connect(429).kind(), ErrorKind::Transient);
+        assert_eq!(connect(503).kind(), ErrorKind::Transient);
+        let dropped = BinanceError::from(WSError::ConnectionClosed);
+        assert_eq!(dropped.kind(), ErrorKind::Transient);
+        let json = BinanceError::Json {
+            error: serde_json::from_str::<u32>("{").unwrap_err(),
+            original: "{".to_string(),
+        };
+        assert_eq!(json.kind(), ErrorKind::DataQuality);
+    }
+}
@@ -1,13 +1,20 @@
-use futures::StreamExt;
+use futures::{SinkExt, StreamExt};
+use std::fmt::Debug;
 pub mod model;
-use model::Depth;
+use model::{Depth, Trade};
+use serde::de::DeserializeOwned;
 use serde_json::de::from_str;
-use tokio_stream::Stream;
+use tokio_stream::{wrappers::UnboundedReceiverStream, Stream};
 use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tracing::Instrument;
 
 mod error;
-pub use error::BinanceError as Error;
+pub use error::{BinanceError as Error, ErrorKind};
 pub type Result<T> = std::result::Result<T, Error>;
+pub use tap::Tap;
+
+/// Binance's public websocket; the stream name is added to the end
+pub const ENDPOINT: &str = "wss://stream.binance.com:9443/ws";
 
 /// A tokio-stream that simply converts the incoming Json to usable structs
 /// Connect to binance and return a new stream
@@ -15,26 +22,104 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub async fn binance_stream(
     instrument: &str,
 ) -> Result<impl Stream<Item = Result<Depth>> + Send + 'static> {
-    let url = format!("wss://stream.binance.com:9443/ws/{instrument}@depth20@100ms");
-    let (client, _response) = connect_async(&url)
+    binance_stream_at(ENDPOINT, instrument, None).await
+}
+
+/// `binance_stream` from `endpoint`, eg. a local replay. `tap` gets each frame before it's parsed
+pub async fn binance_stream_at(
+    endpoint: &str,
+    instrument: &str,
+    tap: Option<Tap>,
+) -> Result<impl Stream<Item = Result<Depth>> + Send + 'static> {
+    stream_at(
+        format!("{endpoint}/{instrument}@depth20@100ms"),
+        instrument,
+        tap,
+    )
+    .await
+}
+
+/// Connect to binance's trades for `instrument` at `endpoint`, showing `tap` every frame
+pub async fn binance_trade_stream_at(
+    endpoint: &str,
+    instrument: &str,
+    tap: Option<Tap>,
+) -> Result<impl Stream<Item = Result<Trade>> + Send + 'static> {
+    stream_at(format!("{endpoint}/{instrument}@trade"), instrument, tap).await
+}
+
+/// Connect to `url`, and parse each text frame it sends as a `T`
+async fn stream_at<T: DeserializeOwned + Debug + Send + 'static>(
+    url: String,
+    instrument: &str,
+    tap: Option<Tap>,
+) -> Result<impl Stream<Item = Result<T>> + Send + 'static> {
+    let (mut client, _response) = connect_async(&url)
         .await
         .map_err(|error| Error::Connect { url, error })?;
-    Ok(Box::pin(client.filter_map(|result| async move {
-        match result {
-            // Incoming message is text; parse it
-            Ok(Message::Text(msg)) => Some(from_str::<Depth>(&msg).map_err(|error| Error::Json {
-                error,
-                original: msg,
-            })),
-            // Filter out and log warnings for non-text messages
-            Ok(unexpected_message) => {
-                log::warn!("Unexpceted message type (not text): {unexpected_message:?}");
-                None
+
+    // Spawn a task that forwards the books to our queue, so it can close the socket properly once
+    // the queue's dropped
+    let (out_send, out_recv) = tokio::sync::mpsc::unbounded_channel();
+    let connection = tracing::info_span!("connection", venue = "binance", instrument);
+    let task = async move {
+        let mut messages = 0u64;
+        loop {
+            let result = tokio::select! {
+                result = client.next() => match result {
+                    Some(result) => result,
+                    None => return,
+                },
+                _ = out_send.closed() => {
+                    if let Err(err) = client.close(None).await {
+                        log::warn!("Unable to close the binance socket: {err:?}");
+                    }
+                    return;
+                }
+            };
+            if let (Some(tap), Ok(frame)) = (&tap, &result) {
+                tap(frame);
+            }
+            // Messages are numbered on each connection, so they can be followed through the server
+            let message = tracing::debug_span!("message", sequence = tracing::field::Empty);
+            let to_send = match result {
+                // Incoming message is text; parse it
+                Ok(Message::Text(msg)) => {
+                    message
+                        .in_scope(|| from_str::<T>(&msg))
+                        .map_err(|error| Error::Json {
+                            error,
+                            original: msg,
+                        })
+                }
+                Ok(Message::Ping(data)) => {
+                    if let Err(err) = client.send(Message::Pong(data)).await {
+                        log::error!("Unable to binance pong: {err:?}")
+                    }
+                    continue;
+                }
+                // Filter out and log warnings for other message types
+                Ok(unexpected_message) => {
+                    log::warn!("Unexpceted message type (not text): {unexpected_message:?}");
+                    continue;
+                }
+                // Convert all errors
+                Err(err) => Err(err.into()),
+            };
+            if to_send.is_ok() {
+                messages += 1;
+                message.record("sequence", messages);
+            }
+            if let Err(err) = out_send.send(to_send) {
+                // Most likely the client has disconnected
+                log::error!("Unable to forward binance message to client: {err:?}");
+                return;
             }
-            // Convert all errors
-            Err(err) => Some(Err(err.into())),
         }
-    })))
+    };
+    tokio::spawn(task.instrument(connection));
+
+    Ok(UnboundedReceiverStream::new(out_recv))
 }
 
 #[cfg(test)]
@@ -64,6 +64,50 @@ impl TryFrom<RawDepth> for Depth {
     }
 }
 
+/// One trade, as binance sends it on the `<symbol>@trade` stream
+/// See: https://github.com/binance/binance-spot-api-docs/blob/master/web-socket-streams.md#trade-streams
+#[derive(Deserialize)]
+struct RawTrade {
+    #[serde(rename = "t")]
+    id: u64,
+    #[serde(rename = "p")]
+    price: String,
+    #[serde(rename = "q")]
+    quantity: String,
+    /// Trade time, in ms since the epoch
+    #[serde(rename = "T")]
+    time: i64,
+    #[serde(rename = "m")]
+    buyer_is_maker: bool,
+}
+
+/// A trade on a particular symbol
+#[derive(Deserialize, Debug)]
+#[serde(try_from = "RawTrade")]
+pub struct Trade {
+    pub id: u64,
+    pub price: f64,
+    pub quantity: f64,
+    /// When binance matched it
+    pub timestamp: DateTime<Utc>,
+    /// If so, the seller took liquidity; otherwise the buyer did
+    pub buyer_is_maker: bool,
+}
+
+impl TryFrom<RawTrade> for Trade {
+    type Error = ParseFloatError;
+
+    fn try_from(value: RawTrade) -> Result<Self, Self::Error> {
+        Ok(Trade {
+            id: value.id,
+            price: value.price.parse()?,
+            quantity: value.quantity.parse()?,
+            timestamp: DateTime::<Utc>::UNIX_EPOCH + chrono::Duration::milliseconds(value.time),
+            buyer_is_maker: value.buyer_is_maker,
+        })
+    }
+}
+
 #[cfg(test)]
 mod unit_test {
     use super::Depth;
@@ -72,11 +116,22 @@ mod unit_test {
     #[test]
     fn test_parse() {
         let input = r#"{"lastUpdateId":5144117438,"bids":[["0.07530500","38.24170000"],["0.07530400","0.12670000"],["0.07530100","8.14710000"],["0.07529600","0.22860000"],["0.07529500","2.70550000"],["0.07528900","0.60620000"],["0.07528700","0.00420000"],["0.07528500","2.81950000"],["0.07528400","3.83370000"],["0.07527300","1.25770000"],["0.07527200","3.93890000"],["0.07527000","11.91820000"],["0.07526900","11.66480000"],["0.07526600","0.17940000"],["0.07526500","10.03450000"],["0.07526400","11.91650000"],["0.07526300","14.16510000"],["0.07526200","60.80000000"],["0.07526100","1.18930000"],["0.07525800","1.12460000"]],"asks":[["0.07530600","3.81910000"],["0.07530700","8.25850000"],["0.07530800","0.10000000"],["0.07531200","2.74780000"],["0.07531300","0.09120000"],["0.07531800","2.36240000"],["0.07531900","0.16480000"],["0.07532100","10.25780000"],["0.07532200","15.12800000"],["0.07532300","16.20000000"],["0.07532400","0.04760000"],["0.07532500","1.00630000"],["0.07532800","3.64420000"],["0.07532900","3.77510000"],["0.07533000","0.53120000"],["0.07533100","2.15300000"],["0.07533200","10.30650000"],["0.07533300","1.32790000"],["0.07533400","23.50000000"],["0.07533900","5.30560000"]]}"#;
-        let depth: Depth = from_str(&input).unwrap();
+        let depth: Depth = from_str(input).unwrap();
         // Make sure it got the amount and quantity the right way around
         dbg!(&depth);
         let super::Price { amount, quantity } = &depth.bids[0];
         assert_eq!(*amount, 0.07530500);
         assert_eq!(*quantity, 38.24170000);
     }
+
+    #[test]
+    fn test_parse_trade() {
+        let input = r#"{"e":"trade","E":1672515782136,"s":"ETHBTC","t":12345,"p":"0.07530500","q":"1.25000000","T":1672515782134,"m":true,"M":true}"#;
+        let trade: super::Trade = from_str(input).unwrap();
+        assert_eq!(trade.id, 12345);
+        assert_eq!(trade.price, 0.07530500);
+        assert_eq!(trade.quantity, 1.25);
+        assert_eq!(trade.timestamp.timestamp_millis(), 1672515782134);
+        assert!(trade.buyer_is_maker);
+    }
 }
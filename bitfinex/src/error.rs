@@ -0,0 +1,41 @@
+use thiserror::Error;
+use tokio_tungstenite::tungstenite::Error as WSError;
+
+#[derive(Error, Debug)]
+pub enum BitfinexError {
+    #[error("Unable to connect to websocket: url: \"{url}\" error: \"{error:?}\"")]
+    Connect { url: String, error: Box<WSError> },
+    #[error("We connected OK, but later got an error while trying to read a message: {0:?}")]
+    MessageError(Box<WSError>),
+    #[error("Unable to send \"{message}\" to bitfinex: {error:?}")]
+    Send {
+        message: String,
+        error: Box<WSError>,
+    },
+    #[error("Unable to parse json. Error: \"{error:?}\" Original: \"{original}\"")]
+    Json {
+        error: serde_json::Error,
+        original: String,
+    },
+    #[error("Unexpected frame: {reason}. Original: \"{original}\"")]
+    UnexpectedFrame {
+        reason: &'static str,
+        original: String,
+    },
+    #[error("Unable to parse number \"{input}\" in a book entry")]
+    Number { input: String },
+    #[error("Bitfinex returned an error. Code: {code:?} Message: \"{msg}\"")]
+    Server { code: Option<u32>, msg: String },
+    #[error("Book checksum mismatch on channel {channel_id}: bitfinex says {expected}, we calculated {calculated}")]
+    Checksum {
+        channel_id: u64,
+        expected: i32,
+        calculated: i32,
+    },
+}
+
+impl From<WSError> for BitfinexError {
+    fn from(error: WSError) -> Self {
+        BitfinexError::MessageError(Box::new(error))
+    }
+}
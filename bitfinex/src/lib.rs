@@ -0,0 +1,196 @@
+use futures::{SinkExt, StreamExt};
+pub mod model;
+use model::{message::OB_CHECKSUM, Book, Event, LocalBook, Message, Precision, Request};
+use tokio_stream::{wrappers::UnboundedReceiverStream, Stream};
+use tokio_tungstenite::{connect_async, tungstenite::Message as TMessage};
+use tracing::Instrument;
+
+mod error;
+pub use error::BitfinexError as Error;
+pub type Result<T> = std::result::Result<T, Error>;
+pub use tap::Tap;
+
+/// Bitfinex's public websocket
+pub const ENDPOINT: &str = "wss://api-pub.bitfinex.com/ws/2";
+
+/// Turn a request into a websocket message
+fn request_message(request: &Request) -> TMessage {
+    // Request only holds strings and numbers, so this can't fail
+    TMessage::Text(serde_json::to_string(request).expect("Serializing bitfinex request"))
+}
+
+/// Connect to bitfinex, turn on checksums, subscribe to the `book` channel and return a stream of
+/// the whole book after every snapshot or update.
+/// `symbol` should come from bitfinex's symbol list, eg. "tETHBTC"
+/// `precision` is `P0` for aggregated price levels, or `R0` for the raw (level 3) order book
+pub async fn bitfinex_stream(
+    symbol: &str,
+    precision: Precision,
+) -> Result<impl Stream<Item = Result<Book>> + Send + 'static> {
+    bitfinex_stream_at(ENDPOINT, symbol, precision, None).await
+}
+
+/// As `bitfinex_stream`, from `endpoint`. `tap` gets the raw frames, checksum messages included
+pub async fn bitfinex_stream_at(
+    endpoint: &str,
+    symbol: &str,
+    precision: Precision,
+    tap: Option<Tap>,
+) -> Result<impl Stream<Item = Result<Book>> + Send + 'static> {
+    let url = endpoint.to_string();
+    let (mut client, _response) = connect_async(&url).await.map_err(|error| Error::Connect {
+        url,
+        error: Box::new(error),
+    })?;
+
+    // Ask for checksums, then subscribe
+    let subscribe = Request::subscribe_book(symbol, precision);
+    for request in [&Request::Conf { flags: OB_CHECKSUM }, &subscribe] {
+        let message = request_message(request);
+        log::debug!("Sending message: {message:?}");
+        client
+            .send(message.clone())
+            .await
+            .map_err(|error| Error::Send {
+                message: message.to_string(),
+                error: Box::new(error),
+            })?;
+    }
+
+    // Spawn a task that keeps the book up to date and checked, and forwards it to our queue
+    let (out_send, out_recv) = tokio::sync::mpsc::unbounded_channel();
+    let connection = tracing::info_span!("connection", venue = "bitfinex", instrument = symbol);
+    let task = async move {
+        let mut books = 0u64;
+        let mut book = LocalBook::new(precision);
+        // The channel id bitfinex gave our subscription; None while we're (re)subscribing
+        let mut channel = None;
+        loop {
+            let result = tokio::select! {
+                result = client.next() => match result {
+                    Some(result) => result,
+                    None => return,
+                },
+                // Nobody's reading any more, so close the socket properly
+                _ = out_send.closed() => {
+                    if let Err(err) = client.close(None).await {
+                        log::warn!("Unable to close the bitfinex socket: {err:?}");
+                    }
+                    return;
+                }
+            };
+            if let (Some(tap), Ok(frame)) = (&tap, &result) {
+                tap(frame);
+            }
+            // Books are numbered on each connection, so they can be followed through the server
+            let message = tracing::debug_span!("message", sequence = tracing::field::Empty);
+            let to_send = match result {
+                Ok(TMessage::Text(msg)) => match message.in_scope(|| Message::parse(&msg)) {
+                    Ok(Message::Event(Event::Subscribed { chan_id, .. })) => {
+                        log::info!("Subscribed to bitfinex book channel {chan_id}");
+                        channel = Some(chan_id);
+                        None
+                    }
+                    Ok(Message::Event(Event::Error { msg, code })) => {
+                        Some(Err(Error::Server { code, msg }))
+                    }
+                    Ok(Message::Event(event)) => {
+                        log::debug!("Bitfinex event: {event:?}");
+                        None
+                    }
+                    Ok(Message::Heartbeat { channel_id }) => {
+                        log::trace!("Heartbeat on bitfinex channel {channel_id}");
+                        None
+                    }
+                    // Data for a channel we've given up on
+                    Ok(
+                        Message::Snapshot { channel_id, .. }
+                        | Message::Update { channel_id, .. }
+                        | Message::Checksum { channel_id, .. },
+                    ) if Some(channel_id) != channel => None,
+                    Ok(Message::Snapshot { entries, .. }) => {
+                        Some(book.apply_snapshot(entries).map(|_| book.to_book()))
+                    }
+                    Ok(Message::Update { entry, .. }) => {
+                        Some(book.apply_update(entry).map(|_| book.to_book()))
+                    }
+                    Ok(Message::Checksum {
+                        channel_id,
+                        checksum,
+                    }) => match book.verify(channel_id, checksum) {
+                        Ok(()) => None,
+                        Err(err) => {
+                            // Our book is wrong; start again from a fresh snapshot
+                            log::warn!("{err}. Resubscribing");
+                            channel = None;
+                            book = LocalBook::new(precision);
+                            for request in [
+                                &Request::Unsubscribe {
+                                    chan_id: channel_id,
+                                },
+                                &subscribe,
+                            ] {
+                                if let Err(err) = client.send(request_message(request)).await {
+                                    log::error!("Unable to resubscribe to bitfinex: {err:?}");
+                                }
+                            }
+                            Some(Err(err))
+                        }
+                    },
+                    Err(err) => Some(Err(err)),
+                },
+                Ok(TMessage::Ping(data)) => {
+                    if let Err(err) = client.send(TMessage::Pong(data)).await {
+                        log::error!("Unable to bitfinex pong: {err:?}")
+                    }
+                    None
+                }
+                // Filter out and log warnings for other message types
+                Ok(unexpected_message) => {
+                    log::warn!("Unexpected message type (not text): {unexpected_message:?}");
+                    None
+                }
+                // Convert all errors
+                Err(err) => Some(Err(err.into())),
+            };
+            if let Some(Ok(_)) = &to_send {
+                books += 1;
+                message.record("sequence", books);
+            }
+            if let Some(to_send) = to_send {
+                if let Err(err) = out_send.send(to_send) {
+                    // Most likely the client has disconnected
+                    log::error!("Unable to forward bitfinex book to client: {err:?}");
+                    return;
+                }
+            }
+        }
+    };
+    tokio::spawn(task.instrument(connection));
+
+    Ok(UnboundedReceiverStream::new(out_recv))
+}
+
+#[cfg(test)]
+mod web_test {
+    use futures::StreamExt;
+
+    use crate::model::Precision;
+
+    /// Test if we can connect to bitfinex and get both kinds of book for ETHBTC
+    #[tokio::test]
+    async fn test_ethbtc() {
+        pretty_env_logger::try_init().ok();
+        for precision in [Precision::P0, Precision::R0] {
+            let mut stream = super::bitfinex_stream("tETHBTC", precision)
+                .await
+                .expect("Unable to connect to bitfinex");
+            match stream.next().await {
+                // Got a book
+                Some(Ok(first)) => dbg!(first),
+                Some(Err(err)) => panic!("First message was an error: {err:?}"),
+                None => panic!("No first message!"),
+            };
+        }
+    }
+}
@@ -0,0 +1,10 @@
+//! Models for bitfinex's v2 public websocket, as described by
+//! <https://docs.bitfinex.com/reference/ws-public-books> and
+//! <https://docs.bitfinex.com/reference/ws-public-raw-books>
+// Messages we send and receive
+pub mod message;
+pub use message::{Event, Message, Precision, RawEntry, Request};
+
+// The book we keep up to date from those messages
+pub mod book;
+pub use book::{Book, LocalBook, Price};
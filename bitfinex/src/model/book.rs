@@ -0,0 +1,254 @@
+//! Keeps a local copy of a bitfinex book up to date, and checks it against bitfinex's checksums
+//! See: <https://docs.bitfinex.com/docs/ws-websocket-checksum>
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use ordered_float::OrderedFloat;
+
+use super::{Precision, RawEntry};
+use crate::{Error, Result};
+
+/// How many entries per side bitfinex includes in the checksum
+const CHECKSUM_DEPTH: usize = 25;
+
+/// The current view of the book, sent to the client after every snapshot or update
+#[derive(Debug, Clone, PartialEq)]
+pub struct Book {
+    pub timestamp: DateTime<Utc>,
+    pub precision: Precision,
+    /// Best (highest) bid first
+    pub bids: Vec<Price>,
+    /// Best (lowest) ask first
+    pub asks: Vec<Price>,
+}
+
+/// A price level (P0) or a single order (R0)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Price {
+    pub price: f64,
+    pub quantity: f64,
+    /// How many orders make up this level; only for P0
+    pub count: Option<u64>,
+    /// Only for R0
+    pub order_id: Option<u64>,
+}
+
+/// A parsed entry, along with the text it came from
+#[derive(Debug, Clone)]
+struct Entry {
+    price: f64,
+    /// Positive for bids, negative for asks
+    amount: f64,
+    count: Option<u64>,
+    order_id: Option<u64>,
+    raw: RawEntry,
+}
+
+impl Entry {
+    fn parse(precision: Precision, raw: RawEntry) -> Result<Entry> {
+        let [a, b, c] = &raw.0;
+        let (price, count, order_id) = match precision {
+            Precision::P0 => (number(a)?, Some(number(b)?), None),
+            Precision::R0 => (number(b)?, None, Some(number(a)?)),
+        };
+        Ok(Entry {
+            price,
+            amount: number(c)?,
+            count,
+            order_id,
+            raw,
+        })
+    }
+
+    /// Sort key: bids are stored with negated prices so both sides iterate best first
+    fn key(&self) -> (OrderedFloat<f64>, u64) {
+        let price = if self.amount > 0.0 {
+            -self.price
+        } else {
+            self.price
+        };
+        (OrderedFloat(price), self.order_id.unwrap_or_default())
+    }
+
+    /// The part of the checksum string this entry contributes: the price (P0) or order id (R0),
+    /// then the amount, eg. "0.06712:1.5"
+    fn checksum_part(&self) -> String {
+        let [a, _, c] = &self.raw.0;
+        format!("{a}:{c}")
+    }
+}
+
+fn number<T: std::str::FromStr>(input: &str) -> Result<T> {
+    input.parse().map_err(|_| Error::Number {
+        input: input.to_string(),
+    })
+}
+
+type Side = BTreeMap<(OrderedFloat<f64>, u64), Entry>;
+
+/// A bitfinex book for one channel
+#[derive(Debug)]
+pub struct LocalBook {
+    precision: Precision,
+    bids: Side,
+    asks: Side,
+}
+
+impl LocalBook {
+    pub fn new(precision: Precision) -> LocalBook {
+        LocalBook {
+            precision,
+            bids: Side::new(),
+            asks: Side::new(),
+        }
+    }
+
+    /// Throw away whatever we had, and start again from a snapshot
+    pub fn apply_snapshot(&mut self, entries: Vec<RawEntry>) -> Result<()> {
+        self.bids.clear();
+        self.asks.clear();
+        entries
+            .into_iter()
+            .try_for_each(|entry| self.apply_update(entry))
+    }
+
+    /// Add, update or remove a single price level or order
+    pub fn apply_update(&mut self, raw: RawEntry) -> Result<()> {
+        let entry = Entry::parse(self.precision, raw)?;
+        let side = if entry.amount > 0.0 {
+            &mut self.bids
+        } else {
+            &mut self.asks
+        };
+        match self.precision {
+            // P0: a count of 0 means the level is gone
+            Precision::P0 => {
+                if entry.count == Some(0) {
+                    side.remove(&entry.key());
+                } else {
+                    side.insert(entry.key(), entry);
+                }
+            }
+            // R0: a price of 0 means the order is gone. Orders can also move price, so always
+            // remove the old one first
+            Precision::R0 => {
+                side.retain(|_, existing| existing.order_id != entry.order_id);
+                if entry.price != 0.0 {
+                    side.insert(entry.key(), entry);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Bitfinex's CRC32 of the top 25 bids and asks, interleaved, as a signed integer
+    pub fn checksum(&self) -> i32 {
+        let mut parts = vec![];
+        let mut bids = self.bids.values().take(CHECKSUM_DEPTH);
+        let mut asks = self.asks.values().take(CHECKSUM_DEPTH);
+        loop {
+            let (bid, ask) = (bids.next(), asks.next());
+            if bid.is_none() && ask.is_none() {
+                break;
+            }
+            parts.extend(bid.map(|entry| entry.checksum_part()));
+            parts.extend(ask.map(|entry| entry.checksum_part()));
+        }
+        crc32fast::hash(parts.join(":").as_bytes()) as i32
+    }
+
+    /// Make sure we agree with bitfinex about what's in the book
+    pub fn verify(&self, channel_id: u64, expected: i32) -> Result<()> {
+        let calculated = self.checksum();
+        if calculated == expected {
+            Ok(())
+        } else {
+            Err(Error::Checksum {
+                channel_id,
+                expected,
+                calculated,
+            })
+        }
+    }
+
+    /// Render the current book
+    pub fn to_book(&self) -> Book {
+        let to_price = |entry: &Entry| Price {
+            price: entry.price,
+            quantity: entry.amount.abs(),
+            count: entry.count,
+            order_id: entry.order_id,
+        };
+        Book {
+            timestamp: Utc::now(),
+            precision: self.precision,
+            bids: self.bids.values().map(to_price).collect(),
+            asks: self.asks.values().map(to_price).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use super::LocalBook;
+    use crate::model::{Precision, RawEntry};
+
+    fn entry(a: &str, b: &str, c: &str) -> RawEntry {
+        RawEntry([a.to_string(), b.to_string(), c.to_string()])
+    }
+
+    #[test]
+    fn test_p0_book() {
+        let mut book = LocalBook::new(Precision::P0);
+        book.apply_snapshot(vec![
+            entry("0.0671", "2", "1.5"),
+            entry("0.0672", "1", "0.5"),
+            entry("0.0673", "3", "-2"),
+            entry("0.0674", "1", "-0.1"),
+        ])
+        .unwrap();
+        // Bids then asks, interleaved, best first
+        assert_eq!(
+            book.checksum(),
+            crc32fast::hash(b"0.0672:0.5:0.0673:-2:0.0671:1.5:0.0674:-0.1") as i32
+        );
+        // Remove the best bid
+        book.apply_update(entry("0.0672", "0", "1")).unwrap();
+        let got = book.to_book();
+        assert_eq!(got.bids.len(), 1);
+        assert_eq!(got.bids[0].price, 0.0671);
+        assert_eq!(got.bids[0].count, Some(2));
+        assert_eq!(got.asks[0].price, 0.0673);
+        assert_eq!(got.asks[0].quantity, 2.0);
+        let checksum = crc32fast::hash(b"0.0671:1.5:0.0673:-2:0.0674:-0.1") as i32;
+        assert!(book.verify(1, checksum).is_ok());
+        assert!(book.verify(1, checksum + 1).is_err());
+    }
+
+    #[test]
+    fn test_r0_book() {
+        let mut book = LocalBook::new(Precision::R0);
+        book.apply_snapshot(vec![
+            entry("101", "0.0671", "1.5"),
+            entry("102", "0.0671", "0.25"),
+            entry("201", "0.0673", "-2"),
+        ])
+        .unwrap();
+        assert_eq!(
+            book.checksum(),
+            crc32fast::hash(b"101:1.5:201:-2:102:0.25") as i32
+        );
+        // Order 101 moves up in price, order 201 is cancelled
+        book.apply_update(entry("101", "0.0672", "1.5")).unwrap();
+        book.apply_update(entry("201", "0", "-1")).unwrap();
+        let got = book.to_book();
+        assert_eq!(
+            got.bids
+                .iter()
+                .map(|price| (price.order_id, price.price))
+                .collect::<Vec<_>>(),
+            vec![(Some(101), 0.0672), (Some(102), 0.0671)]
+        );
+        assert!(got.asks.is_empty());
+    }
+}
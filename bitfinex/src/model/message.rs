@@ -0,0 +1,256 @@
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use crate::{Error, Result};
+
+/// Conf flag asking bitfinex to send a `cs` checksum frame after every book update
+pub const OB_CHECKSUM: u64 = 131072;
+
+/// `P0` gives aggregated price levels, `R0` gives every individual order
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub enum Precision {
+    P0,
+    R0,
+}
+
+/// Requests we send to bitfinex
+/// {"event":"subscribe","channel":"book","symbol":"tETHBTC","prec":"R0","freq":"F0","len":"25"}
+#[derive(Serialize, PartialEq, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Request {
+    Conf {
+        flags: u64,
+    },
+    Subscribe {
+        channel: &'static str,
+        symbol: String,
+        prec: Precision,
+        freq: &'static str,
+        len: &'static str,
+    },
+    Unsubscribe {
+        #[serde(rename = "chanId")]
+        chan_id: u64,
+    },
+}
+
+impl Request {
+    /// Subscribe to the `book` channel, as fast as bitfinex will send it, 25 entries per side
+    pub fn subscribe_book(symbol: &str, precision: Precision) -> Request {
+        Request::Subscribe {
+            channel: "book",
+            symbol: symbol.to_string(),
+            prec: precision,
+            freq: "F0",
+            len: "25",
+        }
+    }
+}
+
+/// The json object messages bitfinex sends, eg. `{"event":"info","version":2}`
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Info {
+        version: Option<u32>,
+        code: Option<u32>,
+        msg: Option<String>,
+    },
+    Conf {
+        status: Option<String>,
+        flags: Option<u64>,
+    },
+    Subscribed {
+        channel: String,
+        #[serde(rename = "chanId")]
+        chan_id: u64,
+        symbol: String,
+        prec: Option<Precision>,
+    },
+    Unsubscribed {
+        status: String,
+        #[serde(rename = "chanId")]
+        chan_id: u64,
+    },
+    Error {
+        msg: String,
+        code: Option<u32>,
+    },
+}
+
+/// One `[a, b, c]` book entry, kept as the exact text bitfinex sent, because the checksum is
+/// calculated over that text.
+/// P0 entries are `[price, count, amount]`, R0 entries are `[order_id, price, amount]`
+#[derive(PartialEq, Debug, Clone)]
+pub struct RawEntry(pub [String; 3]);
+
+/// Everything that can come in on the websocket
+#[derive(PartialEq, Debug)]
+pub enum Message {
+    Event(Event),
+    /// `[chanId, "hb"]`
+    Heartbeat {
+        channel_id: u64,
+    },
+    /// `[chanId, "cs", checksum]`
+    Checksum {
+        channel_id: u64,
+        checksum: i32,
+    },
+    /// `[chanId, [[a, b, c], ...]]`
+    Snapshot {
+        channel_id: u64,
+        entries: Vec<RawEntry>,
+    },
+    /// `[chanId, [a, b, c]]`
+    Update {
+        channel_id: u64,
+        entry: RawEntry,
+    },
+}
+
+impl Message {
+    /// Decode a text websocket frame. Events are objects; channel data comes as arrays
+    pub fn parse(input: &str) -> Result<Message> {
+        let json = |error| Error::Json {
+            error,
+            original: input.to_string(),
+        };
+        let unexpected = |reason| Error::UnexpectedFrame {
+            reason,
+            original: input.to_string(),
+        };
+        if input.trim_start().starts_with('{') {
+            return serde_json::from_str(input)
+                .map(Message::Event)
+                .map_err(json);
+        }
+        let parts: Vec<&RawValue> = serde_json::from_str(input).map_err(json)?;
+        let (channel_id, body) = match parts.as_slice() {
+            [channel_id, body, ..] => (
+                serde_json::from_str::<u64>(channel_id.get()).map_err(json)?,
+                body.get(),
+            ),
+            _ => return Err(unexpected("expected at least a channel id and a body")),
+        };
+        match body {
+            r#""hb""# => Ok(Message::Heartbeat { channel_id }),
+            r#""cs""# => {
+                let checksum = parts
+                    .get(2)
+                    .ok_or_else(|| unexpected("checksum frame without a checksum"))?;
+                Ok(Message::Checksum {
+                    channel_id,
+                    checksum: serde_json::from_str(checksum.get()).map_err(json)?,
+                })
+            }
+            body => {
+                let rows: Vec<&RawValue> = serde_json::from_str(body).map_err(json)?;
+                // A snapshot is a list of entries; an update is a single entry
+                if rows.iter().all(|row| row.get().starts_with('[')) {
+                    let entries = rows
+                        .into_iter()
+                        .map(|row| raw_entry(row.get()).map_err(json))
+                        .collect::<Result<Vec<RawEntry>>>()?;
+                    Ok(Message::Snapshot {
+                        channel_id,
+                        entries,
+                    })
+                } else {
+                    Ok(Message::Update {
+                        channel_id,
+                        entry: raw_entry(body).map_err(json)?,
+                    })
+                }
+            }
+        }
+    }
+}
+
+fn raw_entry(input: &str) -> serde_json::Result<RawEntry> {
+    let [a, b, c]: [&RawValue; 3] = serde_json::from_str(input)?;
+    Ok(RawEntry([
+        a.get().to_string(),
+        b.get().to_string(),
+        c.get().to_string(),
+    ]))
+}
+
+#[cfg(test)]
+mod unit_test {
+    use super::{Event, Message, Precision, RawEntry, Request};
+
+    fn entry(a: &str, b: &str, c: &str) -> RawEntry {
+        RawEntry([a.to_string(), b.to_string(), c.to_string()])
+    }
+
+    #[test]
+    fn test_render_requests() {
+        let subscribe =
+            serde_json::to_string(&Request::subscribe_book("tETHBTC", Precision::R0)).unwrap();
+        assert_eq!(
+            subscribe,
+            r#"{"event":"subscribe","channel":"book","symbol":"tETHBTC","prec":"R0","freq":"F0","len":"25"}"#
+        );
+        let conf = serde_json::to_string(&Request::Conf {
+            flags: super::OB_CHECKSUM,
+        })
+        .unwrap();
+        assert_eq!(conf, r#"{"event":"conf","flags":131072}"#);
+    }
+
+    #[test]
+    fn test_parse_events() {
+        let subscribed = r#"{"event":"subscribed","channel":"book","chanId":17470,"symbol":"tETHBTC","prec":"R0","freq":"F0","len":"25","pair":"ETHBTC"}"#;
+        assert_eq!(
+            Message::parse(subscribed).unwrap(),
+            Message::Event(Event::Subscribed {
+                channel: "book".to_string(),
+                chan_id: 17470,
+                symbol: "tETHBTC".to_string(),
+                prec: Some(Precision::R0),
+            })
+        );
+        let error = r#"{"event":"error","msg":"symbol: invalid","code":10300}"#;
+        assert_eq!(
+            Message::parse(error).unwrap(),
+            Message::Event(Event::Error {
+                msg: "symbol: invalid".to_string(),
+                code: Some(10300),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_channel_data() {
+        assert_eq!(
+            Message::parse("[17470,\"hb\"]").unwrap(),
+            Message::Heartbeat { channel_id: 17470 }
+        );
+        assert_eq!(
+            Message::parse("[17470,\"cs\",-1175357890]").unwrap(),
+            Message::Checksum {
+                channel_id: 17470,
+                checksum: -1175357890
+            }
+        );
+        assert_eq!(
+            Message::parse("[17470,[[0.06712,2,1.5],[0.06715,1,-0.00001]]]").unwrap(),
+            Message::Snapshot {
+                channel_id: 17470,
+                entries: vec![
+                    entry("0.06712", "2", "1.5"),
+                    entry("0.06715", "1", "-0.00001")
+                ],
+            }
+        );
+        assert_eq!(
+            Message::parse("[17470,[106887418001,0.06712,-1e-8]]").unwrap(),
+            Message::Update {
+                channel_id: 17470,
+                entry: entry("106887418001", "0.06712", "-1e-8"),
+            }
+        );
+        assert!(Message::parse("[17470]").is_err());
+    }
+}
@@ -1,5 +1,5 @@
 use thiserror::Error;
-use tokio_tungstenite::tungstenite::Message as TMessage;
+use tokio_tungstenite::tungstenite::{http::StatusCode, Error as WSError, Message as TMessage};
 
 #[derive(Error, Debug)]
 pub enum BitstampError {
@@ -60,6 +60,48 @@ impl BitstampError {
     }
 }
 
+/// What an error means for the stream it came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The connection failed or dropped; connecting again should sort it out
+    Transient,
+    /// Connecting again won't help, eg. because bitstamp doesn't know the instrument
+    Fatal,
+    /// bitstamp sent something we couldn't make sense of. The connection itself is fine
+    DataQuality,
+}
+
+/// A bad url or a refused handshake won't get any better by trying again. Anything else might,
+/// including being told to slow down
+fn connect_kind(error: &WSError) -> ErrorKind {
+    match error {
+        WSError::Url(_) => ErrorKind::Fatal,
+        WSError::Http(response)
+            if response.status().is_client_error()
+                && response.status() != StatusCode::TOO_MANY_REQUESTS =>
+        {
+            ErrorKind::Fatal
+        }
+        _ => ErrorKind::Transient,
+    }
+}
+
+impl BitstampError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            BitstampError::Decoding { .. } | BitstampError::DecodingGeneral { .. } => {
+                ErrorKind::DataQuality
+            }
+            // We couldn't put our own message together, so trying again won't help
+            BitstampError::Encoding { .. } => ErrorKind::Fatal,
+            BitstampError::WebSocket { source, .. } => source
+                .downcast_ref::<WSError>()
+                .map_or(ErrorKind::Transient, connect_kind),
+            BitstampError::WebSocketSend { .. } => ErrorKind::Transient,
+        }
+    }
+}
+
 pub trait Context<T> {
     fn context(self, context: &'static str) -> Result<T, BitstampError>;
     fn message_context(self, message: TMessage, context: &'static str) -> Result<T, BitstampError>;
@@ -90,3 +132,25 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod unit_test {
+    use tokio_tungstenite::tungstenite::{http::Response, Error as WSError};
+
+    use super::{BitstampError, Context, ErrorKind};
+
+    #[test]
+    fn test_kind() {
+        let refused = Err::<(), _>(WSError::Http(
+            Response::builder().status(404).body(None).unwrap(),
+        ));
+        assert_eq!(
+            refused.context("Connecting").unwrap_err().kind(),
+            ErrorKind::Fatal
+        );
+        let dropped = Err::<(), _>(WSError::ConnectionClosed).context("Receiving message");
+        assert_eq!(dropped.unwrap_err().kind(), ErrorKind::Transient);
+        let bad_json = BitstampError::decoding_general("no data".to_string());
+        assert_eq!(bad_json.kind(), ErrorKind::DataQuality);
+    }
+}
@@ -4,29 +4,78 @@ use futures::StreamExt;
 
 pub use error::BitstampError as Error;
 pub use error::Context;
+pub use error::ErrorKind;
 pub mod subscribe;
 use futures::Stream;
 use model::ChannelType;
 use model::CurrencyPair;
 use model::Message;
-pub use subscribe::subscribe;
+pub use subscribe::{subscribe, subscribe_at, ENDPOINT};
 
 pub type Result<T> = std::result::Result<T, Error>;
+pub use tap::Tap;
 pub mod model;
-pub use crate::model::OrderBookData;
+pub use crate::model::{OrderBookData, TradeData};
 
 /// A stream of bitstamp OrderBookData
 pub async fn bitstamp_detail_market_depth_stream(
     instrument: CurrencyPair,
 ) -> Result<impl Stream<Item = Result<OrderBookData>> + Send + 'static> {
-    // TODO: One day, support more types of streams (other than DetailOrderBook)
-    let stream = subscribe(ChannelType::DetailOrderBook, instrument)
+    bitstamp_detail_market_depth_stream_at(ENDPOINT, instrument, None).await
+}
+
+/// The same from `endpoint`, with every frame going past `tap` on its way to being parsed
+pub async fn bitstamp_detail_market_depth_stream_at(
+    endpoint: &str,
+    instrument: CurrencyPair,
+    tap: Option<Tap>,
+) -> Result<impl Stream<Item = Result<OrderBookData>> + Send + 'static> {
+    channel_stream_at(
+        endpoint,
+        ChannelType::DetailOrderBook,
+        instrument,
+        tap,
+        |message| match message {
+            Message::Data { data } => Ok(data),
+            other => Err(other),
+        },
+    )
+    .await
+}
+
+/// A stream of bitstamp's trades in `instrument`, from `endpoint`
+pub async fn bitstamp_live_trades_stream_at(
+    endpoint: &str,
+    instrument: CurrencyPair,
+    tap: Option<Tap>,
+) -> Result<impl Stream<Item = Result<TradeData>> + Send + 'static> {
+    channel_stream_at(
+        endpoint,
+        ChannelType::LiveTrades,
+        instrument,
+        tap,
+        |message| match message {
+            Message::Trade { data } => Ok(data),
+            other => Err(other),
+        },
+    )
+    .await
+}
+
+/// Subscribe to one channel, keeping only the messages `pick` gives back data for
+async fn channel_stream_at<T: Send + 'static>(
+    endpoint: &str,
+    channel_type: ChannelType,
+    instrument: CurrencyPair,
+    tap: Option<Tap>,
+    pick: fn(Message) -> std::result::Result<T, Message>,
+) -> Result<impl Stream<Item = Result<T>> + Send + 'static> {
+    let stream = subscribe_at(endpoint, channel_type, instrument, tap)
         .await?
-        // Filter all the incoming messages, because we only care about OrderBookData
-        .filter_map(|result| async move {
+        // Filter all the incoming messages, because we only care about the channel's data
+        .filter_map(move |result| async move {
             result
                 .map(|message| match message {
-                    Message::Data { data } => Some(data),
                     Message::SubscriptionSucceeded { channel } => {
                         log::info!("Subscribed to {channel:?}");
                         None
@@ -35,10 +84,13 @@ pub async fn bitstamp_detail_market_depth_stream(
                         log::error!("Bitstamp server error returned: {data:?}");
                         None
                     }
-                    other => {
-                        log::warn!("Unexpected message: {other:?}");
-                        None
-                    }
+                    other => match pick(other) {
+                        Ok(data) => Some(data),
+                        Err(other) => {
+                            log::warn!("Unexpected message: {other:?}");
+                            None
+                        }
+                    },
                 })
                 .transpose()
         });
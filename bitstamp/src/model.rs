@@ -6,3 +6,5 @@ pub use message::{Channel, ChannelType, CurrencyPair, Message};
 // Messages we receive
 pub mod order_book;
 pub use order_book::{OrderBookData, Price};
+pub mod trade;
+pub use trade::{Side, TradeData};
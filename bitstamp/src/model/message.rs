@@ -1,6 +1,6 @@
 mod channel;
 mod currency_pair;
-use crate::{Error, OrderBookData, Result};
+use crate::{model::TradeData, Error, OrderBookData, Result};
 use serde::{Deserialize, Serialize};
 use serde_json::to_string;
 
@@ -16,6 +16,10 @@ pub enum Message {
     Subscribe {
         data: ChannelData,
     },
+    #[serde(rename = "bts:unsubscribe")]
+    Unsubscribe {
+        data: ChannelData,
+    },
     #[serde(rename = "bts:subscription_succeeded")]
     SubscriptionSucceeded {
         channel: Channel,
@@ -23,6 +27,9 @@ pub enum Message {
     Data {
         data: OrderBookData,
     },
+    Trade {
+        data: TradeData,
+    },
     Ping(Vec<u8>),
     Pong(Vec<u8>),
     #[serde(rename = "bts:error")]
@@ -58,6 +65,26 @@ impl Message {
         })?;
         Ok(TMessage::Text(as_str))
     }
+
+    /// Generate the request message to unsubscribe from a channel
+    pub fn unsubscribe(channel_type: ChannelType, currency_pair: CurrencyPair) -> Result<TMessage> {
+        let message = Message::Unsubscribe {
+            data: ChannelData {
+                channel: Channel {
+                    channel_type,
+                    pair: currency_pair,
+                },
+            },
+        };
+        let as_str = to_string(&message).map_err(|source| {
+            Error::encoding(
+                "web socket -> creating unsubscribe message",
+                message,
+                source,
+            )
+        })?;
+        Ok(TMessage::Text(as_str))
+    }
 }
 
 impl TryFrom<TMessage> for Message {
@@ -83,7 +110,7 @@ mod unit_test {
     };
 
     use super::{Channel, ChannelType, CurrencyPair, Message};
-    use chrono::{DateTime, NaiveDate, Utc};
+    use chrono::NaiveDate;
     use tokio_tungstenite::tungstenite::Message as TMessage;
 
     #[test]
@@ -123,6 +150,18 @@ mod unit_test {
         assert_eq!(decoded, expected);
     }
 
+    #[test]
+    fn test_unsubscribe_render() {
+        let raw = Message::unsubscribe(ChannelType::DetailOrderBook, CurrencyPair::Ethbtc).unwrap();
+        assert_eq!(
+            raw,
+            TMessage::Text(
+                r#"{"event":"bts:unsubscribe","data":{"channel":"detail_order_book_ethbtc"}}"#
+                    .to_string()
+            )
+        );
+    }
+
     #[test]
     fn test_parse() {
         let input = r#"
@@ -134,13 +173,16 @@ mod unit_test {
             },
             "channel":"detail_order_book_ethbtc",
             "event":"data"}"#;
-        let expected_time = NaiveDate::from_ymd(2022, 05, 1).and_hms_micro(7, 3, 36, 274565);
+        let expected_time = NaiveDate::from_ymd_opt(2022, 5, 1)
+            .and_then(|date| date.and_hms_micro_opt(7, 3, 36, 274565))
+            .unwrap()
+            .and_utc();
         let message: Message = serde_json::from_str(input).unwrap();
         assert_eq!(
             message,
             Message::Data {
                 data: OrderBookData {
-                    timestamp: DateTime::from_utc(expected_time, Utc),
+                    timestamp: expected_time,
                     bids: vec![Price {
                         price: 0.07315713,
                         quantity: 0.40000000,
@@ -156,6 +198,18 @@ mod unit_test {
         )
     }
 
+    #[test]
+    fn test_parse_trade() {
+        let input = r#"{"data": {"id": 229435412, "timestamp": "1651388616", "amount": 0.0125, "amount_str": "0.01250000", "price": 0.07315713, "price_str": "0.07315713", "type": 0, "microtimestamp": "1651388616274565", "buy_order_id": 1485019713925121, "sell_order_id": 1485019732701184}, "channel": "live_trades_ethbtc", "event": "trade"}"#;
+        let message: Message = serde_json::from_str(input).unwrap();
+        match message {
+            Message::Trade { data } => assert_eq!(data.side, crate::model::Side::Buy),
+            other => panic!("Expected a trade, but got {other:?}"),
+        }
+        let channel: Channel = "live_trades_ethbtc".try_into().unwrap();
+        assert_eq!(channel.channel_type, ChannelType::LiveTrades);
+    }
+
     #[test]
     fn test_parse_error() {
         let input = "{\"event\":\"bts:error\",\"channel\":\"\",\"data\":{\"code\":null,\"message\":\"Bad subscription string.\"}}";
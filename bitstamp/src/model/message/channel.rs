@@ -10,6 +10,7 @@ use parse_display::{Display, FromStr};
 #[display(style = "snake_case")]
 pub enum ChannelType {
     DetailOrderBook,
+    LiveTrades,
     // LiveOrders, // TODO: Implement other channel types
 }
 
@@ -1,7 +1,7 @@
 use parse_display::{Display, FromStr};
 
 /// Copied from <https://www.bitstamp.net/websocket/v2/>
-#[derive(Display, FromStr, PartialEq, Debug, Clone, Copy)]
+#[derive(Display, FromStr, PartialEq, Eq, Hash, Debug, Clone, Copy)]
 #[display(style = "snake_case")]
 pub enum CurrencyPair {
     Aavebtc,
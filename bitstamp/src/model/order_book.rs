@@ -13,7 +13,7 @@
 use std::time::Duration;
 
 use crate::Error;
-use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use chrono::{DateTime, Utc};
 
 use serde::{Deserialize, Serialize};
 
@@ -76,7 +76,7 @@ pub struct Price {
 
 impl From<OrderBookData> for OrderBookDataRaw {
     fn from(data: OrderBookData) -> Self {
-        let microtimestamp = (data.timestamp.timestamp_nanos() as u64) / 1000;
+        let microtimestamp = data.timestamp.timestamp_micros() as u64;
         let price_to_str = |price: &Price| {
             (
                 format!("{}", price.price),
@@ -130,11 +130,8 @@ impl TryFrom<OrderBookDataRaw> for OrderBookData {
         })?;
         let micro_secs = chrono::Duration::from_std(Duration::from_micros(duration))
             .map_err(|source| Error::encoding("read micro seconds duration", duration, source))?;
-        let epoch: NaiveDateTime = NaiveDate::from_ymd(1970, 1, 1).and_hms(0, 0, 0);
-        let dt = epoch + micro_secs;
-
         Ok(OrderBookData {
-            timestamp: DateTime::from_utc(dt, Utc),
+            timestamp: DateTime::<Utc>::UNIX_EPOCH + micro_secs,
             bids,
             asks,
         })
@@ -143,7 +140,7 @@ impl TryFrom<OrderBookDataRaw> for OrderBookData {
 
 #[cfg(test)]
 mod unit_test {
-    use chrono::{DateTime, NaiveDate, Utc};
+    use chrono::NaiveDate;
 
     use super::OrderBookData;
 
@@ -159,8 +156,10 @@ mod unit_test {
         dbg!(&data);
         // Should read: Monday, April 18, 2022 2:01:01.276311 AM UTC
         // Converted with https://www.epochconverter.com/
-        let expected_time = NaiveDate::from_ymd(2022, 04, 18).and_hms_micro(2, 1, 1, 276311);
-        let expected_time = DateTime::<Utc>::from_utc(expected_time, Utc);
+        let expected_time = NaiveDate::from_ymd_opt(2022, 4, 18)
+            .and_then(|date| date.and_hms_micro_opt(2, 1, 1, 276311))
+            .unwrap()
+            .and_utc();
         assert_eq!(&data.timestamp, &expected_time);
 
         // Make sure price and quantity are the right way around
@@ -0,0 +1,130 @@
+//! Model the trades we get from the live_trades channel
+//! Example input:
+//!
+//! {"data":
+//!   {"id":229435412,
+//!    "timestamp":"1651388616",
+//!    "amount":0.0125,
+//!    "amount_str":"0.01250000",
+//!    "price":0.07315713,
+//!    "price_str":"0.07315713",
+//!    "type":1,
+//!    "microtimestamp":"1651388616274565",
+//!    "buy_order_id":1485019713925121,
+//!    "sell_order_id":1485019732701184},
+//!   "channel":"live_trades_ethbtc",
+//!   "event":"trade"}
+
+use std::time::Duration;
+
+use crate::Error;
+use chrono::{DateTime, Utc};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize)]
+struct TradeDataRaw {
+    id: u64,
+    microtimestamp: String,
+    amount_str: String,
+    price_str: String,
+    /// 0 if the buyer took liquidity, 1 if the seller did
+    #[serde(rename = "type")]
+    side: u8,
+}
+
+/// One trade - this just models the 'data' part
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[serde(try_from = "TradeDataRaw", into = "TradeDataRaw")]
+pub struct TradeData {
+    pub id: u64,
+    pub timestamp: DateTime<Utc>,
+    pub price: f64,
+    pub amount: f64,
+    /// The side that took liquidity
+    pub side: Side,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+impl From<TradeData> for TradeDataRaw {
+    fn from(data: TradeData) -> Self {
+        TradeDataRaw {
+            id: data.id,
+            microtimestamp: format!("{}", data.timestamp.timestamp_micros()),
+            amount_str: format!("{}", data.amount),
+            price_str: format!("{}", data.price),
+            side: match data.side {
+                Side::Buy => 0,
+                Side::Sell => 1,
+            },
+        }
+    }
+}
+
+impl TryFrom<TradeDataRaw> for TradeData {
+    type Error = Error;
+
+    fn try_from(value: TradeDataRaw) -> Result<Self, Self::Error> {
+        let price = value
+            .price_str
+            .parse()
+            .map_err(|source| Error::decoding("Parse trade price", value.price_str, source))?;
+        let amount = value
+            .amount_str
+            .parse()
+            .map_err(|source| Error::decoding("Parse trade amount", value.amount_str, source))?;
+        let side = match value.side {
+            0 => Side::Buy,
+            1 => Side::Sell,
+            other => {
+                return Err(Error::decoding_general(format!(
+                    "Expected a trade type of 0 (buy) or 1 (sell), but got {other}"
+                )))
+            }
+        };
+
+        // Parse the timestamp
+        let duration = value.microtimestamp.parse().map_err(|source| {
+            Error::decoding(
+                "Parse a micro second timestamp into u64 from trade data",
+                value.microtimestamp,
+                source,
+            )
+        })?;
+        let micro_secs = chrono::Duration::from_std(Duration::from_micros(duration))
+            .map_err(|source| Error::encoding("read micro seconds duration", duration, source))?;
+        Ok(TradeData {
+            id: value.id,
+            timestamp: DateTime::<Utc>::UNIX_EPOCH + micro_secs,
+            price,
+            amount,
+            side,
+        })
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use super::{Side, TradeData};
+
+    #[test]
+    fn test_parse() {
+        let data = r#"{"id": 229435412, "timestamp": "1651388616", "amount": 0.0125, "amount_str": "0.01250000", "price": 0.07315713, "price_str": "0.07315713", "type": 1, "microtimestamp": "1651388616274565", "buy_order_id": 1485019713925121, "sell_order_id": 1485019732701184}"#;
+        let data: TradeData = serde_json::from_str(data).unwrap();
+        assert_eq!(data.id, 229435412);
+        assert_eq!(data.timestamp.timestamp_micros(), 1651388616274565);
+        assert_eq!(data.price, 0.07315713);
+        assert_eq!(data.amount, 0.0125);
+        assert_eq!(data.side, Side::Sell);
+
+        // And back again
+        let again: TradeData =
+            serde_json::from_str(&serde_json::to_string(&data).unwrap()).unwrap();
+        assert_eq!(again, data);
+    }
+}
@@ -1,25 +1,37 @@
-//! Subscribe to bitstamp order_book stream
+//! Subscribe to bitstamp order_book and live_trades streams
 
 use futures::{SinkExt, Stream, StreamExt};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use tokio_tungstenite::{connect_async, tungstenite::Message as TMessage};
+use tracing::Instrument;
 
 use crate::{
     error::Context,
     model::{ChannelType, CurrencyPair, Message},
-    Result,
+    Result, Tap,
 };
 
+/// Bitstamp's public websocket
+pub const ENDPOINT: &str = "wss://ws.bitstamp.net/";
+
 /// Subscribes to the bitstamp websocket and returns a stream of Message results
 pub async fn subscribe(
     channel_type: ChannelType,
     currency_pair: CurrencyPair,
+) -> Result<impl Stream<Item = Result<Message>>> {
+    subscribe_at(ENDPOINT, channel_type, currency_pair, None).await
+}
+
+/// `subscribe` to any bitstamp-like `endpoint`. `tap` is handed each raw frame, pings included
+pub async fn subscribe_at(
+    endpoint: &str,
+    channel_type: ChannelType,
+    currency_pair: CurrencyPair,
+    tap: Option<Tap>,
 ) -> Result<impl Stream<Item = Result<Message>>> {
     // Connect
     log::debug!("Building websocket");
-    let (mut client, _response) = connect_async("wss://ws.bitstamp.net/")
-        .await
-        .context("Connecting")?;
+    let (mut client, _response) = connect_async(endpoint).await.context("Connecting")?;
 
     // Subscribe
     let subscribe = Message::subscribe(channel_type, currency_pair)?;
@@ -29,11 +41,38 @@ pub async fn subscribe(
         .await
         .message_context(subscribe, "Sending subscribe message")?;
 
-    // Spawn a task that can respond to pings, and forward relevant messages to our queue
+    // Spawn a task that can respond to pings, and forward relevant messages to our queue. Once the
+    // queue's dropped it unsubscribes and closes the socket
     let (out_send, out_recv) = tokio::sync::mpsc::unbounded_channel();
-    tokio::spawn(async move {
-        while let Some(result) = client.next().await {
-            let result = result.context("Receiving message");
+    let connection =
+        tracing::info_span!("connection", venue = "bitstamp", instrument = %currency_pair);
+    let task = async move {
+        let mut messages = 0u64;
+        loop {
+            let result = tokio::select! {
+                result = client.next() => match result {
+                    Some(result) => result.context("Receiving message"),
+                    None => return,
+                },
+                _ = out_send.closed() => {
+                    match Message::unsubscribe(channel_type, currency_pair) {
+                        Ok(unsubscribe) => {
+                            log::debug!("Sending unsubscribe message: {unsubscribe:?}");
+                            if let Err(err) = client.send(unsubscribe).await {
+                                log::warn!("Unable to unsubscribe from bitstamp: {err:?}");
+                            }
+                        }
+                        Err(err) => log::error!("{err}"),
+                    }
+                    if let Err(err) = client.close(None).await {
+                        log::warn!("Unable to close the bitstamp socket: {err:?}");
+                    }
+                    return;
+                }
+            };
+            if let (Some(tap), Ok(frame)) = (&tap, &result) {
+                tap(frame);
+            }
             match result {
                 Ok(TMessage::Ping(data)) => {
                     log::info!("Ping: {data:?}");
@@ -42,7 +81,15 @@ pub async fn subscribe(
                     }
                 }
                 result @ Ok(TMessage::Text(_)) => {
-                    if let Err(err) = out_send.send(result.and_then(|tmsg| tmsg.try_into())) {
+                    // Books and trades are numbered on each connection, so they can be followed
+                    // through the server
+                    let message = tracing::debug_span!("message", sequence = tracing::field::Empty);
+                    let parsed = message.in_scope(|| result.and_then(|tmsg| tmsg.try_into()));
+                    if let Ok(Message::Data { .. } | Message::Trade { .. }) = &parsed {
+                        messages += 1;
+                        message.record("sequence", messages);
+                    }
+                    if let Err(err) = out_send.send(parsed) {
                         // Most likely the client has disconnected
                         log::error!("Unable to forward message to client: {err:?}");
                         return;
@@ -55,14 +102,64 @@ pub async fn subscribe(
                         return;
                     }
                 }
-                _ => unreachable!("We didn't expect a message of this type: {result:?}"),
+                // Bitstamp's closing the connection; tungstenite has already answered it
+                Ok(TMessage::Close(frame)) => {
+                    log::info!("Bitstamp closed the connection: {frame:?}");
+                    return;
+                }
+                Ok(other) => log::warn!("Unexpected message type (not text): {other:?}"),
             }
         }
-    });
+    };
+    tokio::spawn(task.instrument(connection));
 
     Ok(UnboundedReceiverStream::new(out_recv))
 }
 
+#[cfg(test)]
+mod unit_test {
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::{accept_async, tungstenite::Message as TMessage};
+
+    use super::subscribe_at;
+    use crate::model::{ChannelType, CurrencyPair, Message};
+
+    /// Frames we don't expect are skipped, and a close ends the stream
+    #[tokio::test]
+    async fn test_close() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = accept_async(stream).await.unwrap();
+            // The subscription
+            socket.next().await.unwrap().unwrap();
+            socket.send(TMessage::Pong(vec![1])).await.unwrap();
+            socket.send(TMessage::Binary(vec![2])).await.unwrap();
+            let data = r#"{"event":"data","channel":"detail_order_book_ethbtc","data":{"timestamp":"1700000000","microtimestamp":"1700000000000000","bids":[],"asks":[]}}"#;
+            socket.send(TMessage::Text(data.to_string())).await.unwrap();
+            socket.close(None).await.unwrap();
+        });
+
+        let endpoint = format!("ws://{addr}");
+        let mut messages = subscribe_at(
+            &endpoint,
+            ChannelType::DetailOrderBook,
+            CurrencyPair::Ethbtc,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            messages.next().await,
+            Some(Ok(Message::Data { .. }))
+        ));
+        assert!(messages.next().await.is_none());
+        server.await.unwrap();
+    }
+}
+
 #[cfg(test)]
 mod web_test {
     use crate::model::{ChannelType, CurrencyPair};
@@ -0,0 +1,37 @@
+use std::num::ParseFloatError;
+
+use thiserror::Error;
+use tokio_tungstenite::tungstenite::Error as WSError;
+
+#[derive(Error, Debug)]
+pub enum BybitError {
+    #[error("Unable to connect to websocket: url: \"{url}\" error: \"{error:?}\"")]
+    Connect { url: String, error: Box<WSError> },
+    #[error("We connected OK, but later got an error while trying to read a message: {0:?}")]
+    MessageError(Box<WSError>),
+    #[error("Unable to send \"{message}\" to bybit: {error:?}")]
+    Send {
+        message: String,
+        error: Box<WSError>,
+    },
+    #[error("Unable to parse json. Error: \"{error:?}\" Original: \"{original}\"")]
+    Json {
+        error: serde_json::Error,
+        original: String,
+    },
+    #[error("Unable to parse number \"{input}\": {error:?}")]
+    Number {
+        input: String,
+        error: ParseFloatError,
+    },
+    #[error("Bybit refused our \"{op}\" request: {reason}")]
+    Refused { op: String, reason: String },
+    #[error("Got a delta for {topic} before we had a snapshot to apply it to")]
+    DeltaBeforeSnapshot { topic: String },
+}
+
+impl From<WSError> for BybitError {
+    fn from(error: WSError) -> Self {
+        BybitError::MessageError(Box::new(error))
+    }
+}
@@ -0,0 +1,173 @@
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+pub mod model;
+use model::{LocalBook, Message, OrderBook, Request};
+use serde_json::de::from_str;
+use tokio::time::{interval_at, Instant};
+use tokio_stream::{wrappers::UnboundedReceiverStream, Stream};
+use tokio_tungstenite::{connect_async, tungstenite::Message as TMessage};
+use tracing::Instrument;
+
+mod error;
+pub use error::BybitError as Error;
+pub type Result<T> = std::result::Result<T, Error>;
+pub use tap::Tap;
+
+/// Bybit drops the connection if it doesn't get a ping at least this often
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Bybit's public spot websocket
+pub const ENDPOINT: &str = "wss://stream.bybit.com/v5/public/spot";
+
+/// Turn a request into a websocket message
+fn request_message(request: &Request) -> TMessage {
+    // Request only holds strings, so this can't fail
+    TMessage::Text(serde_json::to_string(request).expect("Serializing bybit request"))
+}
+
+/// Connect to bybit's spot stream, subscribe to `orderbook.{depth}.{instrument}`, and return a
+/// stream of the whole book after every snapshot or delta.
+/// `instrument` should come from bybit's instrument list, eg. "ETHBTC"
+/// `depth` is one of the depths bybit supports for spot: 1, 50 or 200
+pub async fn bybit_stream(
+    instrument: &str,
+    depth: u16,
+) -> Result<impl Stream<Item = Result<OrderBook>> + Send + 'static> {
+    bybit_stream_at(ENDPOINT, instrument, depth, None).await
+}
+
+/// `bybit_stream` from `endpoint` rather than bybit itself, passing `tap` every frame it sends
+pub async fn bybit_stream_at(
+    endpoint: &str,
+    instrument: &str,
+    depth: u16,
+    tap: Option<Tap>,
+) -> Result<impl Stream<Item = Result<OrderBook>> + Send + 'static> {
+    let url = endpoint.to_string();
+    let (mut client, _response) = connect_async(&url).await.map_err(|error| Error::Connect {
+        url,
+        error: Box::new(error),
+    })?;
+
+    // Subscribe
+    let subscribe = request_message(&Request::subscribe(format!(
+        "orderbook.{depth}.{instrument}"
+    )));
+    log::debug!("Sending subscribe message: {subscribe:?}");
+    client
+        .send(subscribe.clone())
+        .await
+        .map_err(|error| Error::Send {
+            message: subscribe.to_string(),
+            error: Box::new(error),
+        })?;
+
+    // Spawn a task that keeps the connection alive and the book up to date, and forwards the
+    // book to our queue
+    let (out_send, out_recv) = tokio::sync::mpsc::unbounded_channel();
+    let connection = tracing::info_span!("connection", venue = "bybit", instrument = instrument);
+    let task = async move {
+        let mut books = 0u64;
+        let mut book = LocalBook::default();
+        let mut heartbeat = interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+        loop {
+            let result = tokio::select! {
+                _ = heartbeat.tick() => {
+                    let ping = request_message(&Request::ping());
+                    if let Err(err) = client.send(ping).await {
+                        log::error!("Unable to send bybit ping: {err:?}");
+                    }
+                    continue;
+                }
+                result = client.next() => match result {
+                    Some(result) => result,
+                    None => return,
+                },
+                // Nobody's reading any more, so close the socket properly
+                _ = out_send.closed() => {
+                    if let Err(err) = client.close(None).await {
+                        log::warn!("Unable to close the bybit socket: {err:?}");
+                    }
+                    return;
+                }
+            };
+            if let (Some(tap), Ok(frame)) = (&tap, &result) {
+                tap(frame);
+            }
+            // Books are numbered on each connection, so they can be followed through the server
+            let message = tracing::debug_span!("message", sequence = tracing::field::Empty);
+            let to_send = match result {
+                // Incoming message is text; parse it and apply it to the book
+                Ok(TMessage::Text(msg)) => message.in_scope(|| match from_str::<Message>(&msg) {
+                    Ok(Message::OrderBook(update)) => match book.apply(update) {
+                        Ok(true) => book.to_order_book().map(Ok),
+                        Ok(false) => None,
+                        Err(err) => Some(Err(err)),
+                    },
+                    Ok(Message::Response(response)) if response.success == Some(false) => {
+                        Some(Err(Error::Refused {
+                            op: response.op,
+                            reason: response.ret_msg.unwrap_or_default(),
+                        }))
+                    }
+                    Ok(Message::Response(response)) => {
+                        log::debug!("Bybit response: {response:?}");
+                        None
+                    }
+                    Err(error) => Some(Err(Error::Json {
+                        error,
+                        original: msg,
+                    })),
+                }),
+                Ok(TMessage::Ping(data)) => {
+                    if let Err(err) = client.send(TMessage::Pong(data)).await {
+                        log::error!("Unable to bybit pong: {err:?}")
+                    }
+                    None
+                }
+                // Filter out and log warnings for other message types
+                Ok(unexpected_message) => {
+                    log::warn!("Unexpected message type (not text): {unexpected_message:?}");
+                    None
+                }
+                // Convert all errors
+                Err(err) => Some(Err(err.into())),
+            };
+            if let Some(Ok(_)) = &to_send {
+                books += 1;
+                message.record("sequence", books);
+            }
+            if let Some(to_send) = to_send {
+                if let Err(err) = out_send.send(to_send) {
+                    // Most likely the client has disconnected
+                    log::error!("Unable to forward bybit book to client: {err:?}");
+                    return;
+                }
+            }
+        }
+    };
+    tokio::spawn(task.instrument(connection));
+
+    Ok(UnboundedReceiverStream::new(out_recv))
+}
+
+#[cfg(test)]
+mod web_test {
+    use futures::StreamExt;
+
+    /// Test if we can connect to bybit and start downloading ETHBTC
+    #[tokio::test]
+    async fn test_ethbtc() {
+        pretty_env_logger::try_init().ok();
+        let mut stream = super::bybit_stream("ETHBTC", 50)
+            .await
+            .expect("Unable to connect to bybit");
+        match stream.next().await {
+            // Got a book
+            Some(Ok(first)) => dbg!(first),
+            Some(Err(err)) => panic!("First message was an error: {err:?}"),
+            None => panic!("No first message!"),
+        };
+    }
+}
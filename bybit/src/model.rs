@@ -0,0 +1,313 @@
+//! Models for bybit's v5 public orderbook topic
+//! See: <https://bybit-exchange.github.io/docs/v5/websocket/public/orderbook>
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, TimeZone, Utc};
+use ordered_float::OrderedFloat;
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+
+/// Requests we send to bybit, eg. `{"op":"subscribe","args":["orderbook.50.ETHBTC"]}`
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Request {
+    pub op: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+}
+
+impl Request {
+    /// Subscribe to the `orderbook.{depth}.{symbol}` topic
+    pub fn subscribe(topic: String) -> Request {
+        Request {
+            op: "subscribe",
+            args: vec![topic],
+        }
+    }
+
+    /// The heartbeat bybit expects every 20 seconds, or it drops the connection
+    pub fn ping() -> Request {
+        Request {
+            op: "ping",
+            args: vec![],
+        }
+    }
+}
+
+/// Everything bybit sends us on the public spot stream
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Message {
+    OrderBook(OrderBookMessage),
+    Response(Response),
+}
+
+/// Bybit's reply to a `subscribe` or `ping` request
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct Response {
+    pub op: String,
+    #[serde(default)]
+    pub success: Option<bool>,
+    #[serde(default)]
+    pub ret_msg: Option<String>,
+    #[serde(default)]
+    pub conn_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateType {
+    Snapshot,
+    Delta,
+}
+
+/// One push on the `orderbook.{depth}.{symbol}` topic
+/// {"topic":"orderbook.50.ETHBTC","type":"snapshot","ts":1672304484978,
+///  "data":{"s":"ETHBTC","b":[["0.0671","1.2"]],"a":[["0.0672","0.4"]],"u":18521288,"seq":7961638724},
+///  "cts":1672304484976}
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct OrderBookMessage {
+    pub topic: String,
+    #[serde(rename = "type")]
+    pub update_type: UpdateType,
+    /// When bybit's system generated the data (ms)
+    pub ts: i64,
+    pub data: OrderBookUpdate,
+}
+
+/// The `data` part of an orderbook push. Prices and sizes are left as strings until they're
+/// applied to the book
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct OrderBookUpdate {
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "b")]
+    pub bids: Vec<(String, String)>,
+    #[serde(rename = "a")]
+    pub asks: Vec<(String, String)>,
+    /// Update id; `1` means bybit restarted and this is a fresh snapshot
+    #[serde(rename = "u")]
+    pub update_id: u64,
+    /// Cross sequence; lets you compare the different depth topics for the same symbol
+    pub seq: u64,
+}
+
+/// The current view of the book, sent to the client after every snapshot or delta
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderBook {
+    pub timestamp: DateTime<Utc>,
+    pub update_id: u64,
+    pub seq: u64,
+    /// Best (highest) bid first
+    pub bids: Vec<Price>,
+    /// Best (lowest) ask first
+    pub asks: Vec<Price>,
+}
+
+/// The price and quantity of a bid or ask
+#[derive(Debug, Clone, PartialEq)]
+pub struct Price {
+    pub price: f64,
+    pub quantity: f64,
+}
+
+/// Keeps the book up to date as snapshots and deltas come in
+#[derive(Default, Debug)]
+pub struct LocalBook {
+    update_id: Option<u64>,
+    seq: u64,
+    timestamp: i64,
+    bids: BTreeMap<OrderedFloat<f64>, f64>,
+    asks: BTreeMap<OrderedFloat<f64>, f64>,
+}
+
+impl LocalBook {
+    /// Apply a snapshot or delta. Returns false if the message was stale and ignored
+    pub fn apply(&mut self, message: OrderBookMessage) -> Result<bool> {
+        let OrderBookMessage {
+            topic,
+            update_type,
+            ts,
+            data,
+        } = message;
+        // A `u` of 1 is a snapshot sent after a bybit service restart, whatever `type` says
+        let is_snapshot = update_type == UpdateType::Snapshot || data.update_id == 1;
+        if is_snapshot {
+            self.bids.clear();
+            self.asks.clear();
+        } else {
+            match self.update_id {
+                None => return Err(Error::DeltaBeforeSnapshot { topic }),
+                Some(last) if data.update_id <= last => {
+                    log::debug!(
+                        "Ignoring stale delta {} <= {last} on {topic}",
+                        data.update_id
+                    );
+                    return Ok(false);
+                }
+                Some(_) => (),
+            }
+        }
+        apply_levels(&mut self.bids, data.bids)?;
+        apply_levels(&mut self.asks, data.asks)?;
+        self.update_id = Some(data.update_id);
+        self.seq = data.seq;
+        self.timestamp = ts;
+        Ok(true)
+    }
+
+    /// Render the current book; only valid once a snapshot has been applied
+    pub fn to_order_book(&self) -> Option<OrderBook> {
+        let update_id = self.update_id?;
+        let to_price = |(price, quantity): (&OrderedFloat<f64>, &f64)| Price {
+            price: price.into_inner(),
+            quantity: *quantity,
+        };
+        Some(OrderBook {
+            timestamp: Utc
+                .timestamp_millis_opt(self.timestamp)
+                .single()
+                .unwrap_or_else(Utc::now),
+            update_id,
+            seq: self.seq,
+            bids: self.bids.iter().rev().map(to_price).collect(),
+            asks: self.asks.iter().map(to_price).collect(),
+        })
+    }
+}
+
+/// Insert, update, or (for a size of zero) delete each level
+fn apply_levels(
+    side: &mut BTreeMap<OrderedFloat<f64>, f64>,
+    levels: Vec<(String, String)>,
+) -> Result<()> {
+    let parse = |input: String| -> Result<f64> {
+        input
+            .parse()
+            .map_err(|error| Error::Number { input, error })
+    };
+    for (price, quantity) in levels {
+        let price = OrderedFloat(parse(price)?);
+        let quantity = parse(quantity)?;
+        if quantity == 0.0 {
+            side.remove(&price);
+        } else {
+            side.insert(price, quantity);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod unit_test {
+    use super::{LocalBook, Message, OrderBookMessage, Price, Request, UpdateType};
+    use crate::Error;
+    use serde_json::from_str;
+
+    const SNAPSHOT: &str = r#"{"topic":"orderbook.50.ETHBTC","ts":1687940967466,"type":"snapshot","data":{"s":"ETHBTC","b":[["0.06710","1.5"],["0.06709","2.0"]],"a":[["0.06712","0.3"],["0.06713","4.0"]],"u":100,"seq":5000},"cts":1687940967464}"#;
+
+    fn delta(update_id: u64, bids: &str, asks: &str) -> OrderBookMessage {
+        let input = format!(
+            r#"{{"topic":"orderbook.50.ETHBTC","ts":1687940967500,"type":"delta","data":{{"s":"ETHBTC","b":{bids},"a":{asks},"u":{update_id},"seq":5001}},"cts":1687940967490}}"#
+        );
+        from_str(&input).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let message: Message = from_str(SNAPSHOT).unwrap();
+        let book = match message {
+            Message::OrderBook(book) => book,
+            other => panic!("Expected an orderbook message: {other:?}"),
+        };
+        assert_eq!(book.update_type, UpdateType::Snapshot);
+        assert_eq!(book.data.update_id, 100);
+        assert_eq!(book.data.seq, 5000);
+        assert_eq!(
+            book.data.bids[0],
+            ("0.06710".to_string(), "1.5".to_string())
+        );
+
+        let pong = r#"{"success":true,"ret_msg":"pong","conn_id":"0970e817-426e-429a-a679-ff7f55e0b16a","op":"ping"}"#;
+        match from_str::<Message>(pong).unwrap() {
+            Message::Response(response) => {
+                assert_eq!(response.op, "ping");
+                assert_eq!(response.ret_msg.as_deref(), Some("pong"));
+            }
+            other => panic!("Expected a response: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_render_requests() {
+        let subscribe =
+            serde_json::to_string(&Request::subscribe("orderbook.50.ETHBTC".to_string())).unwrap();
+        assert_eq!(
+            subscribe,
+            r#"{"op":"subscribe","args":["orderbook.50.ETHBTC"]}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&Request::ping()).unwrap(),
+            r#"{"op":"ping"}"#
+        );
+    }
+
+    #[test]
+    fn test_snapshot_and_delta() {
+        let mut book = LocalBook::default();
+        // Deltas are useless until we have a snapshot
+        assert!(matches!(
+            book.apply(delta(101, "[]", "[]")),
+            Err(Error::DeltaBeforeSnapshot { .. })
+        ));
+        assert!(book.to_order_book().is_none());
+
+        assert!(book.apply(from_str(SNAPSHOT).unwrap()).unwrap());
+        // Remove the best bid, add a new ask, and change an existing one
+        assert!(book
+            .apply(delta(
+                101,
+                r#"[["0.06710","0"]]"#,
+                r#"[["0.06711","0.1"],["0.06713","1.0"]]"#
+            ))
+            .unwrap());
+        // Replayed deltas are ignored
+        assert!(!book
+            .apply(delta(101, r#"[["0.06700","9"]]"#, "[]"))
+            .unwrap());
+
+        let got = book.to_order_book().unwrap();
+        assert_eq!(got.update_id, 101);
+        assert_eq!(got.seq, 5001);
+        assert_eq!(
+            got.bids,
+            vec![Price {
+                price: 0.06709,
+                quantity: 2.0
+            }]
+        );
+        assert_eq!(
+            got.asks,
+            vec![
+                Price {
+                    price: 0.06711,
+                    quantity: 0.1
+                },
+                Price {
+                    price: 0.06712,
+                    quantity: 0.3
+                },
+                Price {
+                    price: 0.06713,
+                    quantity: 1.0
+                },
+            ]
+        );
+
+        // u == 1 means bybit restarted; treat it as a fresh snapshot even if it says delta
+        assert!(book.apply(delta(1, r#"[["0.05","1"]]"#, "[]")).unwrap());
+        let got = book.to_order_book().unwrap();
+        assert_eq!(got.bids.len(), 1);
+        assert!(got.asks.is_empty());
+    }
+}
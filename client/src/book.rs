@@ -0,0 +1,369 @@
+//! Rebuilds the book from a `BookUpdates` stream
+use std::{cmp::Ordering, collections::HashMap};
+
+use futures::{stream, Stream};
+use thiserror::Error;
+use tonic::{transport::Channel, Status, Streaming};
+
+use crate::api::{
+    book_update::Update, orderbook_aggregator_client::OrderbookAggregatorClient, Action,
+    BookUpdate, Level, LevelChange, Side, Summary, SummaryRequest, VenueStatus,
+};
+
+/// Why an update couldn't be applied. After any of these, the book needs a new snapshot
+#[derive(Error, Debug, PartialEq)]
+pub enum BookError {
+    #[error("Update {got} arrived before any snapshot")]
+    NoSnapshot { got: u64 },
+    #[error("Expected update {expected} but got {got}")]
+    Gap { expected: u64, got: u64 },
+    #[error("Checksum mismatch at update {sequence}: server sent {expected}, we calculated {calculated}")]
+    Checksum {
+        sequence: u64,
+        expected: u32,
+        calculated: u32,
+    },
+    #[error("Update {sequence} is malformed: {reason}")]
+    Malformed { sequence: u64, reason: &'static str },
+}
+
+/// One side of the book: the level at each (exchange, price)
+type BookSide = HashMap<(String, u64), Level>;
+
+/// A level's place in the book
+fn key(level: &Level) -> (String, u64) {
+    (level.exchange.clone(), level.price.to_bits())
+}
+
+/// Best price first, then by exchange; the order the checksum is calculated in
+fn sorted(side: &BookSide, which: Side) -> Vec<Level> {
+    let mut levels: Vec<Level> = side.values().cloned().collect();
+    levels.sort_by(|a, b| {
+        let by_price = a.price.partial_cmp(&b.price).unwrap_or(Ordering::Equal);
+        let by_price = match which {
+            Side::Bid => by_price.reverse(),
+            Side::Ask => by_price,
+        };
+        by_price.then_with(|| a.exchange.cmp(&b.exchange))
+    });
+    levels
+}
+
+fn side_checksum(levels: &[Level]) -> String {
+    levels
+        .iter()
+        .map(|level| format!("{}:{}:{}", level.exchange, level.price, level.amount))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The book as we've rebuilt it so far
+#[derive(Debug, Default, Clone)]
+pub struct LocalBook {
+    /// The last update applied; None until we've had a snapshot
+    sequence: Option<u64>,
+    bids: BookSide,
+    asks: BookSide,
+    spread: f64,
+    venues: Vec<VenueStatus>,
+    merged_time_us: i64,
+    sent_time_us: i64,
+}
+
+impl LocalBook {
+    /// The last update applied, if we've had a snapshot
+    pub fn sequence(&self) -> Option<u64> {
+        self.sequence
+    }
+
+    /// Apply the next update from the server. On an error the book is cleared, and the caller
+    /// should call `BookUpdates` again for a new snapshot
+    pub fn apply(&mut self, update: BookUpdate) -> Result<(), BookError> {
+        let result = self.try_apply(update);
+        if result.is_err() {
+            *self = LocalBook::default();
+        }
+        result
+    }
+
+    fn try_apply(&mut self, update: BookUpdate) -> Result<(), BookError> {
+        let sequence = update.sequence;
+        match update.update {
+            Some(Update::Snapshot(summary)) => {
+                *self = LocalBook::default();
+                for level in summary.bids {
+                    self.bids.insert(key(&level), level);
+                }
+                for level in summary.asks {
+                    self.asks.insert(key(&level), level);
+                }
+                self.spread = summary.spread;
+                self.venues = summary.venues;
+                self.merged_time_us = summary.merged_time_us;
+                self.sent_time_us = summary.sent_time_us;
+            }
+            Some(Update::Delta(delta)) => {
+                let last = self
+                    .sequence
+                    .ok_or(BookError::NoSnapshot { got: sequence })?;
+                if sequence != last + 1 {
+                    return Err(BookError::Gap {
+                        expected: last + 1,
+                        got: sequence,
+                    });
+                }
+                for change in delta.changes {
+                    self.apply_change(sequence, change)?;
+                }
+                self.spread = delta.spread;
+                self.venues = delta.venues;
+                self.merged_time_us = delta.merged_time_us;
+                self.sent_time_us = delta.sent_time_us;
+            }
+            None => {
+                return Err(BookError::Malformed {
+                    sequence,
+                    reason: "no snapshot or delta",
+                })
+            }
+        }
+        self.sequence = Some(sequence);
+        if let Some(expected) = update.checksum {
+            let calculated = self.checksum();
+            if calculated != expected.crc32 {
+                return Err(BookError::Checksum {
+                    sequence,
+                    expected: expected.crc32,
+                    calculated,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_change(&mut self, sequence: u64, change: LevelChange) -> Result<(), BookError> {
+        let malformed = |reason| BookError::Malformed { sequence, reason };
+        let side = match Side::from_i32(change.side).ok_or_else(|| malformed("unknown side"))? {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        };
+        let level = change
+            .level
+            .ok_or_else(|| malformed("change without a level"))?;
+        match Action::from_i32(change.action).ok_or_else(|| malformed("unknown action"))? {
+            Action::Insert | Action::Update => {
+                side.insert(key(&level), level);
+            }
+            Action::Delete => {
+                side.remove(&key(&level));
+            }
+        }
+        Ok(())
+    }
+
+    /// The checksum of the book, as described in orderbook.proto
+    pub fn checksum(&self) -> u32 {
+        let book = format!(
+            "{}|{}",
+            side_checksum(&sorted(&self.bids, Side::Bid)),
+            side_checksum(&sorted(&self.asks, Side::Ask))
+        );
+        crc32fast::hash(book.as_bytes())
+    }
+
+    /// The book as a `Summary`. Levels at the same price are ordered by exchange
+    pub fn summary(&self) -> Summary {
+        Summary {
+            spread: self.spread,
+            bids: sorted(&self.bids, Side::Bid),
+            asks: sorted(&self.asks, Side::Ask),
+            venues: self.venues.clone(),
+            merged_time_us: self.merged_time_us,
+            sent_time_us: self.sent_time_us,
+        }
+    }
+}
+
+/// Subscribe to `BookUpdates` and rebuild the book, yielding it after every update. If we miss an
+/// update or a checksum doesn't match, we resubscribe for a new snapshot
+pub fn book_stream(
+    client: OrderbookAggregatorClient<Channel>,
+    request: SummaryRequest,
+) -> impl Stream<Item = Result<Summary, Status>> {
+    let state = (
+        client,
+        request,
+        None::<Streaming<BookUpdate>>,
+        LocalBook::default(),
+    );
+    stream::unfold(Some(state), |state| async move {
+        let (mut client, request, mut updates, mut book) = state?;
+        loop {
+            let stream = match &mut updates {
+                Some(stream) => stream,
+                None => match client.book_updates(request.clone()).await {
+                    Ok(response) => updates.insert(response.into_inner()),
+                    Err(status) => return Some((Err(status), None)),
+                },
+            };
+            match stream.message().await {
+                Ok(Some(update)) => match book.apply(update) {
+                    Ok(()) => {
+                        let summary = book.summary();
+                        return Some((Ok(summary), Some((client, request, updates, book))));
+                    }
+                    Err(err) => {
+                        log::warn!("Resyncing book: {err}");
+                        updates = None;
+                    }
+                },
+                Ok(None) => return None,
+                Err(status) => return Some((Err(status), None)),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod unit_test {
+    use super::{BookError, LocalBook};
+    use crate::api::{
+        book_update::Update, Action, BookDelta, BookUpdate, Checksum, Level, LevelChange, Side,
+        Summary,
+    };
+
+    fn level(exchange: &str, price: f64, amount: f64) -> Level {
+        Level {
+            exchange: exchange.to_string(),
+            price,
+            amount,
+            ..Level::default()
+        }
+    }
+
+    fn change(side: Side, action: Action, level: Level) -> LevelChange {
+        LevelChange {
+            side: side as i32,
+            action: action as i32,
+            level: Some(level),
+        }
+    }
+
+    fn delta(sequence: u64, changes: Vec<LevelChange>) -> BookUpdate {
+        BookUpdate {
+            sequence,
+            update: Some(Update::Delta(BookDelta {
+                changes,
+                ..BookDelta::default()
+            })),
+            checksum: None,
+        }
+    }
+
+    /// The example in orderbook.proto, so our formatting can't drift from the server's
+    #[test]
+    fn test_checksum_vector() {
+        let mut book = LocalBook::default();
+        let snapshot = Summary {
+            bids: vec![level("binance", 0.05, 1.5)],
+            asks: vec![level("bitstamp", 1e-7, 2.0)],
+            ..Summary::default()
+        };
+        book.apply(BookUpdate {
+            sequence: 1,
+            update: Some(Update::Snapshot(snapshot)),
+            checksum: None,
+        })
+        .unwrap();
+        assert_eq!(book.checksum(), 1290291321);
+    }
+
+    #[test]
+    fn test_rebuild() {
+        let mut book = LocalBook::default();
+        assert_eq!(
+            book.apply(delta(1, vec![])),
+            Err(BookError::NoSnapshot { got: 1 })
+        );
+
+        let snapshot = Summary {
+            spread: -1.0,
+            bids: vec![level("binance", 1.0, 5.0), level("bitstamp", 0.9, 1.0)],
+            asks: vec![level("binance", 2.0, 5.0)],
+            ..Summary::default()
+        };
+        book.apply(BookUpdate {
+            sequence: 1,
+            update: Some(Update::Snapshot(snapshot.clone())),
+            checksum: None,
+        })
+        .unwrap();
+        assert_eq!(book.summary(), snapshot);
+
+        book.apply(delta(
+            2,
+            vec![
+                change(Side::Bid, Action::Update, level("binance", 1.0, 4.0)),
+                change(Side::Bid, Action::Delete, level("bitstamp", 0.9, 0.0)),
+                change(Side::Bid, Action::Insert, level("bybit", 1.0, 1.0)),
+                change(Side::Ask, Action::Insert, level("bybit", 1.9, 1.0)),
+            ],
+        ))
+        .unwrap();
+        let summary = book.summary();
+        assert_eq!(book.sequence(), Some(2));
+        assert_eq!(
+            summary.bids,
+            vec![level("binance", 1.0, 4.0), level("bybit", 1.0, 1.0)]
+        );
+        assert_eq!(
+            summary.asks,
+            vec![level("bybit", 1.9, 1.0), level("binance", 2.0, 5.0)]
+        );
+        assert_eq!(
+            book.checksum(),
+            crc32fast::hash(b"binance:1:4,bybit:1:1|bybit:1.9:1,binance:2:5")
+        );
+    }
+
+    #[test]
+    fn test_gap_and_checksum() {
+        let mut book = LocalBook::default();
+        let snapshot = BookUpdate {
+            sequence: 1,
+            update: Some(Update::Snapshot(Summary::default())),
+            checksum: Some(Checksum {
+                crc32: crc32fast::hash(b"|"),
+            }),
+        };
+        book.apply(snapshot.clone()).unwrap();
+        assert_eq!(
+            book.apply(delta(3, vec![])),
+            Err(BookError::Gap {
+                expected: 2,
+                got: 3
+            })
+        );
+        // The book needs a new snapshot after that
+        assert_eq!(book.sequence(), None);
+
+        book.apply(snapshot).unwrap();
+        let mut bad = delta(
+            2,
+            vec![change(
+                Side::Bid,
+                Action::Insert,
+                level("binance", 1.0, 1.0),
+            )],
+        );
+        bad.checksum = Some(Checksum { crc32: 42 });
+        assert!(matches!(
+            book.apply(bad),
+            Err(BookError::Checksum {
+                sequence: 2,
+                expected: 42,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,244 @@
+//! How long summaries take to reach us, stage by stage, from the times the server puts on them.
+//! The stages that cross from one machine to another are only as accurate as the two clocks are
+//! in sync
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::api::Summary;
+
+/// How many of each stage's latest latencies the percentiles are taken over
+const WINDOW: usize = 10_000;
+
+/// A stretch of the way from the exchange to us
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stage {
+    /// From the exchange's time on a book to the server receiving it
+    ExchangeToServer,
+    /// From the server receiving a book to merging it
+    Merge,
+    /// From merging a summary to sending it
+    Send,
+    /// From the server sending a summary to us receiving it
+    ServerToClient,
+    /// The whole way, from the exchange's time on a book to us receiving it
+    ExchangeToClient,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 5] = [
+        Stage::ExchangeToServer,
+        Stage::Merge,
+        Stage::Send,
+        Stage::ServerToClient,
+        Stage::ExchangeToClient,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Stage::ExchangeToServer => "exchange→server",
+            Stage::Merge => "merge",
+            Stage::Send => "send",
+            Stage::ServerToClient => "server→client",
+            Stage::ExchangeToClient => "exchange→client",
+        }
+    }
+}
+
+/// The time now in Unix microseconds, like the server's times
+pub fn now_us() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros() as i64)
+}
+
+/// The latest `WINDOW` latencies of a stage, in the order they came and sorted
+#[derive(Debug, Default)]
+struct Window {
+    latest: VecDeque<i64>,
+    sorted: Vec<i64>,
+}
+
+impl Window {
+    fn push(&mut self, sample: i64) {
+        if self.latest.len() == WINDOW {
+            if let Some(oldest) = self.latest.pop_front() {
+                // It's in there, as everything in `latest` is
+                if let Ok(index) = self.sorted.binary_search(&oldest) {
+                    self.sorted.remove(index);
+                }
+            }
+        }
+        self.latest.push_back(sample);
+        let index = self.sorted.partition_point(|&other| other <= sample);
+        self.sorted.insert(index, sample);
+    }
+}
+
+/// The latest latencies, in microseconds, by stage
+#[derive(Debug, Default)]
+pub struct Latencies {
+    samples: HashMap<Stage, Window>,
+    /// When the server received the last book we counted from each venue. A venue's book stays in
+    /// the summaries until it sends another, and should only be counted once
+    counted: HashMap<String, i64>,
+}
+
+impl Latencies {
+    fn add(&mut self, stage: Stage, from_us: i64, to_us: i64) {
+        // 0 is a time the server doesn't know
+        if from_us > 0 && to_us > 0 {
+            self.samples.entry(stage).or_default().push(to_us - from_us);
+        }
+    }
+
+    /// Count `summary`, which we received at `received_us`, and any venue books new in it
+    pub fn record(&mut self, summary: &Summary, received_us: i64) {
+        for venue in &summary.venues {
+            let received = venue.received_time_us;
+            if venue.stale
+                || self.counted.insert(venue.exchange.clone(), received) == Some(received)
+            {
+                continue;
+            }
+            self.add(Stage::ExchangeToServer, venue.exchange_time_us, received);
+            self.add(Stage::Merge, received, summary.merged_time_us);
+            self.add(Stage::ExchangeToClient, venue.exchange_time_us, received_us);
+        }
+        self.add(Stage::Send, summary.merged_time_us, summary.sent_time_us);
+        self.add(Stage::ServerToClient, summary.sent_time_us, received_us);
+    }
+
+    /// How many latencies we have for `stage`, up to the latest `WINDOW`
+    pub fn count(&self, stage: Stage) -> usize {
+        self.samples
+            .get(&stage)
+            .map_or(0, |window| window.sorted.len())
+    }
+
+    /// The nearest-rank `percentile` (0 to 100) of `stage`'s latest latencies, or None if there
+    /// are none
+    pub fn percentile(&self, stage: Stage, percentile: f64) -> Option<i64> {
+        let samples = &self.samples.get(&stage)?.sorted;
+        let rank = (percentile / 100.0 * samples.len() as f64).ceil() as usize;
+        samples.get(rank.saturating_sub(1)).copied()
+    }
+}
+
+/// A line per stage, with the median, 90th and 99th percentiles, and the worst, in milliseconds
+impl fmt::Display for Latencies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for stage in Stage::ALL {
+            write!(f, "{:<16} n={:<7}", stage.name(), self.count(stage))?;
+            for (name, percentile) in [("p50", 50.0), ("p90", 90.0), ("p99", 99.0), ("max", 100.0)]
+            {
+                match self.percentile(stage, percentile) {
+                    Some(us) => write!(f, " {name}={:>8.3}ms", us as f64 / 1000.0)?,
+                    None => write!(f, " {name}={:>10}", "-")?,
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use super::{Latencies, Stage, WINDOW};
+    use crate::api::{Summary, VenueStatus};
+
+    fn venue(exchange: &str, exchange_time_us: i64, received_time_us: i64) -> VenueStatus {
+        VenueStatus {
+            exchange: exchange.to_string(),
+            exchange_time_us,
+            received_time_us,
+            ..VenueStatus::default()
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let mut latencies = Latencies::default();
+        // bitstamp's book arrives; binance doesn't say when its book happened
+        let first = Summary {
+            venues: vec![venue("binance", 0, 500), venue("bitstamp", 1_000, 1_200)],
+            merged_time_us: 1_300,
+            sent_time_us: 1_350,
+            ..Summary::default()
+        };
+        latencies.record(&first, 2_000);
+        // Then only binance's changes
+        let second = Summary {
+            venues: vec![venue("binance", 0, 2_500), venue("bitstamp", 1_000, 1_200)],
+            merged_time_us: 2_600,
+            sent_time_us: 2_700,
+            ..Summary::default()
+        };
+        latencies.record(&second, 2_900);
+
+        assert_eq!(latencies.count(Stage::ExchangeToServer), 1);
+        assert_eq!(
+            latencies.percentile(Stage::ExchangeToServer, 50.0),
+            Some(200)
+        );
+        assert_eq!(
+            latencies.percentile(Stage::ExchangeToClient, 50.0),
+            Some(1_000)
+        );
+        // Both of binance's books and bitstamp's one
+        assert_eq!(latencies.count(Stage::Merge), 3);
+        assert_eq!(latencies.percentile(Stage::Merge, 100.0), Some(800));
+        assert_eq!(latencies.percentile(Stage::Send, 50.0), Some(50));
+        assert_eq!(latencies.percentile(Stage::ServerToClient, 50.0), Some(200));
+        assert_eq!(
+            latencies.percentile(Stage::ServerToClient, 100.0),
+            Some(650)
+        );
+
+        // Stale venues aren't in the merged book
+        let mut stale = venue("bybit", 1_000, 1_100);
+        stale.stale = true;
+        latencies.record(
+            &Summary {
+                venues: vec![stale],
+                ..Summary::default()
+            },
+            3_000,
+        );
+        assert_eq!(latencies.count(Stage::Merge), 3);
+        assert!(latencies.to_string().contains("exchange→client"));
+    }
+
+    #[test]
+    fn test_percentile() {
+        let mut latencies = Latencies::default();
+        assert_eq!(latencies.percentile(Stage::Send, 50.0), None);
+        for us in (1..=100).rev() {
+            latencies.add(Stage::Send, 0, 0);
+            latencies.add(Stage::Send, 1, us + 1);
+        }
+        assert_eq!(latencies.count(Stage::Send), 100);
+        assert_eq!(latencies.percentile(Stage::Send, 0.0), Some(1));
+        assert_eq!(latencies.percentile(Stage::Send, 50.0), Some(50));
+        assert_eq!(latencies.percentile(Stage::Send, 99.0), Some(99));
+        assert_eq!(latencies.percentile(Stage::Send, 100.0), Some(100));
+    }
+
+    #[test]
+    fn test_window() {
+        let mut latencies = Latencies::default();
+        // The oldest are the slowest, and they're dropped
+        for us in (1..=WINDOW as i64 + 500).rev() {
+            latencies.add(Stage::Send, 1, us + 1);
+        }
+        assert_eq!(latencies.count(Stage::Send), WINDOW);
+        assert_eq!(
+            latencies.percentile(Stage::Send, 100.0),
+            Some(WINDOW as i64)
+        );
+        assert_eq!(latencies.percentile(Stage::Send, 0.0), Some(1));
+    }
+}
@@ -1 +1,3 @@
 pub mod api;
+pub mod book;
+pub mod latency;
@@ -1,17 +1,128 @@
-use client::api::{orderbook_aggregator_client::OrderbookAggregatorClient, Empty};
-use tonic::Request;
+use std::{path::PathBuf, time::Duration};
+
+use clap::Parser;
+use client::{
+    api::{orderbook_aggregator_client::OrderbookAggregatorClient, SummaryRequest},
+    latency::{now_us, Latencies},
+};
+use tonic::{
+    metadata::{Ascii, MetadataValue},
+    service::Interceptor,
+    transport::{Certificate, Channel, ClientTlsConfig, Identity},
+    Request, Status,
+};
+
+/// How often latencies are printed
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Streams the merged order book from the server
+#[derive(Parser, Debug)]
+struct Args {
+    /// The instrument, eg. btcusd; the server's default if not given
+    instrument: Option<String>,
+    /// Levels per side; the server's default if not given
+    depth: Option<u32>,
+    /// Group levels into ticks of this size, eg. 0.00001; not grouped if not given
+    #[arg(long)]
+    tick: Option<String>,
+    /// The server. Use https:// to connect over TLS
+    #[arg(long, env = "ORDERBOOK_URL", default_value = "http://127.0.0.1:8000")]
+    url: String,
+    /// The CA to check the server's certificate against, in PEM, rather than the system's
+    #[arg(long, env = "ORDERBOOK_TLS_CA")]
+    tls_ca: Option<PathBuf>,
+    /// Our certificate, in PEM, for servers that require clients to present one
+    #[arg(long, env = "ORDERBOOK_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// Our certificate's private key, in PEM
+    #[arg(long, env = "ORDERBOOK_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// The name the server's certificate should have, if it's not the host in the url
+    #[arg(long, env = "ORDERBOOK_TLS_DOMAIN")]
+    tls_domain: Option<String>,
+    /// Our API key, for servers that need one
+    #[arg(long, env = "ORDERBOOK_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+    /// Rather than printing each summary, print how long each stage from the exchange to us is
+    /// taking, every 10 seconds
+    #[arg(long)]
+    latency: bool,
+}
+
+fn read(path: &PathBuf) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|err| panic!("Unable to read {path:?}: {err}"))
+}
+
+impl Args {
+    fn tls(&self) -> ClientTlsConfig {
+        let mut tls = ClientTlsConfig::new();
+        if let Some(ca) = &self.tls_ca {
+            tls = tls.ca_certificate(Certificate::from_pem(read(ca)));
+        }
+        if let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) {
+            tls = tls.identity(Identity::from_pem(read(cert), read(key)));
+        }
+        if let Some(domain) = &self.tls_domain {
+            tls = tls.domain_name(domain);
+        }
+        tls
+    }
+}
+
+/// Sends our API key, if we have one, with every call
+struct ApiKey(Option<MetadataValue<Ascii>>);
+
+impl Interceptor for ApiKey {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(authorization) = &self.0 {
+            request
+                .metadata_mut()
+                .insert("authorization", authorization.clone());
+        }
+        Ok(request)
+    }
+}
 
 #[tokio::main]
 async fn main() {
-    let mut client = OrderbookAggregatorClient::connect("ws://127.0.0.1:8000")
-        .await
-        .expect("connect");
+    let args = Args::parse();
+    let request = SummaryRequest {
+        instrument: args.instrument.clone().unwrap_or_default(),
+        depth: args.depth.unwrap_or_default(),
+        tick_size: args.tick.clone().unwrap_or_default(),
+        ..SummaryRequest::default()
+    };
+    let mut endpoint = Channel::from_shared(args.url.clone()).expect("url");
+    if args.url.starts_with("https://") {
+        endpoint = endpoint.tls_config(args.tls()).expect("TLS config");
+    }
+    let channel = endpoint.connect().await.expect("connect");
+    let authorization: Option<MetadataValue<Ascii>> = args
+        .api_key
+        .as_ref()
+        .map(|key| format!("Bearer {key}").parse().expect("API key"));
+    let mut client = OrderbookAggregatorClient::with_interceptor(channel, ApiKey(authorization));
     let mut stream = client
-        .book_summary(Request::new(Empty {}))
+        .book_summary(Request::new(request))
         .await
         .expect("Getting stream")
         .into_inner();
-    while let Some(summary) = stream.message().await.expect("next message") {
-        println!("{summary:?}");
+    let mut latencies = Latencies::default();
+    let mut reports = tokio::time::interval_at(
+        tokio::time::Instant::now() + REPORT_INTERVAL,
+        REPORT_INTERVAL,
+    );
+    loop {
+        tokio::select! {
+            message = stream.message() => match message.expect("next message") {
+                Some(summary) if args.latency => latencies.record(&summary, now_us()),
+                Some(summary) => println!("{summary:?}"),
+                None => break,
+            },
+            _ = reports.tick(), if args.latency => println!("{latencies}"),
+        }
+    }
+    if args.latency {
+        println!("{latencies}");
     }
 }
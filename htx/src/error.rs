@@ -0,0 +1,32 @@
+use thiserror::Error;
+use tokio_tungstenite::tungstenite::Error as WSError;
+
+#[derive(Error, Debug)]
+pub enum HtxError {
+    #[error("Unable to connect to websocket: url: \"{url}\" error: \"{error:?}\"")]
+    Connect { url: String, error: Box<WSError> },
+    #[error("We connected OK, but later got an error while trying to read a message: {0:?}")]
+    MessageError(Box<WSError>),
+    #[error("Unable to send \"{message}\" to htx: {error:?}")]
+    Send {
+        message: String,
+        error: Box<WSError>,
+    },
+    #[error("Unable to gunzip a binary frame: {0:?}")]
+    Decompress(#[from] std::io::Error),
+    #[error("Unable to parse json. Error: \"{error:?}\" Original: \"{original}\"")]
+    Json {
+        error: serde_json::Error,
+        original: String,
+    },
+    #[error("HTX returned an error. Code: \"{code}\" Message: \"{msg}\"")]
+    Server { code: String, msg: String },
+    #[error("Gap in the incremental feed: expected prevSeqNum {expected}, got {got}")]
+    SequenceGap { expected: u64, got: u64 },
+}
+
+impl From<WSError> for HtxError {
+    fn from(error: WSError) -> Self {
+        HtxError::MessageError(Box::new(error))
+    }
+}
@@ -0,0 +1,359 @@
+use futures::{SinkExt, StreamExt};
+pub mod model;
+use model::{Book, MbpBook, Message, Request};
+use tokio_stream::{wrappers::UnboundedReceiverStream, Stream};
+use tokio_tungstenite::{connect_async, tungstenite::Message as TMessage};
+use tracing::Instrument;
+
+mod error;
+pub use error::HtxError as Error;
+pub type Result<T> = std::result::Result<T, Error>;
+pub use tap::Tap;
+
+/// HTX's public websocket for `depth.step0`
+pub const ENDPOINT: &str = "wss://api.huobi.pro/ws";
+/// HTX's public websocket for the MBP incremental feed
+pub const MBP_ENDPOINT: &str = "wss://api.huobi.pro/feed";
+
+/// Turn a request into a websocket message
+fn request_message(request: &Request) -> TMessage {
+    // Request only holds strings and numbers, so this can't fail
+    TMessage::Text(serde_json::to_string(request).expect("Serializing htx request"))
+}
+
+/// Which feed we're reading, and the state it needs
+enum Feed {
+    /// `market.$symbol.depth.step0`: every push is a whole book
+    Depth,
+    /// `market.$symbol.mbp.$levels`: incremental updates on top of a snapshot we `req`
+    Mbp { topic: String, book: MbpBook },
+}
+
+impl Feed {
+    /// Handle a push or reply. Returns the book to forward (if any) and a request to send (if any)
+    fn handle(&mut self, message: Message) -> (Option<Result<Book>>, Option<Request>) {
+        match (self, message) {
+            (_, Message::Ping { ping }) => (None, Some(Request::Pong { pong: ping })),
+            (_, Message::Response(response)) if response.status != "ok" => (
+                Some(Err(Error::Server {
+                    code: response.err_code.unwrap_or_default(),
+                    msg: response.err_msg.unwrap_or_default(),
+                })),
+                None,
+            ),
+            (Feed::Depth, Message::Push { ts, tick, .. }) => {
+                (Some(Ok(Book::from_depth(ts, tick))), None)
+            }
+            (Feed::Mbp { topic, book }, Message::Push { ts, tick, .. }) => {
+                match book.apply_update(ts, tick) {
+                    Ok(true) => (book.to_book().map(Ok), None),
+                    Ok(false) => (None, None),
+                    // Lost our place; ask for a new snapshot
+                    Err(err) => (Some(Err(err)), Some(snapshot_request(topic))),
+                }
+            }
+            (
+                Feed::Mbp { topic, book },
+                Message::Response(model::Response {
+                    data: Some(tick), ..
+                }),
+            ) => match book.apply_snapshot(chrono::Utc::now().timestamp_millis(), tick) {
+                Ok(()) => (book.to_book().map(Ok), None),
+                Err(err) => (Some(Err(err)), Some(snapshot_request(topic))),
+            },
+            (_, Message::Response(response)) => {
+                log::debug!("HTX response: {response:?}");
+                (None, None)
+            }
+        }
+    }
+}
+
+fn snapshot_request(topic: &str) -> Request {
+    Request::Req {
+        req: topic.to_string(),
+        id: topic.to_string(),
+    }
+}
+
+/// Connect to HTX and return a stream of full books from the `market.$symbol.depth.step0` topic.
+/// `symbol` should come from HTX's symbol list, eg. "ethbtc"
+pub async fn htx_depth_stream(
+    symbol: &str,
+) -> Result<impl Stream<Item = Result<Book>> + Send + 'static> {
+    htx_depth_stream_at(ENDPOINT, symbol, None).await
+}
+
+/// `htx_depth_stream` from `endpoint`. `tap` sees each frame still gzipped
+pub async fn htx_depth_stream_at(
+    endpoint: &str,
+    symbol: &str,
+    tap: Option<Tap>,
+) -> Result<impl Stream<Item = Result<Book>> + Send + 'static> {
+    let topic = format!("market.{symbol}.depth.step0");
+    let subscribe = Request::Sub {
+        id: topic.clone(),
+        sub: topic,
+    };
+    htx_stream(endpoint, symbol, vec![subscribe], Feed::Depth, tap).await
+}
+
+/// Connect to HTX and return a stream of books kept up to date from the market-by-price
+/// incremental feed, `market.$symbol.mbp.$levels`.
+/// `levels` is one of the depths HTX supports: 5, 20 or 150
+pub async fn htx_mbp_stream(
+    symbol: &str,
+    levels: u16,
+) -> Result<impl Stream<Item = Result<Book>> + Send + 'static> {
+    htx_mbp_stream_at(MBP_ENDPOINT, symbol, levels, None).await
+}
+
+/// `htx_mbp_stream` from `endpoint` rather than `MBP_ENDPOINT`. `tap` sees each frame as it
+/// arrives, snapshots and updates alike
+pub async fn htx_mbp_stream_at(
+    endpoint: &str,
+    symbol: &str,
+    levels: u16,
+    tap: Option<Tap>,
+) -> Result<impl Stream<Item = Result<Book>> + Send + 'static> {
+    let topic = format!("market.{symbol}.mbp.{levels}");
+    // Subscribe first, so no updates are missed between the snapshot and the feed
+    let requests = vec![
+        Request::Sub {
+            sub: topic.clone(),
+            id: topic.clone(),
+        },
+        snapshot_request(&topic),
+    ];
+    let feed = Feed::Mbp {
+        topic,
+        book: MbpBook::default(),
+    };
+    htx_stream(endpoint, symbol, requests, feed, tap).await
+}
+
+/// Connect, send our requests, and spawn a task that inflates and handles everything HTX sends
+async fn htx_stream(
+    url: &str,
+    symbol: &str,
+    requests: Vec<Request>,
+    mut feed: Feed,
+    tap: Option<Tap>,
+) -> Result<impl Stream<Item = Result<Book>> + Send + 'static> {
+    let url = url.to_string();
+    let (mut client, _response) = connect_async(&url).await.map_err(|error| Error::Connect {
+        url,
+        error: Box::new(error),
+    })?;
+    for request in requests {
+        let message = request_message(&request);
+        log::debug!("Sending message: {message:?}");
+        client
+            .send(message.clone())
+            .await
+            .map_err(|error| Error::Send {
+                message: message.to_string(),
+                error: Box::new(error),
+            })?;
+    }
+
+    let (out_send, out_recv) = tokio::sync::mpsc::unbounded_channel();
+    let connection = tracing::info_span!("connection", venue = "htx", instrument = symbol);
+    let task = async move {
+        let mut books = 0u64;
+        loop {
+            let result = tokio::select! {
+                result = client.next() => match result {
+                    Some(result) => result,
+                    None => return,
+                },
+                // Nobody's reading any more, so close the socket properly
+                _ = out_send.closed() => {
+                    if let Err(err) = client.close(None).await {
+                        log::warn!("Unable to close the htx socket: {err:?}");
+                    }
+                    return;
+                }
+            };
+            if let (Some(tap), Ok(frame)) = (&tap, &result) {
+                tap(frame);
+            }
+            // Books are numbered on each connection, so they can be followed through the server
+            let message = tracing::debug_span!("message", sequence = tracing::field::Empty);
+            let (to_send, reply) = match result {
+                // Everything HTX sends is gzipped json
+                Ok(TMessage::Binary(data)) => {
+                    message.in_scope(|| match Message::from_gzip(&data) {
+                        Ok(message) => feed.handle(message),
+                        Err(err) => (Some(Err(err)), None),
+                    })
+                }
+                Ok(TMessage::Ping(data)) => {
+                    if let Err(err) = client.send(TMessage::Pong(data)).await {
+                        log::error!("Unable to htx pong: {err:?}")
+                    }
+                    (None, None)
+                }
+                // Filter out and log warnings for other message types
+                Ok(unexpected_message) => {
+                    log::warn!("Unexpected message type (not binary): {unexpected_message:?}");
+                    (None, None)
+                }
+                // Convert all errors
+                Err(err) => (Some(Err(err.into())), None),
+            };
+            if let Some(Ok(_)) = &to_send {
+                books += 1;
+                message.record("sequence", books);
+            }
+            if let Some(reply) = reply {
+                if let Err(err) = client.send(request_message(&reply)).await {
+                    log::error!("Unable to send {reply:?} to htx: {err:?}");
+                }
+            }
+            if let Some(to_send) = to_send {
+                if let Err(err) = out_send.send(to_send) {
+                    // Most likely the client has disconnected
+                    log::error!("Unable to forward htx book to client: {err:?}");
+                    return;
+                }
+            }
+        }
+    };
+    tokio::spawn(task.instrument(connection));
+
+    Ok(UnboundedReceiverStream::new(out_recv))
+}
+
+#[cfg(test)]
+mod unit_test {
+    use std::{
+        io::Write,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use flate2::{write::GzEncoder, Compression};
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::{accept_async, tungstenite::Message as TMessage};
+
+    use super::{htx_mbp_stream_at, Feed, Tap};
+    use crate::model::{MbpBook, Message, Request, Tick};
+
+    fn gzip(input: &str) -> TMessage {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(input.as_bytes()).unwrap();
+        TMessage::Binary(encoder.finish().unwrap())
+    }
+
+    /// The incremental feed from a local server: we subscribe, ask for a snapshot, and build on
+    /// it, with the tap seeing every frame
+    #[tokio::test]
+    async fn test_mbp_at() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = accept_async(stream).await.unwrap();
+            let sub = socket.next().await.unwrap().unwrap();
+            assert!(sub.to_string().contains(r#""sub":"market.ethbtc.mbp.150""#));
+            let req = socket.next().await.unwrap().unwrap();
+            assert!(req.to_string().contains(r#""req":"market.ethbtc.mbp.150""#));
+            let snapshot = r#"{"id":"market.ethbtc.mbp.150","rep":"market.ethbtc.mbp.150","status":"ok","data":{"seqNum":5,"bids":[[0.0671,1.5]],"asks":[[0.0672,0.3]]}}"#;
+            socket.send(gzip(snapshot)).await.unwrap();
+            let update = r#"{"ch":"market.ethbtc.mbp.150","ts":1630981077464,"tick":{"seqNum":6,"prevSeqNum":5,"bids":[[0.0671,0]],"asks":[]}}"#;
+            socket.send(gzip(update)).await.unwrap();
+            socket.close(None).await.unwrap();
+        });
+
+        let frames = Arc::new(AtomicUsize::new(0));
+        let tap: Tap = {
+            let frames = frames.clone();
+            Arc::new(move |_| {
+                frames.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+        let endpoint = format!("ws://{addr}");
+        let mut books = htx_mbp_stream_at(&endpoint, "ethbtc", 150, Some(tap))
+            .await
+            .unwrap();
+        let snapshot = books.next().await.unwrap().unwrap();
+        assert_eq!((snapshot.seq_num, snapshot.bids.len()), (5, 1));
+        let updated = books.next().await.unwrap().unwrap();
+        assert_eq!((updated.seq_num, updated.bids.len()), (6, 0));
+        server.await.unwrap();
+        // Both books, and perhaps the close
+        assert!(frames.load(Ordering::SeqCst) >= 2);
+    }
+
+    #[test]
+    fn test_ping_pong() {
+        let (book, reply) = Feed::Depth.handle(Message::Ping { ping: 42 });
+        assert!(book.is_none());
+        assert_eq!(reply, Some(Request::Pong { pong: 42 }));
+    }
+
+    #[test]
+    fn test_mbp_gap_requests_snapshot() {
+        let mut feed = Feed::Mbp {
+            topic: "market.ethbtc.mbp.150".to_string(),
+            book: MbpBook::default(),
+        };
+        let tick = |seq_num, prev_seq_num| Tick {
+            seq_num: Some(seq_num),
+            prev_seq_num: Some(prev_seq_num),
+            ..Tick::default()
+        };
+        let snapshot = Message::Response(crate::model::Response {
+            id: None,
+            status: "ok".to_string(),
+            subbed: None,
+            rep: Some("market.ethbtc.mbp.150".to_string()),
+            data: Some(tick(5, 0)),
+            err_code: None,
+            err_msg: None,
+        });
+        let (book, reply) = feed.handle(snapshot);
+        assert_eq!(book.unwrap().unwrap().seq_num, 5);
+        assert!(reply.is_none());
+
+        let push = Message::Push {
+            ch: "market.ethbtc.mbp.150".to_string(),
+            ts: 0,
+            tick: tick(8, 7),
+        };
+        let (book, reply) = feed.handle(push);
+        assert!(book.unwrap().is_err());
+        assert_eq!(
+            reply,
+            Some(Request::Req {
+                req: "market.ethbtc.mbp.150".to_string(),
+                id: "market.ethbtc.mbp.150".to_string(),
+            })
+        );
+    }
+}
+
+#[cfg(test)]
+mod web_test {
+    use futures::StreamExt;
+
+    /// Test if we can connect to HTX and get both feeds for ethbtc
+    #[tokio::test]
+    async fn test_ethbtc() {
+        pretty_env_logger::try_init().ok();
+        let depth = super::htx_depth_stream("ethbtc").await.unwrap().boxed();
+        let mbp = super::htx_mbp_stream("ethbtc", 150).await.unwrap().boxed();
+        for mut stream in [depth, mbp] {
+            match stream.next().await {
+                // Got a book
+                Some(Ok(first)) => dbg!(first),
+                Some(Err(err)) => panic!("First message was an error: {err:?}"),
+                None => panic!("No first message!"),
+            };
+        }
+    }
+}
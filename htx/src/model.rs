@@ -0,0 +1,9 @@
+//! Models for HTX's market websocket, as described by
+//! <https://www.htx.com/en-us/opend/newApiPages/?id=7ec53b69-7773-11ed-9966-0242ac110003>
+// Messages we send and receive
+pub mod message;
+pub use message::{Message, Request, Response, Tick};
+
+// The book we build from those messages
+pub mod book;
+pub use book::{Book, MbpBook, Price};
@@ -0,0 +1,214 @@
+//! Turns HTX pushes into books. `depth.step0` pushes are whole books already; the MBP feed has to
+//! be synced against a snapshot and kept up to date by sequence number.
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, TimeZone, Utc};
+use ordered_float::OrderedFloat;
+
+use super::Tick;
+use crate::{Error, Result};
+
+/// How many MBP updates we'll hold on to while waiting for a snapshot
+const MAX_PENDING: usize = 1000;
+
+/// The current view of the book, sent to the client after every change
+#[derive(Debug, Clone, PartialEq)]
+pub struct Book {
+    pub timestamp: DateTime<Utc>,
+    /// `version` for `depth.step0`, `seqNum` for MBP
+    pub seq_num: u64,
+    /// Best (highest) bid first
+    pub bids: Vec<Price>,
+    /// Best (lowest) ask first
+    pub asks: Vec<Price>,
+}
+
+/// The price and quantity of a bid or ask
+#[derive(Debug, Clone, PartialEq)]
+pub struct Price {
+    pub price: f64,
+    pub quantity: f64,
+}
+
+fn timestamp(ts: i64) -> DateTime<Utc> {
+    Utc.timestamp_millis_opt(ts)
+        .single()
+        .unwrap_or_else(Utc::now)
+}
+
+impl Book {
+    /// A `depth.step0` push is already a whole book
+    pub fn from_depth(ts: i64, tick: Tick) -> Book {
+        let to_price = |(price, quantity)| Price { price, quantity };
+        Book {
+            timestamp: timestamp(ts),
+            seq_num: tick.version.unwrap_or_default(),
+            bids: tick.bids.into_iter().map(to_price).collect(),
+            asks: tick.asks.into_iter().map(to_price).collect(),
+        }
+    }
+}
+
+type Side = BTreeMap<OrderedFloat<f64>, f64>;
+
+/// Insert, update, or (for a size of zero) delete each level
+fn apply_levels(side: &mut Side, levels: Vec<(f64, f64)>) {
+    for (price, quantity) in levels {
+        if quantity == 0.0 {
+            side.remove(&OrderedFloat(price));
+        } else {
+            side.insert(OrderedFloat(price), quantity);
+        }
+    }
+}
+
+/// A book built from the MBP incremental feed
+#[derive(Debug, Default)]
+pub struct MbpBook {
+    /// The last sequence number applied; None until we've had a snapshot
+    seq_num: Option<u64>,
+    timestamp: i64,
+    bids: Side,
+    asks: Side,
+    /// Updates that arrived before the snapshot did
+    pending: Vec<(i64, Tick)>,
+}
+
+impl MbpBook {
+    /// True once we have a snapshot and every update since
+    pub fn is_synced(&self) -> bool {
+        self.seq_num.is_some()
+    }
+
+    /// Apply an incremental update. Returns false if it was buffered (we're waiting on a
+    /// snapshot) or stale. On a sequence gap the book is thrown away; the caller should ask for a
+    /// new snapshot
+    pub fn apply_update(&mut self, ts: i64, tick: Tick) -> Result<bool> {
+        let last = match self.seq_num {
+            Some(last) => last,
+            None => {
+                if self.pending.len() >= MAX_PENDING {
+                    self.pending.remove(0);
+                }
+                self.pending.push((ts, tick));
+                return Ok(false);
+            }
+        };
+        let (seq_num, prev_seq_num) = (
+            tick.seq_num.unwrap_or_default(),
+            tick.prev_seq_num.unwrap_or_default(),
+        );
+        if seq_num <= last {
+            log::debug!("Ignoring stale MBP update {seq_num} <= {last}");
+            return Ok(false);
+        }
+        if prev_seq_num != last {
+            // Start again; keep this update in case it follows the next snapshot
+            *self = MbpBook::default();
+            self.pending.push((ts, tick));
+            return Err(Error::SequenceGap {
+                expected: last,
+                got: prev_seq_num,
+            });
+        }
+        apply_levels(&mut self.bids, tick.bids);
+        apply_levels(&mut self.asks, tick.asks);
+        self.seq_num = Some(seq_num);
+        self.timestamp = ts;
+        Ok(true)
+    }
+
+    /// Start from the snapshot we asked for with a `req`, then catch up on anything that came in
+    /// while we waited for it
+    pub fn apply_snapshot(&mut self, ts: i64, tick: Tick) -> Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+        *self = MbpBook::default();
+        apply_levels(&mut self.bids, tick.bids);
+        apply_levels(&mut self.asks, tick.asks);
+        self.seq_num = Some(tick.seq_num.unwrap_or_default());
+        self.timestamp = ts;
+        for (ts, tick) in pending {
+            self.apply_update(ts, tick)?;
+        }
+        Ok(())
+    }
+
+    /// Render the current book; only valid once we're synced
+    pub fn to_book(&self) -> Option<Book> {
+        let seq_num = self.seq_num?;
+        let to_price = |(price, quantity): (&OrderedFloat<f64>, &f64)| Price {
+            price: price.into_inner(),
+            quantity: *quantity,
+        };
+        Some(Book {
+            timestamp: timestamp(self.timestamp),
+            seq_num,
+            bids: self.bids.iter().rev().map(to_price).collect(),
+            asks: self.asks.iter().map(to_price).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use super::{MbpBook, Price};
+    use crate::{model::Tick, Error};
+
+    fn tick(seq_num: u64, prev_seq_num: u64, bids: Vec<(f64, f64)>, asks: Vec<(f64, f64)>) -> Tick {
+        Tick {
+            bids,
+            asks,
+            version: None,
+            seq_num: Some(seq_num),
+            prev_seq_num: Some(prev_seq_num),
+        }
+    }
+
+    #[test]
+    fn test_sync() {
+        let mut book = MbpBook::default();
+        // Updates before the snapshot are buffered
+        assert!(!book
+            .apply_update(1, tick(10, 9, vec![(0.5, 1.0)], vec![]))
+            .unwrap());
+        assert!(!book
+            .apply_update(2, tick(11, 10, vec![(0.6, 1.0)], vec![]))
+            .unwrap());
+        assert!(!book.is_synced());
+        assert!(book.to_book().is_none());
+
+        // The snapshot is at 10, so only the second buffered update applies
+        book.apply_snapshot(3, tick(10, 0, vec![(0.4, 2.0)], vec![(0.7, 1.0)]))
+            .unwrap();
+        assert!(book.is_synced());
+        let got = book.to_book().unwrap();
+        assert_eq!(got.seq_num, 11);
+        assert_eq!(
+            got.bids,
+            vec![
+                Price {
+                    price: 0.6,
+                    quantity: 1.0
+                },
+                Price {
+                    price: 0.4,
+                    quantity: 2.0
+                }
+            ]
+        );
+
+        // Deletes, then a gap
+        assert!(book
+            .apply_update(4, tick(12, 11, vec![], vec![(0.7, 0.0)]))
+            .unwrap());
+        assert!(book.to_book().unwrap().asks.is_empty());
+        assert!(matches!(
+            book.apply_update(5, tick(15, 14, vec![], vec![])),
+            Err(Error::SequenceGap {
+                expected: 12,
+                got: 14
+            })
+        ));
+        assert!(!book.is_synced());
+    }
+}
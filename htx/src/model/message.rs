@@ -0,0 +1,152 @@
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+
+/// Requests we send to HTX
+#[derive(Serialize, PartialEq, Debug)]
+#[serde(untagged)]
+pub enum Request {
+    /// Subscribe to a topic, eg. `{"sub":"market.ethbtc.depth.step0","id":"ethbtc"}`
+    Sub { sub: String, id: String },
+    /// Ask for a one-off reply, eg. the MBP snapshot: `{"req":"market.ethbtc.mbp.150","id":"ethbtc"}`
+    Req { req: String, id: String },
+    /// Answer to HTX's heartbeat: `{"pong":1492420473027}`
+    Pong { pong: u64 },
+}
+
+/// Everything HTX sends us, once it's been gunzipped
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(untagged)]
+pub enum Message {
+    /// `{"ping":1492420473027}`; we have to answer with the same number or get disconnected
+    Ping { ping: u64 },
+    /// A push on a topic we subscribed to
+    /// {"ch":"market.ethbtc.depth.step0","ts":1630981077464,"tick":{"bids":[[0.0671,1.5]],"asks":[[0.0672,0.3]],"version":1}}
+    Push { ch: String, ts: i64, tick: Tick },
+    /// The answer to a `sub` or `req`
+    Response(Response),
+}
+
+/// The answer to a `sub` or `req`
+/// {"id":"ethbtc","status":"ok","subbed":"market.ethbtc.depth.step0","ts":1630981077464}
+/// {"id":"ethbtc","status":"error","err-code":"bad-request","err-msg":"invalid topic","ts":1630981077464}
+#[derive(Deserialize, PartialEq, Debug)]
+pub struct Response {
+    pub id: Option<String>,
+    pub status: String,
+    pub subbed: Option<String>,
+    pub rep: Option<String>,
+    /// The MBP snapshot, when this is a reply to a `req`
+    pub data: Option<Tick>,
+    #[serde(rename = "err-code")]
+    pub err_code: Option<String>,
+    #[serde(rename = "err-msg")]
+    pub err_msg: Option<String>,
+}
+
+/// Book data. `depth.step0` pushes a full book with a `version`; the MBP feed pushes changed
+/// levels (a size of 0 means delete) with `seqNum` and `prevSeqNum`
+#[derive(Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct Tick {
+    #[serde(default)]
+    pub bids: Vec<(f64, f64)>,
+    #[serde(default)]
+    pub asks: Vec<(f64, f64)>,
+    pub version: Option<u64>,
+    #[serde(rename = "seqNum")]
+    pub seq_num: Option<u64>,
+    #[serde(rename = "prevSeqNum")]
+    pub prev_seq_num: Option<u64>,
+}
+
+impl Message {
+    /// HTX gzips every frame it sends; inflate it and parse it
+    pub fn from_gzip(input: &[u8]) -> Result<Message> {
+        let mut text = String::new();
+        GzDecoder::new(input).read_to_string(&mut text)?;
+        serde_json::from_str(&text).map_err(|error| Error::Json {
+            error,
+            original: text,
+        })
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::{Message, Request, Tick};
+
+    fn gzip(input: &str) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(input.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_render_requests() {
+        let sub = Request::Sub {
+            sub: "market.ethbtc.depth.step0".to_string(),
+            id: "ethbtc".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_string(&sub).unwrap(),
+            r#"{"sub":"market.ethbtc.depth.step0","id":"ethbtc"}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&Request::Pong {
+                pong: 1492420473027
+            })
+            .unwrap(),
+            r#"{"pong":1492420473027}"#
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let ping = gzip(r#"{"ping":1492420473027}"#);
+        assert_eq!(
+            Message::from_gzip(&ping).unwrap(),
+            Message::Ping {
+                ping: 1492420473027
+            }
+        );
+
+        let push = gzip(
+            r#"{"ch":"market.ethbtc.mbp.150","ts":1573199608679,"tick":{"seqNum":100020146795,"prevSeqNum":100020146794,"asks":[[0.0672,0]]}}"#,
+        );
+        assert_eq!(
+            Message::from_gzip(&push).unwrap(),
+            Message::Push {
+                ch: "market.ethbtc.mbp.150".to_string(),
+                ts: 1573199608679,
+                tick: Tick {
+                    bids: vec![],
+                    asks: vec![(0.0672, 0.0)],
+                    version: None,
+                    seq_num: Some(100020146795),
+                    prev_seq_num: Some(100020146794),
+                }
+            }
+        );
+
+        let error = gzip(
+            r#"{"status":"error","ts":1630981077464,"id":"ethbtc","err-code":"bad-request","err-msg":"invalid topic market.nope.depth.step0"}"#,
+        );
+        match Message::from_gzip(&error).unwrap() {
+            Message::Response(response) => {
+                assert_eq!(response.status, "error");
+                assert_eq!(response.err_code.as_deref(), Some("bad-request"));
+            }
+            other => panic!("Expected a response: {other:?}"),
+        }
+
+        // Text that isn't gzipped is an error, not a panic
+        assert!(Message::from_gzip(br#"{"ping":1}"#).is_err());
+    }
+}
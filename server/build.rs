@@ -1,5 +1,18 @@
+use std::{env, path::PathBuf};
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Building protobufs");
-    tonic_build::compile_protos("../protobufs/orderbook.proto")?;
+    // Every service we serve, described for server reflection
+    let descriptors = PathBuf::from(env::var("OUT_DIR")?).join("descriptors.bin");
+    tonic_build::configure()
+        .file_descriptor_set_path(descriptors)
+        .compile(
+            &[
+                "../protobufs/orderbook.proto",
+                "../protobufs/google/rpc/status.proto",
+                "../protobufs/google/rpc/error_details.proto",
+            ],
+            &["../protobufs"],
+        )?;
     Ok(())
 }
@@ -1,5 +1,14 @@
 tonic::include_proto!("orderbook");
 
+/// The standard model for gRPC error details
+pub mod rpc {
+    tonic::include_proto!("google.rpc");
+}
+
+/// Every proto file the server was built from, as an encoded `FileDescriptorSet`, for server
+/// reflection
+pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("descriptors");
+
 #[cfg(test)]
 pub mod web_test {
 
@@ -14,7 +23,8 @@ pub mod web_test {
 
     use super::{
         orderbook_aggregator_client::OrderbookAggregatorClient,
-        orderbook_aggregator_server::OrderbookAggregator, Empty, Level, Summary,
+        orderbook_aggregator_server::OrderbookAggregator, BookAtRequest, BookUpdate, Level,
+        RangeRequest, Summary, SummaryRequest, VenueStatusReply, VenueStatusRequest,
     };
 
     /// Just a Simple server that streams a single summary, then ends
@@ -30,12 +40,15 @@ pub mod web_test {
                     exchange: "binance".to_string(),
                     price: 1.1,
                     amount: 50.0,
+                    ..Level::default()
                 }],
                 asks: vec![Level {
                     exchange: "bitstamp".to_string(),
                     price: 2.2,
                     amount: 50.0,
+                    ..Level::default()
                 }],
+                ..Summary::default()
             };
             Simple {
                 single: Some(summary),
@@ -63,7 +76,7 @@ pub mod web_test {
 
         fn book_summary<'life0, 'async_trait>(
             &'life0 self,
-            _request: tonic::Request<Empty>,
+            _request: tonic::Request<SummaryRequest>,
         ) -> Pin<
             Box<
                 dyn Future<Output = Result<tonic::Response<Self::BookSummaryStream>, tonic::Status>>
@@ -77,24 +90,68 @@ pub mod web_test {
         {
             Box::pin(async { Ok(Response::new(Simple::new())) })
         }
+
+        type BookUpdatesStream = Pin<Box<dyn Stream<Item = Result<BookUpdate, Status>> + Send>>;
+
+        async fn book_updates(
+            &self,
+            _request: tonic::Request<SummaryRequest>,
+        ) -> Result<Response<Self::BookUpdatesStream>, Status> {
+            Err(Status::unimplemented("Simple only does summaries"))
+        }
+
+        async fn get_book_snapshot(
+            &self,
+            _request: tonic::Request<SummaryRequest>,
+        ) -> Result<Response<Summary>, Status> {
+            Err(Status::unimplemented("Simple only does summaries"))
+        }
+
+        async fn get_venue_status(
+            &self,
+            _request: tonic::Request<VenueStatusRequest>,
+        ) -> Result<Response<VenueStatusReply>, Status> {
+            Err(Status::unimplemented("Simple only does summaries"))
+        }
+
+        async fn get_book_at(
+            &self,
+            _request: tonic::Request<BookAtRequest>,
+        ) -> Result<Response<Summary>, Status> {
+            Err(Status::unimplemented("Simple only does summaries"))
+        }
+
+        type StreamRangeStream = Simple;
+
+        async fn stream_range(
+            &self,
+            _request: tonic::Request<RangeRequest>,
+        ) -> Result<Response<Self::StreamRangeStream>, Status> {
+            Err(Status::unimplemented("Simple only does summaries"))
+        }
     }
 
     #[tokio::test]
     async fn test_simple_server() -> anyhow::Result<()> {
-        // Make the server
-        let addr = "0.0.0.0:8000".parse().unwrap();
+        // Make the server, on any free port
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
         let simple = Simple::new();
-        let server = tokio::spawn(crate::serve(addr, simple));
+        let server = tokio::spawn(crate::serve_on(
+            listener,
+            simple,
+            crate::config::Compression::Gzip,
+        ));
         dbg!(&server);
 
         let client = spawn(async move {
             // Connect to the server and recieve one message
-            let mut client = OrderbookAggregatorClient::connect("http://127.0.0.1:8000")
+            let mut client = OrderbookAggregatorClient::connect(format!("http://{addr}"))
                 .await
                 .unwrap();
 
             let mut s = client
-                .book_summary(tonic::Request::new(Empty {}))
+                .book_summary(tonic::Request::new(SummaryRequest::default()))
                 .await
                 .unwrap()
                 .into_inner();
@@ -0,0 +1,436 @@
+//! API keys, and what each client is entitled to. Keys come from a credentials file (see
+//! credentials.example.toml); without one, anybody can have anything
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use bitstamp::model::CurrencyPair;
+use serde::Deserialize;
+use tonic::{metadata::MetadataMap, service::Interceptor, Request, Status};
+
+use crate::{api::SummaryRequest, config::ConfigError, request::SummaryParams, venue::Venue};
+
+/// The credentials file, as written
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct CredentialsFile {
+    #[serde(default)]
+    clients: BTreeMap<String, ClientConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct ClientConfig {
+    key: String,
+    #[serde(default)]
+    instruments: Vec<String>,
+    venues: Option<Vec<String>>,
+    max_depth: Option<usize>,
+    max_streams: Option<usize>,
+}
+
+/// What a client may ask for
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Entitlements {
+    /// Empty means any
+    pub instruments: Vec<CurrencyPair>,
+    /// None means any
+    pub venues: Option<BTreeSet<Venue>>,
+    pub max_depth: Option<usize>,
+    /// How many `BookSummary` and `BookUpdates` streams it can have open at once
+    pub max_streams: Option<usize>,
+}
+
+/// A client with a valid key. Handlers find this in the request's extensions
+#[derive(Debug, Clone, PartialEq)]
+pub struct Client {
+    pub name: String,
+    pub entitlements: Entitlements,
+}
+
+impl Entitlements {
+    /// Check `params` against what the client's entitled to. Anything the client left to the
+    /// server's defaults is narrowed down to its entitlements, rather than refused
+    #[allow(clippy::result_large_err)]
+    pub fn check(
+        &self,
+        name: &str,
+        request: &SummaryRequest,
+        mut params: SummaryParams,
+    ) -> Result<SummaryParams, Status> {
+        if !self.instruments.is_empty() && !self.instruments.contains(&params.instrument) {
+            return Err(Status::permission_denied(format!(
+                "{name} isn't entitled to {}",
+                params.instrument
+            )));
+        }
+        if let Some(allowed) = &self.venues {
+            if request.venues.is_empty() {
+                params.venues = params.venues.intersection(allowed).copied().collect();
+                if params.venues.is_empty() {
+                    return Err(Status::permission_denied(format!(
+                        "{name} isn't entitled to any of the venues"
+                    )));
+                }
+            } else if let Some(venue) = params.venues.difference(allowed).next() {
+                return Err(Status::permission_denied(format!(
+                    "{name} isn't entitled to {venue}"
+                )));
+            }
+        }
+        if let Some(max_depth) = self.max_depth {
+            if request.depth == 0 {
+                params.depth = params.depth.min(max_depth);
+            } else if params.depth > max_depth {
+                return Err(Status::permission_denied(format!(
+                    "{name} is entitled to a depth of at most {max_depth}"
+                )));
+            }
+        }
+        Ok(params)
+    }
+
+    /// Whether the client may see anything of `venue`'s `instrument` book
+    pub fn allows(&self, venue: Venue, instrument: CurrencyPair) -> bool {
+        (self.instruments.is_empty() || self.instruments.contains(&instrument))
+            && self
+                .venues
+                .as_ref()
+                .is_none_or(|venues| venues.contains(&venue))
+    }
+}
+
+/// The clients, by key
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Credentials {
+    clients: HashMap<String, Arc<Client>>,
+}
+
+fn invalid(key: String, reason: String) -> ConfigError {
+    ConfigError::Invalid { key, reason }
+}
+
+impl Credentials {
+    /// Read and check a credentials file
+    pub fn load(path: &Path) -> Result<Credentials, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|error| ConfigError::Read {
+            path: path.to_owned(),
+            error,
+        })?;
+        Credentials::parse(path, &text)
+    }
+
+    /// Parse and check a credentials file's contents
+    pub fn parse(path: &Path, text: &str) -> Result<Credentials, ConfigError> {
+        let file: CredentialsFile = toml::from_str(text).map_err(|error| ConfigError::Parse {
+            path: path.to_owned(),
+            error,
+        })?;
+        let mut clients = HashMap::new();
+        for (name, config) in file.clients {
+            let key = format!("clients.{name}");
+            let instruments = config
+                .instruments
+                .iter()
+                .enumerate()
+                .map(|(index, instrument)| {
+                    instrument.to_lowercase().parse().map_err(|_| {
+                        invalid(
+                            format!("{key}.instruments[{index}]"),
+                            format!("unknown instrument \"{instrument}\""),
+                        )
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+            let venues = config
+                .venues
+                .map(|venues| {
+                    venues
+                        .iter()
+                        .enumerate()
+                        .map(|(index, venue)| {
+                            venue.to_lowercase().parse().map_err(|_| {
+                                invalid(
+                                    format!("{key}.venues[{index}]"),
+                                    format!("unknown venue \"{venue}\""),
+                                )
+                            })
+                        })
+                        .collect::<Result<_, _>>()
+                })
+                .transpose()?;
+            if config.key.is_empty() {
+                return Err(invalid(format!("{key}.key"), "is empty".to_string()));
+            }
+            if config.max_streams == Some(0) {
+                return Err(invalid(
+                    format!("{key}.max_streams"),
+                    "must be at least 1".to_string(),
+                ));
+            }
+            let client = Client {
+                name: name.clone(),
+                entitlements: Entitlements {
+                    instruments,
+                    venues,
+                    max_depth: config.max_depth,
+                    max_streams: config.max_streams,
+                },
+            };
+            if clients.insert(config.key, Arc::new(client)).is_some() {
+                return Err(invalid(
+                    format!("{key}.key"),
+                    "another client has the same key".to_string(),
+                ));
+            }
+        }
+        Ok(Credentials { clients })
+    }
+}
+
+/// The key from `authorization: Bearer <key>` or `x-api-key: <key>`
+fn api_key(metadata: &MetadataMap) -> Option<&str> {
+    if let Some(authorization) = metadata.get("authorization") {
+        return authorization.to_str().ok()?.strip_prefix("Bearer ");
+    }
+    metadata.get("x-api-key")?.to_str().ok()
+}
+
+/// Checks keys, and keeps count of each client's open streams. Clones share everything
+#[derive(Debug, Clone, Default)]
+pub struct Auth {
+    credentials: Arc<RwLock<Option<Credentials>>>,
+    streams: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl Auth {
+    pub fn new(credentials: Option<Credentials>) -> Auth {
+        Auth {
+            credentials: Arc::new(RwLock::new(credentials)),
+            streams: Arc::default(),
+        }
+    }
+
+    /// Switch to new credentials. Streams that are already open carry on
+    pub fn reload(&self, credentials: Option<Credentials>) {
+        *self.credentials.write().unwrap() = credentials;
+    }
+
+    /// The interceptor: turn away requests without a valid key, and tell the handlers who the
+    /// client is by adding an `Arc<Client>` to the request's extensions
+    #[allow(clippy::result_large_err)]
+    pub fn authenticate(&self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let credentials = self.credentials.read().unwrap();
+        let credentials = match credentials.as_ref() {
+            Some(credentials) => credentials,
+            None => return Ok(request),
+        };
+        let key = api_key(request.metadata()).ok_or_else(|| {
+            Status::unauthenticated("Send an API key as `authorization: Bearer <key>`")
+        })?;
+        let client = credentials
+            .clients
+            .get(key)
+            .ok_or_else(|| Status::unauthenticated("Unknown API key"))?
+            .clone();
+        request.extensions_mut().insert(client);
+        Ok(request)
+    }
+
+    /// Count a stream against the client's limit, until the guard's dropped
+    #[allow(clippy::result_large_err)]
+    pub fn open_stream(&self, client: &Client) -> Result<StreamGuard, Status> {
+        let mut streams = self.streams.lock().unwrap();
+        let open = streams.entry(client.name.clone()).or_default();
+        if let Some(max_streams) = client.entitlements.max_streams {
+            if *open >= max_streams {
+                return Err(Status::resource_exhausted(format!(
+                    "{} already has {max_streams} streams open",
+                    client.name
+                )));
+            }
+        }
+        *open += 1;
+        Ok(StreamGuard {
+            name: client.name.clone(),
+            streams: self.streams.clone(),
+        })
+    }
+}
+
+impl Interceptor for Auth {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        self.authenticate(request)
+    }
+}
+
+/// One of a client's open streams
+#[derive(Debug)]
+pub struct StreamGuard {
+    name: String,
+    streams: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some(open) = streams.get_mut(&self.name) {
+            *open -= 1;
+            if *open == 0 {
+                streams.remove(&self.name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use std::{path::Path, sync::Arc};
+
+    use bitstamp::model::CurrencyPair;
+    use tonic::{Code, Request};
+
+    use super::{Auth, Client, Credentials};
+    use crate::{
+        api::SummaryRequest,
+        config::ConfigError,
+        request::{RequestDefaults, SummaryParams},
+        venue::Venue,
+    };
+
+    fn credentials() -> Credentials {
+        Credentials::parse(
+            Path::new("credentials.example.toml"),
+            include_str!("../credentials.example.toml"),
+        )
+        .unwrap()
+    }
+
+    fn client(auth: &Auth, key: &str) -> Result<Arc<Client>, Code> {
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert("authorization", format!("Bearer {key}").parse().unwrap());
+        let request = auth.authenticate(request).map_err(|status| status.code())?;
+        Ok(request.extensions().get::<Arc<Client>>().unwrap().clone())
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let auth = Auth::new(Some(credentials()));
+        assert_eq!(client(&auth, "example-trader-key").unwrap().name, "trader");
+        assert_eq!(client(&auth, "wrong").unwrap_err(), Code::Unauthenticated);
+        let status = auth.authenticate(Request::new(())).unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert("x-api-key", "example-dashboard-key".parse().unwrap());
+        assert!(auth.authenticate(request).is_ok());
+
+        // Without credentials, anyone gets in
+        let open = Auth::new(None);
+        assert!(open.authenticate(Request::new(())).is_ok());
+    }
+
+    #[test]
+    fn test_entitlements() {
+        let auth = Auth::new(Some(credentials()));
+        let dashboard = client(&auth, "example-dashboard-key").unwrap();
+        let defaults = RequestDefaults {
+            instruments: vec![CurrencyPair::Ethbtc, CurrencyPair::Btcusd],
+            ..RequestDefaults::new(CurrencyPair::Ethbtc)
+        };
+        let check = |request: SummaryRequest| {
+            let params = SummaryParams::from_request(request.clone(), &defaults).unwrap();
+            dashboard
+                .entitlements
+                .check(&dashboard.name, &request, params)
+                .map_err(|status| status.code())
+        };
+
+        // Defaults are narrowed down to what the client's entitled to
+        let params = check(SummaryRequest::default()).unwrap();
+        assert_eq!(params.depth, 5);
+        assert_eq!(
+            params.venues.into_iter().collect::<Vec<_>>(),
+            vec![Venue::Binance, Venue::Bitstamp]
+        );
+        // But asking for more than that is refused
+        let denied = |request| check(request).unwrap_err();
+        assert_eq!(
+            denied(SummaryRequest {
+                depth: 10,
+                ..SummaryRequest::default()
+            }),
+            Code::PermissionDenied
+        );
+        assert_eq!(
+            denied(SummaryRequest {
+                venues: vec!["bybit".to_string()],
+                ..SummaryRequest::default()
+            }),
+            Code::PermissionDenied
+        );
+        assert_eq!(
+            denied(SummaryRequest {
+                instrument: "btcusd".to_string(),
+                ..SummaryRequest::default()
+            }),
+            Code::PermissionDenied
+        );
+
+        assert!(dashboard
+            .entitlements
+            .allows(Venue::Bitstamp, CurrencyPair::Ethbtc));
+        assert!(!dashboard
+            .entitlements
+            .allows(Venue::Bybit, CurrencyPair::Ethbtc));
+        assert!(!dashboard
+            .entitlements
+            .allows(Venue::Binance, CurrencyPair::Btcusd));
+    }
+
+    #[test]
+    fn test_max_streams() {
+        let auth = Auth::new(Some(credentials()));
+        let dashboard = client(&auth, "example-dashboard-key").unwrap();
+        let first = auth.open_stream(&dashboard).unwrap();
+        let _second = auth.open_stream(&dashboard).unwrap();
+        let status = auth.open_stream(&dashboard).unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        // Another client has its own count
+        let trader = client(&auth, "example-trader-key").unwrap();
+        let _trader = auth.open_stream(&trader).unwrap();
+        // Closing a stream makes room for another
+        drop(first);
+        assert!(auth.open_stream(&dashboard).is_ok());
+    }
+
+    #[test]
+    fn test_invalid() {
+        let invalid_key = |text: &str| match Credentials::parse(Path::new("test.toml"), text) {
+            Err(ConfigError::Invalid { key, .. }) => key,
+            other => panic!("Expected an invalid key, got {other:?}"),
+        };
+        assert_eq!(
+            invalid_key("[clients.a]\nkey = \"k\"\ninstruments = [\"doge\"]"),
+            "clients.a.instruments[0]"
+        );
+        assert_eq!(
+            invalid_key("[clients.a]\nkey = \"k\"\nvenues = [\"nasdaq\"]"),
+            "clients.a.venues[0]"
+        );
+        assert_eq!(
+            invalid_key("[clients.a]\nkey = \"k\"\n[clients.b]\nkey = \"k\""),
+            "clients.b.key"
+        );
+        assert_eq!(
+            invalid_key("[clients.a]\nkey = \"k\"\nmax_streams = 0"),
+            "clients.a.max_streams"
+        );
+    }
+}
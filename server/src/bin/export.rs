@@ -0,0 +1,128 @@
+//! Exports summaries and venue books merged from captured frames to Parquet, the same as the
+//! server's `[export]` setting. The rows are timed by the replay's clock, so they have the times
+//! they'd have had live, as long as the frames aren't played faster than they can be merged. At
+//! max speed they always are
+use std::{collections::BTreeSet, path::PathBuf, time::Duration};
+
+use anyhow::{anyhow, Result};
+use bitstamp::model::CurrencyPair;
+use clap::Parser;
+use server::{
+    api::SummaryRequest,
+    export::{ExportSettings, Exporter},
+    hub::Hub,
+    recorder::captures,
+    replay::{Replay, Speed},
+    request::{RequestDefaults, SummaryParams},
+    telemetry,
+    venue::Venue,
+};
+use tokio::{net::TcpListener, sync::watch};
+
+/// How long the last frames get to be merged and exported once they've all been played
+const DRAIN: Duration = Duration::from_secs(1);
+
+/// Command line flags. Each can also be set with the environment variable shown
+#[derive(Parser, Debug)]
+#[command(about = "Exports order books merged from captured venue frames to Parquet files")]
+struct Args {
+    /// Capture files, or directories of them
+    #[arg(required = true)]
+    captures: Vec<PathBuf>,
+    /// Where to write the Parquet files
+    #[arg(long, env = "ORDERBOOK_EXPORT_DIR")]
+    dir: PathBuf,
+    /// How fast to play the frames: "1x" as they were captured, "10x", or "max". A book's time is
+    /// late by however long it took to merge, times this, except at "max", which waits for each
+    /// frame to be merged and exported before playing the next
+    #[arg(long, default_value = "10x")]
+    speed: Speed,
+    /// Levels of each side to keep
+    #[arg(long, default_value_t = 10)]
+    levels: usize,
+    /// Only export these venues; every venue in the captures if not set
+    #[arg(long, value_delimiter = ',')]
+    venues: Vec<Venue>,
+    /// A tracing filter, eg. "info,server=debug"
+    #[arg(long, env = "ORDERBOOK_LOG", default_value = "info")]
+    log: String,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let _telemetry = telemetry::init(&args.log, None)?;
+    let mut paths = vec![];
+    for path in args.captures {
+        match path.is_dir() {
+            true => paths.extend(captures(&path)?),
+            false => paths.push(path),
+        }
+    }
+    let mut replay = Replay::load(&paths)?;
+    if !args.venues.is_empty() {
+        replay.retain(|frame| {
+            args.venues
+                .iter()
+                .any(|venue| venue.to_string() == frame.venue)
+        });
+    }
+    anyhow::ensure!(!replay.is_empty(), "There are no frames to export");
+    anyhow::ensure!(args.levels > 0, "--levels must be more than 0");
+
+    // Merge each instrument from just the venues captured for it, so playing can start
+    let mut feeds: Vec<(CurrencyPair, BTreeSet<Venue>)> = vec![];
+    for (venue, instrument) in replay.feeds() {
+        let venue: Venue = venue
+            .parse()
+            .map_err(|_| anyhow!("Unknown venue {venue}"))?;
+        let instrument = instrument
+            .parse()
+            .map_err(|_| anyhow!("Unknown instrument {instrument}"))?;
+        match feeds.iter_mut().find(|(feed, _)| *feed == instrument) {
+            Some((_, venues)) => {
+                venues.insert(venue);
+            }
+            None => feeds.push((instrument, BTreeSet::from([venue]))),
+        }
+    }
+
+    let exporter = Exporter::start(ExportSettings {
+        dir: args.dir,
+        levels: args.levels,
+        // One file per partition
+        rotate_after: Duration::MAX,
+    })?;
+    let mut playing = replay.play(TcpListener::bind("127.0.0.1:0").await?, args.speed)?;
+    let hub = Venue::ALL.into_iter().fold(
+        Hub::default()
+            .with_clock(playing.clock().clone())
+            .with_exporter(exporter.clone())
+            // Captures only have the books
+            .without_trades(),
+        |hub, venue| hub.with_endpoint(venue, playing.endpoint(venue)),
+    );
+    for (instrument, venues) in feeds {
+        let request = SummaryRequest {
+            instrument: instrument.to_string(),
+            depth: args.levels as u32,
+            venues: venues.iter().map(Venue::to_string).collect(),
+            ..SummaryRequest::default()
+        };
+        let params = SummaryParams::from_request(request, &RequestDefaults::new(instrument))?;
+        let (hub, exporter) = (hub.clone(), exporter.clone());
+        tokio::spawn(async move {
+            let (_keeping, params) = watch::channel(params);
+            hub.keep(params, |summary| exporter.summary(instrument, summary))
+                .await
+        });
+    }
+
+    playing.finished().await;
+    log::info!("Every frame has been played");
+    tokio::time::sleep(DRAIN).await;
+    hub.shutdown().await;
+    tokio::task::spawn_blocking(move || exporter.stop()).await?;
+    log::info!("Exported");
+    Ok(())
+}
@@ -0,0 +1,115 @@
+//! Records every frame the venues send, to the same capture files as the server's `[record]`
+//! setting, without serving anything
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use bitstamp::model::CurrencyPair;
+use clap::Parser;
+use futures::StreamExt;
+use server::{
+    clock::Clock,
+    connection::{reconnecting, SharedStats},
+    recorder::{RecordSettings, Recorder},
+    telemetry,
+    venue::Venue,
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Command line flags. Each can also be set with the environment variable shown
+#[derive(Parser, Debug)]
+#[command(about = "Records every frame the exchanges send to zstd-compressed NDJSON files")]
+struct Args {
+    /// Where to write the capture files
+    #[arg(long, env = "ORDERBOOK_RECORD_DIR")]
+    dir: PathBuf,
+    /// The instruments to record
+    #[arg(
+        long,
+        env = "ORDERBOOK_INSTRUMENTS",
+        value_delimiter = ',',
+        default_value = "ethbtc"
+    )]
+    instruments: Vec<CurrencyPair>,
+    /// Only record these venues; every venue if not set
+    #[arg(long, env = "ORDERBOOK_VENUES", value_delimiter = ',')]
+    venues: Vec<Venue>,
+    /// Start a new file once this much has been written, before compression
+    #[arg(long, default_value_t = 100)]
+    rotate_mb: u64,
+    /// Start a new file once the current one is this old
+    #[arg(long, default_value_t = 60)]
+    rotate_minutes: u64,
+    /// A tracing filter, eg. "info,server=debug"
+    #[arg(long, env = "ORDERBOOK_LOG", default_value = "info")]
+    log: String,
+}
+
+/// Keep `venue` connected for `instrument`, recording everything, until we give up on it
+async fn record(recorder: Recorder, venue: Venue, instrument: CurrencyPair) {
+    let name = format!("{venue} {instrument}");
+    let endpoint = venue.default_endpoint();
+    let tap = recorder.tap(venue, instrument);
+    let first = venue.connect(instrument, endpoint, Some(tap), Clock::System);
+    let first = match first.await {
+        Ok(stream) => stream,
+        Err(err) => {
+            log::error!("Unable to connect to {name}: {err}");
+            return;
+        }
+    };
+    // Each reconnect is recorded as a new connection
+    let connect = move || {
+        let tap = recorder.tap(venue, instrument);
+        async move {
+            venue
+                .connect(instrument, endpoint, Some(tap), Clock::System)
+                .await
+        }
+    };
+    let mut books = reconnecting(
+        name.clone(),
+        first,
+        connect,
+        SharedStats::default(),
+        Arc::default(),
+    );
+    // The books themselves aren't needed; the frames they came from have been recorded
+    while let Some(book) = books.next().await {
+        if let Err(err) = book {
+            log::warn!("{name}: {err}");
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let _telemetry = telemetry::init(&args.log, None)?;
+    anyhow::ensure!(
+        args.rotate_mb > 0 && args.rotate_minutes > 0,
+        "--rotate-mb and --rotate-minutes must be more than 0"
+    );
+    let recorder = Recorder::start(RecordSettings {
+        dir: args.dir,
+        rotate_bytes: args.rotate_mb * 1024 * 1024,
+        rotate_after: Duration::from_secs(args.rotate_minutes * 60),
+    })?;
+    let venues = match args.venues.is_empty() {
+        true => Venue::ALL.to_vec(),
+        false => args.venues,
+    };
+    for &instrument in &args.instruments {
+        for &venue in &venues {
+            tokio::spawn(record(recorder.clone(), venue, instrument));
+        }
+    }
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => log::info!("Got Ctrl-C"),
+        _ = terminate.recv() => log::info!("Got SIGTERM"),
+    }
+    tokio::task::spawn_blocking(move || recorder.stop()).await?;
+    log::info!("Stopped recording");
+    Ok(())
+}
@@ -0,0 +1,95 @@
+//! Serves summaries merged from captured frames instead of the live venues, for testing clients
+//! against a reproducible market. The server shuts down once every frame has been played
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use anyhow::Result;
+use bitstamp::model::CurrencyPair;
+use clap::Parser;
+use server::{
+    config::Compression,
+    recorder::captures,
+    replay::{Replay, Speed},
+    serve_until, telemetry,
+    venue::Venue,
+    SummaryServer,
+};
+use tokio::{
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+};
+
+/// How long clients get to finish once the replay's over
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(10);
+
+/// Command line flags. Each can also be set with the environment variable shown
+#[derive(Parser, Debug)]
+#[command(about = "Serves order book summaries merged from captured venue frames")]
+struct Args {
+    /// Capture files, or directories of them
+    #[arg(required = true)]
+    captures: Vec<PathBuf>,
+    /// How fast to play the frames: "1x" as they were captured, "10x", or "max"
+    #[arg(long, default_value = "1x")]
+    speed: Speed,
+    /// Where to serve the summaries
+    #[arg(long, env = "ORDERBOOK_LISTEN", default_value = "127.0.0.1:8000")]
+    listen: SocketAddr,
+    /// The instrument clients get if they don't ask for one
+    #[arg(long, default_value = "ethbtc")]
+    instrument: CurrencyPair,
+    /// Only replay these venues; every venue in the captures if not set. Playing starts once
+    /// there's a client for each of them
+    #[arg(long, value_delimiter = ',')]
+    venues: Vec<Venue>,
+    /// A tracing filter, eg. "info,server=debug"
+    #[arg(long, env = "ORDERBOOK_LOG", default_value = "info")]
+    log: String,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let _telemetry = telemetry::init(&args.log, None)?;
+    let mut paths = vec![];
+    for path in args.captures {
+        match path.is_dir() {
+            true => paths.extend(captures(&path)?),
+            false => paths.push(path),
+        }
+    }
+    let mut replay = Replay::load(&paths)?;
+    if !args.venues.is_empty() {
+        replay.retain(|frame| {
+            args.venues
+                .iter()
+                .any(|venue| venue.to_string() == frame.venue)
+        });
+    }
+    anyhow::ensure!(!replay.is_empty(), "There are no frames to replay");
+
+    let mut playing = replay.play(TcpListener::bind("127.0.0.1:0").await?, args.speed)?;
+    let service = Venue::ALL.into_iter().fold(
+        SummaryServer::new(args.instrument).with_clock(playing.clock().clone()),
+        |service, venue| service.with_endpoint(venue, playing.endpoint(venue)),
+    );
+    let listener = TcpListener::bind(args.listen).await?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let shutdown = async move {
+        tokio::select! {
+            _ = playing.finished() => log::info!("Every frame has been replayed"),
+            _ = tokio::signal::ctrl_c() => log::info!("Got Ctrl-C"),
+            _ = terminate.recv() => log::info!("Got SIGTERM"),
+        }
+    };
+    serve_until(
+        listener,
+        service,
+        Compression::Gzip,
+        None,
+        shutdown,
+        SHUTDOWN_DEADLINE,
+    )
+    .await?;
+    log::info!("Shut down");
+    Ok(())
+}
@@ -0,0 +1,266 @@
+//! Where the times we stamp on books and summaries come from. Normally that's the system's clock,
+//! but a replay runs a virtual one, moving it along to each captured frame's time as it plays the
+//! frame back, so the summaries come out the same however fast it goes. A virtual clock also keeps
+//! track of which of the streams it's timing have something to do, so a replay can wait for them
+//! to settle before moving it on
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Wake, Waker},
+    time::Duration,
+};
+
+use chrono::{DateTime, TimeZone, Utc};
+use futures::{stream::BoxStream, Stream, StreamExt};
+use tokio::{sync::watch, time::Instant};
+use tokio_stream::wrappers::{IntervalStream, WatchStream};
+
+use crate::model::now_us;
+
+/// What we tell the time by. Clones of a virtual clock share it
+#[derive(Debug, Clone, Default)]
+pub enum Clock {
+    #[default]
+    System,
+    Virtual(Arc<VirtualTime>),
+}
+
+/// A clock that only moves when it's told to
+#[derive(Debug)]
+pub struct VirtualTime {
+    /// Where it started, in Unix microseconds, and the real instant that maps to
+    start_us: i64,
+    started: Instant,
+    /// The time now, in Unix microseconds
+    now_us: watch::Sender<i64>,
+    /// How many tracked streams have something to do
+    busy: watch::Sender<usize>,
+}
+
+impl Clock {
+    /// A virtual clock, stopped at `start_us` Unix microseconds until it's moved on
+    pub fn virtual_at(start_us: i64) -> Clock {
+        Clock::Virtual(Arc::new(VirtualTime {
+            start_us,
+            started: Instant::now(),
+            now_us: watch::channel(start_us).0,
+            busy: watch::channel(0).0,
+        }))
+    }
+
+    /// The time now, in Unix microseconds
+    pub fn now_us(&self) -> i64 {
+        match self {
+            Clock::System => now_us(),
+            Clock::Virtual(time) => *time.now_us.borrow(),
+        }
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        match self {
+            Clock::System => Utc::now(),
+            Clock::Virtual(_) => Utc.timestamp_nanos(self.now_us() * 1000),
+        }
+    }
+
+    /// The time now as an `Instant`, for measuring ages and staleness. A virtual clock's instants
+    /// are as far apart as its times are, however long really passed between them
+    pub fn instant(&self) -> Instant {
+        match self {
+            Clock::System => Instant::now(),
+            Clock::Virtual(time) => {
+                let elapsed = self.now_us().saturating_sub(time.start_us).max(0);
+                time.started + Duration::from_micros(elapsed as u64)
+            }
+        }
+    }
+
+    /// Move a virtual clock on to `time_us` Unix microseconds. It never goes backwards, and the
+    /// system clock can't be moved at all
+    pub fn advance_to(&self, time_us: i64) {
+        if let Clock::Virtual(time) = self {
+            if time_us > *time.now_us.borrow() {
+                time.now_us.send_replace(time_us);
+            }
+        }
+    }
+
+    /// Ticks every `period` of the clock's time, starting straight away
+    pub fn ticks(&self, period: Duration) -> BoxStream<'static, ()> {
+        let time = match self {
+            Clock::System => {
+                return IntervalStream::new(tokio::time::interval(period))
+                    .map(drop)
+                    .boxed()
+            }
+            Clock::Virtual(time) => time,
+        };
+        let period_us = period.as_micros().max(1) as i64;
+        // One tick however many periods a single move skips over
+        let mut next_us = None;
+        self.tracked(
+            WatchStream::new(time.now_us.subscribe()).filter_map(move |now_us| {
+                let due = next_us.is_none_or(|next_us| now_us >= next_us);
+                if due {
+                    next_us = Some(now_us - now_us.rem_euclid(period_us) + period_us);
+                }
+                futures::future::ready(due.then_some(()))
+            }),
+        )
+    }
+
+    /// `stream`, counted as busy by a virtual clock from when it's woken, or first made, until
+    /// whatever's reading it has dealt with everything it had and is waiting for more. Items it
+    /// passes on are only dealt with once it's polled again
+    pub fn tracked<S>(&self, stream: S) -> BoxStream<'static, S::Item>
+    where
+        S: Stream + Send + 'static,
+    {
+        match self {
+            Clock::System => stream.boxed(),
+            Clock::Virtual(time) => {
+                time.busy.send_modify(|busy| *busy += 1);
+                let state = Tracking {
+                    busy: true,
+                    woken: false,
+                };
+                Tracked {
+                    stream: stream.boxed(),
+                    state: Arc::new(TrackedState {
+                        time: time.clone(),
+                        state: Mutex::new(state),
+                    }),
+                }
+                .boxed()
+            }
+        }
+    }
+
+    /// Resolves once none of a virtual clock's tracked streams have anything to do. The system
+    /// clock's are never waited for
+    pub async fn settled(&self) {
+        if let Clock::Virtual(time) = self {
+            let mut busy = time.busy.subscribe();
+            while *busy.borrow_and_update() > 0 {
+                // The sender's in `time`, so it can't be dropped
+                busy.changed().await.ok();
+            }
+        }
+    }
+}
+
+struct Tracking {
+    /// Counted in the clock's busy streams
+    busy: bool,
+    /// Woken since it was last polled
+    woken: bool,
+}
+
+struct TrackedState {
+    time: Arc<VirtualTime>,
+    state: Mutex<Tracking>,
+}
+
+impl TrackedState {
+    fn set_busy(&self, state: &mut Tracking, busy: bool) {
+        if state.busy != busy {
+            state.busy = busy;
+            self.time.busy.send_modify(|count| match busy {
+                true => *count += 1,
+                false => *count -= 1,
+            });
+        }
+    }
+}
+
+/// Wakes the task reading a tracked stream, having counted the stream as busy
+struct TrackedWaker {
+    state: Arc<TrackedState>,
+    waker: Waker,
+}
+
+impl Wake for TrackedWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        {
+            let mut state = self.state.state.lock().unwrap();
+            state.woken = true;
+            self.state.set_busy(&mut state, true);
+        }
+        self.waker.wake_by_ref();
+    }
+}
+
+/// See `Clock::tracked`
+struct Tracked<T> {
+    stream: BoxStream<'static, T>,
+    state: Arc<TrackedState>,
+}
+
+impl<T> Stream for Tracked<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.state.state.lock().unwrap().woken = false;
+        let waker = Waker::from(Arc::new(TrackedWaker {
+            state: self.state.clone(),
+            waker: cx.waker().clone(),
+        }));
+        let poll = self
+            .stream
+            .poll_next_unpin(&mut Context::from_waker(&waker));
+        let mut state = self.state.state.lock().unwrap();
+        match &poll {
+            // Whatever it's waiting for has to wake it, unless it already has
+            Poll::Pending if !state.woken => self.state.set_busy(&mut state, false),
+            Poll::Pending => {}
+            // Including the end, until it's dropped
+            Poll::Ready(_) => self.state.set_busy(&mut state, true),
+        }
+        poll
+    }
+}
+
+impl<T> Drop for Tracked<T> {
+    fn drop(&mut self) {
+        let mut state = self.state.state.lock().unwrap();
+        self.state.set_busy(&mut state, false);
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use std::time::Duration;
+
+    use futures::{FutureExt, StreamExt};
+
+    use super::Clock;
+
+    #[tokio::test]
+    async fn test_virtual() {
+        let clock = Clock::virtual_at(1_000_000);
+        let start = clock.instant();
+        let mut ticks = clock.ticks(Duration::from_secs(1));
+        assert_eq!(ticks.next().await, Some(()));
+        assert!(ticks.next().now_or_never().is_none());
+
+        clock.advance_to(1_500_000);
+        assert_eq!(clock.now_us(), 1_500_000);
+        assert_eq!(clock.now().timestamp_millis(), 1_500);
+        assert_eq!(clock.instant() - start, Duration::from_millis(500));
+        assert!(ticks.next().now_or_never().is_none());
+
+        // Past the next whole second
+        clock.advance_to(4_200_000);
+        assert_eq!(ticks.next().await, Some(()));
+        assert!(ticks.next().now_or_never().is_none());
+
+        // It doesn't go backwards
+        clock.advance_to(2_000_000);
+        assert_eq!(clock.now_us(), 4_200_000);
+        assert_eq!(clock.instant() - start, Duration::from_millis(3200));
+    }
+}
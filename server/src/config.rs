@@ -0,0 +1,767 @@
+//! The server's settings. They come from a TOML file, then environment variables, then command
+//! line flags, each overriding the last
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use bitstamp::model::CurrencyPair;
+use clap::Parser;
+use parse_display::{Display, FromStr};
+use serde::Deserialize;
+use thiserror::Error;
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    auth::Credentials,
+    export::ExportSettings,
+    history::HistorySettings,
+    hub::DEFAULT_IDLE_GRACE,
+    merge::StaleAfter,
+    recorder::RecordSettings,
+    request::{parse_tick, RequestDefaults, DEFAULT_DEPTH, MAX_DEPTH},
+    tls::TlsSettings,
+    venue::Venue,
+};
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Unable to read {path:?}: {error}")]
+    Read {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    #[error("Unable to parse {path:?}: {error}")]
+    Parse {
+        path: PathBuf,
+        error: toml::de::Error,
+    },
+    #[error("Invalid `{key}`: {reason}")]
+    Invalid { key: String, reason: String },
+}
+
+fn invalid(key: impl Into<String>, reason: impl Into<String>) -> ConfigError {
+    ConfigError::Invalid {
+        key: key.into(),
+        reason: reason.into(),
+    }
+}
+
+#[derive(Display, FromStr, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[display(style = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    None,
+}
+
+/// The config file, as written. See config.toml for what each setting does
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct Config {
+    pub listen: SocketAddr,
+    /// Where to serve Prometheus metrics over HTTP; nowhere if not set
+    pub metrics_listen: Option<SocketAddr>,
+    /// Where to send traces over OTLP/HTTP; nowhere if not set
+    pub otlp_endpoint: Option<String>,
+    pub instruments: Vec<String>,
+    pub depth: usize,
+    pub max_depth: usize,
+    /// The tick size each instrument's consolidated levels are combined by, as a decimal string
+    /// so it's exact, eg. "0.00001"
+    pub ticks: BTreeMap<String, String>,
+    pub stale_after_ms: u64,
+    pub idle_grace_ms: u64,
+    pub shutdown_timeout_ms: u64,
+    pub compression: Compression,
+    pub log: String,
+    /// A credentials file; without one, no API key is needed
+    pub credentials: Option<PathBuf>,
+    pub tls: TlsConfig,
+    pub record: RecordConfig,
+    pub history: HistoryConfig,
+    pub export: ExportConfig,
+    pub venues: BTreeMap<String, VenueConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: ([127, 0, 0, 1], 8000).into(),
+            metrics_listen: None,
+            otlp_endpoint: None,
+            instruments: vec![CurrencyPair::Ethbtc.to_string()],
+            depth: DEFAULT_DEPTH,
+            max_depth: MAX_DEPTH,
+            ticks: BTreeMap::new(),
+            stale_after_ms: 10_000,
+            idle_grace_ms: DEFAULT_IDLE_GRACE.as_millis() as u64,
+            shutdown_timeout_ms: 10_000,
+            compression: Compression::Gzip,
+            log: "info".to_string(),
+            credentials: None,
+            tls: TlsConfig::default(),
+            record: RecordConfig::default(),
+            history: HistoryConfig::default(),
+            export: ExportConfig::default(),
+            venues: BTreeMap::new(),
+        }
+    }
+}
+
+/// PEM files for TLS. With none of them set, the server listens in plaintext
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields, default)]
+pub struct TlsConfig {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// If set, clients must present a certificate signed by this CA
+    pub client_ca: Option<PathBuf>,
+}
+
+/// Recording the venues' raw frames. Nothing's recorded without a `dir`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct RecordConfig {
+    pub dir: Option<PathBuf>,
+    /// Start a new file once this much has been written, before compression
+    pub rotate_mb: u64,
+    /// Start a new file once the current one is this old
+    pub rotate_minutes: u64,
+}
+
+impl Default for RecordConfig {
+    fn default() -> Self {
+        RecordConfig {
+            dir: None,
+            rotate_mb: 100,
+            rotate_minutes: 60,
+        }
+    }
+}
+
+/// Keeping every merged book, for `GetBookAt` and `StreamRange`. Nothing's kept without a `dir`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct HistoryConfig {
+    pub dir: Option<PathBuf>,
+    /// Start a new segment once the current one is this big
+    pub segment_mb: u64,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            dir: None,
+            segment_mb: 64,
+        }
+    }
+}
+
+/// Exporting summaries and venue books to Parquet. Nothing's exported without a `dir`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct ExportConfig {
+    pub dir: Option<PathBuf>,
+    /// Levels of each side to keep
+    pub levels: usize,
+    /// Start a new file once the current one is this old
+    pub rotate_minutes: u64,
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        ExportConfig {
+            dir: None,
+            levels: DEFAULT_DEPTH,
+            rotate_minutes: 60,
+        }
+    }
+}
+
+/// Settings for one venue. Venues that aren't in the file get the defaults
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct VenueConfig {
+    pub enabled: bool,
+    pub endpoint: Option<String>,
+    pub stale_after_ms: Option<u64>,
+}
+
+impl Default for VenueConfig {
+    fn default() -> Self {
+        VenueConfig {
+            enabled: true,
+            endpoint: None,
+            stale_after_ms: None,
+        }
+    }
+}
+
+/// Command line flags. Each can also be set with the environment variable shown
+#[derive(Parser, Debug, Default)]
+#[command(about = "Merges order books from several exchanges and streams them over gRPC")]
+pub struct Args {
+    /// A TOML config file
+    #[arg(long, env = "ORDERBOOK_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// The address to serve gRPC on; use port 0 to pick any free port
+    #[arg(long, env = "ORDERBOOK_LISTEN")]
+    pub listen: Option<SocketAddr>,
+    /// The address to serve Prometheus metrics on, at /metrics
+    #[arg(long, env = "ORDERBOOK_METRICS_LISTEN")]
+    pub metrics_listen: Option<SocketAddr>,
+    /// An OpenTelemetry collector to send traces to over OTLP/HTTP, eg. `http://localhost:4318`
+    #[arg(long, env = "ORDERBOOK_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+    /// The instruments clients may ask for; the first is the default
+    #[arg(long, env = "ORDERBOOK_INSTRUMENTS", value_delimiter = ',')]
+    pub instruments: Option<Vec<String>>,
+    /// Enable only these venues
+    #[arg(long, env = "ORDERBOOK_VENUES", value_delimiter = ',')]
+    pub venues: Option<Vec<String>>,
+    /// Connect to a venue somewhere other than its default, eg. `binance=wss://localhost:9443/ws`
+    #[arg(long = "endpoint", env = "ORDERBOOK_ENDPOINTS", value_delimiter = ',')]
+    pub endpoints: Vec<String>,
+    /// Levels per side when a client doesn't ask for a depth
+    #[arg(long, env = "ORDERBOOK_DEPTH")]
+    pub depth: Option<usize>,
+    /// How long a venue can go quiet before it's left out of the summaries
+    #[arg(long, env = "ORDERBOOK_STALE_AFTER_MS")]
+    pub stale_after_ms: Option<u64>,
+    /// How long to wait for clients to finish when shutting down
+    #[arg(long, env = "ORDERBOOK_SHUTDOWN_TIMEOUT_MS")]
+    pub shutdown_timeout_ms: Option<u64>,
+    /// gzip or none
+    #[arg(long, env = "ORDERBOOK_COMPRESSION")]
+    pub compression: Option<Compression>,
+    /// A tracing filter, eg. "info,server=debug"
+    #[arg(long, env = "ORDERBOOK_LOG")]
+    pub log: Option<String>,
+    /// A credentials file listing the API keys clients can use, and what each may have
+    #[arg(long, env = "ORDERBOOK_CREDENTIALS")]
+    pub credentials: Option<PathBuf>,
+    /// The server's certificate, in PEM; serve TLS rather than plaintext
+    #[arg(long, env = "ORDERBOOK_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+    /// The server certificate's private key, in PEM
+    #[arg(long, env = "ORDERBOOK_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+    /// Require clients to present a certificate signed by this CA
+    #[arg(long, env = "ORDERBOOK_TLS_CLIENT_CA")]
+    pub tls_client_ca: Option<PathBuf>,
+    /// Record every frame from the venues to capture files in this directory
+    #[arg(long, env = "ORDERBOOK_RECORD_DIR")]
+    pub record_dir: Option<PathBuf>,
+    /// Keep every merged book in this directory, so clients can look back at them
+    #[arg(long, env = "ORDERBOOK_HISTORY_DIR")]
+    pub history_dir: Option<PathBuf>,
+    /// Export summaries and venue books to Parquet files in this directory
+    #[arg(long, env = "ORDERBOOK_EXPORT_DIR")]
+    pub export_dir: Option<PathBuf>,
+}
+
+impl Config {
+    /// Parse a config file's contents
+    pub fn parse(path: &Path, text: &str) -> Result<Config, ConfigError> {
+        toml::from_str(text).map_err(|error| ConfigError::Parse {
+            path: path.to_owned(),
+            error,
+        })
+    }
+
+    /// Read the config file named in `args` (if any) and apply the overrides from `args`
+    pub fn load(args: &Args) -> Result<Config, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => {
+                let text = std::fs::read_to_string(path).map_err(|error| ConfigError::Read {
+                    path: path.clone(),
+                    error,
+                })?;
+                Config::parse(path, &text)?
+            }
+            None => Config::default(),
+        };
+        config.apply(args)?;
+        Ok(config)
+    }
+
+    /// Apply the environment and command line overrides
+    pub fn apply(&mut self, args: &Args) -> Result<(), ConfigError> {
+        if let Some(listen) = args.listen {
+            self.listen = listen;
+        }
+        if let Some(metrics_listen) = args.metrics_listen {
+            self.metrics_listen = Some(metrics_listen);
+        }
+        if let Some(otlp_endpoint) = &args.otlp_endpoint {
+            self.otlp_endpoint = Some(otlp_endpoint.clone());
+        }
+        if let Some(instruments) = &args.instruments {
+            self.instruments = instruments.clone();
+        }
+        if let Some(venues) = &args.venues {
+            for venue in self.venues.values_mut() {
+                venue.enabled = false;
+            }
+            for name in Venue::ALL.iter().map(Venue::to_string) {
+                self.venues.entry(name).or_default().enabled = false;
+            }
+            for name in venues {
+                self.venues.entry(name.to_lowercase()).or_default().enabled = true;
+            }
+        }
+        for endpoint in &args.endpoints {
+            let (name, url) = endpoint.split_once('=').ok_or_else(|| {
+                invalid(
+                    "endpoint",
+                    format!("expected venue=url, but got \"{endpoint}\""),
+                )
+            })?;
+            self.venues.entry(name.to_lowercase()).or_default().endpoint = Some(url.to_string());
+        }
+        if let Some(depth) = args.depth {
+            self.depth = depth;
+        }
+        if let Some(stale_after_ms) = args.stale_after_ms {
+            self.stale_after_ms = stale_after_ms;
+        }
+        if let Some(shutdown_timeout_ms) = args.shutdown_timeout_ms {
+            self.shutdown_timeout_ms = shutdown_timeout_ms;
+        }
+        if let Some(compression) = args.compression {
+            self.compression = compression;
+        }
+        if let Some(log) = &args.log {
+            self.log = log.clone();
+        }
+        if let Some(credentials) = &args.credentials {
+            self.credentials = Some(credentials.clone());
+        }
+        if let Some(cert) = &args.tls_cert {
+            self.tls.cert = Some(cert.clone());
+        }
+        if let Some(key) = &args.tls_key {
+            self.tls.key = Some(key.clone());
+        }
+        if let Some(client_ca) = &args.tls_client_ca {
+            self.tls.client_ca = Some(client_ca.clone());
+        }
+        if let Some(dir) = &args.record_dir {
+            self.record.dir = Some(dir.clone());
+        }
+        if let Some(dir) = &args.history_dir {
+            self.history.dir = Some(dir.clone());
+        }
+        if let Some(dir) = &args.export_dir {
+            self.export.dir = Some(dir.clone());
+        }
+        Ok(())
+    }
+
+    /// Check everything, and turn it into the types the server uses
+    pub fn settings(&self) -> Result<Settings, ConfigError> {
+        let instruments = self
+            .instruments
+            .iter()
+            .enumerate()
+            .map(|(index, name)| {
+                name.to_lowercase().parse().map_err(|_| {
+                    invalid(
+                        format!("instruments[{index}]"),
+                        format!("unknown instrument \"{name}\""),
+                    )
+                })
+            })
+            .collect::<Result<Vec<CurrencyPair>, _>>()?;
+        let instrument = *instruments
+            .first()
+            .ok_or_else(|| invalid("instruments", "there must be at least one"))?;
+
+        if self.max_depth == 0 {
+            return Err(invalid("max_depth", "must be at least 1"));
+        }
+        if self.depth == 0 || self.depth > self.max_depth {
+            return Err(invalid(
+                "depth",
+                format!("must be between 1 and max_depth ({})", self.max_depth),
+            ));
+        }
+        let mut ticks = HashMap::new();
+        for (name, tick) in &self.ticks {
+            let key = format!("ticks.{name}");
+            let instrument = name
+                .to_lowercase()
+                .parse()
+                .map_err(|_| invalid(&key, format!("unknown instrument \"{name}\"")))?;
+            let tick = parse_tick(tick)
+                .map_err(|_| invalid(&key, "must be a decimal more than 0, eg. \"0.00001\""))?;
+            ticks.insert(instrument, tick);
+        }
+        let positive = |key: &str, ms: u64| match ms {
+            0 => Err(invalid(key, "must be more than 0")),
+            ms => Ok(Duration::from_millis(ms)),
+        };
+        let mut stale_after = StaleAfter::new(positive("stale_after_ms", self.stale_after_ms)?);
+        let idle_grace = positive("idle_grace_ms", self.idle_grace_ms)?;
+        let shutdown_timeout = positive("shutdown_timeout_ms", self.shutdown_timeout_ms)?;
+
+        let mut venues: BTreeSet<Venue> = Venue::ALL.into_iter().collect();
+        let mut endpoints = BTreeMap::new();
+        for (name, config) in &self.venues {
+            let key = format!("venues.{name}");
+            let venue: Venue = name
+                .parse()
+                .map_err(|_| invalid(&key, format!("unknown venue \"{name}\"")))?;
+            if !config.enabled {
+                venues.remove(&venue);
+            }
+            if let Some(endpoint) = &config.endpoint {
+                if !endpoint.starts_with("ws://") && !endpoint.starts_with("wss://") {
+                    return Err(invalid(
+                        format!("{key}.endpoint"),
+                        format!("\"{endpoint}\" isn't a ws:// or wss:// URL"),
+                    ));
+                }
+                endpoints.insert(venue, endpoint.clone());
+            }
+            if let Some(ms) = config.stale_after_ms {
+                stale_after = stale_after.with_venue(venue, positive(&key, ms)?);
+            }
+        }
+        if venues.is_empty() {
+            return Err(invalid("venues", "every venue is disabled"));
+        }
+
+        let tls = match (&self.tls.cert, &self.tls.key) {
+            (Some(cert), Some(key)) => Some(TlsSettings {
+                cert: cert.clone(),
+                key: key.clone(),
+                client_ca: self.tls.client_ca.clone(),
+            }),
+            (Some(_), None) => return Err(invalid("tls.key", "tls.cert needs a key")),
+            (None, Some(_)) => return Err(invalid("tls.cert", "tls.key needs a certificate")),
+            (None, None) if self.tls.client_ca.is_some() => {
+                return Err(invalid("tls.client_ca", "needs tls.cert and tls.key"))
+            }
+            (None, None) => None,
+        };
+        if let Some(endpoint) = &self.otlp_endpoint {
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                return Err(invalid(
+                    "otlp_endpoint",
+                    format!("expected an http or https url, but got \"{endpoint}\""),
+                ));
+            }
+        }
+        let record = match &self.record.dir {
+            Some(dir) => {
+                for (key, value) in [
+                    ("record.rotate_mb", self.record.rotate_mb),
+                    ("record.rotate_minutes", self.record.rotate_minutes),
+                ] {
+                    if value == 0 {
+                        return Err(invalid(key, "must be more than 0"));
+                    }
+                }
+                Some(RecordSettings {
+                    dir: dir.clone(),
+                    rotate_bytes: self.record.rotate_mb * 1024 * 1024,
+                    rotate_after: Duration::from_secs(self.record.rotate_minutes * 60),
+                })
+            }
+            None => None,
+        };
+        let history = match &self.history.dir {
+            Some(_) if self.history.segment_mb == 0 => {
+                return Err(invalid("history.segment_mb", "must be more than 0"))
+            }
+            Some(dir) => Some(HistorySettings {
+                dir: dir.clone(),
+                segment_bytes: self.history.segment_mb * 1024 * 1024,
+            }),
+            None => None,
+        };
+        let export = match &self.export.dir {
+            Some(dir) => {
+                for (key, value) in [
+                    ("export.levels", self.export.levels as u64),
+                    ("export.rotate_minutes", self.export.rotate_minutes),
+                ] {
+                    if value == 0 {
+                        return Err(invalid(key, "must be more than 0"));
+                    }
+                }
+                Some(ExportSettings {
+                    dir: dir.clone(),
+                    levels: self.export.levels,
+                    rotate_after: Duration::from_secs(self.export.rotate_minutes * 60),
+                })
+            }
+            None => None,
+        };
+        EnvFilter::try_new(&self.log).map_err(|err| invalid("log", err.to_string()))?;
+        let credentials = self
+            .credentials
+            .as_deref()
+            .map(Credentials::load)
+            .transpose()?;
+
+        Ok(Settings {
+            listen: self.listen,
+            metrics_listen: self.metrics_listen,
+            otlp_endpoint: self.otlp_endpoint.clone(),
+            defaults: RequestDefaults {
+                instrument,
+                instruments,
+                venues,
+                depth: self.depth,
+                max_depth: self.max_depth,
+                ticks,
+            },
+            endpoints,
+            stale_after,
+            idle_grace,
+            shutdown_timeout,
+            compression: self.compression,
+            log: self.log.clone(),
+            credentials,
+            tls,
+            record,
+            history,
+            export,
+        })
+    }
+}
+
+/// Checked settings, ready to use
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub listen: SocketAddr,
+    pub metrics_listen: Option<SocketAddr>,
+    pub otlp_endpoint: Option<String>,
+    pub defaults: RequestDefaults,
+    /// Only the venues that aren't using their default endpoint
+    pub endpoints: BTreeMap<Venue, String>,
+    pub stale_after: StaleAfter,
+    pub idle_grace: Duration,
+    pub shutdown_timeout: Duration,
+    pub compression: Compression,
+    pub log: String,
+    pub credentials: Option<Credentials>,
+    pub tls: Option<TlsSettings>,
+    pub record: Option<RecordSettings>,
+    pub history: Option<HistorySettings>,
+    pub export: Option<ExportSettings>,
+}
+
+#[cfg(test)]
+mod unit_test {
+    use std::{path::Path, time::Duration};
+
+    use bitstamp::model::CurrencyPair;
+    use clap::Parser;
+    use rust_decimal::Decimal;
+
+    use super::{Args, Compression, Config, ConfigError};
+    use crate::venue::Venue;
+
+    fn invalid_key(text: &str) -> String {
+        let config = Config::parse(Path::new("test.toml"), text).unwrap();
+        match config.settings() {
+            Err(ConfigError::Invalid { key, .. }) => key,
+            other => panic!("Expected {text:?} to be invalid, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_example_config() {
+        // The example config should always be valid, and the same as the defaults
+        let config = Config::parse(Path::new("config.toml"), include_str!("../config.toml"));
+        let settings = config.unwrap().settings().unwrap();
+        assert_eq!(settings, Config::default().settings().unwrap());
+
+        // Its tick size, uncommented, is exact
+        let example = include_str!("../config.toml").replace("# [ticks]\n# ", "[ticks]\n");
+        let config = Config::parse(Path::new("config.toml"), &example);
+        let settings = config.unwrap().settings().unwrap();
+        assert_eq!(
+            settings.defaults.ticks.get(&CurrencyPair::Ethbtc),
+            Some(&Decimal::new(1, 5))
+        );
+    }
+
+    #[test]
+    fn test_config_file() {
+        let config = Config::parse(
+            Path::new("test.toml"),
+            r#"
+                listen = "0.0.0.0:0"
+                instruments = ["btcusd", "ethbtc"]
+                depth = 5
+                compression = "none"
+
+                [ticks]
+                ethbtc = "0.00001"
+
+                [venues.htx]
+                enabled = false
+
+                [venues.binance]
+                endpoint = "ws://localhost:9443/ws"
+                stale_after_ms = 2000
+            "#,
+        )
+        .unwrap();
+        let settings = config.settings().unwrap();
+        assert_eq!(settings.listen.port(), 0);
+        assert_eq!(settings.defaults.instrument, CurrencyPair::Btcusd);
+        assert_eq!(settings.defaults.depth, 5);
+        assert_eq!(
+            settings.defaults.ticks.get(&CurrencyPair::Ethbtc),
+            Some(&Decimal::new(1, 5))
+        );
+        assert!(!settings.defaults.venues.contains(&Venue::Htx));
+        assert_eq!(
+            settings.endpoints.get(&Venue::Binance).unwrap(),
+            "ws://localhost:9443/ws"
+        );
+        assert_eq!(
+            settings.stale_after.threshold(Venue::Binance),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            settings.stale_after.threshold(Venue::Bitstamp),
+            Duration::from_secs(10)
+        );
+        assert_eq!(settings.compression, Compression::None);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(
+            invalid_key(r#"instruments = ["ethbtc", "doge"]"#),
+            "instruments[1]"
+        );
+        assert_eq!(invalid_key("instruments = []"), "instruments");
+        assert_eq!(invalid_key("depth = 500"), "depth");
+        assert_eq!(invalid_key("stale_after_ms = 0"), "stale_after_ms");
+        assert_eq!(invalid_key("[venues.nasdaq]"), "venues.nasdaq");
+        assert_eq!(invalid_key("[ticks]\ndogebtc = \"0.1\""), "ticks.dogebtc");
+        assert_eq!(invalid_key("[ticks]\nethbtc = \"0\""), "ticks.ethbtc");
+        assert_eq!(invalid_key("[ticks]\nethbtc = \"-0.1\""), "ticks.ethbtc");
+        assert_eq!(invalid_key("[ticks]\nethbtc = \"1e-5\""), "ticks.ethbtc");
+        assert_eq!(invalid_key("[tls]\ncert = \"server.pem\""), "tls.key");
+        assert_eq!(
+            invalid_key("[tls]\nclient_ca = \"ca.pem\""),
+            "tls.client_ca"
+        );
+        assert_eq!(
+            invalid_key("[venues.bybit]\nendpoint = \"https://bybit.com\""),
+            "venues.bybit.endpoint"
+        );
+        assert_eq!(
+            invalid_key("[venues.bybit]\nstale_after_ms = 0"),
+            "venues.bybit"
+        );
+        assert_eq!(
+            invalid_key("otlp_endpoint = \"localhost:4318\""),
+            "otlp_endpoint"
+        );
+        assert_eq!(invalid_key("log = \"server=loud\""), "log");
+        assert_eq!(
+            invalid_key("[record]\ndir = \"captures\"\nrotate_mb = 0"),
+            "record.rotate_mb"
+        );
+        // Nothing's recorded without a directory, so there's nothing to check
+        assert!(
+            Config::parse(Path::new("test.toml"), "[record]\nrotate_mb = 0")
+                .unwrap()
+                .settings()
+                .unwrap()
+                .record
+                .is_none()
+        );
+        assert_eq!(
+            invalid_key("[history]\ndir = \"history\"\nsegment_mb = 0"),
+            "history.segment_mb"
+        );
+        assert_eq!(
+            invalid_key("[export]\ndir = \"export\"\nlevels = 0"),
+            "export.levels"
+        );
+
+        // Typos and type errors are caught while parsing, and name the key too
+        let err = Config::parse(Path::new("test.toml"), "dpeth = 5").unwrap_err();
+        assert!(err.to_string().contains("dpeth"), "{err}");
+        let err =
+            Config::parse(Path::new("test.toml"), "[venues.binance]\nenabled = 1").unwrap_err();
+        assert!(err.to_string().contains("enabled"), "{err}");
+    }
+
+    #[test]
+    fn test_overrides() {
+        let mut config = Config::parse(
+            Path::new("test.toml"),
+            "depth = 5\n[venues.binance]\nendpoint = \"ws://localhost:1/ws\"",
+        )
+        .unwrap();
+        let args = Args::parse_from([
+            "server",
+            "--listen",
+            "127.0.0.1:0",
+            "--venues",
+            "binance,Bybit",
+            "--endpoint",
+            "bybit=ws://localhost:2",
+            "--depth",
+            "20",
+            "--shutdown-timeout-ms",
+            "500",
+            "--metrics-listen",
+            "127.0.0.1:9000",
+            "--otlp-endpoint",
+            "http://localhost:4318",
+            "--record-dir",
+            "captures",
+            "--history-dir",
+            "history",
+            "--export-dir",
+            "export",
+        ]);
+        config.apply(&args).unwrap();
+        let settings = config.settings().unwrap();
+        assert_eq!(settings.listen.port(), 0);
+        assert_eq!(settings.defaults.depth, 20);
+        assert_eq!(settings.shutdown_timeout, Duration::from_millis(500));
+        assert_eq!(settings.metrics_listen, Some(([127, 0, 0, 1], 9000).into()));
+        assert_eq!(
+            settings.otlp_endpoint.as_deref(),
+            Some("http://localhost:4318")
+        );
+        let record = settings.record.as_ref().unwrap();
+        assert_eq!(record.dir, std::path::PathBuf::from("captures"));
+        assert_eq!(record.rotate_bytes, 100 * 1024 * 1024);
+        assert_eq!(record.rotate_after, Duration::from_secs(3600));
+        let history = settings.history.as_ref().unwrap();
+        assert_eq!(history.dir, std::path::PathBuf::from("history"));
+        assert_eq!(history.segment_bytes, 64 * 1024 * 1024);
+        let export = settings.export.as_ref().unwrap();
+        assert_eq!(export.dir, std::path::PathBuf::from("export"));
+        assert_eq!(export.levels, 10);
+        assert_eq!(export.rotate_after, Duration::from_secs(3600));
+        assert_eq!(
+            settings.defaults.venues.into_iter().collect::<Vec<_>>(),
+            vec![Venue::Binance, Venue::Bybit]
+        );
+        assert_eq!(settings.endpoints.len(), 2);
+
+        let args = Args::parse_from(["server", "--endpoint", "bybit"]);
+        assert!(matches!(
+            config.apply(&args),
+            Err(ConfigError::Invalid { key, .. }) if key == "endpoint"
+        ));
+    }
+}
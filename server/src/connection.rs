@@ -0,0 +1,349 @@
+//! Keeps a venue connected, reconnecting when the exchange drops us, and keeps track of how the
+//! connection is doing for `GetVenueStatus`. Gives up on a fatal error, as reconnecting won't help
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use futures::{stream, Future, StreamExt};
+use tokio::{sync::Notify, time::Instant};
+
+use crate::{
+    api::ConnectionState,
+    venue::{ErrorKind, VenueError, VenueFeed},
+};
+
+/// How long we wait before the first reconnect; it doubles every time one fails
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+/// How long we count messages over to work out the rate
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// How one venue connection is doing
+#[derive(Debug, Clone)]
+pub struct ConnectionStats {
+    pub state: ConnectionState,
+    pub last_message: Option<DateTime<Utc>>,
+    pub reconnects: u32,
+    pub messages: u64,
+    /// The fatal error we gave up on, until we next connect
+    pub failure: Option<String>,
+    /// The rate over the last complete window
+    rate: f64,
+    window_start: Instant,
+    window_messages: u64,
+}
+
+impl Default for ConnectionStats {
+    fn default() -> Self {
+        ConnectionStats {
+            state: ConnectionState::Connecting,
+            last_message: None,
+            reconnects: 0,
+            messages: 0,
+            failure: None,
+            rate: 0.0,
+            window_start: Instant::now(),
+            window_messages: 0,
+        }
+    }
+}
+
+impl ConnectionStats {
+    /// Count a book from the venue
+    pub fn message(&mut self, now: Instant) {
+        self.messages += 1;
+        self.last_message = Some(Utc::now());
+        self.window_messages += 1;
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= RATE_WINDOW {
+            self.rate = self.window_messages as f64 / elapsed.as_secs_f64();
+            self.window_start = now;
+            self.window_messages = 0;
+        }
+    }
+
+    /// Give up on the connection because of `error`
+    pub fn fail(&mut self, error: &VenueError) {
+        self.state = ConnectionState::Disconnected;
+        self.failure = Some(error.to_string());
+    }
+
+    /// Messages per second. If the venue has gone quiet this falls away, rather than sticking at
+    /// the last window's rate
+    pub fn messages_per_second(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= RATE_WINDOW {
+            self.window_messages as f64 / elapsed.as_secs_f64()
+        } else {
+            self.rate
+        }
+    }
+}
+
+pub type SharedStats = Arc<Mutex<ConnectionStats>>;
+
+/// Marks the connection as disconnected when the stream is dropped, eg. because nobody's using it
+struct Disconnect(SharedStats);
+
+impl Drop for Disconnect {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().state = ConnectionState::Disconnected;
+    }
+}
+
+/// Read from `first`, and whenever a connection ends call `connect` for a new one, backing off
+/// while that fails. `restart` drops the current connection and connects again straight away, eg.
+/// when the endpoint has changed. Errors are passed on; after a fatal one, from the connection or
+/// from connecting, it ends. `name` is for the logs
+pub fn reconnecting<T, F, Fut>(
+    name: String,
+    first: VenueFeed<T>,
+    connect: F,
+    stats: SharedStats,
+    restart: Arc<Notify>,
+) -> VenueFeed<T>
+where
+    T: Send + 'static,
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<VenueFeed<T>, VenueError>> + Send + 'static,
+{
+    {
+        let mut stats = stats.lock().unwrap();
+        stats.state = ConnectionState::Connected;
+        stats.failure = None;
+    }
+    let state = (Some(first), RECONNECT_DELAY, Disconnect(stats), false);
+    let stream = stream::unfold(state, move |(mut current, mut delay, stats, failed)| {
+        let name = name.clone();
+        let restart = restart.clone();
+        let reconnect = (current.is_none() && !failed).then(&connect);
+        async move {
+            if failed {
+                return None;
+            }
+            // Passes a fatal error on, then ends
+            let give_up = |err: VenueError, stats: Disconnect| {
+                log::error!("{name} failed, giving up: {err}");
+                stats.0.lock().unwrap().fail(&err);
+                Some((Some(Err(err)), (None, delay, stats, true)))
+            };
+            if let Some(reconnect) = reconnect {
+                tokio::time::sleep(delay).await;
+                match reconnect.await {
+                    Ok(stream) => {
+                        let mut stats = stats.0.lock().unwrap();
+                        stats.state = ConnectionState::Connected;
+                        stats.reconnects += 1;
+                        stats.failure = None;
+                        current = Some(stream);
+                    }
+                    Err(err) if err.kind() == ErrorKind::Fatal => return give_up(err, stats),
+                    Err(err) => {
+                        delay = (delay * 2).clamp(RECONNECT_DELAY, MAX_RECONNECT_DELAY);
+                        return Some((Some(Err(err)), (None, delay, stats, false)));
+                    }
+                }
+            }
+            let item = tokio::select! {
+                item = current.as_mut()?.next() => item,
+                _ = restart.notified() => {
+                    log::info!("Restarting {name}");
+                    stats.0.lock().unwrap().state = ConnectionState::Reconnecting;
+                    return Some((None, (None, Duration::ZERO, stats, false)));
+                }
+            };
+            match item {
+                Some(Ok(book)) => {
+                    stats.0.lock().unwrap().message(Instant::now());
+                    delay = RECONNECT_DELAY;
+                    Some((Some(Ok(book)), (current, delay, stats, false)))
+                }
+                Some(Err(err)) if err.kind() == ErrorKind::Fatal => give_up(err, stats),
+                Some(Err(err)) => Some((Some(Err(err)), (current, delay, stats, false))),
+                None => {
+                    log::warn!("{name} closed; reconnecting in {delay:?}");
+                    stats.0.lock().unwrap().state = ConnectionState::Reconnecting;
+                    Some((None, (None, delay, stats, false)))
+                }
+            }
+        }
+    });
+    // The Nones are just us going round again to reconnect
+    Box::pin(stream.filter_map(futures::future::ready))
+}
+
+#[cfg(test)]
+mod unit_test {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use futures::{stream, StreamExt};
+    use tokio::{sync::Notify, time::Instant};
+
+    use super::{reconnecting, ConnectionStats, SharedStats};
+    use crate::{
+        api::ConnectionState,
+        model::VenueBook,
+        venue::{VenueError, VenueStream},
+    };
+
+    fn books(count: usize) -> VenueStream {
+        Box::pin(stream::iter(
+            (0..count).map(|_| Ok::<_, VenueError>(VenueBook::default())),
+        ))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reconnects() {
+        let stats = SharedStats::default();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let connect = {
+            let attempts = attempts.clone();
+            move || {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    // The first reconnect fails; the second gets another two books
+                    if attempt == 0 {
+                        Err(VenueError::Bybit(bybit::Error::DeltaBeforeSnapshot {
+                            topic: "test".to_string(),
+                        }))
+                    } else {
+                        Ok(books(2))
+                    }
+                }
+            }
+        };
+        let mut stream = reconnecting(
+            "test".to_string(),
+            books(1),
+            connect,
+            stats.clone(),
+            Arc::default(),
+        );
+        assert!(stream.next().await.unwrap().is_ok());
+        assert_eq!(stats.lock().unwrap().state, ConnectionState::Connected);
+
+        let start = Instant::now();
+        assert!(stream.next().await.unwrap().is_err());
+        assert_eq!(stats.lock().unwrap().state, ConnectionState::Reconnecting);
+        assert!(stream.next().await.unwrap().is_ok());
+        // We waited a second before the first attempt, then two before the next
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+        assert!(stream.next().await.unwrap().is_ok());
+        {
+            let stats = stats.lock().unwrap();
+            assert_eq!(stats.state, ConnectionState::Connected);
+            assert_eq!(stats.reconnects, 1);
+            assert_eq!(stats.messages, 3);
+            assert!(stats.last_message.is_some());
+        }
+
+        drop(stream);
+        assert_eq!(stats.lock().unwrap().state, ConnectionState::Disconnected);
+    }
+
+    fn refused() -> VenueError {
+        VenueError::Bybit(bybit::Error::Refused {
+            op: "subscribe".to_string(),
+            reason: "unknown symbol".to_string(),
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fatal() {
+        // Refused mid-stream: passed on, then the stream ends without reconnecting
+        let stats = SharedStats::default();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let connect = {
+            let attempts = attempts.clone();
+            move || {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                async { Ok(books(1)) }
+            }
+        };
+        let first = Box::pin(books(1).chain(stream::iter([Err(refused())])));
+        let mut stream = reconnecting(
+            "test".to_string(),
+            first,
+            connect,
+            stats.clone(),
+            Arc::default(),
+        );
+        assert!(stream.next().await.unwrap().is_ok());
+        assert!(stream.next().await.unwrap().is_err());
+        assert!(stream.next().await.is_none());
+        assert_eq!(attempts.load(Ordering::SeqCst), 0);
+        {
+            let stats = stats.lock().unwrap();
+            assert_eq!(stats.state, ConnectionState::Disconnected);
+            assert!(stats.failure.as_ref().unwrap().contains("unknown symbol"));
+        }
+
+        // Refused when reconnecting: the same, however long we'd have kept trying otherwise
+        let stats = SharedStats::default();
+        let connect = || async { Err(refused()) };
+        let mut stream = reconnecting(
+            "test".to_string(),
+            books(0),
+            connect,
+            stats.clone(),
+            Arc::default(),
+        );
+        assert!(stream.next().await.unwrap().is_err());
+        assert!(stream.next().await.is_none());
+        assert!(stats.lock().unwrap().failure.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restart() {
+        let stats = SharedStats::default();
+        let restart = Arc::new(Notify::new());
+        // A connection that never sends anything, so only a restart gets us another
+        let quiet: VenueStream = Box::pin(stream::pending());
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let connect = {
+            let attempts = attempts.clone();
+            move || {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                async move { Ok(Box::pin(books(1).chain(stream::pending())) as VenueStream) }
+            }
+        };
+        let mut stream = reconnecting(
+            "test".to_string(),
+            quiet,
+            connect,
+            stats.clone(),
+            restart.clone(),
+        );
+        restart.notify_one();
+        let start = Instant::now();
+        assert!(stream.next().await.unwrap().is_ok());
+        // No backing off for a restart
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+        let stats = stats.lock().unwrap();
+        assert_eq!(stats.state, ConnectionState::Connected);
+        assert_eq!(stats.reconnects, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate() {
+        let mut stats = ConnectionStats::default();
+        let start = Instant::now();
+        for tick in 1..=20 {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            stats.message(start + Duration::from_millis(500 * tick));
+        }
+        assert_eq!(stats.messages_per_second(Instant::now()), 2.0);
+        // Gone quiet
+        tokio::time::sleep(Duration::from_secs(20)).await;
+        assert_eq!(stats.messages_per_second(Instant::now()), 0.0);
+    }
+}
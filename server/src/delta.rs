@@ -0,0 +1,393 @@
+//! Turns a stream of summaries into a snapshot followed by deltas, for `BookUpdates`, and back
+use std::{cmp::Ordering, collections::HashMap};
+
+use crate::api::{
+    book_update::Update, Action, BookDelta, BookUpdate, Checksum, Level, LevelChange, Side, Summary,
+};
+
+/// How many deltas we send between checksums
+const CHECKSUM_EVERY: u64 = 10;
+
+/// Levels in checksum order: best price first, then by exchange
+fn sorted(levels: &[Level], side: Side) -> Vec<&Level> {
+    let mut sorted: Vec<&Level> = levels.iter().collect();
+    sorted.sort_by(|a, b| {
+        let by_price = a.price.partial_cmp(&b.price).unwrap_or(Ordering::Equal);
+        let by_price = match side {
+            Side::Bid => by_price.reverse(),
+            Side::Ask => by_price,
+        };
+        by_price.then_with(|| a.exchange.cmp(&b.exchange))
+    });
+    sorted
+}
+
+/// The checksum of a book, as described in orderbook.proto. `f64`'s `Display` is the decimal
+/// form it describes
+pub fn checksum(bids: &[Level], asks: &[Level]) -> u32 {
+    let side = |levels: &[Level], side| {
+        sorted(levels, side)
+            .into_iter()
+            .map(|level| format!("{}:{}:{}", level.exchange, level.price, level.amount))
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    let book = format!("{}|{}", side(bids, Side::Bid), side(asks, Side::Ask));
+    crc32fast::hash(book.as_bytes())
+}
+
+/// A level's place in the book: its exchange and price
+fn key(level: &Level) -> (String, u64) {
+    (level.exchange.clone(), level.price.to_bits())
+}
+
+/// What's changed on one side of the book. A level is updated if its amount changes, or how much
+/// of it each venue has
+fn diff(side: Side, old: &[Level], new: &[Level]) -> Vec<LevelChange> {
+    let old: HashMap<_, &Level> = old.iter().map(|level| (key(level), level)).collect();
+    let mut changes = vec![];
+    for level in new {
+        let action = match old.get(&key(level)) {
+            None => Action::Insert,
+            Some(old) if *old != level => Action::Update,
+            Some(_) => continue,
+        };
+        changes.push(LevelChange {
+            side: side as i32,
+            action: action as i32,
+            level: Some(level.clone()),
+        });
+    }
+    let new: HashMap<_, &Level> = new.iter().map(|level| (key(level), level)).collect();
+    for ((exchange, price), _) in old {
+        if !new.contains_key(&(exchange.clone(), price)) {
+            changes.push(LevelChange {
+                side: side as i32,
+                action: Action::Delete as i32,
+                level: Some(Level {
+                    exchange,
+                    price: f64::from_bits(price),
+                    ..Level::default()
+                }),
+            });
+        }
+    }
+    changes
+}
+
+/// `levels` with `changes` to their side applied, in checksum order
+fn apply_side(side: Side, levels: &[Level], changes: &[LevelChange]) -> Vec<Level> {
+    let mut book: HashMap<_, Level> = levels
+        .iter()
+        .map(|level| (key(level), level.clone()))
+        .collect();
+    let changes = changes.iter().filter(|change| change.side == side as i32);
+    for (change, level) in changes.filter_map(|change| Some((change, change.level.as_ref()?))) {
+        match Action::from_i32(change.action) {
+            Some(Action::Delete) => book.remove(&key(level)),
+            _ => book.insert(key(level), level.clone()),
+        };
+    }
+    let levels: Vec<Level> = book.into_values().collect();
+    sorted(&levels, side).into_iter().cloned().collect()
+}
+
+/// The summary `delta` was made from, given the one before it. Its levels come out best price
+/// first, then by exchange, which is how a merged summary has them
+pub fn apply(last: &Summary, delta: BookDelta) -> Summary {
+    Summary {
+        spread: delta.spread,
+        bids: apply_side(Side::Bid, &last.bids, &delta.changes),
+        asks: apply_side(Side::Ask, &last.asks, &delta.changes),
+        venues: delta.venues,
+        merged_time_us: delta.merged_time_us,
+        sent_time_us: delta.sent_time_us,
+    }
+}
+
+/// Remembers what one client has been sent, so the next summary can go as a delta
+#[derive(Debug, Default)]
+pub struct DeltaEncoder {
+    sequence: u64,
+    last: Option<Summary>,
+}
+
+impl DeltaEncoder {
+    /// The next update for the client: a snapshot the first time, then deltas
+    pub fn encode(&mut self, summary: Summary) -> BookUpdate {
+        self.sequence += 1;
+        let update = match &self.last {
+            None => Update::Snapshot(summary.clone()),
+            Some(last) => {
+                let mut changes = diff(Side::Bid, &last.bids, &summary.bids);
+                changes.extend(diff(Side::Ask, &last.asks, &summary.asks));
+                Update::Delta(BookDelta {
+                    changes,
+                    spread: summary.spread,
+                    venues: summary.venues.clone(),
+                    merged_time_us: summary.merged_time_us,
+                    sent_time_us: summary.sent_time_us,
+                })
+            }
+        };
+        let checksum =
+            (self.last.is_none() || self.sequence.is_multiple_of(CHECKSUM_EVERY)).then(|| {
+                Checksum {
+                    crc32: checksum(&summary.bids, &summary.asks),
+                }
+            });
+        self.last = Some(summary);
+        BookUpdate {
+            sequence: self.sequence,
+            update: Some(update),
+            checksum,
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use super::{apply, checksum, DeltaEncoder};
+    use crate::api::{book_update::Update, Action, Level, Side, Summary, VenueContribution};
+
+    fn level(exchange: &str, price: f64, amount: f64) -> Level {
+        Level {
+            exchange: exchange.to_string(),
+            price,
+            amount,
+            ..Level::default()
+        }
+    }
+
+    /// Fixed, so clients in other languages can check their formatting against them
+    #[test]
+    fn test_checksum_vectors() {
+        // The example in orderbook.proto
+        let bids = [level("binance", 0.05, 1.5)];
+        let asks = [level("bitstamp", 1e-7, 2.0)];
+        assert_eq!(checksum(&bids, &asks), 1290291321);
+        // "binance:0.05:1.5,bitstamp:0.05:2|bybit:0.0000001:1500000000000000000000"
+        let bids = [level("bitstamp", 0.05, 2.0), level("binance", 0.05, 1.5)];
+        let asks = [level("bybit", 1e-7, 1.5e21)];
+        assert_eq!(checksum(&bids, &asks), 2217203815);
+    }
+
+    #[test]
+    fn test_snapshot_then_deltas() {
+        let mut encoder = DeltaEncoder::default();
+        let first = Summary {
+            spread: -1.0,
+            bids: vec![level("binance", 1.0, 5.0), level("bitstamp", 0.9, 1.0)],
+            asks: vec![level("binance", 2.0, 5.0)],
+            ..Summary::default()
+        };
+        let update = encoder.encode(first.clone());
+        assert_eq!(update.sequence, 1);
+        assert_eq!(update.update, Some(Update::Snapshot(first.clone())));
+        assert_eq!(
+            update.checksum.unwrap().crc32,
+            checksum(&first.bids, &first.asks)
+        );
+
+        let second = Summary {
+            spread: -0.9,
+            bids: vec![level("binance", 1.0, 4.0), level("bybit", 0.95, 1.0)],
+            asks: vec![level("binance", 2.0, 5.0)],
+            venues: vec![],
+            merged_time_us: 1_000,
+            sent_time_us: 1_500,
+        };
+        let update = encoder.encode(second);
+        assert_eq!(update.sequence, 2);
+        assert!(update.checksum.is_none());
+        let delta = match update.update {
+            Some(Update::Delta(delta)) => delta,
+            other => panic!("Expected a delta, got {other:?}"),
+        };
+        assert_eq!(delta.spread, -0.9);
+        assert_eq!((delta.merged_time_us, delta.sent_time_us), (1_000, 1_500));
+        let mut changes: Vec<_> = delta
+            .changes
+            .iter()
+            .map(|change| {
+                let level = change.level.as_ref().unwrap();
+                (
+                    Side::from_i32(change.side).unwrap(),
+                    Action::from_i32(change.action).unwrap(),
+                    level.exchange.as_str(),
+                    level.price,
+                )
+            })
+            .collect();
+        changes.sort_by_key(|(_, action, exchange, _)| (*action, *exchange));
+        assert_eq!(
+            changes,
+            vec![
+                (Side::Bid, Action::Insert, "bybit", 0.95),
+                (Side::Bid, Action::Update, "binance", 1.0),
+                (Side::Bid, Action::Delete, "bitstamp", 0.9),
+            ]
+        );
+
+        // Checksums come round every so often
+        let checksums = (3..=20)
+            .map(|_| encoder.encode(Summary::default()))
+            .filter(|update| update.checksum.is_some())
+            .map(|update| update.sequence)
+            .collect::<Vec<_>>();
+        assert_eq!(checksums, vec![10, 20]);
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut encoder = DeltaEncoder::default();
+        let first = Summary {
+            spread: -1.0,
+            bids: vec![level("binance", 1.0, 5.0), level("bitstamp", 0.9, 1.0)],
+            asks: vec![level("binance", 2.0, 5.0), level("bybit", 2.0, 3.0)],
+            ..Summary::default()
+        };
+        let second = Summary {
+            spread: -0.95,
+            bids: vec![
+                level("bitstamp", 1.05, 2.0),
+                level("binance", 1.0, 4.0),
+                level("bybit", 1.0, 1.0),
+            ],
+            asks: vec![level("bybit", 2.0, 3.0)],
+            venues: vec![],
+            merged_time_us: 1_000,
+            sent_time_us: 0,
+        };
+        encoder.encode(first.clone());
+        let delta = match encoder.encode(second.clone()).update {
+            Some(Update::Delta(delta)) => delta,
+            other => panic!("Expected a delta, got {other:?}"),
+        };
+        assert_eq!(apply(&first, delta), second);
+    }
+
+    #[test]
+    fn test_consolidated_update() {
+        // The same amount, but moved from one venue to another, is still an update
+        let consolidated = |binance, bitstamp| Level {
+            contributions: vec![
+                VenueContribution {
+                    exchange: "binance".to_string(),
+                    amount: binance,
+                },
+                VenueContribution {
+                    exchange: "bitstamp".to_string(),
+                    amount: bitstamp,
+                },
+            ],
+            ..level("", 1.0, 3.0)
+        };
+        let first = Summary {
+            bids: vec![consolidated(1.0, 2.0)],
+            ..Summary::default()
+        };
+        let second = Summary {
+            bids: vec![consolidated(2.0, 1.0)],
+            ..Summary::default()
+        };
+        let mut encoder = DeltaEncoder::default();
+        encoder.encode(first.clone());
+        let delta = match encoder.encode(second.clone()).update {
+            Some(Update::Delta(delta)) => delta,
+            other => panic!("Expected a delta, got {other:?}"),
+        };
+        assert_eq!(delta.changes.len(), 1);
+        assert_eq!(delta.changes[0].action, Action::Update as i32);
+        assert_eq!(apply(&first, delta), second);
+    }
+
+    #[test]
+    fn test_checksum_order() {
+        // Levels at the same price are ordered by exchange, wherever they were in the summary
+        let bids = [level("bybit", 1.0, 1.0), level("binance", 1.0, 2.0)];
+        let reordered = [bids[1].clone(), bids[0].clone()];
+        assert_eq!(checksum(&bids, &[]), checksum(&reordered, &[]));
+        assert_ne!(checksum(&bids, &[]), checksum(&[], &bids));
+        assert_eq!(
+            checksum(&[level("binance", 1.0, 2.0)], &[]),
+            crc32fast::hash(b"binance:1:2|")
+        );
+    }
+
+    #[test]
+    fn test_client_rebuilds_book() {
+        use prost::Message;
+
+        // Whatever we send, the client's book should end up matching the summary
+        let summaries = [
+            Summary {
+                spread: -1.0,
+                bids: vec![level("binance", 1.0, 5.0), level("bitstamp", 0.9, 1.0)],
+                asks: vec![level("binance", 2.0, 5.0), level("bybit", 2.0, 3.0)],
+                ..Summary::default()
+            },
+            Summary {
+                spread: -0.5,
+                bids: vec![level("bitstamp", 1.5, 1.0), level("binance", 1.0, 5.0)],
+                asks: vec![level("bybit", 2.0, 1.0)],
+                ..Summary::default()
+            },
+            Summary::default(),
+        ];
+        let mut encoder = DeltaEncoder::default();
+        let mut book = client::book::LocalBook::default();
+        for summary in summaries.into_iter().cycle().take(25) {
+            let update = encoder.encode(summary.clone());
+            let on_the_wire = client::api::BookUpdate::decode(&*update.encode_to_vec()).unwrap();
+            book.apply(on_the_wire).unwrap();
+            assert_eq!(
+                book.checksum(),
+                checksum(&summary.bids, &summary.asks),
+                "after update {}",
+                update.sequence
+            );
+        }
+    }
+
+    #[test]
+    fn test_bitstamp_orders_at_one_price() {
+        use bitstamp::{model::Price, OrderBookData};
+        use chrono::Utc;
+        use prost::Message;
+
+        use crate::model::make_merged_market_depth;
+
+        // Bitstamp's book is by order, so two orders can share a price
+        let order = |price, quantity, order_id| Price {
+            price,
+            quantity,
+            order_id,
+        };
+        let books = [
+            vec![order(1.0, 1.0, 1), order(1.0, 2.0, 2), order(0.9, 1.0, 3)],
+            vec![order(1.0, 1.0, 1), order(1.0, 4.0, 2), order(0.9, 1.0, 3)],
+            vec![order(1.0, 4.0, 2), order(0.95, 1.0, 4)],
+        ];
+        let mut encoder = DeltaEncoder::default();
+        let mut book = client::book::LocalBook::default();
+        for bids in books.into_iter().cycle().take(25) {
+            let data = OrderBookData {
+                timestamp: Utc::now(),
+                bids,
+                asks: vec![order(2.0, 1.0, 5), order(2.0, 1.0, 6)],
+            };
+            let summary = make_merged_market_depth([data.into()], 10, None);
+            assert_eq!(summary.asks, vec![level("bitstamp", 2.0, 2.0)]);
+            let update = encoder.encode(summary.clone());
+            let on_the_wire = client::api::BookUpdate::decode(&*update.encode_to_vec()).unwrap();
+            book.apply(on_the_wire).unwrap();
+            assert_eq!(
+                book.checksum(),
+                checksum(&summary.bids, &summary.asks),
+                "after update {}",
+                update.sequence
+            );
+        }
+    }
+}
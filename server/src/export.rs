@@ -0,0 +1,718 @@
+//! Exports merged summaries, and each venue's books and trades, to Parquet, for analysis in pandas
+//! or polars. Files are partitioned hive-style, so readers pick the partitions up as columns:
+//! `summaries/date=2024-05-01/instrument=ethbtc/`,
+//! `books/date=2024-05-01/instrument=ethbtc/venue=binance/` and
+//! `trades/date=2024-05-01/instrument=ethbtc/venue=binance/`. A book's levels are flattened into
+//! columns, best first: `bid_0_price`, `bid_0_amount`, `bid_1_price`, and so on. Like capture
+//! files, a file only gets its final name once it's complete
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use arrow_array::{
+    ArrayRef, Float64Array, RecordBatch, StringArray, TimestampMicrosecondArray, UInt64Array,
+};
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef, TimeUnit};
+use bitstamp::model::CurrencyPair;
+use chrono::{TimeZone, Utc};
+use parquet::{
+    arrow::ArrowWriter,
+    basic::{Compression, ZstdLevel},
+    errors::ParquetError,
+    file::properties::WriterProperties,
+};
+use thiserror::Error;
+
+use crate::{
+    api::{Level, Summary},
+    model::{unix_us, VenueBook, VenueTrade},
+    venue::Venue,
+};
+
+/// What export files' names end with
+pub const EXTENSION: &str = "parquet";
+/// Added to an export file's name while it's being written
+const PARTIAL: &str = "partial";
+/// Rows are handed to the Parquet writer this many at a time
+const BATCH_ROWS: usize = 1024;
+/// Rows per row group, so a reader can skip through a file without reading it all
+const ROW_GROUP_ROWS: usize = 64 * 1024;
+/// Every time is in Unix microseconds, in UTC
+const TIME_ZONE: &str = "UTC";
+
+/// Where to export to, how many levels of each side to keep, and when to start a new file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportSettings {
+    pub dir: PathBuf,
+    pub levels: usize,
+    pub rotate_after: Duration,
+}
+
+#[derive(Error, Debug)]
+enum ExportError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Parquet(#[from] ParquetError),
+    #[error(transparent)]
+    Arrow(#[from] ArrowError),
+}
+
+/// A book, with no more levels than we keep, or a trade to export
+enum Row {
+    Summary(Summary),
+    Book(VenueBook),
+    Trade(VenueTrade),
+}
+
+impl Row {
+    /// When it happened, in Unix microseconds, which decides its date
+    fn time_us(&self) -> i64 {
+        match self {
+            Row::Summary(summary) => summary.merged_time_us,
+            Row::Book(book) => unix_us(book.received_time),
+            Row::Trade(trade) => unix_us(trade.received_time),
+        }
+    }
+}
+
+/// What a file has in it: merged summaries, or one venue's books or trades
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Table {
+    Summaries,
+    Books(Venue),
+    Trades(Venue),
+}
+
+/// Which files a row goes in
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Partition {
+    table: Table,
+    instrument: CurrencyPair,
+    /// eg. "2024-05-01"
+    date: String,
+}
+
+impl Partition {
+    fn new(table: Table, instrument: CurrencyPair, time_us: i64) -> Partition {
+        let date = Utc.timestamp_nanos(time_us * 1000).format("%Y-%m-%d");
+        Partition {
+            table,
+            instrument,
+            date: date.to_string(),
+        }
+    }
+
+    fn dir(&self, root: &Path) -> PathBuf {
+        let date = format!("date={}", self.date);
+        let instrument = format!("instrument={}", self.instrument);
+        let (name, venue) = match self.table {
+            Table::Summaries => ("summaries", None),
+            Table::Books(venue) => ("books", Some(venue)),
+            Table::Trades(venue) => ("trades", Some(venue)),
+        };
+        let dir = root.join(name).join(date).join(instrument);
+        match venue {
+            Some(venue) => dir.join(format!("venue={venue}")),
+            None => dir,
+        }
+    }
+}
+
+/// The columns of a merged summary, with `levels` levels a side
+pub fn summary_schema(levels: usize) -> Schema {
+    let mut fields = vec![
+        Field::new("merged_time", timestamp(), false),
+        Field::new("spread", DataType::Float64, false),
+    ];
+    for side in ["bid", "ask"] {
+        for level in 0..levels {
+            fields.extend([
+                Field::new(format!("{side}_{level}_exchange"), DataType::Utf8, true),
+                Field::new(format!("{side}_{level}_price"), DataType::Float64, true),
+                Field::new(format!("{side}_{level}_amount"), DataType::Float64, true),
+            ]);
+        }
+    }
+    Schema::new(fields)
+}
+
+/// The columns of one venue's book, with `levels` levels a side
+pub fn book_schema(levels: usize) -> Schema {
+    let mut fields = vec![
+        Field::new("received_time", timestamp(), false),
+        // Not every venue says
+        Field::new("exchange_time", timestamp(), true),
+        Field::new("sequence", DataType::UInt64, false),
+    ];
+    for side in ["bid", "ask"] {
+        for level in 0..levels {
+            fields.extend([
+                Field::new(format!("{side}_{level}_price"), DataType::Float64, true),
+                Field::new(format!("{side}_{level}_amount"), DataType::Float64, true),
+            ]);
+        }
+    }
+    Schema::new(fields)
+}
+
+/// The columns of one venue's trades. `side` is the side that took liquidity, "buy" or "sell"
+pub fn trade_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("received_time", timestamp(), false),
+        Field::new("exchange_time", timestamp(), true),
+        Field::new("trade_id", DataType::UInt64, false),
+        Field::new("price", DataType::Float64, false),
+        Field::new("amount", DataType::Float64, false),
+        Field::new("side", DataType::Utf8, false),
+    ])
+}
+
+fn timestamp() -> DataType {
+    DataType::Timestamp(TimeUnit::Microsecond, Some(TIME_ZONE.into()))
+}
+
+fn timestamps(times: impl Iterator<Item = Option<i64>>) -> ArrayRef {
+    Arc::new(TimestampMicrosecondArray::from_iter(times).with_timezone(TIME_ZONE))
+}
+
+/// Each side's levels, flattened into columns: with `exchange` its exchange, then its price and
+/// amount, for each level
+fn level_columns<'a, T: 'a>(
+    rows: &'a [T],
+    levels: usize,
+    exchange: bool,
+    sides: impl Fn(&'a T) -> [&'a [Level]; 2],
+) -> Vec<ArrayRef> {
+    let mut columns: Vec<ArrayRef> = vec![];
+    for side in 0..2 {
+        for level in 0..levels {
+            let at = |row| sides(row)[side].get(level);
+            if exchange {
+                let exchanges = rows
+                    .iter()
+                    .map(|row| at(row).map(|at| at.exchange.as_str()));
+                columns.push(Arc::new(StringArray::from_iter(exchanges)));
+            }
+            let prices = rows.iter().map(|row| at(row).map(|at| at.price));
+            columns.push(Arc::new(Float64Array::from_iter(prices)));
+            let amounts = rows.iter().map(|row| at(row).map(|at| at.amount));
+            columns.push(Arc::new(Float64Array::from_iter(amounts)));
+        }
+    }
+    columns
+}
+
+fn summary_batch(
+    schema: SchemaRef,
+    levels: usize,
+    summaries: &[Summary],
+) -> Result<RecordBatch, ArrowError> {
+    let mut columns = vec![
+        timestamps(summaries.iter().map(|summary| Some(summary.merged_time_us))),
+        Arc::new(Float64Array::from_iter_values(
+            summaries.iter().map(|summary| summary.spread),
+        )),
+    ];
+    columns.extend(level_columns(summaries, levels, true, |summary| {
+        [&summary.bids, &summary.asks]
+    }));
+    RecordBatch::try_new(schema, columns)
+}
+
+fn book_batch(
+    schema: SchemaRef,
+    levels: usize,
+    books: &[VenueBook],
+) -> Result<RecordBatch, ArrowError> {
+    let mut columns = vec![
+        timestamps(books.iter().map(|book| Some(unix_us(book.received_time)))),
+        timestamps(
+            books
+                .iter()
+                .map(|book| book.exchange_time.map(|time| unix_us(Some(time)))),
+        ),
+        Arc::new(UInt64Array::from_iter_values(
+            books.iter().map(|book| book.sequence),
+        )),
+    ];
+    columns.extend(level_columns(books, levels, false, |book| {
+        [&book.bids, &book.asks]
+    }));
+    RecordBatch::try_new(schema, columns)
+}
+
+fn trade_batch(schema: SchemaRef, trades: &[VenueTrade]) -> Result<RecordBatch, ArrowError> {
+    let columns: Vec<ArrayRef> = vec![
+        timestamps(
+            trades
+                .iter()
+                .map(|trade| Some(unix_us(trade.received_time))),
+        ),
+        timestamps(
+            trades
+                .iter()
+                .map(|trade| trade.exchange_time.map(|time| unix_us(Some(time)))),
+        ),
+        Arc::new(UInt64Array::from_iter_values(
+            trades.iter().map(|trade| trade.id),
+        )),
+        Arc::new(Float64Array::from_iter_values(
+            trades.iter().map(|trade| trade.price),
+        )),
+        Arc::new(Float64Array::from_iter_values(
+            trades.iter().map(|trade| trade.amount),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            trades.iter().map(|trade| trade.taker.to_string()),
+        )),
+    ];
+    RecordBatch::try_new(schema, columns)
+}
+
+/// A row, and the partition it goes in
+type Export = (Partition, Row);
+
+/// Hands books and trades to the thread writing them out. Clones share it
+#[derive(Clone)]
+pub struct Exporter {
+    /// None once we've stopped
+    rows: Arc<Mutex<Option<Sender<Export>>>>,
+    writer: Arc<Mutex<Option<JoinHandle<()>>>>,
+    levels: usize,
+}
+
+impl Exporter {
+    /// Start writing export files to `settings.dir`, creating it if need be
+    pub fn start(settings: ExportSettings) -> io::Result<Exporter> {
+        fs::create_dir_all(&settings.dir)?;
+        log::info!("Exporting to {:?}", settings.dir);
+        let levels = settings.levels;
+        let (sender, receiver) = channel();
+        let writer = std::thread::Builder::new()
+            .name("exporter".to_string())
+            .spawn(move || write_rows(&settings, receiver))?;
+        Ok(Exporter {
+            rows: Arc::new(Mutex::new(Some(sender))),
+            writer: Arc::new(Mutex::new(Some(writer))),
+            levels,
+        })
+    }
+
+    /// Export a merged summary of `instrument`
+    pub fn summary(&self, instrument: CurrencyPair, mut summary: Summary) {
+        summary.bids.truncate(self.levels);
+        summary.asks.truncate(self.levels);
+        self.export(Table::Summaries, instrument, Row::Summary(summary));
+    }
+
+    /// Export a book from `venue`
+    pub fn book(&self, venue: Venue, instrument: CurrencyPair, book: &VenueBook) {
+        let top = |levels: &[Level]| levels.iter().take(self.levels).cloned().collect();
+        let book = VenueBook {
+            bids: top(&book.bids),
+            asks: top(&book.asks),
+            ..*book
+        };
+        self.export(Table::Books(venue), instrument, Row::Book(book));
+    }
+
+    /// Export a trade from `venue`
+    pub fn trade(&self, venue: Venue, instrument: CurrencyPair, trade: &VenueTrade) {
+        self.export(Table::Trades(venue), instrument, Row::Trade(trade.clone()));
+    }
+
+    fn export(&self, table: Table, instrument: CurrencyPair, row: Row) {
+        let partition = Partition::new(table, instrument, row.time_us());
+        if let Some(rows) = self.rows.lock().unwrap().as_ref() {
+            // The writer only goes once we've stopped
+            rows.send((partition, row)).ok();
+        }
+    }
+
+    /// Stop exporting, and wait for everything so far to be written. Rows after this are dropped.
+    /// Blocks, so call it from somewhere that can
+    pub fn stop(&self) {
+        self.rows.lock().unwrap().take();
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            if writer.join().is_err() {
+                log::error!("The exporter's writer panicked");
+            }
+        }
+    }
+}
+
+/// The export file being written for one partition
+struct Part {
+    path: PathBuf,
+    writer: ArrowWriter<File>,
+    schema: SchemaRef,
+    levels: usize,
+    /// Waiting to be handed to the writer
+    rows: Vec<Row>,
+    started: Instant,
+}
+
+impl Part {
+    fn create(
+        root: &Path,
+        partition: &Partition,
+        levels: usize,
+        time_us: i64,
+    ) -> Result<Part, ExportError> {
+        let dir = partition.dir(root);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("part-{time_us}.{EXTENSION}"));
+        let file = File::create(path.with_extension(format!("{EXTENSION}.{PARTIAL}")))?;
+        let schema = Arc::new(match partition.table {
+            Table::Summaries => summary_schema(levels),
+            Table::Books(_) => book_schema(levels),
+            Table::Trades(_) => trade_schema(),
+        });
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::ZSTD(ZstdLevel::default()))
+            .set_max_row_group_size(ROW_GROUP_ROWS)
+            .build();
+        let writer = ArrowWriter::try_new(file, schema.clone(), Some(properties))?;
+        log::info!("Exporting to {path:?}");
+        Ok(Part {
+            path,
+            writer,
+            schema,
+            levels,
+            rows: vec![],
+            started: Instant::now(),
+        })
+    }
+
+    fn write(&mut self, row: Row) -> Result<(), ExportError> {
+        self.rows.push(row);
+        if self.rows.len() >= BATCH_ROWS {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Hand the rows waiting to the writer. A part only ever gets one kind of row
+    fn flush(&mut self) -> Result<(), ExportError> {
+        let rows = std::mem::take(&mut self.rows);
+        let batch = match rows.first() {
+            None => return Ok(()),
+            Some(Row::Summary(_)) => {
+                let summaries: Vec<_> = rows
+                    .into_iter()
+                    .filter_map(|row| match row {
+                        Row::Summary(summary) => Some(summary),
+                        _ => None,
+                    })
+                    .collect();
+                summary_batch(self.schema.clone(), self.levels, &summaries)?
+            }
+            Some(Row::Book(_)) => {
+                let books: Vec<_> = rows
+                    .into_iter()
+                    .filter_map(|row| match row {
+                        Row::Book(book) => Some(book),
+                        _ => None,
+                    })
+                    .collect();
+                book_batch(self.schema.clone(), self.levels, &books)?
+            }
+            Some(Row::Trade(_)) => {
+                let trades: Vec<_> = rows
+                    .into_iter()
+                    .filter_map(|row| match row {
+                        Row::Trade(trade) => Some(trade),
+                        _ => None,
+                    })
+                    .collect();
+                trade_batch(self.schema.clone(), &trades)?
+            }
+        };
+        self.writer.write(&batch)?;
+        Ok(())
+    }
+
+    /// Finish the file off, and give it its final name
+    fn finish(mut self) -> Result<PathBuf, ExportError> {
+        self.flush()?;
+        self.writer.close()?;
+        fs::rename(
+            self.path.with_extension(format!("{EXTENSION}.{PARTIAL}")),
+            &self.path,
+        )?;
+        Ok(self.path)
+    }
+}
+
+fn finish(part: Part) {
+    match part.finish() {
+        Ok(path) => log::info!("Finished {path:?}"),
+        Err(err) => log::error!("Unable to finish an export file: {err}"),
+    }
+}
+
+/// Write rows until the exporter stops. A partition's file is finished once it's old enough, or
+/// once its table and instrument have moved on to the next day
+fn write_rows(settings: &ExportSettings, rows: Receiver<Export>) {
+    let mut parts: HashMap<Partition, Part> = HashMap::new();
+    for (partition, row) in rows {
+        let done: Vec<_> = parts
+            .iter()
+            .filter(|(open, part)| {
+                part.started.elapsed() >= settings.rotate_after
+                    || (open.table == partition.table
+                        && open.instrument == partition.instrument
+                        && open.date != partition.date)
+            })
+            .map(|(open, _)| open.clone())
+            .collect();
+        for open in done {
+            finish(parts.remove(&open).unwrap());
+        }
+        let part = match parts.get_mut(&partition) {
+            Some(part) => part,
+            None => match Part::create(&settings.dir, &partition, settings.levels, row.time_us()) {
+                Ok(part) => parts.entry(partition).or_insert(part),
+                Err(err) => {
+                    log::error!(
+                        "Unable to start an export file in {:?}: {err}",
+                        settings.dir
+                    );
+                    continue;
+                }
+            },
+        };
+        if let Err(err) = part.write(row) {
+            log::error!("Unable to export to {:?}: {err}", part.path);
+        }
+    }
+    for (_, part) in parts {
+        finish(part);
+    }
+}
+
+/// The complete export files under `dir`, in no particular order
+pub fn exports(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            paths.extend(exports(&path)?);
+        } else if path
+            .extension()
+            .is_some_and(|extension| extension == EXTENSION)
+        {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod unit_test {
+    use std::{
+        fs::File,
+        path::{Path, PathBuf},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use arrow_array::{
+        cast::AsArray,
+        types::{Float64Type, TimestampMicrosecondType, UInt64Type},
+        Array, RecordBatch,
+    };
+    use bitstamp::model::CurrencyPair;
+    use chrono::{TimeZone, Utc};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use super::{book_schema, exports, summary_schema, trade_schema, ExportSettings, Exporter};
+    use crate::{
+        api::{Level, Summary},
+        model::{TakerSide, VenueBook, VenueTrade},
+        venue::Venue,
+    };
+
+    /// An empty directory of our own under the system's temporary one
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("orderbook-{name}-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    fn level(exchange: &str, price: f64) -> Level {
+        Level {
+            exchange: exchange.to_string(),
+            price,
+            amount: 1.0,
+            ..Level::default()
+        }
+    }
+
+    /// Every row in the export file that's in `partition`
+    fn read(dir: &Path, partition: &str) -> RecordBatch {
+        let paths: Vec<_> = exports(dir)
+            .unwrap()
+            .into_iter()
+            .filter(|path| path.to_str().unwrap().contains(partition))
+            .collect();
+        assert_eq!(paths.len(), 1, "{paths:?}");
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&paths[0]).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut batches: Vec<_> = reader.collect::<Result<_, _>>().unwrap();
+        assert_eq!(batches.len(), 1);
+        batches.remove(0)
+    }
+
+    #[test]
+    fn test_export() {
+        let dir = temp_dir("export");
+        let exporter = Exporter::start(ExportSettings {
+            dir: dir.clone(),
+            levels: 2,
+            rotate_after: Duration::from_secs(3600),
+        })
+        .unwrap();
+        // 2024-05-01T23:59:59Z, and a second later, the next day
+        let day_end = Utc.with_ymd_and_hms(2024, 5, 1, 23, 59, 59).unwrap();
+        let next_day = day_end + chrono::Duration::seconds(1);
+        exporter.summary(
+            CurrencyPair::Ethbtc,
+            Summary {
+                spread: 0.5,
+                bids: vec![level("binance", 2.0), level("htx", 1.5), level("htx", 1.0)],
+                asks: vec![level("bybit", 2.5)],
+                merged_time_us: day_end.timestamp_micros(),
+                ..Summary::default()
+            },
+        );
+        let book = |sequence, time, exchange_time| VenueBook {
+            bids: vec![level("binance", 2.0)],
+            asks: vec![level("binance", 3.0), level("binance", 4.0)],
+            received: None,
+            sequence,
+            exchange_time,
+            received_time: Some(time),
+        };
+        exporter.book(
+            Venue::Binance,
+            CurrencyPair::Ethbtc,
+            &book(1, day_end, None),
+        );
+        exporter.book(
+            Venue::Binance,
+            CurrencyPair::Ethbtc,
+            &book(2, day_end, Some(day_end)),
+        );
+        exporter.book(
+            Venue::Binance,
+            CurrencyPair::Ethbtc,
+            &book(3, next_day, None),
+        );
+        let trade = |id, taker| VenueTrade {
+            id,
+            price: 2.5,
+            amount: 0.25,
+            taker,
+            exchange_time: Some(day_end),
+            received_time: Some(day_end),
+        };
+        exporter.trade(
+            Venue::Bitstamp,
+            CurrencyPair::Ethbtc,
+            &trade(7, TakerSide::Buy),
+        );
+        exporter.trade(
+            Venue::Bitstamp,
+            CurrencyPair::Ethbtc,
+            &trade(8, TakerSide::Sell),
+        );
+        exporter.stop();
+        // Stopped, so this goes nowhere
+        exporter.book(Venue::Htx, CurrencyPair::Ethbtc, &book(4, next_day, None));
+        assert_eq!(exports(&dir).unwrap().len(), 4);
+
+        let summaries = read(&dir, "summaries/date=2024-05-01/instrument=ethbtc/");
+        assert_eq!(summaries.schema(), Arc::new(summary_schema(2)));
+        assert_eq!(summaries.num_rows(), 1);
+        let column = |name| summaries.column_by_name(name).unwrap().clone();
+        assert_eq!(
+            column("merged_time")
+                .as_primitive::<TimestampMicrosecondType>()
+                .value(0),
+            day_end.timestamp_micros()
+        );
+        assert_eq!(column("bid_1_exchange").as_string::<i32>().value(0), "htx");
+        assert_eq!(
+            column("bid_1_price").as_primitive::<Float64Type>().value(0),
+            1.5
+        );
+        assert_eq!(
+            column("ask_0_price").as_primitive::<Float64Type>().value(0),
+            2.5
+        );
+        assert!(column("ask_1_price").is_null(0));
+
+        let books = read(
+            &dir,
+            "books/date=2024-05-01/instrument=ethbtc/venue=binance/",
+        );
+        assert_eq!(books.schema(), Arc::new(book_schema(2)));
+        assert_eq!(books.num_rows(), 2);
+        let column = |name| books.column_by_name(name).unwrap().clone();
+        let sequences = column("sequence");
+        let sequences = sequences.as_primitive::<UInt64Type>();
+        assert_eq!(sequences.values().to_vec(), vec![1, 2]);
+        let exchange_times = column("exchange_time");
+        assert!(exchange_times.is_null(0));
+        assert_eq!(
+            exchange_times
+                .as_primitive::<TimestampMicrosecondType>()
+                .value(1),
+            day_end.timestamp_micros()
+        );
+        assert!(column("bid_1_price").is_null(0));
+        assert_eq!(
+            column("ask_1_price").as_primitive::<Float64Type>().value(1),
+            4.0
+        );
+
+        let books = read(
+            &dir,
+            "books/date=2024-05-02/instrument=ethbtc/venue=binance/",
+        );
+        assert_eq!(books.num_rows(), 1);
+
+        let trades = read(
+            &dir,
+            "trades/date=2024-05-01/instrument=ethbtc/venue=bitstamp/",
+        );
+        assert_eq!(trades.schema(), Arc::new(trade_schema()));
+        let column = |name| trades.column_by_name(name).unwrap().clone();
+        let ids = column("trade_id");
+        assert_eq!(
+            ids.as_primitive::<UInt64Type>().values().to_vec(),
+            vec![7, 8]
+        );
+        let sides = column("side");
+        let sides: Vec<_> = sides.as_string::<i32>().iter().flatten().collect();
+        assert_eq!(sides, vec!["buy", "sell"]);
+        assert_eq!(
+            column("amount").as_primitive::<Float64Type>().value(1),
+            0.25
+        );
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,267 @@
+//! The standard gRPC health checking service, for load balancers, as `tonic-health` serves it.
+//! The server as a whole (the empty service name, or "orderbook.OrderbookAggregator") is serving
+//! until it starts shutting down. Each instrument we serve, eg.
+//! "orderbook.OrderbookAggregator/ethbtc", is serving while it can get books from at least one
+//! venue
+use std::{collections::HashMap, time::Duration};
+
+use bitstamp::model::CurrencyPair;
+use tokio::task::JoinHandle;
+use tonic_health::{
+    proto::health_server::{Health, HealthServer},
+    server::{health_reporter, HealthReporter},
+    ServingStatus,
+};
+
+use crate::SummaryServer;
+
+/// The name the server as a whole goes by, and that instruments' names start with
+pub const SERVICE: &str = "orderbook.OrderbookAggregator";
+/// How often the statuses are brought up to date
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Stops keeping the statuses up to date when dropped
+pub struct Reporting(Option<JoinHandle<()>>);
+
+impl Drop for Reporting {
+    fn drop(&mut self) {
+        if let Some(task) = &self.0 {
+            task.abort();
+        }
+    }
+}
+
+/// The health service, kept up to date with how `server` is doing for as long as the `Reporting`
+/// is kept. Without a server, only the server as a whole is known
+pub async fn serve(server: Option<SummaryServer>) -> (HealthServer<impl Health>, Reporting) {
+    let (reporter, service) = health_reporter();
+    let mut reporter = Reporter {
+        reporter,
+        // `health_reporter` starts with the server as a whole serving. Setting it again would
+        // have every new watch of it hear that twice
+        reported: HashMap::from([(String::new(), ServingStatus::Serving)]),
+    };
+    reporter.update(server.as_ref()).await;
+    let task = server.map(|server| tokio::spawn(reporter.run(server)));
+    (service, Reporting(task))
+}
+
+/// `instrument`'s service name
+fn instrument_service(instrument: CurrencyPair) -> String {
+    format!("{SERVICE}/{instrument}")
+}
+
+/// How each instrument we serve is doing. Every instrument is, if we don't have a list, so those
+/// we've connected for stand in for it
+fn instrument_statuses(server: &SummaryServer) -> HashMap<String, ServingStatus> {
+    let defaults = server.defaults.read().unwrap().clone();
+    let mut instruments = defaults.instruments;
+    if instruments.is_empty() {
+        instruments.push(defaults.instrument);
+        for instrument in server.hub.connected_instruments() {
+            if !instruments.contains(&instrument) {
+                instruments.push(instrument);
+            }
+        }
+    }
+    instruments
+        .into_iter()
+        .map(|instrument| {
+            let status = match server.hub.instrument_connected(instrument) {
+                true => ServingStatus::Serving,
+                false => ServingStatus::NotServing,
+            };
+            (instrument_service(instrument), status)
+        })
+        .collect()
+}
+
+struct Reporter {
+    reporter: HealthReporter,
+    /// What's been reported, so watchers only hear about changes
+    reported: HashMap<String, ServingStatus>,
+}
+
+impl Reporter {
+    /// Bring every status up to date, forgetting services not in `statuses`
+    async fn report(&mut self, statuses: HashMap<String, ServingStatus>) {
+        let gone: Vec<_> = self
+            .reported
+            .keys()
+            .filter(|service| !statuses.contains_key(*service))
+            .cloned()
+            .collect();
+        for service in gone {
+            self.reporter.clear_service_status(&service).await;
+            self.reported.remove(&service);
+        }
+        for (service, status) in statuses {
+            if self.reported.get(&service) != Some(&status) {
+                self.reporter.set_service_status(&service, status).await;
+                self.reported.insert(service, status);
+            }
+        }
+    }
+
+    /// Report the server as a whole serving, and how each of `server`'s instruments is doing
+    async fn update(&mut self, server: Option<&SummaryServer>) {
+        let serving = ServingStatus::Serving;
+        let mut statuses =
+            HashMap::from([(String::new(), serving), (SERVICE.to_string(), serving)]);
+        if let Some(server) = server {
+            statuses.extend(instrument_statuses(server));
+        }
+        self.report(statuses).await;
+    }
+
+    async fn run(mut self, server: SummaryServer) {
+        let mut updates = tokio::time::interval(UPDATE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = updates.tick() => self.update(Some(&server)).await,
+                _ = server.hub.shutting_down() => break,
+            }
+        }
+        // A watch only ends when its status is cleared; dropping the reporter doesn't end it, as
+        // the service holds the statuses too. So watchers hear that nothing's serving, then the
+        // statuses are cleared to end their watches, so they don't hold the shutdown up, then set
+        // again for checks. `test_shutdown` makes sure a tonic-health upgrade doesn't change that
+        let services: Vec<_> = self.reported.keys().cloned().collect();
+        let stopped = || {
+            services
+                .iter()
+                .map(|service| (service.clone(), ServingStatus::NotServing))
+                .collect()
+        };
+        self.report(stopped()).await;
+        self.report(HashMap::new()).await;
+        self.report(stopped()).await;
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use std::time::Duration;
+
+    use bitstamp::model::CurrencyPair;
+    use futures::StreamExt;
+    use tokio::{net::TcpListener, sync::oneshot};
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::{transport::Server, Code};
+    use tonic_health::proto::{
+        health_check_response::ServingStatus, health_client::HealthClient, HealthCheckRequest,
+    };
+
+    use super::{serve, SERVICE};
+    use crate::{config::Compression, SummaryServer};
+
+    /// A client for the health service kept up to date with `server`
+    async fn client(
+        server: Option<SummaryServer>,
+    ) -> (HealthClient<tonic::transport::Channel>, super::Reporting) {
+        let (health, reporting) = serve(server).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(health)
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let client = HealthClient::connect(format!("http://{addr}"))
+            .await
+            .unwrap();
+        (client, reporting)
+    }
+
+    fn request(service: &str) -> HealthCheckRequest {
+        HealthCheckRequest {
+            service: service.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_status() {
+        let server = SummaryServer::new(CurrencyPair::Ethbtc);
+        server.defaults.write().unwrap().instruments = vec![CurrencyPair::Ethbtc];
+        let (mut health, _reporting) = client(Some(server.clone())).await;
+        let checks = health.clone();
+        let status = |service: &str| {
+            let mut health = checks.clone();
+            let request = request(service);
+            async move {
+                health
+                    .check(request)
+                    .await
+                    .map(|reply| reply.into_inner().status)
+                    .map_err(|status| status.code())
+            }
+        };
+        let serving = Ok(ServingStatus::Serving as i32);
+        assert_eq!(status("").await, serving);
+        assert_eq!(status(SERVICE).await, serving);
+        let ethbtc = format!("{SERVICE}/ethbtc");
+        assert_eq!(status(&ethbtc).await, serving);
+        // Instruments the server doesn't offer are unknown
+        assert_eq!(
+            status(&format!("{SERVICE}/btcusd")).await,
+            Err(Code::NotFound)
+        );
+        assert_eq!(status("orderbook.Other").await, Err(Code::NotFound));
+
+        let mut watch = health.watch(request(&ethbtc)).await.unwrap().into_inner();
+        let status_now = watch.next().await.unwrap().unwrap().status;
+        assert_eq!(status_now, ServingStatus::Serving as i32);
+
+        // Shutting down, everything stops serving, and watches end
+        server.hub.shutdown().await;
+        let status_now = watch.next().await.unwrap().unwrap().status;
+        assert_eq!(status_now, ServingStatus::NotServing as i32);
+        assert!(watch.next().await.is_none());
+        let not_serving = Ok(ServingStatus::NotServing as i32);
+        assert_eq!(status("").await, not_serving);
+        assert_eq!(status(&ethbtc).await, not_serving);
+    }
+
+    /// Through the whole server, an open watch ends well before the shutdown deadline
+    #[tokio::test]
+    async fn test_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let deadline = Duration::from_secs(30);
+        let serving = tokio::spawn(crate::serve_until(
+            listener,
+            SummaryServer::new(CurrencyPair::Ethbtc),
+            Compression::None,
+            None,
+            async {
+                stopped.await.ok();
+            },
+            deadline,
+        ));
+        let mut health = HealthClient::connect(format!("http://{addr}"))
+            .await
+            .unwrap();
+        let mut watch = health.watch(request("")).await.unwrap().into_inner();
+        let status = watch.next().await.unwrap().unwrap().status;
+        assert_eq!(status, ServingStatus::Serving as i32);
+
+        stop.send(()).unwrap();
+        let ended = tokio::time::timeout(Duration::from_secs(5), async {
+            let status = watch.next().await.unwrap().unwrap().status;
+            assert_eq!(status, ServingStatus::NotServing as i32);
+            assert!(watch.next().await.is_none());
+            serving.await.unwrap().unwrap();
+        });
+        ended.await.expect("The watch held up the shutdown");
+    }
+
+    #[tokio::test]
+    async fn test_without_server() {
+        let (mut health, _reporting) = client(None).await;
+        let reply = health.check(request(SERVICE)).await.unwrap().into_inner();
+        assert_eq!(reply.status, ServingStatus::Serving as i32);
+        let status = health.check(request(&format!("{SERVICE}/ethbtc"))).await;
+        assert_eq!(status.unwrap_err().code(), Code::NotFound);
+    }
+}
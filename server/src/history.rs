@@ -0,0 +1,514 @@
+//! Keeps every merged book, so clients can ask what the book was at any moment since. Each
+//! instrument's books are appended to segment files as length-delimited `BookUpdate`s: a snapshot
+//! every so often, and deltas in between. Alongside each segment is an index of where its
+//! snapshots are, so a book can be rebuilt from the nearest snapshot before it, without reading
+//! the whole segment through
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+use bitstamp::model::CurrencyPair;
+use prost::Message;
+
+use crate::{
+    api::{book_update::Update, BookUpdate, Summary},
+    delta::{apply, checksum, DeltaEncoder},
+};
+
+/// What segment files' names end with
+const SEGMENT: &str = "seg";
+/// What index files' names end with
+const INDEX: &str = "idx";
+/// The most deltas we ever apply to rebuild a book
+const SNAPSHOT_EVERY: u64 = 100;
+/// Each index entry is a snapshot's merge time and its offset in the segment, both little-endian
+const INDEX_ENTRY: usize = 16;
+
+/// A merged book, and the instrument it's for
+type Book = (CurrencyPair, Summary);
+
+/// Where to keep history, and how big a segment gets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistorySettings {
+    pub dir: PathBuf,
+    pub segment_bytes: u64,
+}
+
+/// The segment being written for one instrument
+struct Segment {
+    path: PathBuf,
+    file: BufWriter<File>,
+    index: BufWriter<File>,
+    bytes: u64,
+    updates: u64,
+    encoder: DeltaEncoder,
+}
+
+impl Segment {
+    /// A new segment for `instrument`, named for when its first book was merged, so the names
+    /// sort in time order
+    fn create(dir: &Path, instrument: CurrencyPair, start_us: i64) -> io::Result<Segment> {
+        let dir = dir.join(instrument.to_string());
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{start_us:020}.{SEGMENT}"));
+        let open = |path: &Path| OpenOptions::new().create(true).append(true).open(path);
+        let file = open(&path)?;
+        let bytes = file.metadata()?.len();
+        let index = open(&path.with_extension(INDEX))?;
+        log::info!("Keeping {instrument} history in {path:?}");
+        Ok(Segment {
+            path,
+            file: BufWriter::new(file),
+            index: BufWriter::new(index),
+            bytes,
+            updates: 0,
+            encoder: DeltaEncoder::default(),
+        })
+    }
+
+    /// Append `summary`, as a delta from the last one unless it's time for a snapshot. Flushed
+    /// straight away, so it can be read back as soon as it's written
+    fn write(&mut self, summary: Summary) -> io::Result<()> {
+        let snapshot = self.updates.is_multiple_of(SNAPSHOT_EVERY);
+        if snapshot {
+            self.encoder = DeltaEncoder::default();
+        }
+        let merged_time_us = summary.merged_time_us;
+        let update = self
+            .encoder
+            .encode(summary)
+            .encode_length_delimited_to_vec();
+        self.file.write_all(&update)?;
+        self.file.flush()?;
+        // Only once the snapshot's there to be read
+        if snapshot {
+            self.index.write_all(&merged_time_us.to_le_bytes())?;
+            self.index.write_all(&self.bytes.to_le_bytes())?;
+            self.index.flush()?;
+        }
+        self.bytes += update.len() as u64;
+        self.updates += 1;
+        Ok(())
+    }
+}
+
+/// Hands books to the thread writing them out. Clones share it
+#[derive(Clone)]
+pub struct HistoryWriter {
+    /// None once we've stopped
+    books: Arc<Mutex<Option<Sender<Book>>>>,
+    writer: Arc<Mutex<Option<JoinHandle<()>>>>,
+    history: History,
+}
+
+impl HistoryWriter {
+    /// Start writing history to `settings.dir`, creating it if need be
+    pub fn start(settings: HistorySettings) -> io::Result<HistoryWriter> {
+        fs::create_dir_all(&settings.dir)?;
+        log::info!("Keeping history in {:?}", settings.dir);
+        let history = History::new(settings.dir.clone());
+        let (sender, receiver) = channel();
+        let writer = std::thread::Builder::new()
+            .name("history".to_string())
+            .spawn(move || write_books(&settings, receiver))?;
+        Ok(HistoryWriter {
+            books: Arc::new(Mutex::new(Some(sender))),
+            writer: Arc::new(Mutex::new(Some(writer))),
+            history,
+        })
+    }
+
+    /// Add `summary` to `instrument`'s history
+    pub fn write(&self, instrument: CurrencyPair, summary: Summary) {
+        if let Some(books) = self.books.lock().unwrap().as_ref() {
+            // The writer only goes once we've stopped
+            books.send((instrument, summary)).ok();
+        }
+    }
+
+    /// Reads back what we've written
+    pub fn history(&self) -> History {
+        self.history.clone()
+    }
+
+    /// Stop, and wait for everything so far to be written. Books after this are dropped. Blocks,
+    /// so call it from somewhere that can
+    pub fn stop(&self) {
+        self.books.lock().unwrap().take();
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            if writer.join().is_err() {
+                log::error!("The history writer panicked");
+            }
+        }
+    }
+}
+
+/// Write books until we stop, starting a new segment whenever an instrument's current one is full
+fn write_books(settings: &HistorySettings, books: Receiver<Book>) {
+    let mut segments: HashMap<CurrencyPair, Segment> = HashMap::new();
+    for (instrument, summary) in books {
+        let full = segments
+            .get(&instrument)
+            .is_some_and(|segment| segment.bytes >= settings.segment_bytes);
+        if full {
+            segments.remove(&instrument);
+        }
+        let segment = match segments.get_mut(&instrument) {
+            Some(segment) => segment,
+            None => match Segment::create(&settings.dir, instrument, summary.merged_time_us) {
+                Ok(segment) => segments.entry(instrument).or_insert(segment),
+                Err(err) => {
+                    log::error!("Unable to start a {instrument} history segment: {err}");
+                    continue;
+                }
+            },
+        };
+        if let Err(err) = segment.write(summary) {
+            log::error!("Unable to write history to {:?}: {err}", segment.path);
+        }
+    }
+}
+
+fn invalid(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// The books in the segment at `path`, rebuilt from the snapshot at `offset`. A partly written
+/// update at the end is left for next time
+fn read_segment(path: &Path, offset: u64) -> io::Result<impl Iterator<Item = io::Result<Summary>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut position = offset;
+    let mut last: Option<Summary> = None;
+    let path = path.to_owned();
+    Ok(std::iter::from_fn(move || {
+        let update = match read_update(&mut reader) {
+            Ok(Some((length, data))) => {
+                position += length;
+                BookUpdate::decode(&*data)
+            }
+            Ok(None) => return None,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return None,
+            Err(err) => return Some(Err(err)),
+        };
+        let book = update
+            .map_err(|err| invalid(format!("{path:?} is corrupt: {err}")))
+            .and_then(|update| {
+                let book = match (update.update, &last) {
+                    (Some(Update::Snapshot(summary)), _) => summary,
+                    (Some(Update::Delta(delta)), Some(last)) => apply(last, delta),
+                    _ => {
+                        return Err(invalid(format!(
+                            "{path:?} has a delta with nothing before it"
+                        )))
+                    }
+                };
+                match update.checksum {
+                    Some(expected) if expected.crc32 != checksum(&book.bids, &book.asks) => Err(
+                        invalid(format!("{path:?} doesn't match its checksum at {position}")),
+                    ),
+                    _ => Ok(book),
+                }
+            });
+        last = book.as_ref().ok().cloned();
+        Some(book)
+    }))
+}
+
+/// The next length-delimited update in `reader`, and how many bytes it took up with its length.
+/// None at the end of the segment
+fn read_update(reader: &mut impl Read) -> io::Result<Option<(u64, Vec<u8>)>> {
+    let mut length = 0;
+    let mut read = 0;
+    loop {
+        let mut byte = [0];
+        if reader.read(&mut byte)? == 0 {
+            return match read {
+                0 => Ok(None),
+                _ => Err(io::ErrorKind::UnexpectedEof.into()),
+            };
+        }
+        length |= u64::from(byte[0] & 0x7f) << (7 * read);
+        read += 1;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        if read == 10 {
+            return Err(invalid("A length in the segment is too long".to_string()));
+        }
+    }
+    let mut data = vec![0; length as usize];
+    reader.read_exact(&mut data)?;
+    Ok(Some((read + length, data)))
+}
+
+/// Where the snapshots in the segment at `path` are, as (merge time, offset)
+fn read_index(path: &Path) -> io::Result<Vec<(i64, u64)>> {
+    let data = fs::read(path.with_extension(INDEX))?;
+    Ok(data
+        .chunks_exact(INDEX_ENTRY)
+        .map(|entry| {
+            let (time, offset) = entry.split_at(INDEX_ENTRY / 2);
+            (
+                i64::from_le_bytes(time.try_into().unwrap()),
+                u64::from_le_bytes(offset.try_into().unwrap()),
+            )
+        })
+        .collect())
+}
+
+/// Reads books back out of the history
+#[derive(Debug, Clone)]
+pub struct History {
+    dir: PathBuf,
+}
+
+impl History {
+    pub fn new(dir: PathBuf) -> History {
+        History { dir }
+    }
+
+    /// `instrument`'s segments, with when each starts, oldest first
+    fn segments(&self, instrument: CurrencyPair) -> io::Result<Vec<(i64, PathBuf)>> {
+        let entries = match fs::read_dir(self.dir.join(instrument.to_string())) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+        let mut segments = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == SEGMENT)
+            {
+                let start = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str()?.parse().ok());
+                if let Some(start) = start {
+                    segments.push((start, path));
+                }
+            }
+        }
+        segments.sort();
+        Ok(segments)
+    }
+
+    /// Every book from the last snapshot at or before `from_us` on, or from the very start if
+    /// there's nothing that early
+    fn books_from(
+        &self,
+        instrument: CurrencyPair,
+        from_us: i64,
+    ) -> io::Result<impl Iterator<Item = io::Result<Summary>>> {
+        let mut segments = self.segments(instrument)?;
+        let first = segments
+            .iter()
+            .rposition(|(start, _)| *start <= from_us)
+            .unwrap_or(0);
+        let segments = segments.split_off(first);
+        let offset = match segments.first() {
+            Some((_, path)) => read_index(path)?
+                .into_iter()
+                .rev()
+                .find(|(time, _)| *time <= from_us)
+                .map_or(0, |(_, offset)| offset),
+            None => 0,
+        };
+        let offsets = std::iter::once(offset).chain(std::iter::repeat(0));
+        Ok(segments.into_iter().zip(offsets).flat_map(
+            |((_, path), offset)| -> Box<dyn Iterator<Item = _>> {
+                match read_segment(&path, offset) {
+                    Ok(books) => Box::new(books),
+                    Err(err) => Box::new(std::iter::once(Err(err))),
+                }
+            },
+        ))
+    }
+
+    /// `instrument`'s book as it was at `time_us`: the last one merged at or before then. None if
+    /// the history doesn't go back that far
+    pub fn book_at(&self, instrument: CurrencyPair, time_us: i64) -> io::Result<Option<Summary>> {
+        let mut book = None;
+        for next in self.books_from(instrument, time_us)? {
+            let next = next?;
+            if next.merged_time_us > time_us {
+                break;
+            }
+            book = Some(next);
+        }
+        Ok(book)
+    }
+
+    /// `instrument`'s book as it was at `from_us`, if the history goes back that far, then every
+    /// book merged after it up to and including `to_us`
+    pub fn range(
+        &self,
+        instrument: CurrencyPair,
+        from_us: i64,
+        to_us: i64,
+    ) -> io::Result<impl Iterator<Item = io::Result<Summary>>> {
+        let mut books = self.books_from(instrument, from_us)?.peekable();
+        let mut first = None;
+        while let Some(book) = books.next_if(|book| {
+            book.as_ref()
+                .is_ok_and(|book| book.merged_time_us <= from_us)
+        }) {
+            first = book.ok();
+        }
+        let after = books.take_while(move |book| {
+            book.as_ref()
+                .map_or(true, |book| book.merged_time_us <= to_us)
+        });
+        Ok(first.map(Ok).into_iter().chain(after))
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use std::path::PathBuf;
+
+    use bitstamp::model::CurrencyPair;
+    use pretty_assertions::assert_eq;
+
+    use super::{HistorySettings, HistoryWriter, SNAPSHOT_EVERY};
+    use crate::api::{Level, Summary};
+
+    /// An empty directory of our own under the system's temporary one
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("orderbook-{name}-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    /// The `n`th book: a level that moves about, and one that comes and goes
+    fn book(n: i64) -> Summary {
+        let level = |exchange: &str, price, amount| Level {
+            exchange: exchange.to_string(),
+            price,
+            amount,
+            ..Level::default()
+        };
+        let mut bids = vec![level("binance", 1.0 + n as f64 / 1000.0, 1.0)];
+        if n % 3 == 0 {
+            bids.push(level("bitstamp", 0.5, n as f64));
+        }
+        Summary {
+            spread: bids[0].price - 2.0,
+            bids,
+            asks: vec![level("bybit", 2.0, n as f64)],
+            merged_time_us: n * 1000,
+            ..Summary::default()
+        }
+    }
+
+    #[test]
+    fn test_history() {
+        let dir = temp_dir("history");
+        let writer = HistoryWriter::start(HistorySettings {
+            dir: dir.clone(),
+            // A few snapshots to a segment
+            segment_bytes: 20_000,
+        })
+        .unwrap();
+        let count = SNAPSHOT_EVERY as i64 * 10;
+        for n in 1..=count {
+            writer.write(CurrencyPair::Ethbtc, book(n));
+        }
+        writer.write(CurrencyPair::Btcusd, book(1));
+        writer.stop();
+        let history = writer.history();
+        assert!(history.segments(CurrencyPair::Ethbtc).unwrap().len() > 1);
+
+        // The last book at or before the time, however far it is from a snapshot or segment start
+        for n in [1, 2, 99, 100, 101, 250, 777, count] {
+            let at = history
+                .book_at(CurrencyPair::Ethbtc, n * 1000 + 500)
+                .unwrap();
+            assert_eq!(at, Some(book(n)));
+            let at = history.book_at(CurrencyPair::Ethbtc, n * 1000).unwrap();
+            assert_eq!(at, Some(book(n)));
+        }
+        assert_eq!(history.book_at(CurrencyPair::Ethbtc, 999).unwrap(), None);
+        assert_eq!(
+            history.book_at(CurrencyPair::Btcusd, 1_000_000).unwrap(),
+            Some(book(1))
+        );
+        assert_eq!(
+            history.book_at(CurrencyPair::Ltcbtc, 1_000_000).unwrap(),
+            None
+        );
+
+        // The book as it was at the start, then every change, across segments
+        let range: Vec<_> = history
+            .range(CurrencyPair::Ethbtc, 150_500, 420_000)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(range, (150..=420).map(book).collect::<Vec<_>>());
+        let range: Vec<_> = history
+            .range(CurrencyPair::Ethbtc, 0, 2_500)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(range, vec![book(1), book(2)]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_bitstamp_history() {
+        use bitstamp::{model::Price, OrderBookData};
+        use chrono::Utc;
+
+        use crate::model::make_merged_market_depth;
+
+        // Bitstamp's book is by order, so there can be several at a price
+        let book = |n: i64| {
+            let order = |price, quantity, order_id| Price {
+                price,
+                quantity,
+                order_id,
+            };
+            let data = OrderBookData {
+                timestamp: Utc::now(),
+                bids: vec![order(1.0, 1.0, 1), order(1.0, n as f64, 2)],
+                asks: vec![order(2.0, 1.0, 3), order(2.0, 1.0, 4)],
+            };
+            Summary {
+                merged_time_us: n * 1000,
+                ..make_merged_market_depth([data.into()], 10, None)
+            }
+        };
+        let dir = temp_dir("bitstamp-history");
+        let writer = HistoryWriter::start(HistorySettings {
+            dir: dir.clone(),
+            segment_bytes: 1 << 20,
+        })
+        .unwrap();
+        // Past a few checksums
+        for n in 1..=30 {
+            writer.write(CurrencyPair::Ethbtc, book(n));
+        }
+        writer.stop();
+        let history = writer.history();
+        for n in [1, 10, 11, 30] {
+            let at = history.book_at(CurrencyPair::Ethbtc, n * 1000).unwrap();
+            assert_eq!(at, Some(book(n)));
+        }
+        let range: Vec<_> = history
+            .range(CurrencyPair::Ethbtc, 0, 30_000)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(range, (1..=30).map(book).collect::<Vec<_>>());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
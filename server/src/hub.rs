@@ -0,0 +1,1146 @@
+//! Shares upstream connections between clients. There's one connection per venue and instrument,
+//! and one merged stream per distinct request, however many clients are watching them. Each is
+//! shut down once nobody has been watching it for the idle grace period.
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    hash::Hash,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use bitstamp::model::CurrencyPair;
+use futures::{
+    future::{join_all, pending, ready},
+    stream, Future, Stream, StreamExt,
+};
+use tokio::{
+    sync::{watch, Mutex as AsyncMutex, Notify},
+    task::JoinHandle,
+    time::Instant,
+};
+use tokio_stream::wrappers::WatchStream;
+use tonic::Status;
+use tracing::{Instrument, Span};
+
+use crate::{
+    api::{ConnectionState, Summary, VenueConnection},
+    clock::Clock,
+    config::Settings,
+    connection::{reconnecting, SharedStats},
+    export::Exporter,
+    merge::{merge_venues, MergeOptions, StaleAfter},
+    metrics::METRICS,
+    model::VenueBook,
+    recorder::Recorder,
+    request::SummaryParams,
+    status::{unavailable, VenueFailure},
+    venue::{ErrorKind, Venue, VenueError, VenueStream},
+};
+
+/// How long an upstream is kept going after its last subscriber leaves, by default
+pub const DEFAULT_IDLE_GRACE: Duration = Duration::from_secs(30);
+/// How long a snapshot request waits for the first summary
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often an upstream checks whether anyone is still subscribed
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long `keep` waits before merging again, when a merge it's keeping ends
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// A running upstream
+struct Upstream<T> {
+    sender: Arc<watch::Sender<Option<T>>>,
+    /// Tells the upstream's task to stop
+    stop: Arc<Notify>,
+}
+
+/// The running upstream for one key, if there is one. Locked while starting or stopping it
+type Slot<T> = Arc<AsyncMutex<Option<Upstream<T>>>>;
+
+/// Runs at most one upstream stream per key, and shares the latest item from it with every
+/// subscriber
+pub struct Registry<K, T> {
+    slots: Arc<Mutex<HashMap<K, Slot<T>>>>,
+    idle_grace: Duration,
+}
+
+impl<K, T> Clone for Registry<K, T> {
+    fn clone(&self) -> Self {
+        Registry {
+            slots: self.slots.clone(),
+            idle_grace: self.idle_grace,
+        }
+    }
+}
+
+impl<K, T> Registry<K, T>
+where
+    K: Eq + Hash + Clone + Debug + Send + 'static,
+    T: Clone + Send + Sync + 'static,
+{
+    pub fn new(idle_grace: Duration) -> Self {
+        Registry {
+            slots: Arc::default(),
+            idle_grace,
+        }
+    }
+
+    /// How many upstreams are running
+    pub async fn running(&self) -> usize {
+        self.running_keys().await.len()
+    }
+
+    /// The keys of the upstreams that are running
+    pub async fn running_keys(&self) -> Vec<K> {
+        let slots: Vec<_> = self
+            .slots
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, slot)| (key.clone(), slot.clone()))
+            .collect();
+        let mut running = vec![];
+        for (key, slot) in slots {
+            if slot.lock().await.is_some() {
+                running.push(key);
+            }
+        }
+        running
+    }
+
+    /// Stop the upstreams whose keys match `stop`. Their subscribers' streams end
+    pub async fn stop_where(&self, stop: impl Fn(&K) -> bool) {
+        let slots: Vec<_> = self
+            .slots
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(key, _)| stop(key))
+            .map(|(key, slot)| (key.clone(), slot.clone()))
+            .collect();
+        for (key, slot) in slots {
+            if let Some(upstream) = slot.lock().await.take() {
+                log::info!("Stopping upstream {key:?}");
+                upstream.stop.notify_one();
+            }
+        }
+    }
+
+    /// Subscribe to `key`'s upstream, calling `start` to create it if it isn't running. The
+    /// receiver holds the latest item (None until there's been one), and closes when the upstream
+    /// ends. A new upstream is started and run in `span`
+    pub async fn subscribe<F, Fut, S, E>(
+        &self,
+        key: K,
+        span: Span,
+        start: F,
+    ) -> Result<watch::Receiver<Option<T>>, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<S, E>>,
+        S: Stream<Item = T> + Send + 'static,
+    {
+        let slot = self
+            .slots
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let mut running = slot.lock().await;
+        if let Some(upstream) = running.as_ref() {
+            log::debug!("Sharing upstream {key:?}");
+            return Ok(upstream.sender.subscribe());
+        }
+        log::info!("Starting upstream {key:?}");
+        let source = start().instrument(span.clone()).await?;
+        let (sender, receiver) = watch::channel(None);
+        let upstream = Upstream {
+            sender: Arc::new(sender),
+            stop: Arc::new(Notify::new()),
+        };
+        let run = run_upstream(
+            key,
+            source,
+            upstream.sender.clone(),
+            upstream.stop.clone(),
+            slot.clone(),
+            self.idle_grace,
+        );
+        tokio::spawn(run.instrument(span));
+        *running = Some(upstream);
+        Ok(receiver)
+    }
+}
+
+/// Forward the upstream's items to the subscribers until it ends, it's told to stop, or nobody has
+/// been subscribed for `idle_grace`
+async fn run_upstream<K, T, S>(
+    key: K,
+    source: S,
+    sender: Arc<watch::Sender<Option<T>>>,
+    stop: Arc<Notify>,
+    slot: Slot<T>,
+    idle_grace: Duration,
+) where
+    K: Debug,
+    S: Stream<Item = T>,
+{
+    let mut source = Box::pin(source);
+    let mut checks = tokio::time::interval(IDLE_CHECK_INTERVAL);
+    let mut idle_since = None;
+    loop {
+        tokio::select! {
+            item = source.next() => match item {
+                Some(item) => {
+                    sender.send_replace(Some(item));
+                }
+                None => {
+                    log::warn!("Upstream {key:?} ended");
+                    break;
+                }
+            },
+            // Whoever stopped us has already emptied the slot
+            _ = stop.notified() => return,
+            _ = checks.tick() => {
+                if sender.receiver_count() > 0 {
+                    idle_since = None;
+                    continue;
+                }
+                let since = *idle_since.get_or_insert_with(Instant::now);
+                if since.elapsed() < idle_grace {
+                    continue;
+                }
+                // Nobody can subscribe while we hold the slot, so check again under the lock
+                let mut running = slot.lock().await;
+                if sender.receiver_count() == 0 {
+                    log::info!("Stopping idle upstream {key:?}");
+                    *running = None;
+                    return;
+                }
+            }
+        }
+    }
+    // Unless we've been stopped and something else has started since
+    let mut running = slot.lock().await;
+    if running
+        .as_ref()
+        .is_some_and(|upstream| Arc::ptr_eq(&upstream.sender, &sender))
+    {
+        *running = None;
+    }
+}
+
+/// The items from a registry subscription, starting with the latest one if there is one
+pub fn latest<T>(receiver: watch::Receiver<Option<T>>) -> impl Stream<Item = T> + Send + 'static
+where
+    T: Clone + Send + Sync + 'static,
+{
+    WatchStream::new(receiver).filter_map(ready)
+}
+
+/// A venue connection we've made
+#[derive(Default)]
+struct Connection {
+    stats: SharedStats,
+    /// Makes the open connection, if there is one, reconnect
+    restart: Arc<Notify>,
+}
+
+/// Resolves once the hub starts shutting down
+async fn shutting_down(mut shutdown: watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            // The hub's gone without shutting down
+            pending::<()>().await;
+        }
+    }
+}
+
+/// Like `latest`, but ending when `shutdown` is set, with the latest item again as the final one
+fn until_shutdown<T>(
+    receiver: watch::Receiver<Option<T>>,
+    shutdown: watch::Receiver<bool>,
+) -> impl Stream<Item = T> + Send + 'static
+where
+    T: Clone + Send + Sync + 'static,
+{
+    let last = receiver.clone();
+    let stopped = shutdown.clone();
+    // Only if we're shutting down, rather than because the upstream ended
+    let last = stream::once(async move {
+        if *stopped.borrow() {
+            last.borrow().clone()
+        } else {
+            None
+        }
+    });
+    latest(receiver)
+        .take_until(shutting_down(shutdown))
+        .chain(last.filter_map(ready))
+}
+
+/// Every client's summaries come through here, so that they share venue connections and merges
+#[derive(Clone)]
+pub struct Hub {
+    venues: Registry<(Venue, CurrencyPair), VenueBook>,
+    summaries: Registry<SummaryParams, Summary>,
+    /// Every venue connection we've made, whether or not it's still open
+    connections: Arc<Mutex<HashMap<(Venue, CurrencyPair), Connection>>>,
+    /// Where to connect to, for venues that aren't using their default endpoint. Reconnects pick
+    /// up changes
+    endpoints: Arc<RwLock<BTreeMap<Venue, String>>>,
+    /// Merges started after a change get the new thresholds
+    stale_after: Arc<RwLock<StaleAfter>>,
+    /// Set once we're shutting down
+    shutdown: Arc<watch::Sender<bool>>,
+    /// Records every venue connection's frames, if we're recording
+    recorder: Option<Recorder>,
+    /// Exports every venue book, and the trades of venues that have them, if we're exporting
+    exporter: Option<Exporter>,
+    /// Whether the exporter gets trades too
+    trades: bool,
+    /// What books and summaries are timed by
+    clock: Clock,
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Hub {
+            venues: Registry::new(DEFAULT_IDLE_GRACE),
+            summaries: Registry::new(DEFAULT_IDLE_GRACE),
+            connections: Arc::default(),
+            endpoints: Arc::default(),
+            stale_after: Arc::default(),
+            shutdown: Arc::new(watch::channel(false).0),
+            recorder: None,
+            exporter: None,
+            trades: true,
+            clock: Clock::default(),
+        }
+    }
+}
+
+impl Hub {
+    /// Change how long a venue can go quiet before it's left out of the summaries
+    pub fn with_stale_after(self, stale_after: StaleAfter) -> Self {
+        *self.stale_after.write().unwrap() = stale_after;
+        self
+    }
+
+    /// Connect to `venue` at `endpoint` rather than its default
+    pub fn with_endpoint(self, venue: Venue, endpoint: String) -> Self {
+        self.endpoints.write().unwrap().insert(venue, endpoint);
+        self
+    }
+
+    /// Record the frames from every venue connection made from now on
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Export every book from the venue connections made from now on, and their venues' trades
+    pub fn with_exporter(mut self, exporter: Exporter) -> Self {
+        self.exporter = Some(exporter);
+        self
+    }
+
+    /// Export books but not trades, eg. when the venues are a replay of captured books
+    pub fn without_trades(mut self) -> Self {
+        self.trades = false;
+        self
+    }
+
+    /// Time books and summaries by `clock` rather than the system's, eg. a replay's
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// What books and summaries are timed by
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    /// Change how long connections and merges are kept going after their last client leaves
+    pub fn with_idle_grace(mut self, idle_grace: Duration) -> Self {
+        self.venues = Registry::new(idle_grace);
+        self.summaries = Registry::new(idle_grace);
+        self
+    }
+
+    /// How many venue connections are open
+    pub async fn venue_connections(&self) -> usize {
+        self.venues.running().await
+    }
+
+    /// Switch to new settings without disturbing anything they don't affect. Merges and
+    /// connections for venues or instruments that are no longer served are stopped, which ends
+    /// their clients' streams; connections whose endpoint has changed reconnect to the new one.
+    /// Anything newly enabled starts when a client asks for it
+    pub async fn reload(&self, settings: &Settings) {
+        let defaults = &settings.defaults;
+        let served = |venue: &Venue, instrument: &CurrencyPair| {
+            defaults.venues.contains(venue)
+                && (defaults.instruments.is_empty() || defaults.instruments.contains(instrument))
+        };
+        let moved: Vec<Venue> = {
+            let mut endpoints = self.endpoints.write().unwrap();
+            let moved = Venue::ALL
+                .into_iter()
+                .filter(|venue| endpoints.get(venue) != settings.endpoints.get(venue))
+                .collect();
+            *endpoints = settings.endpoints.clone();
+            moved
+        };
+        *self.stale_after.write().unwrap() = settings.stale_after.clone();
+
+        self.summaries
+            .stop_where(|params| {
+                !params
+                    .venues
+                    .iter()
+                    .all(|venue| served(venue, &params.instrument))
+            })
+            .await;
+        self.venues
+            .stop_where(|(venue, instrument)| !served(venue, instrument))
+            .await;
+        for (venue, instrument) in self.venues.running_keys().await {
+            if moved.contains(&venue) {
+                if let Some(connection) = self.connections.lock().unwrap().get(&(venue, instrument))
+                {
+                    connection.restart.notify_one();
+                }
+            }
+        }
+    }
+
+    /// The merged summaries for `params`, shared with every other client that asked for the same
+    /// thing. A client joining late gets the latest summary straight away. When the hub shuts down
+    /// the stream ends, after sending the latest summary again as the final one. If it ends
+    /// because the venues have failed, the final item is an `Unavailable` error naming them
+    #[allow(clippy::result_large_err)]
+    pub async fn summaries(
+        &self,
+        params: SummaryParams,
+    ) -> Result<impl Stream<Item = Result<Summary, Status>> + Send + 'static, Status> {
+        if *self.shutdown.borrow() {
+            return Err(Status::unavailable("The server is shutting down"));
+        }
+        let hub = self.clone();
+        let span =
+            tracing::info_span!("merge", instrument = %params.instrument, depth = params.depth);
+        let receiver = self
+            .summaries
+            .subscribe(params.clone(), span, {
+                let params = params.clone();
+                || async move { hub.merge(params).await }
+            })
+            .await?;
+        let hub = self.clone();
+        let failed = stream::once(async move {
+            let failures = hub.failures(&params);
+            match failures.is_empty() || hub.is_shutting_down() {
+                true => None,
+                false => Some(Err(unavailable("Every venue has failed", &failures))),
+            }
+        });
+        // Tracked, so a replay at max speed waits for every client to take each summary
+        let summaries = self
+            .clock
+            .tracked(until_shutdown(receiver, self.shutdown.subscribe()));
+        Ok(summaries.map(Ok).chain(failed.filter_map(ready)))
+    }
+
+    /// Hand each of `params`' summaries to `each` once, for as long as the hub's running, whether
+    /// or not any client's asking for them, merging again whenever the merge ends. When `params`
+    /// change, eg. on a reload, the merge is swapped for one with the new params, and when their
+    /// sender's dropped we stop
+    pub async fn keep(
+        &self,
+        mut params: watch::Receiver<SummaryParams>,
+        mut each: impl FnMut(Summary),
+    ) {
+        // A merge's last summary is sent again as its final one when we shut down
+        let mut last = None;
+        while !self.is_shutting_down() {
+            let current = params.borrow_and_update().clone();
+            let instrument = current.instrument;
+            let mut changed = false;
+            match self.summaries(current.clone()).await {
+                Ok(summaries) => {
+                    let mut summaries = Box::pin(summaries);
+                    while !changed {
+                        tokio::select! {
+                            summary = summaries.next() => match summary {
+                                Some(Ok(summary)) if last.as_ref() == Some(&summary) => {}
+                                Some(Ok(summary)) => {
+                                    last = Some(summary.clone());
+                                    each(summary)
+                                }
+                                Some(Err(status)) => {
+                                    log::warn!("Kept {instrument}: {}", status.message())
+                                }
+                                None => break,
+                            },
+                            result = params.changed() => match result {
+                                Ok(()) => changed = *params.borrow() != current,
+                                Err(_) => return,
+                            },
+                        }
+                    }
+                }
+                Err(status) => log::warn!("Unable to keep {instrument}: {}", status.message()),
+            }
+            if !changed {
+                tokio::select! {
+                    _ = tokio::time::sleep(RESUBSCRIBE_DELAY) => {}
+                    result = params.changed() => if result.is_err() {
+                        return;
+                    },
+                }
+            }
+        }
+    }
+
+    /// The venues `params` asks for that we've given up on
+    fn failures(&self, params: &SummaryParams) -> Vec<VenueFailure> {
+        let connections = self.connections.lock().unwrap();
+        let mut failures: Vec<_> = params
+            .venues
+            .iter()
+            .filter_map(|&venue| {
+                let stats = connections.get(&(venue, params.instrument))?.stats.clone();
+                let error = stats.lock().unwrap().failure.clone()?;
+                Some(VenueFailure {
+                    venue,
+                    instrument: params.instrument,
+                    kind: ErrorKind::Fatal,
+                    error,
+                })
+            })
+            .collect();
+        failures.sort_by_key(|failure| failure.venue);
+        failures
+    }
+
+    /// Stop taking new subscriptions, end every subscriber's stream with the latest summary, and
+    /// close the venue connections
+    pub async fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+        self.summaries.stop_where(|_| true).await;
+        self.venues.stop_where(|_| true).await;
+    }
+
+    /// Whether the hub has started shutting down
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Resolves once the hub starts shutting down
+    pub async fn shutting_down(&self) {
+        shutting_down(self.shutdown.subscribe()).await
+    }
+
+    /// Every instrument we've made a venue connection for, whether or not it's still open
+    pub fn connected_instruments(&self) -> Vec<CurrencyPair> {
+        let mut instruments = vec![];
+        for (_, instrument) in self.connections.lock().unwrap().keys() {
+            if !instruments.contains(instrument) {
+                instruments.push(*instrument);
+            }
+        }
+        instruments
+    }
+
+    /// Whether clients can get books for `instrument`: at least one of its open venue
+    /// connections is connected, or none are open yet, in which case they're made when a client
+    /// asks
+    pub fn instrument_connected(&self, instrument: CurrencyPair) -> bool {
+        let states: Vec<_> = self
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((_, pair), _)| *pair == instrument)
+            .map(|(_, connection)| connection.stats.lock().unwrap().state)
+            .filter(|state| *state != ConnectionState::Disconnected)
+            .collect();
+        states.is_empty() || states.contains(&ConnectionState::Connected)
+    }
+
+    /// The latest summary for `params`. If nobody's been asking for it we have to start it, and
+    /// wait for the first one
+    #[allow(clippy::result_large_err)]
+    pub async fn snapshot(&self, params: SummaryParams) -> Result<Summary, Status> {
+        let mut summaries = Box::pin(self.summaries(params).await?);
+        match tokio::time::timeout(SNAPSHOT_TIMEOUT, summaries.next()).await {
+            Ok(Some(summary)) => summary,
+            Ok(None) => Err(Status::unavailable("Every venue has disconnected")),
+            Err(_) => Err(Status::unavailable("No book from any venue yet")),
+        }
+    }
+
+    /// How each venue connection that `visible` is true of is doing, in venue then instrument
+    /// order
+    pub fn connection_statuses(
+        &self,
+        visible: impl Fn(Venue, CurrencyPair) -> bool,
+    ) -> Vec<VenueConnection> {
+        let now = Instant::now();
+        let mut statuses: Vec<_> = self
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((venue, instrument), _)| visible(*venue, *instrument))
+            .map(|((venue, instrument), connection)| {
+                let stats = connection.stats.lock().unwrap();
+                VenueConnection {
+                    exchange: venue.to_string(),
+                    instrument: instrument.to_string(),
+                    state: stats.state as i32,
+                    last_message_ms: stats
+                        .last_message
+                        .map(|time| time.timestamp_millis())
+                        .unwrap_or_default(),
+                    reconnects: stats.reconnects,
+                    messages: stats.messages,
+                    messages_per_second: stats.messages_per_second(now),
+                }
+            })
+            .collect();
+        statuses.sort_by(|a, b| (&a.exchange, &a.instrument).cmp(&(&b.exchange, &b.instrument)));
+        statuses
+    }
+
+    /// Connect to the requested venues (through the hub) and merge them
+    #[allow(clippy::result_large_err)]
+    async fn merge(
+        &self,
+        params: SummaryParams,
+    ) -> Result<impl Stream<Item = Summary> + Send + 'static, Status> {
+        let instrument = params.instrument;
+        // Connect to all the venues at once. A venue we can't reach is left out, rather than
+        // failing the whole request
+        let connections = params.venues.iter().map(|&venue| async move {
+            match self.venue(venue, instrument).await {
+                Ok(stream) => Ok((venue, stream)),
+                Err(err) => {
+                    log::error!("Unable to connect to {venue}: {err:?}");
+                    Err(VenueFailure::new(venue, instrument, &err))
+                }
+            }
+        });
+        let (venues, failures): (Vec<_>, Vec<_>) = join_all(connections)
+            .await
+            .into_iter()
+            .partition(Result::is_ok);
+        let venues: Vec<_> = venues.into_iter().flatten().collect();
+        if venues.is_empty() {
+            let failures: Vec<_> = failures.into_iter().filter_map(Result::err).collect();
+            return Err(unavailable("Unable to connect to any venue", &failures));
+        }
+        let options = MergeOptions {
+            depth: params.depth,
+            aggregation: params.aggregation,
+            tick: params.tick,
+            stale_after: self.stale_after.read().unwrap().clone(),
+            clock: self.clock.clone(),
+        };
+        Ok(merge_venues(venues, options).inspect(move |summary| METRICS.book(instrument, summary)))
+    }
+
+    /// One venue's books, from the shared connection. Errors are logged and counted here, as
+    /// there's no one subscriber to give them to. It ends if we give up on the venue
+    async fn venue(
+        &self,
+        venue: Venue,
+        instrument: CurrencyPair,
+    ) -> Result<VenueStream, VenueError> {
+        let endpoints = self.endpoints.clone();
+        // Looked up on every connect, so a reload can move us
+        let endpoint = move || {
+            endpoints
+                .read()
+                .unwrap()
+                .get(&venue)
+                .cloned()
+                .unwrap_or_else(|| venue.default_endpoint().to_string())
+        };
+        let connections = self.connections.clone();
+        let clock = self.clock.clone();
+        // Each connection, reconnects included, is recorded as a new one
+        let recorder = self.recorder.clone();
+        let tap = move || {
+            recorder
+                .as_ref()
+                .map(|recorder| recorder.tap(venue, instrument))
+        };
+        let exporter = self.exporter.clone();
+        let with_trades = self.trades;
+        let span = tracing::info_span!("venue", %venue, %instrument);
+        let receiver = self
+            .venues
+            .subscribe((venue, instrument), span, || async move {
+                log::debug!("Creating {venue} stream");
+                // A fresh restart signal, so one sent while nothing was connected doesn't linger
+                let (stats, restart) = {
+                    let mut connections = connections.lock().unwrap();
+                    let connection = connections.entry((venue, instrument)).or_default();
+                    connection.restart = Arc::default();
+                    (connection.stats.clone(), connection.restart.clone())
+                };
+                stats.lock().unwrap().state = ConnectionState::Connecting;
+                let first = venue
+                    .connect(instrument, &endpoint(), tap(), clock.clone())
+                    .await
+                    .inspect_err(|err| match err.kind() {
+                        ErrorKind::Fatal => stats.lock().unwrap().fail(err),
+                        _ => stats.lock().unwrap().state = ConnectionState::Disconnected,
+                    })?;
+                let name = format!("{venue} {instrument}");
+                let labels = [venue.to_string(), instrument.to_string()];
+                let reconnects = METRICS.reconnects.with_label_values(&labels);
+                let messages = METRICS.venue_messages.with_label_values(&labels);
+                let parse_errors = METRICS.parse_errors.with_label_values(&labels);
+                let errors = move |kind: ErrorKind| {
+                    let [venue, instrument] = &labels;
+                    METRICS
+                        .venue_errors
+                        .with_label_values(&[venue, instrument, &kind.to_string()])
+                };
+                // Trades are only wanted for exporting, and only for as long as the books are
+                let trades = exporter
+                    .clone()
+                    .filter(|_| with_trades && venue.has_trades())
+                    .map(|exporter| {
+                        export_trades(venue, instrument, endpoint.clone(), clock.clone(), exporter)
+                    });
+                // Tracks the books from the connection through to the registry
+                let tracker = clock.clone();
+                // Only reconnects come through here; the first connection was made above
+                let connect = move || {
+                    let endpoint = endpoint();
+                    let tap = tap();
+                    let clock = clock.clone();
+                    let reconnects = reconnects.clone();
+                    async move {
+                        let stream = venue.connect(instrument, &endpoint, tap, clock).await?;
+                        reconnects.inc();
+                        Ok(stream)
+                    }
+                };
+                let stream = reconnecting(name, first, connect, stats, restart);
+                Ok::<_, VenueError>(tracker.tracked(stream).filter_map(move |result| {
+                    // Holds on to the trades, so they stop when the books do
+                    let _trades = &trades;
+                    ready(match result {
+                        Ok(book) => {
+                            messages.inc();
+                            if let Some(exporter) = &exporter {
+                                exporter.book(venue, instrument, &book);
+                            }
+                            Some(book)
+                        }
+                        Err(err) => {
+                            let kind = err.kind();
+                            errors(kind).inc();
+                            if kind == ErrorKind::DataQuality {
+                                parse_errors.inc();
+                            }
+                            log::warn!("Failed {venue} {kind} item: {err:?}");
+                            None
+                        }
+                    })
+                }))
+            })
+            .await?;
+        Ok(self.clock.tracked(latest(receiver).map(Ok)))
+    }
+}
+
+/// Stops exporting a venue's trades when dropped
+struct TradeExport(JoinHandle<()>);
+
+impl Drop for TradeExport {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Export `venue`'s trades in `instrument`, reconnecting to `endpoint` like the books do, until the
+/// `TradeExport` is dropped
+fn export_trades(
+    venue: Venue,
+    instrument: CurrencyPair,
+    endpoint: impl Fn() -> String + Send + 'static,
+    clock: Clock,
+    exporter: Exporter,
+) -> TradeExport {
+    let connect = move || {
+        let endpoint = endpoint();
+        let clock = clock.clone();
+        async move { venue.connect_trades(instrument, &endpoint, clock).await }
+    };
+    let task = async move {
+        let first = match connect().await {
+            Ok(stream) => stream,
+            Err(err) if err.kind() == ErrorKind::Fatal => {
+                log::error!("Unable to export {venue} {instrument} trades: {err}");
+                return;
+            }
+            // Ends straight away, so the reconnecting starts
+            Err(err) => {
+                log::warn!("Unable to connect to {venue} {instrument} trades: {err}");
+                stream::empty().boxed()
+            }
+        };
+        let name = format!("{venue} {instrument} trades");
+        // Its own stats, as `GetVenueStatus` is about the books
+        let mut trades = reconnecting(name, first, connect, SharedStats::default(), Arc::default());
+        while let Some(result) = trades.next().await {
+            match result {
+                Ok(trade) => exporter.trade(venue, instrument, &trade),
+                Err(err) => log::warn!("Failed {venue} {instrument} trade: {err:?}"),
+            }
+        }
+    };
+    let span = tracing::info_span!("trades", %venue, %instrument);
+    TradeExport(tokio::spawn(task.instrument(span)))
+}
+
+#[cfg(test)]
+mod unit_test {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use futures::{stream, StreamExt};
+
+    use tokio::sync::{oneshot, watch};
+    use tonic::{Code, Status};
+    use tracing::Span;
+
+    use bitstamp::model::CurrencyPair;
+
+    use super::{latest, until_shutdown, Connection, Hub, Registry};
+    use crate::{
+        api::{ConnectionState, Summary, SummaryRequest, VenueConnection},
+        export::{exports, ExportSettings, Exporter},
+        request::{RequestDefaults, SummaryParams},
+        status::error_infos,
+        venue::{Venue, VenueError},
+    };
+
+    /// A binance that sends one book, or one trade, and then nothing
+    async fn serve_binance(listener: tokio::net::TcpListener, traded: oneshot::Sender<()>) {
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::{
+            handshake::server::{Request, Response},
+            Message,
+        };
+
+        let mut traded = Some(traded);
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut path = String::new();
+            // The handshake's own error response, which we never send
+            #[allow(clippy::result_large_err)]
+            let callback = |request: &Request, response: Response| {
+                path = request.uri().path().to_string();
+                Ok(response)
+            };
+            let mut socket = tokio_tungstenite::accept_hdr_async(stream, callback)
+                .await
+                .unwrap();
+            let frame = match path.ends_with("@trade") {
+                true => {
+                    r#"{"e":"trade","E":1700000000001,"s":"ETHBTC","t":42,"p":"0.05","q":"1.5","T":1700000000000,"m":true,"M":true}"#
+                }
+                false => r#"{"lastUpdateId":1,"bids":[["0.05","1.0"]],"asks":[["0.06","1.0"]]}"#,
+            };
+            socket.send(Message::Text(frame.into())).await.unwrap();
+            if path.ends_with("@trade") {
+                traded.take().map(|traded| traded.send(()));
+            }
+            tokio::spawn(async move { while socket.next().await.is_some() {} });
+        }
+    }
+
+    #[tokio::test]
+    async fn test_export_trades() {
+        let dir = std::env::temp_dir().join(format!("orderbook-trades-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        let exporter = Exporter::start(ExportSettings {
+            dir: dir.clone(),
+            levels: 1,
+            rotate_after: Duration::from_secs(3600),
+        })
+        .unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("ws://{}", listener.local_addr().unwrap());
+        let (traded, trade_sent) = oneshot::channel();
+        tokio::spawn(serve_binance(listener, traded));
+        let hub = Hub::default()
+            .with_endpoint(Venue::Binance, endpoint)
+            .with_exporter(exporter.clone());
+
+        let mut books = hub
+            .venue(Venue::Binance, CurrencyPair::Ethbtc)
+            .await
+            .unwrap();
+        assert!(books.next().await.unwrap().is_ok());
+        trade_sent.await.unwrap();
+        // Give the trade time to get from the socket to the exporter
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        drop(books);
+        tokio::task::spawn_blocking(move || exporter.stop())
+            .await
+            .unwrap();
+
+        // Dated by when we got them, so today
+        let mut tables: Vec<_> = exports(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|path| {
+                let path = path
+                    .strip_prefix(&dir)
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_owned();
+                assert!(path.contains("/instrument=ethbtc/venue=binance/"), "{path}");
+                path.split('/').next().unwrap().to_owned()
+            })
+            .collect();
+        tables.sort();
+        assert_eq!(tables, vec!["books", "trades"]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shared_upstream() {
+        let registry = Registry::<&str, u32>::new(Duration::from_secs(30));
+        let starts = Arc::new(AtomicUsize::new(0));
+        let subscribe = || {
+            let starts = starts.clone();
+            registry.subscribe("ethbtc", Span::none(), move || async move {
+                starts.fetch_add(1, Ordering::SeqCst);
+                Ok::<_, ()>(stream::iter([1, 2]).chain(stream::pending()))
+            })
+        };
+
+        let mut first = latest(subscribe().await.unwrap());
+        assert_eq!(first.next().await, Some(2));
+        // A late joiner shares the upstream, and gets the latest item straight away
+        let mut second = latest(subscribe().await.unwrap());
+        assert_eq!(second.next().await, Some(2));
+        assert_eq!(starts.load(Ordering::SeqCst), 1);
+        assert_eq!(registry.running().await, 1);
+
+        // Everyone leaves, but someone comes back within the grace period
+        drop((first, second));
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        let third = subscribe().await.unwrap();
+        assert_eq!(starts.load(Ordering::SeqCst), 1);
+
+        // Once it's been idle for the grace period it's stopped, and the next subscriber starts
+        // it again
+        drop(third);
+        tokio::time::sleep(Duration::from_secs(35)).await;
+        assert_eq!(registry.running().await, 0);
+        subscribe().await.unwrap();
+        assert_eq!(starts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_ends() {
+        let registry = Registry::<&str, u32>::new(Duration::from_secs(30));
+        let receiver = registry
+            .subscribe("ethbtc", Span::none(), || async {
+                Ok::<_, ()>(stream::iter([1]))
+            })
+            .await
+            .unwrap();
+        // The subscription ends with the upstream, and nothing is left running
+        latest(receiver).collect::<Vec<_>>().await;
+        assert_eq!(registry.running().await, 0);
+
+        let failed = registry
+            .subscribe("ethbtc", Span::none(), || async {
+                Err::<stream::Empty<u32>, _>("refused")
+            })
+            .await;
+        assert_eq!(failed.unwrap_err(), "refused");
+        assert_eq!(registry.running().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_stop() {
+        let registry = Registry::<&str, u32>::new(Duration::from_secs(30));
+        let subscribe = |key| {
+            registry.subscribe(key, Span::none(), || async {
+                Ok::<_, ()>(stream::iter([1]).chain(stream::pending()))
+            })
+        };
+        let mut ethbtc = latest(subscribe("ethbtc").await.unwrap());
+        let mut btcusdt = latest(subscribe("btcusdt").await.unwrap());
+        assert_eq!(ethbtc.next().await, Some(1));
+        assert_eq!(btcusdt.next().await, Some(1));
+
+        // Stopping one ends its subscribers' streams, and leaves the other alone
+        registry.stop_where(|key| *key == "ethbtc").await;
+        assert_eq!(ethbtc.next().await, None);
+        assert_eq!(registry.running_keys().await, vec!["btcusdt"]);
+
+        // It can be started again straight away
+        let mut ethbtc = latest(subscribe("ethbtc").await.unwrap());
+        assert_eq!(ethbtc.next().await, Some(1));
+        assert_eq!(registry.running().await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let (sender, receiver) = watch::channel(None);
+        let (shutdown, shutdown_receiver) = watch::channel(false);
+        let mut stream = Box::pin(until_shutdown(receiver, shutdown_receiver));
+        sender.send(Some(1)).unwrap();
+        assert_eq!(stream.next().await, Some(1));
+        sender.send(Some(2)).unwrap();
+        assert_eq!(stream.next().await, Some(2));
+
+        // The latest item comes again as the final one, then the stream ends
+        shutdown.send(true).unwrap();
+        assert_eq!(stream.next().await, Some(2));
+        assert_eq!(stream.next().await, None);
+
+        // An upstream ending on its own doesn't repeat anything
+        let (sender, receiver) = watch::channel(Some(1));
+        let (_shutdown, shutdown_receiver) = watch::channel(false);
+        let mut stream = Box::pin(until_shutdown(receiver, shutdown_receiver));
+        assert_eq!(stream.next().await, Some(1));
+        drop(sender);
+        assert_eq!(stream.next().await, None);
+
+        // Once the hub's shutting down, nobody new can subscribe
+        let hub = Hub::default();
+        hub.shutdown().await;
+        let params = SummaryParams::from_request(
+            Default::default(),
+            &RequestDefaults::new(bitstamp::model::CurrencyPair::Ethbtc),
+        )
+        .unwrap();
+        let status = hub.summaries(params).await.err().unwrap();
+        assert_eq!(status.code(), Code::Unavailable);
+    }
+
+    #[test]
+    fn test_instrument_connected() {
+        let hub = Hub::default();
+        let set = |venue, state| {
+            let mut connections = hub.connections.lock().unwrap();
+            let connection: &mut Connection = connections
+                .entry((venue, CurrencyPair::Ethbtc))
+                .or_default();
+            connection.stats.lock().unwrap().state = state;
+        };
+        // Nothing's open, so we'd connect when asked
+        assert!(hub.instrument_connected(CurrencyPair::Ethbtc));
+        set(Venue::Binance, ConnectionState::Reconnecting);
+        set(Venue::Bitstamp, ConnectionState::Disconnected);
+        assert!(!hub.instrument_connected(CurrencyPair::Ethbtc));
+        assert!(hub.instrument_connected(CurrencyPair::Btcusd));
+        set(Venue::Bitstamp, ConnectionState::Connected);
+        assert!(hub.instrument_connected(CurrencyPair::Ethbtc));
+    }
+
+    #[test]
+    fn test_connection_statuses() {
+        let hub = Hub::default();
+        for key in [
+            (Venue::Bitstamp, CurrencyPair::Ethbtc),
+            (Venue::Binance, CurrencyPair::Btcusd),
+            (Venue::Binance, CurrencyPair::Ethbtc),
+        ] {
+            hub.connections.lock().unwrap().entry(key).or_default();
+        }
+        let listed = |statuses: Vec<VenueConnection>| -> Vec<_> {
+            statuses
+                .into_iter()
+                .map(|status| format!("{} {}", status.exchange, status.instrument))
+                .collect()
+        };
+        assert_eq!(
+            listed(hub.connection_statuses(|_, _| true)),
+            ["binance btcusd", "binance ethbtc", "bitstamp ethbtc"]
+        );
+        let ethbtc = |_, instrument| instrument == CurrencyPair::Ethbtc;
+        assert_eq!(
+            listed(hub.connection_statuses(ethbtc)),
+            ["binance ethbtc", "bitstamp ethbtc"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_venue_failures() {
+        let params = |venues: &[&str]| {
+            let request = SummaryRequest {
+                venues: venues.iter().map(|venue| venue.to_string()).collect(),
+                ..SummaryRequest::default()
+            };
+            SummaryParams::from_request(request, &RequestDefaults::new(CurrencyPair::Ethbtc))
+                .unwrap()
+        };
+        // Nothing's listening on port 1, so bybit can't be reached
+        let hub = Hub::default().with_endpoint(Venue::Bybit, "ws://127.0.0.1:1".to_string());
+        let status = hub.summaries(params(&["bybit"])).await.err().unwrap();
+        assert_eq!(status.code(), Code::Unavailable);
+        let infos = error_infos(&status);
+        assert_eq!(infos.len(), 1);
+        assert_eq!(infos[0].metadata["venue"], "bybit");
+        assert_eq!(infos[0].metadata["kind"], "transient");
+
+        // A merge that ends because we gave up on its venues ends its clients' streams with them
+        let params = params(&["binance", "bitstamp"]);
+        let (end, ended) = oneshot::channel::<()>();
+        let upstream = stream::iter([Summary::default()])
+            .chain(stream::once(ended).filter_map(|_| async { None }));
+        let _running = hub
+            .summaries
+            .subscribe(params.clone(), Span::none(), || async {
+                Ok::<_, Status>(upstream)
+            })
+            .await
+            .unwrap();
+        let mut summaries = Box::pin(hub.summaries(params.clone()).await.unwrap());
+        assert!(summaries.next().await.unwrap().is_ok());
+        let refused = VenueError::Bybit(bybit::Error::Refused {
+            op: "subscribe".to_string(),
+            reason: "unknown symbol".to_string(),
+        });
+        hub.connections
+            .lock()
+            .unwrap()
+            .entry((Venue::Binance, CurrencyPair::Ethbtc))
+            .or_default()
+            .stats
+            .lock()
+            .unwrap()
+            .fail(&refused);
+        end.send(()).unwrap();
+        let status = summaries.next().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+        let infos = error_infos(&status);
+        assert_eq!(infos.len(), 1);
+        assert_eq!(infos[0].metadata["venue"], "binance");
+        assert_eq!(infos[0].metadata["kind"], "fatal");
+        assert!(summaries.next().await.is_none());
+    }
+}
@@ -1,52 +1,456 @@
 use anyhow::Result;
 use bitstamp::model::CurrencyPair;
-use futures::{Future, Stream, StreamExt};
-use model::make_merged_market_depth;
-use std::{net::SocketAddr, pin::Pin};
-use tonic::transport::Server;
+use config::{Compression, Settings};
+use futures::{future, Future, Stream, TryStreamExt};
+use hub::Hub;
+use merge::StaleAfter;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+use tokio::{
+    net::TcpListener,
+    sync::{oneshot, watch},
+};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{
+    codegen::InterceptedService,
+    transport::{Server, ServerTlsConfig},
+    Status,
+};
+use tracing::{Instrument, Span};
 
-use api::{orderbook_aggregator_server::OrderbookAggregator, Summary};
+use api::{orderbook_aggregator_server::OrderbookAggregator, BookUpdate, Summary, SummaryRequest};
+use auth::{Auth, Client, StreamGuard};
+use delta::DeltaEncoder;
+use metrics::METRICS;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic_health::proto::health_server::{Health, HealthServer};
 
 pub mod api;
+pub mod auth;
+pub mod clock;
+pub mod config;
+pub mod connection;
+pub mod delta;
+pub mod export;
+pub mod health;
+pub mod history;
 
 pub use binance::binance_stream;
+pub use bitfinex::bitfinex_stream;
 pub use bitstamp::bitstamp_detail_market_depth_stream;
+pub use bybit::bybit_stream;
+pub use htx::htx_depth_stream;
 
+pub mod hub;
+pub mod merge;
+pub mod metrics;
 pub mod model;
+pub mod recorder;
+pub mod reload;
+pub mod replay;
+pub mod request;
+pub mod status;
+pub mod telemetry;
+pub mod tls;
+pub mod venue;
+use clock::Clock;
+use export::Exporter;
+use history::{History, HistoryWriter};
+use recorder::Recorder;
+use request::{RequestDefaults, SummaryParams};
+use tls::PeerIdentity;
+use venue::Venue;
 
 /// Start the grpc server
 pub async fn serve<S>(addr: SocketAddr, service: S) -> Result<()>
 where
     S: OrderbookAggregator + Send + Sync + 'static,
 {
-    log::info!("Orderbook server listening on {:?}", addr);
+    let listener = TcpListener::bind(addr).await?;
+    serve_on(listener, service, Compression::Gzip).await
+}
+
+/// Start the grpc server on a listener that's already bound, eg. to port 0 so the OS picks a port.
+/// Health checks only cover the server as a whole, as there's no hub to ask about instruments
+pub async fn serve_on<S>(listener: TcpListener, service: S, compression: Compression) -> Result<()>
+where
+    S: OrderbookAggregator + Send + Sync + 'static,
+{
+    let (health, _reporting) = health::serve(None).await;
+    run(
+        listener,
+        service,
+        compression,
+        None,
+        Auth::default(),
+        health,
+        future::pending(),
+    )
+    .await
+}
+
+/// Serve, over TLS if there's a `tls` config, until `shutdown` resolves. Then shut down
+/// gracefully: stop taking new calls, end every client's stream with a final summary and an OK
+/// status, and close the venue connections. If clients are still connected after `deadline`, we
+/// give up on them and return anyway
+pub async fn serve_until(
+    listener: TcpListener,
+    service: SummaryServer,
+    compression: Compression,
+    tls: Option<ServerTlsConfig>,
+    shutdown: impl Future<Output = ()>,
+    deadline: Duration,
+) -> Result<()> {
+    let hub = service.hub.clone();
+    let auth = service.auth.clone();
+    let (health, _reporting) = health::serve(Some(service.clone())).await;
+    let (stopping, stopped) = oneshot::channel();
+    let shutdown = async move {
+        shutdown.await;
+        log::info!("Shutting down; waiting up to {deadline:?} for clients to finish");
+        stopping.send(()).ok();
+        hub.shutdown().await;
+    };
+    let server = run(listener, service, compression, tls, auth, health, shutdown);
+    tokio::pin!(server);
+    tokio::select! {
+        biased;
+        result = &mut server => return result,
+        _ = stopped => {}
+    }
+    match tokio::time::timeout(deadline, server).await {
+        Ok(result) => result?,
+        Err(_) => log::warn!("Clients were still connected after {deadline:?}; stopping anyway"),
+    }
+    Ok(())
+}
 
-    let service = api::orderbook_aggregator_server::OrderbookAggregatorServer::new(service)
-        .send_gzip()
-        .accept_gzip();
+/// Serve `service`, which needs an API key if `auth` has credentials, alongside health checks and
+/// reflection, which don't
+async fn run<S, H>(
+    listener: TcpListener,
+    service: S,
+    compression: Compression,
+    tls: Option<ServerTlsConfig>,
+    auth: Auth,
+    health: HealthServer<H>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()>
+where
+    S: OrderbookAggregator + Send + Sync + 'static,
+    H: Health,
+{
+    let mut server = Server::builder();
+    if let Some(tls) = tls {
+        server = server.tls_config(tls)?;
+    }
+    log::info!("Orderbook server listening on {:?}", listener.local_addr()?);
+
+    let service = api::orderbook_aggregator_server::OrderbookAggregatorServer::new(service);
+    let service = match compression {
+        Compression::Gzip => service.send_gzip().accept_gzip(),
+        Compression::None => service,
+    };
+    let service = InterceptedService::new(service, auth);
+    let reflection = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(api::FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(
+            tonic_health::proto::GRPC_HEALTH_V1_FILE_DESCRIPTOR_SET,
+        )
+        .build()?;
 
-    Server::builder().add_service(service).serve(addr).await?;
+    server
+        .add_service(service)
+        .add_service(health)
+        .add_service(reflection)
+        .serve_with_incoming_shutdown(TcpListenerStream::new(listener), shutdown)
+        .await?;
 
     Ok(())
 }
 
+/// Takes each kept summary, and the instrument it's for
+type Sink = Arc<dyn Fn(CurrencyPair, Summary) + Send + Sync>;
+
+/// What's merged in the background: each instrument we serve, with its default params, for every
+/// sink. Reloads change the params, and drop the sender of an instrument we no longer serve
+#[derive(Default)]
+struct Kept {
+    sinks: Vec<Sink>,
+    params: HashMap<CurrencyPair, watch::Sender<SummaryParams>>,
+}
+
+/// Clones share everything, so one can be served while another is reloaded
+#[derive(Clone)]
 pub struct SummaryServer {
-    instrument: CurrencyPair,
+    defaults: Arc<RwLock<RequestDefaults>>,
+    hub: Hub,
+    auth: Auth,
+    /// None if we aren't keeping history
+    history: Option<History>,
+    kept: Arc<Mutex<Kept>>,
 }
 
 impl SummaryServer {
     pub fn new(instrument: CurrencyPair) -> Self {
-        SummaryServer { instrument }
+        SummaryServer {
+            defaults: Arc::new(RwLock::new(RequestDefaults::new(instrument))),
+            hub: Hub::default(),
+            auth: Auth::default(),
+            history: None,
+            kept: Arc::default(),
+        }
+    }
+
+    /// A server set up from the config file and command line
+    pub fn from_settings(settings: &Settings) -> Self {
+        let hub = settings.endpoints.iter().fold(
+            Hub::default()
+                .with_stale_after(settings.stale_after.clone())
+                .with_idle_grace(settings.idle_grace),
+            |hub, (venue, endpoint)| hub.with_endpoint(*venue, endpoint.clone()),
+        );
+        SummaryServer {
+            defaults: Arc::new(RwLock::new(settings.defaults.clone())),
+            hub,
+            auth: Auth::new(settings.credentials.clone()),
+            history: None,
+            kept: Arc::default(),
+        }
+    }
+
+    /// Switch to new settings. Clients whose streams aren't affected carry on as they were
+    pub async fn reload(&self, settings: &Settings) {
+        *self.defaults.write().unwrap() = settings.defaults.clone();
+        self.auth.reload(settings.credentials.clone());
+        // Before the hub stops anything, so what's kept is merged again with the new params
+        self.keep_defaults();
+        self.hub.reload(settings).await;
+    }
+
+    /// Change how long a venue can go quiet before it's left out of the summaries
+    pub fn with_stale_after(mut self, stale_after: StaleAfter) -> Self {
+        self.hub = self.hub.with_stale_after(stale_after);
+        self
+    }
+
+    /// Connect to `venue` at `endpoint` rather than its default
+    pub fn with_endpoint(mut self, venue: Venue, endpoint: String) -> Self {
+        self.hub = self.hub.with_endpoint(venue, endpoint);
+        self
+    }
+
+    /// Record the frames from every venue connection
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.hub = self.hub.with_recorder(recorder);
+        self
+    }
+
+    /// Time books and summaries by `clock` rather than the system's, eg. a replay's
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.hub = self.hub.with_clock(clock);
+        self
+    }
+
+    /// Change how long venue connections are kept open after their last client leaves
+    pub fn with_idle_grace(mut self, idle_grace: Duration) -> Self {
+        self.hub = self.hub.with_idle_grace(idle_grace);
+        self
+    }
+
+    /// Export every book from the venue connections
+    pub fn with_exporter(mut self, exporter: Exporter) -> Self {
+        self.hub = self.hub.with_exporter(exporter);
+        self
+    }
+
+    /// Answer `GetBookAt` and `StreamRange` from `history`
+    pub fn with_history(mut self, history: History) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    /// Merge each instrument we serve in the background, whether or not any client's asking for
+    /// it, and keep its default book in `writer`'s history until the hub shuts down
+    pub fn keep_history(&self, writer: &HistoryWriter) {
+        let writer = writer.clone();
+        self.keep(Arc::new(move |instrument, summary| {
+            writer.write(instrument, summary)
+        }));
+    }
+
+    /// Merge each instrument we serve in the background, like `keep_history`, and export its
+    /// default book with `exporter` until the hub shuts down
+    pub fn export_summaries(&self, exporter: &Exporter) {
+        let exporter = exporter.clone();
+        self.keep(Arc::new(move |instrument, summary| {
+            exporter.summary(instrument, summary)
+        }));
+    }
+
+    /// Keep every instrument we serve for `sink`, now and after reloads
+    fn keep(&self, sink: Sink) {
+        {
+            let mut kept = self.kept.lock().unwrap();
+            for (&instrument, params) in &kept.params {
+                self.spawn_keeper(instrument, params.subscribe(), sink.clone());
+            }
+            kept.sinks.push(sink);
+        }
+        self.keep_defaults();
+    }
+
+    /// Bring what's kept up to date with the defaults: start keeping instruments we've started
+    /// serving, stop keeping those we no longer serve, and change the params of the rest
+    fn keep_defaults(&self) {
+        let mut served: HashMap<CurrencyPair, SummaryParams> = self
+            .default_params()
+            .into_iter()
+            .map(|params| (params.instrument, params))
+            .collect();
+        let mut kept = self.kept.lock().unwrap();
+        kept.params
+            .retain(|instrument, sender| match served.remove(instrument) {
+                Some(params) => {
+                    if *sender.borrow() != params {
+                        sender.send_replace(params);
+                    }
+                    true
+                }
+                None => false,
+            });
+        for (instrument, params) in served {
+            let (sender, receiver) = watch::channel(params);
+            for sink in &kept.sinks {
+                self.spawn_keeper(instrument, receiver.clone(), sink.clone());
+            }
+            kept.params.insert(instrument, sender);
+        }
+    }
+
+    fn spawn_keeper(
+        &self,
+        instrument: CurrencyPair,
+        params: watch::Receiver<SummaryParams>,
+        sink: Sink,
+    ) {
+        let hub = self.hub.clone();
+        tokio::spawn(async move { hub.keep(params, |summary| sink(instrument, summary)).await });
+    }
+
+    /// What a client gets for each instrument we serve, if it asks for nothing more
+    fn default_params(&self) -> Vec<SummaryParams> {
+        let defaults = self.defaults.read().unwrap().clone();
+        let instruments = match defaults.instruments.is_empty() {
+            true => vec![defaults.instrument],
+            false => defaults.instruments.clone(),
+        };
+        instruments
+            .into_iter()
+            .filter_map(|instrument| {
+                let request = SummaryRequest {
+                    instrument: instrument.to_string(),
+                    ..SummaryRequest::default()
+                };
+                SummaryParams::from_request(request, &defaults)
+                    .inspect_err(|status| {
+                        log::error!("Unable to keep {instrument}: {}", status.message())
+                    })
+                    .ok()
+            })
+            .collect()
+    }
+
+    /// Check that history's kept, and that the client may see `instrument`'s default book, all of
+    /// whose venues and levels are in the history
+    #[allow(clippy::result_large_err)]
+    fn history_access<T>(
+        &self,
+        request: &tonic::Request<T>,
+        instrument: String,
+    ) -> Result<(History, CurrencyPair), Status> {
+        let history = self
+            .history
+            .clone()
+            .ok_or_else(|| Status::failed_precondition("This server doesn't keep history"))?;
+        let defaults = self.defaults.read().unwrap().clone();
+        let request_all = SummaryRequest {
+            instrument,
+            depth: defaults.depth as u32,
+            venues: defaults.venues.iter().map(Venue::to_string).collect(),
+            ..SummaryRequest::default()
+        };
+        let params = SummaryParams::from_request(request_all.clone(), &defaults)?;
+        let params = match request.extensions().get::<Arc<Client>>() {
+            Some(client) => client
+                .entitlements
+                .check(&client.name, &request_all, params)?,
+            None => params,
+        };
+        Ok((history, params.instrument))
+    }
+
+    /// Check a summary request against the server's settings and the client's entitlements. A
+    /// `stream` counts against the client's limit on open streams
+    #[allow(clippy::result_large_err)]
+    fn subscription(
+        &self,
+        request: tonic::Request<SummaryRequest>,
+        stream: bool,
+    ) -> Result<Subscription, Status> {
+        let client = request.extensions().get::<Arc<Client>>().cloned();
+        let peer = PeerIdentity::from_request(&request);
+        let request = request.into_inner();
+        let params = SummaryParams::from_request(request.clone(), &self.defaults.read().unwrap())?;
+        let client = match client {
+            Some(client) => client,
+            None => {
+                let who = match peer {
+                    Some(peer) => peer.to_string(),
+                    None => "anonymous".to_string(),
+                };
+                return Ok(Subscription {
+                    params,
+                    who,
+                    guard: None,
+                });
+            }
+        };
+        let params = client.entitlements.check(&client.name, &request, params)?;
+        let guard = if stream {
+            Some(self.auth.open_stream(&client)?)
+        } else {
+            None
+        };
+        Ok(Subscription {
+            params,
+            who: client.name.clone(),
+            guard,
+        })
     }
 }
 
+/// A checked summary request, and who it's from
+struct Subscription {
+    params: SummaryParams,
+    /// Who the client is, for the logs and metrics
+    who: String,
+    /// Holds the stream's place in the client's limit, for as long as it's open
+    guard: Option<StreamGuard>,
+}
+
 impl OrderbookAggregator for SummaryServer {
     type BookSummaryStream =
         Pin<Box<dyn Stream<Item = Result<Summary, tonic::Status>> + Send + 'static>>;
 
     fn book_summary<'life0, 'async_trait>(
         &'life0 self,
-        _request: tonic::Request<api::Empty>,
+        request: tonic::Request<api::SummaryRequest>,
     ) -> core::pin::Pin<
         Box<
             dyn Future<Output = Result<tonic::Response<Self::BookSummaryStream>, tonic::Status>>
@@ -58,53 +462,231 @@ impl OrderbookAggregator for SummaryServer {
         'life0: 'async_trait,
         Self: 'async_trait,
     {
-        Box::pin(get_summary_stream(self.instrument))
+        let subscription = self.subscription(request, true);
+        let hub = self.hub.clone();
+        Box::pin(async move { get_summary_stream(&hub, subscription?).await })
+    }
+
+    type BookUpdatesStream =
+        Pin<Box<dyn Stream<Item = Result<BookUpdate, tonic::Status>> + Send + 'static>>;
+
+    fn book_updates<'life0, 'async_trait>(
+        &'life0 self,
+        request: tonic::Request<api::SummaryRequest>,
+    ) -> core::pin::Pin<
+        Box<
+            dyn Future<Output = Result<tonic::Response<Self::BookUpdatesStream>, tonic::Status>>
+                + core::marker::Send
+                + 'async_trait,
+        >,
+    >
+    where
+        'life0: 'async_trait,
+        Self: 'async_trait,
+    {
+        let subscription = self.subscription(request, true);
+        let hub = self.hub.clone();
+        Box::pin(async move { get_update_stream(&hub, subscription?).await })
+    }
+
+    fn get_book_snapshot<'life0, 'async_trait>(
+        &'life0 self,
+        request: tonic::Request<api::SummaryRequest>,
+    ) -> core::pin::Pin<
+        Box<
+            dyn Future<Output = Result<tonic::Response<Summary>, tonic::Status>>
+                + core::marker::Send
+                + 'async_trait,
+        >,
+    >
+    where
+        'life0: 'async_trait,
+        Self: 'async_trait,
+    {
+        let subscription = self.subscription(request, false);
+        let hub = self.hub.clone();
+        Box::pin(async move {
+            let summary = hub.snapshot(subscription?.params).await?;
+            Ok(tonic::Response::new(Summary {
+                sent_time_us: hub.clock().now_us(),
+                ..summary
+            }))
+        })
+    }
+
+    fn get_venue_status<'life0, 'async_trait>(
+        &'life0 self,
+        request: tonic::Request<api::VenueStatusRequest>,
+    ) -> core::pin::Pin<
+        Box<
+            dyn Future<Output = Result<tonic::Response<api::VenueStatusReply>, tonic::Status>>
+                + core::marker::Send
+                + 'async_trait,
+        >,
+    >
+    where
+        'life0: 'async_trait,
+        Self: 'async_trait,
+    {
+        // Only the connections a client could subscribe to
+        let client = request.extensions().get::<Arc<Client>>().cloned();
+        let connections = self.hub.connection_statuses(|venue, instrument| {
+            client
+                .as_ref()
+                .is_none_or(|client| client.entitlements.allows(venue, instrument))
+        });
+        Box::pin(async move { Ok(tonic::Response::new(api::VenueStatusReply { connections })) })
+    }
+
+    fn get_book_at<'life0, 'async_trait>(
+        &'life0 self,
+        request: tonic::Request<api::BookAtRequest>,
+    ) -> core::pin::Pin<
+        Box<
+            dyn Future<Output = Result<tonic::Response<Summary>, tonic::Status>>
+                + core::marker::Send
+                + 'async_trait,
+        >,
+    >
+    where
+        'life0: 'async_trait,
+        Self: 'async_trait,
+    {
+        let instrument = request.get_ref().instrument.clone();
+        let access = self.history_access(&request, instrument);
+        let time_us = request.get_ref().time_us;
+        let clock = self.hub.clock().clone();
+        Box::pin(async move {
+            let (history, instrument) = access?;
+            let book = tokio::task::spawn_blocking(move || history.book_at(instrument, time_us))
+                .await
+                .map_err(|err| Status::internal(err.to_string()))?
+                .map_err(|err| Status::internal(format!("Unable to read history: {err}")))?;
+            let book = book.ok_or_else(|| {
+                Status::not_found(format!("No {instrument} history at or before {time_us}"))
+            })?;
+            Ok(tonic::Response::new(Summary {
+                sent_time_us: clock.now_us(),
+                ..book
+            }))
+        })
+    }
+
+    type StreamRangeStream = ReceiverStream<Result<Summary, tonic::Status>>;
+
+    fn stream_range<'life0, 'async_trait>(
+        &'life0 self,
+        request: tonic::Request<api::RangeRequest>,
+    ) -> core::pin::Pin<
+        Box<
+            dyn Future<Output = Result<tonic::Response<Self::StreamRangeStream>, tonic::Status>>
+                + core::marker::Send
+                + 'async_trait,
+        >,
+    >
+    where
+        'life0: 'async_trait,
+        Self: 'async_trait,
+    {
+        let instrument = request.get_ref().instrument.clone();
+        let access = self.history_access(&request, instrument);
+        let api::RangeRequest { from_us, to_us, .. } = *request.get_ref();
+        let clock = self.hub.clock().clone();
+        Box::pin(async move {
+            let (history, instrument) = access?;
+            if from_us > to_us {
+                return Err(Status::invalid_argument(format!(
+                    "from_us {from_us} is after to_us {to_us}"
+                )));
+            }
+            let (sender, receiver) = tokio::sync::mpsc::channel(RANGE_BUFFER);
+            // Reading's blocking, and sending waits for the client, so this gets a thread of its own
+            tokio::task::spawn_blocking(move || {
+                let books = match history.range(instrument, from_us, to_us) {
+                    Ok(books) => books,
+                    Err(err) => {
+                        let status = Status::internal(format!("Unable to read history: {err}"));
+                        sender.blocking_send(Err(status)).ok();
+                        return;
+                    }
+                };
+                for book in books {
+                    let book = book
+                        .map(|book| Summary {
+                            sent_time_us: clock.now_us(),
+                            ..book
+                        })
+                        .map_err(|err| Status::internal(format!("Unable to read history: {err}")));
+                    let failed = book.is_err();
+                    // Stop reading once the client's gone
+                    if sender.blocking_send(book).is_err() || failed {
+                        return;
+                    }
+                }
+            });
+            Ok(tonic::Response::new(ReceiverStream::new(receiver)))
+        })
     }
 }
 
+/// How many books a `StreamRange` reads ahead of the client
+const RANGE_BUFFER: usize = 16;
+
+/// The span a client's stream is served in. Each thing sent to it gets a "send" span inside it
+fn client_span(rpc: &str, who: &str, params: &SummaryParams) -> Span {
+    tracing::info_span!("client_stream", rpc, client = who, instrument = %params.instrument)
+}
+
 async fn get_summary_stream(
-    instrument: CurrencyPair,
+    hub: &Hub,
+    subscription: Subscription,
 ) -> Result<tonic::Response<<SummaryServer as OrderbookAggregator>::BookSummaryStream>, tonic::Status>
 {
-    log::info!("Creating orderbook summary stream");
-    // Create a stream of binance market depth results
-    log::debug!("Creating binance stream");
-    let binance_stream = binance_stream(&format!("{}", instrument))
-        .await
-        .map_err(|err| {
-            log::error!("{err:?}");
-            tonic::Status::internal("Internal error")
-        })?
-        .map(|result| {
-            log::debug!("Got bitstamp reply: {:?}", result);
-            result.map_err(|err| {
-                log::warn!("Failed binance item: {:?}", err);
-                tonic::Status::internal("Retrieving binance order-book")
-            })
-        });
-    // bitstamp market depth results
-    log::debug!("Creating bitstamp stream");
-    let bitstamp_stream = bitstamp_detail_market_depth_stream(instrument)
-        .await
-        .map_err(|err| {
-            log::error!("{err:?}");
-            tonic::Status::internal("Internal error")
-        })?
-        .map(|result| {
-            log::debug!("Got bitstamp reply: {:?}", result);
-            result.map_err(|err| {
-                log::warn!("Failed bitstamp item: {:?}", err);
-                tonic::Status::internal("Internal error")
-            })
-        });
-    // Zip them together and convert them into a merged market depth
-    let stream = binance_stream
-        .zip(bitstamp_stream)
-        .map(|(binance_result, bitstream_result)| {
-            binance_result.and_then(|binance_data| {
-                bitstream_result
-                    .map(|bitstream_data| make_merged_market_depth(binance_data, bitstream_data))
-            })
-        });
+    let Subscription { params, who, guard } = subscription;
+    let span = client_span("book_summary", &who, &params);
+    span.in_scope(|| log::info!("Creating orderbook summary stream for {who}: {params:?}"));
+    let summaries = hub.summaries(params).instrument(span.clone()).await?;
+    let open = METRICS.open_stream("book_summary");
+    let sent = METRICS.summaries_sent.with_label_values(&[who]);
+    let clock = hub.clock().clone();
+    let mut sequence = 0u64;
+    let stream = summaries.map_ok(move |summary| {
+        let _open = (&guard, &open);
+        sequence += 1;
+        let _send = tracing::debug_span!(parent: &span, "send", sequence).entered();
+        sent.inc();
+        Summary {
+            sent_time_us: clock.now_us(),
+            ..summary
+        }
+    });
+    Ok(tonic::Response::new(Box::pin(stream)))
+}
+
+async fn get_update_stream(
+    hub: &Hub,
+    subscription: Subscription,
+) -> Result<tonic::Response<<SummaryServer as OrderbookAggregator>::BookUpdatesStream>, tonic::Status>
+{
+    let Subscription { params, who, guard } = subscription;
+    let span = client_span("book_updates", &who, &params);
+    span.in_scope(|| log::info!("Creating orderbook update stream for {who}: {params:?}"));
+    // Each client gets its own encoder, as each has seen a different set of summaries
+    let mut encoder = DeltaEncoder::default();
+    let summaries = hub.summaries(params).instrument(span.clone()).await?;
+    let open = METRICS.open_stream("book_updates");
+    let sent = METRICS.summaries_sent.with_label_values(&[who]);
+    let clock = hub.clock().clone();
+    let mut sequence = 0u64;
+    let stream = summaries.map_ok(move |summary| {
+        let _open = (&guard, &open);
+        sequence += 1;
+        let _send = tracing::debug_span!(parent: &span, "send", sequence).entered();
+        sent.inc();
+        encoder.encode(Summary {
+            sent_time_us: clock.now_us(),
+            ..summary
+        })
+    });
     Ok(tonic::Response::new(Box::pin(stream)))
 }
@@ -1,41 +1,107 @@
 use anyhow::Result;
-use bitstamp::model::CurrencyPair;
-use server::{serve, SummaryServer};
+use clap::Parser;
+use server::{
+    config::{Args, Config},
+    export::Exporter,
+    history::HistoryWriter,
+    metrics::serve_metrics,
+    recorder::Recorder,
+    reload::watch_config,
+    serve_until, telemetry,
+    tls::TlsSettings,
+    SummaryServer,
+};
+use tokio::{
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    pretty_env_logger::init();
-    let addr = "127.0.0.1:8000".parse().unwrap();
-    let service = SummaryServer::new(CurrencyPair::Ethbtc);
+    let args = Args::parse();
+    let settings = Config::load(&args)?.settings()?;
+    let _telemetry = telemetry::init(&settings.log, settings.otlp_endpoint.as_deref())?;
+    let listener = TcpListener::bind(settings.listen).await?;
+    if let Some(addr) = settings.metrics_listen {
+        tokio::spawn(serve_metrics(TcpListener::bind(addr).await?));
+    }
+    let recorder = settings.record.clone().map(Recorder::start).transpose()?;
+    let history = settings
+        .history
+        .clone()
+        .map(HistoryWriter::start)
+        .transpose()?;
+    let exporter = settings.export.clone().map(Exporter::start).transpose()?;
+    let mut service = SummaryServer::from_settings(&settings);
+    if let Some(recorder) = &recorder {
+        service = service.with_recorder(recorder.clone());
+    }
+    if let Some(history) = &history {
+        service = service.with_history(history.history());
+        service.keep_history(history);
+    }
+    if let Some(exporter) = &exporter {
+        service = service.with_exporter(exporter.clone());
+        service.export_summaries(exporter);
+    }
+    let compression = settings.compression;
+    let tls = settings
+        .tls
+        .as_ref()
+        .map(TlsSettings::server_config)
+        .transpose()?;
+    let deadline = settings.shutdown_timeout;
+    tokio::spawn(watch_config(args, service.clone(), settings));
 
-    serve(addr, service).await
+    let mut terminate = signal(SignalKind::terminate())?;
+    let shutdown = async move {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => log::info!("Got Ctrl-C"),
+            _ = terminate.recv() => log::info!("Got SIGTERM"),
+        }
+    };
+    serve_until(listener, service, compression, tls, shutdown, deadline).await?;
+    if let Some(recorder) = recorder {
+        tokio::task::spawn_blocking(move || recorder.stop()).await?;
+    }
+    if let Some(history) = history {
+        tokio::task::spawn_blocking(move || history.stop()).await?;
+    }
+    if let Some(exporter) = exporter {
+        tokio::task::spawn_blocking(move || exporter.stop()).await?;
+    }
+    log::info!("Shut down");
+    Ok(())
 }
 
 #[cfg(test)]
 mod web_test {
     use bitstamp::model::CurrencyPair;
     use server::{
-        api::{orderbook_aggregator_client::OrderbookAggregatorClient, Empty},
-        SummaryServer,
+        api::{orderbook_aggregator_client::OrderbookAggregatorClient, SummaryRequest},
+        config::Compression,
+        serve_on, SummaryServer,
     };
-    use tokio::spawn;
+    use tokio::{net::TcpListener, spawn};
 
     #[tokio::test]
     async fn test_live_stream() {
         pretty_env_logger::try_init().ok();
-        let addr = "127.0.0.1:8000".parse().unwrap();
+        // Any free port, so this doesn't collide with a running server
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
         let service = SummaryServer::new(CurrencyPair::Ethbtc);
-        let _server = spawn(crate::serve(addr, service));
+        let _server = spawn(serve_on(listener, service, Compression::Gzip));
         let client = spawn(async move {
             // Connect to the server and recieve one message
             log::info!("Client connecting");
-            let mut client = OrderbookAggregatorClient::connect("http://127.0.0.1:8000")
+            let mut client = OrderbookAggregatorClient::connect(format!("http://{addr}"))
                 .await
                 .unwrap();
 
             log::info!("Client calling book_summary");
             let mut s = client
-                .book_summary(tonic::Request::new(Empty {}))
+                .book_summary(tonic::Request::new(SummaryRequest::default()))
                 .await
                 .unwrap()
                 .into_inner();
@@ -0,0 +1,420 @@
+//! Combine-latest merging of any number of venues: we keep the latest book from each venue, and
+//! produce a new summary whenever any one of them changes, or goes stale
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+
+use futures::{
+    stream::{self, select_all},
+    Stream, StreamExt,
+};
+use rust_decimal::Decimal;
+use tokio::time::Instant;
+
+use crate::{
+    api::{Aggregation, Summary, VenueStatus},
+    clock::Clock,
+    metrics::METRICS,
+    model::{make_consolidated_market_depth, make_merged_market_depth, unix_us, VenueBook},
+    request::DEFAULT_DEPTH,
+    venue::{Venue, VenueError, VenueStream},
+};
+
+/// How often we check whether a quiet venue has gone stale
+const STALENESS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a venue can go without sending us a book before we stop trusting it
+#[derive(Debug, Clone, PartialEq)]
+pub struct StaleAfter {
+    default: Duration,
+    venues: BTreeMap<Venue, Duration>,
+}
+
+impl Default for StaleAfter {
+    fn default() -> Self {
+        StaleAfter::new(Duration::from_secs(10))
+    }
+}
+
+impl StaleAfter {
+    /// Use the same threshold for every venue
+    pub fn new(default: Duration) -> StaleAfter {
+        StaleAfter {
+            default,
+            venues: BTreeMap::new(),
+        }
+    }
+
+    /// Override the threshold for one venue, eg. one that only publishes once a second
+    pub fn with_venue(mut self, venue: Venue, threshold: Duration) -> StaleAfter {
+        self.venues.insert(venue, threshold);
+        self
+    }
+
+    pub fn threshold(&self, venue: Venue) -> Duration {
+        self.venues.get(&venue).copied().unwrap_or(self.default)
+    }
+}
+
+/// How to merge the venues' books
+#[derive(Debug, Clone)]
+pub struct MergeOptions {
+    /// Levels per side
+    pub depth: usize,
+    pub aggregation: Aggregation,
+    /// The tick levels are grouped by; consolidated levels are only combined at the same price,
+    /// and per venue ones aren't grouped, if not set
+    pub tick: Option<Decimal>,
+    pub stale_after: StaleAfter,
+    /// What the summaries are timed by, and staleness measured against
+    pub clock: Clock,
+}
+
+impl Default for MergeOptions {
+    fn default() -> Self {
+        MergeOptions {
+            depth: DEFAULT_DEPTH,
+            aggregation: Aggregation::PerVenue,
+            tick: None,
+            stale_after: StaleAfter::default(),
+            clock: Clock::default(),
+        }
+    }
+}
+
+/// Something that happened on one of the venue streams
+enum Event {
+    Update(Venue, Result<VenueBook, VenueError>),
+    Ended(Venue),
+    /// Time to see if anyone has gone quiet
+    Check,
+}
+
+/// The latest book we have from each venue, and when we got it
+#[derive(Default, Debug)]
+pub struct MergedBook {
+    books: BTreeMap<Venue, (Instant, VenueBook)>,
+    options: MergeOptions,
+    /// The venues that were stale last time we made a summary
+    stale: BTreeSet<Venue>,
+}
+
+impl MergedBook {
+    pub fn new(options: MergeOptions) -> MergedBook {
+        MergedBook {
+            options,
+            ..MergedBook::default()
+        }
+    }
+
+    /// Replace `venue`'s book
+    pub fn update(&mut self, venue: Venue, book: VenueBook, received: Instant) {
+        self.books.insert(venue, (received, book));
+    }
+
+    /// Forget about `venue`, eg. because its stream has ended
+    pub fn remove(&mut self, venue: Venue) {
+        self.books.remove(&venue);
+    }
+
+    /// The venues we currently have a book for
+    pub fn venues(&self) -> impl Iterator<Item = Venue> + '_ {
+        self.books.keys().copied()
+    }
+
+    /// The venues that haven't sent us anything for longer than their threshold
+    pub fn stale_venues(&self, now: Instant) -> BTreeSet<Venue> {
+        self.books
+            .iter()
+            .filter(|(venue, (received, _))| {
+                now.saturating_duration_since(*received)
+                    > self.options.stale_after.threshold(**venue)
+            })
+            .map(|(venue, _)| *venue)
+            .collect()
+    }
+
+    /// True if a venue has gone stale, or come back, since the last summary
+    pub fn staleness_changed(&self, now: Instant) -> bool {
+        self.stale_venues(now) != self.stale
+    }
+
+    /// Merge the latest book from every venue that isn't stale
+    pub fn summary(&mut self, now: Instant) -> Summary {
+        self.stale = self.stale_venues(now);
+        let fresh = self
+            .books
+            .iter()
+            .filter(|(venue, _)| !self.stale.contains(venue))
+            .map(|(_, (_, book))| book.clone());
+        let depth = self.options.depth;
+        let mut summary = match self.options.aggregation {
+            Aggregation::PerVenue => make_merged_market_depth(fresh, depth, self.options.tick),
+            Aggregation::Consolidated => {
+                make_consolidated_market_depth(fresh, depth, self.options.tick)
+            }
+        };
+        summary.venues = self
+            .books
+            .iter()
+            .map(|(venue, (received, book))| VenueStatus {
+                exchange: venue.to_string(),
+                age_ms: now.saturating_duration_since(*received).as_millis() as u64,
+                stale: self.stale.contains(venue),
+                exchange_time_us: unix_us(book.exchange_time),
+                received_time_us: unix_us(book.received_time),
+            })
+            .collect();
+        summary.merged_time_us = self.options.clock.now_us();
+        summary
+    }
+}
+
+/// Merge the streams from all the venues. A new summary is produced every time any venue sends a
+/// new book, and whenever a venue goes stale (it's then left out until it sends something again).
+/// Errors are logged and that venue keeps its last good book; when a venue's stream ends its book
+/// is dropped from the summary. The merged stream only ends when every venue has.
+pub fn merge_venues(
+    venues: impl IntoIterator<Item = (Venue, VenueStream)>,
+    options: MergeOptions,
+) -> impl Stream<Item = Summary> + Send + 'static {
+    let venue_streams: Vec<_> = venues
+        .into_iter()
+        .map(|(venue, stream)| {
+            stream
+                .map(move |result| Event::Update(venue, result))
+                .chain(stream::once(async move { Event::Ended(venue) }))
+                .boxed()
+        })
+        .collect();
+    let mut live_venues = venue_streams.len();
+    let clock = options.clock.clone();
+    let checks = clock
+        .ticks(STALENESS_CHECK_INTERVAL)
+        .map(|_| Event::Check)
+        .boxed();
+    let events = select_all(venue_streams.into_iter().chain([checks]));
+    events
+        .scan(MergedBook::new(options), move |merged, event| {
+            let now = clock.instant();
+            let summary = match event {
+                Event::Update(venue, Ok(book)) => {
+                    let span = tracing::debug_span!("book", %venue, sequence = book.sequence);
+                    let _entered = span.enter();
+                    // A merge that's just started gets each venue's latest book, however long ago
+                    // it came, so it's as old as when it arrived, not when we first saw it
+                    let received = book.received;
+                    merged.update(venue, book, received.unwrap_or(now));
+                    let summary = merged.summary(now);
+                    if let Some(received) = received {
+                        METRICS
+                            .upstream_to_emit
+                            .with_label_values(&[venue.to_string()])
+                            .observe(
+                                clock
+                                    .instant()
+                                    .saturating_duration_since(received)
+                                    .as_secs_f64(),
+                            );
+                    }
+                    Some(Some(summary))
+                }
+                Event::Update(venue, Err(err)) => {
+                    log::warn!("Failed {venue} item: {err:?}");
+                    Some(None)
+                }
+                Event::Ended(venue) => {
+                    log::warn!("{venue} stream ended; merging without it");
+                    merged.remove(venue);
+                    live_venues -= 1;
+                    Some(Some(merged.summary(now)))
+                }
+                // The staleness checks go on forever, so stop once every venue has ended
+                Event::Check if live_venues == 0 => None,
+                Event::Check if merged.staleness_changed(now) => {
+                    log::warn!("Stale venues: {:?}", merged.stale_venues(now));
+                    Some(Some(merged.summary(now)))
+                }
+                Event::Check => Some(None),
+            };
+            futures::future::ready(summary)
+        })
+        .filter_map(futures::future::ready)
+}
+
+#[cfg(test)]
+mod unit_test {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use tokio::{sync::mpsc::unbounded_channel, time::Instant};
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+
+    use super::{merge_venues, MergeOptions, StaleAfter};
+    use crate::{
+        api::Level,
+        model::VenueBook,
+        venue::{Venue, VenueError, VenueStream},
+    };
+
+    fn book(exchange: &str, bid: f64, ask: f64) -> VenueBook {
+        let level = |price| Level {
+            exchange: exchange.to_string(),
+            price,
+            amount: 1.0,
+            ..Level::default()
+        };
+        VenueBook {
+            bids: vec![level(bid)],
+            asks: vec![level(ask)],
+            received: None,
+            sequence: 0,
+            exchange_time: None,
+            received_time: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_combine_latest() {
+        let (binance_send, binance_recv) = unbounded_channel();
+        let (bitstamp_send, bitstamp_recv) = unbounded_channel();
+        let binance: VenueStream = Box::pin(UnboundedReceiverStream::new(binance_recv));
+        let bitstamp: VenueStream = Box::pin(UnboundedReceiverStream::new(bitstamp_recv));
+        let mut merged = Box::pin(merge_venues(
+            [(Venue::Binance, binance), (Venue::Bitstamp, bitstamp)],
+            MergeOptions::default(),
+        ));
+
+        // We get a summary as soon as the first venue has a book; no waiting for the others
+        binance_send.send(Ok(book("binance", 1.0, 2.0))).unwrap();
+        let summary = merged.next().await.unwrap();
+        assert_eq!(summary.bids.len(), 1);
+
+        // Then every update from any venue produces a new summary
+        bitstamp_send.send(Ok(book("bitstamp", 1.5, 2.5))).unwrap();
+        let summary = merged.next().await.unwrap();
+        assert_eq!(summary.bids[0].exchange, "bitstamp");
+        assert_eq!(summary.asks[0].exchange, "binance");
+        bitstamp_send.send(Ok(book("bitstamp", 1.6, 2.5))).unwrap();
+        bitstamp_send.send(Ok(book("bitstamp", 1.7, 2.5))).unwrap();
+        assert_eq!(merged.next().await.unwrap().bids[0].price, 1.6);
+        assert_eq!(merged.next().await.unwrap().bids[0].price, 1.7);
+
+        // An error doesn't end anything, and bitstamp keeps its last book
+        bitstamp_send
+            .send(Err(VenueError::Bybit(bybit::Error::DeltaBeforeSnapshot {
+                topic: "test".to_string(),
+            })))
+            .unwrap();
+        binance_send.send(Ok(book("binance", 1.0, 2.4))).unwrap();
+        let summary = merged.next().await.unwrap();
+        assert_eq!(summary.bids[0].price, 1.7);
+        assert_eq!(summary.asks[0].price, 2.4);
+
+        // When bitstamp ends, we carry on with just binance
+        drop(bitstamp_send);
+        let summary = merged.next().await.unwrap();
+        assert!(summary.bids.iter().all(|level| level.exchange == "binance"));
+        binance_send.send(Ok(book("binance", 1.1, 2.4))).unwrap();
+        assert_eq!(merged.next().await.unwrap().bids[0].price, 1.1);
+
+        // Only when every venue is gone does the merged stream end
+        drop(binance_send);
+        assert!(merged.next().await.unwrap().bids.is_empty());
+        assert!(merged.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_no_venues() {
+        let mut merged = Box::pin(merge_venues(
+            Vec::<(Venue, VenueStream)>::new(),
+            MergeOptions::default(),
+        ));
+        assert!(merged.next().await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stale_venue_is_excluded() {
+        let (binance_send, binance_recv) = unbounded_channel();
+        let (bitstamp_send, bitstamp_recv) = unbounded_channel();
+        let binance: VenueStream = Box::pin(UnboundedReceiverStream::new(binance_recv));
+        let bitstamp: VenueStream = Box::pin(UnboundedReceiverStream::new(bitstamp_recv));
+        let stale_after = StaleAfter::new(Duration::from_secs(60))
+            .with_venue(Venue::Bitstamp, Duration::from_secs(5));
+        let mut merged = Box::pin(merge_venues(
+            [(Venue::Binance, binance), (Venue::Bitstamp, bitstamp)],
+            MergeOptions {
+                stale_after,
+                ..MergeOptions::default()
+            },
+        ));
+
+        binance_send.send(Ok(book("binance", 1.0, 2.0))).unwrap();
+        merged.next().await.unwrap();
+        bitstamp_send.send(Ok(book("bitstamp", 1.5, 2.5))).unwrap();
+        let summary = merged.next().await.unwrap();
+        assert_eq!(summary.bids[0].exchange, "bitstamp");
+        assert!(summary.venues.iter().all(|venue| !venue.stale));
+
+        // Bitstamp goes quiet. With no other updates, the next summary comes from the staleness
+        // check, and leaves bitstamp out
+        let summary = merged.next().await.unwrap();
+        assert!(summary.bids.iter().all(|level| level.exchange == "binance"));
+        let bitstamp_status = summary
+            .venues
+            .iter()
+            .find(|venue| venue.exchange == "bitstamp")
+            .unwrap();
+        assert!(bitstamp_status.stale);
+        assert!(bitstamp_status.age_ms > 5000);
+        let binance_status = summary
+            .venues
+            .iter()
+            .find(|venue| venue.exchange == "binance")
+            .unwrap();
+        assert!(!binance_status.stale);
+
+        // It's back as soon as it sends something
+        bitstamp_send.send(Ok(book("bitstamp", 1.6, 2.5))).unwrap();
+        let summary = merged.next().await.unwrap();
+        assert_eq!(summary.bids[0].exchange, "bitstamp");
+        assert!(summary.venues.iter().all(|venue| !venue.stale));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_late_merge_of_quiet_venue() {
+        // Bitstamp's last book came a minute before the merge started, and it's been quiet since
+        let quiet = VenueBook {
+            received: Some(Instant::now()),
+            ..book("bitstamp", 1.5, 2.5)
+        };
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        let (binance_send, binance_recv) = unbounded_channel();
+        let (bitstamp_send, bitstamp_recv) = unbounded_channel();
+        let binance: VenueStream = Box::pin(UnboundedReceiverStream::new(binance_recv));
+        let bitstamp: VenueStream = Box::pin(UnboundedReceiverStream::new(bitstamp_recv));
+        let mut merged = Box::pin(merge_venues(
+            [(Venue::Binance, binance), (Venue::Bitstamp, bitstamp)],
+            MergeOptions::default(),
+        ));
+
+        // The hub hands the new merge bitstamp's cached book, which is already stale
+        bitstamp_send.send(Ok(quiet)).unwrap();
+        let summary = merged.next().await.unwrap();
+        assert!(summary.bids.is_empty());
+        let bitstamp_status = &summary.venues[0];
+        assert_eq!(bitstamp_status.exchange, "bitstamp");
+        assert!(bitstamp_status.stale);
+        assert_eq!(bitstamp_status.age_ms, 60_000);
+
+        binance_send
+            .send(Ok(VenueBook {
+                received: Some(Instant::now()),
+                ..book("binance", 1.0, 2.0)
+            }))
+            .unwrap();
+        let summary = merged.next().await.unwrap();
+        assert!(summary.bids.iter().all(|level| level.exchange == "binance"));
+    }
+}
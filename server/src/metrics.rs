@@ -0,0 +1,256 @@
+//! Prometheus metrics, served over HTTP at `/metrics`
+use std::{convert::Infallible, sync::LazyLock};
+
+use anyhow::Result;
+use bitstamp::model::CurrencyPair;
+use hyper::{
+    server::conn::AddrIncoming,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use prometheus::{
+    exponential_buckets, Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use tokio::net::TcpListener;
+
+use crate::api::Summary;
+
+/// Every metric the server keeps
+pub struct Metrics {
+    registry: Registry,
+    /// Books we've had from each venue, by venue and instrument
+    pub venue_messages: IntCounterVec,
+    /// Messages from a venue we couldn't make sense of, by venue and instrument
+    pub parse_errors: IntCounterVec,
+    /// Errors from a venue, by venue, instrument and kind
+    pub venue_errors: IntCounterVec,
+    /// Successful reconnects to a venue, by venue and instrument
+    pub reconnects: IntCounterVec,
+    /// From a venue's book arriving to the merged summary with it in being emitted, by venue
+    pub upstream_to_emit: HistogramVec,
+    /// Open `BookSummary` and `BookUpdates` streams, by rpc
+    pub active_streams: IntGaugeVec,
+    /// Summaries and updates sent, by client
+    pub summaries_sent: IntCounterVec,
+    /// The latest merged book, by instrument
+    pub spread: GaugeVec,
+    pub best_bid: GaugeVec,
+    pub best_ask: GaugeVec,
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+fn counter(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    let counter = IntCounterVec::new(Opts::new(name, help), labels).expect("valid metric");
+    registry
+        .register(Box::new(counter.clone()))
+        .expect("unique metric");
+    counter
+}
+
+fn gauge(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> GaugeVec {
+    let gauge = GaugeVec::new(Opts::new(name, help), labels).expect("valid metric");
+    registry
+        .register(Box::new(gauge.clone()))
+        .expect("unique metric");
+    gauge
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new_custom(Some("orderbook".to_string()), None).unwrap();
+        let upstream_to_emit = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_to_emit_seconds",
+                "Time from a venue's book arriving to the merged summary with it being emitted",
+            )
+            // 100µs to about 3s
+            .buckets(exponential_buckets(0.0001, 2.0, 16).unwrap()),
+            &["venue"],
+        )
+        .expect("valid metric");
+        registry
+            .register(Box::new(upstream_to_emit.clone()))
+            .expect("unique metric");
+        let active_streams = IntGaugeVec::new(
+            Opts::new("active_streams", "Open BookSummary and BookUpdates streams"),
+            &["rpc"],
+        )
+        .expect("valid metric");
+        registry
+            .register(Box::new(active_streams.clone()))
+            .expect("unique metric");
+        Metrics {
+            venue_messages: counter(
+                &registry,
+                "venue_messages_total",
+                "Books received from each venue",
+                &["venue", "instrument"],
+            ),
+            parse_errors: counter(
+                &registry,
+                "venue_parse_errors_total",
+                "Messages from a venue that couldn't be parsed",
+                &["venue", "instrument"],
+            ),
+            venue_errors: counter(
+                &registry,
+                "venue_errors_total",
+                "Errors from each venue, by whether they're transient, fatal or about the data",
+                &["venue", "instrument", "kind"],
+            ),
+            reconnects: counter(
+                &registry,
+                "venue_reconnects_total",
+                "Successful reconnects to a venue",
+                &["venue", "instrument"],
+            ),
+            upstream_to_emit,
+            active_streams,
+            summaries_sent: counter(
+                &registry,
+                "summaries_sent_total",
+                "Summaries and updates sent to each client",
+                &["client"],
+            ),
+            spread: gauge(
+                &registry,
+                "spread",
+                "The latest merged book's spread",
+                &["instrument"],
+            ),
+            best_bid: gauge(
+                &registry,
+                "best_bid",
+                "The latest merged book's best bid",
+                &["instrument"],
+            ),
+            best_ask: gauge(
+                &registry,
+                "best_ask",
+                "The latest merged book's best ask",
+                &["instrument"],
+            ),
+            registry,
+        }
+    }
+
+    /// Update the book gauges from a merged summary of `instrument`
+    pub fn book(&self, instrument: CurrencyPair, summary: &Summary) {
+        let instrument = instrument.to_string();
+        let labels = [instrument.as_str()];
+        if let (Some(bid), Some(ask)) = (summary.bids.first(), summary.asks.first()) {
+            self.spread.with_label_values(&labels).set(summary.spread);
+            self.best_bid.with_label_values(&labels).set(bid.price);
+            self.best_ask.with_label_values(&labels).set(ask.price);
+        }
+    }
+
+    /// Count a stream as open until the guard is dropped
+    pub fn open_stream(&self, rpc: &str) -> OpenStream {
+        let gauge = self.active_streams.with_label_values(&[rpc]);
+        gauge.inc();
+        OpenStream(gauge)
+    }
+
+    /// Everything, in Prometheus' text format
+    pub fn render(&self) -> String {
+        let mut text = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut text)
+            .expect("text encoding");
+        String::from_utf8(text).expect("utf8 metrics")
+    }
+}
+
+/// Keeps a stream counted in `active_streams`
+pub struct OpenStream(IntGauge);
+
+impl Drop for OpenStream {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+async fn respond(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    Ok(match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(
+                hyper::header::CONTENT_TYPE,
+                TextEncoder::new().format_type(),
+            )
+            .body(Body::from(METRICS.render()))
+            .unwrap(),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap(),
+    })
+}
+
+/// Serve `/metrics` over HTTP on `listener`
+pub async fn serve_metrics(listener: TcpListener) -> Result<()> {
+    log::info!("Metrics at http://{}/metrics", listener.local_addr()?);
+    let make_service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(respond)) });
+    Server::builder(AddrIncoming::from_listener(listener)?)
+        .serve(make_service)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod unit_test {
+    use bitstamp::model::CurrencyPair;
+    use hyper::{body::to_bytes, Client, StatusCode};
+    use tokio::net::TcpListener;
+
+    use super::{serve_metrics, METRICS};
+    use crate::api::{Level, Summary};
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let level = |price| Level {
+            exchange: "binance".to_string(),
+            price,
+            amount: 1.0,
+            ..Level::default()
+        };
+        let summary = Summary {
+            spread: 0.5,
+            bids: vec![level(10.0)],
+            asks: vec![level(10.5)],
+            ..Summary::default()
+        };
+        METRICS.book(CurrencyPair::Ltcbtc, &summary);
+        let open = METRICS.open_stream("test_metrics");
+        METRICS
+            .venue_messages
+            .with_label_values(&["binance", "ltcbtc"])
+            .inc();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_metrics(listener));
+        let client = Client::new();
+        let get = |path: &str| client.get(format!("http://{addr}{path}").parse().unwrap());
+        let response = get("/metrics").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let text = String::from_utf8(to_bytes(response).await.unwrap().to_vec()).unwrap();
+        for line in [
+            "orderbook_spread{instrument=\"ltcbtc\"} 0.5",
+            "orderbook_best_bid{instrument=\"ltcbtc\"} 10",
+            "orderbook_best_ask{instrument=\"ltcbtc\"} 10.5",
+            "orderbook_active_streams{rpc=\"test_metrics\"} 1",
+            "orderbook_venue_messages_total{instrument=\"ltcbtc\",venue=\"binance\"} 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{line} isn't in\n{text}");
+        }
+        drop(open);
+        let text = METRICS.render();
+        assert!(text.contains("orderbook_active_streams{rpc=\"test_metrics\"} 0"));
+
+        let response = get("/nothing").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
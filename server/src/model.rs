@@ -1,6 +1,14 @@
-use std::cmp::Ordering;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+};
 
-use crate::api::Level;
+use chrono::{DateTime, Utc};
+use parse_display::Display;
+use rust_decimal::Decimal;
+use tokio::time::Instant;
+
+use crate::api::{Level, Side, VenueContribution};
 
 impl From<binance::model::Price> for Level {
     fn from(input: binance::model::Price) -> Self {
@@ -9,6 +17,7 @@ impl From<binance::model::Price> for Level {
             // TODO: rename amount and quantitiy in binance to reduce confusion
             price: input.amount,
             amount: input.quantity,
+            contributions: vec![],
         }
     }
 }
@@ -19,52 +28,384 @@ impl From<bitstamp::model::Price> for Level {
             exchange: "bitstamp".to_string(),
             price: input.price,
             amount: input.quantity,
+            contributions: vec![],
+        }
+    }
+}
+
+impl From<bybit::model::Price> for Level {
+    fn from(input: bybit::model::Price) -> Self {
+        Level {
+            exchange: "bybit".to_string(),
+            price: input.price,
+            amount: input.quantity,
+            contributions: vec![],
+        }
+    }
+}
+
+impl From<bitfinex::model::Price> for Level {
+    fn from(input: bitfinex::model::Price) -> Self {
+        Level {
+            exchange: "bitfinex".to_string(),
+            price: input.price,
+            amount: input.quantity,
+            contributions: vec![],
+        }
+    }
+}
+
+impl From<htx::model::Price> for Level {
+    fn from(input: htx::model::Price) -> Self {
+        Level {
+            exchange: "htx".to_string(),
+            price: input.price,
+            amount: input.quantity,
+            contributions: vec![],
+        }
+    }
+}
+
+/// One venue's book, converted into `Level`s, ready to be merged with the other venues
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VenueBook {
+    pub bids: Vec<Level>,
+    pub asks: Vec<Level>,
+    /// When we got it from the venue, as an instant on the clock it's timed by, for its age and the
+    /// latency metrics
+    pub received: Option<Instant>,
+    /// Its number on the venue connection, counting from 1, for tracing. 0 if it's not known
+    pub sequence: u64,
+    /// When the venue says the book happened, if it does
+    pub exchange_time: Option<DateTime<Utc>>,
+    /// When we got it, by the wall clock, for clients measuring latency
+    pub received_time: Option<DateTime<Utc>>,
+}
+
+/// `time` in Unix microseconds, or 0 if there isn't one, as the api has it
+pub fn unix_us(time: Option<DateTime<Utc>>) -> i64 {
+    time.map_or(0, |time| {
+        time.timestamp() * 1_000_000 + i64::from(time.timestamp_subsec_micros())
+    })
+}
+
+/// The time now, in Unix microseconds
+pub fn now_us() -> i64 {
+    unix_us(Some(Utc::now()))
+}
+
+impl From<binance::model::Depth> for VenueBook {
+    fn from(input: binance::model::Depth) -> Self {
+        VenueBook {
+            bids: input.bids.into_iter().map(Level::from).collect(),
+            asks: input.asks.into_iter().map(Level::from).collect(),
+            received: None,
+            sequence: 0,
+            exchange_time: None,
+            received_time: None,
         }
     }
 }
 
-/// Takes the two order_books from our two client libraries and make a new order_book, ready to serve
+/// Bitstamp's detail book lists each order, so there can be several at a price. Everything
+/// downstream keys a level by its exchange and price, so they're summed into one level per price,
+/// in the order the prices first came
+fn per_price(orders: Vec<bitstamp::model::Price>) -> Vec<Level> {
+    let mut levels: Vec<Level> = vec![];
+    let mut at: HashMap<u64, usize> = HashMap::new();
+    for order in orders {
+        match at.get(&order.price.to_bits()) {
+            Some(&i) => levels[i].amount += order.quantity,
+            None => {
+                at.insert(order.price.to_bits(), levels.len());
+                levels.push(order.into());
+            }
+        }
+    }
+    levels
+}
+
+impl From<bitstamp::model::OrderBookData> for VenueBook {
+    fn from(input: bitstamp::model::OrderBookData) -> Self {
+        VenueBook {
+            bids: per_price(input.bids),
+            asks: per_price(input.asks),
+            received: None,
+            sequence: 0,
+            exchange_time: Some(input.timestamp),
+            received_time: None,
+        }
+    }
+}
+
+impl From<bybit::model::OrderBook> for VenueBook {
+    fn from(input: bybit::model::OrderBook) -> Self {
+        VenueBook {
+            bids: input.bids.into_iter().map(Level::from).collect(),
+            asks: input.asks.into_iter().map(Level::from).collect(),
+            received: None,
+            sequence: 0,
+            exchange_time: Some(input.timestamp),
+            received_time: None,
+        }
+    }
+}
+
+impl From<bitfinex::model::Book> for VenueBook {
+    fn from(input: bitfinex::model::Book) -> Self {
+        VenueBook {
+            bids: input.bids.into_iter().map(Level::from).collect(),
+            asks: input.asks.into_iter().map(Level::from).collect(),
+            received: None,
+            sequence: 0,
+            exchange_time: None,
+            received_time: None,
+        }
+    }
+}
+
+impl From<htx::model::Book> for VenueBook {
+    fn from(input: htx::model::Book) -> Self {
+        VenueBook {
+            bids: input.bids.into_iter().map(Level::from).collect(),
+            asks: input.asks.into_iter().map(Level::from).collect(),
+            received: None,
+            sequence: 0,
+            exchange_time: Some(input.timestamp),
+            received_time: None,
+        }
+    }
+}
+
+/// Which side of a trade took liquidity
+#[derive(Display, Debug, Clone, Copy, PartialEq, Eq)]
+#[display(style = "snake_case")]
+pub enum TakerSide {
+    Buy,
+    Sell,
+}
+
+/// One trade from a venue
+#[derive(Debug, Clone, PartialEq)]
+pub struct VenueTrade {
+    /// The venue's id for it
+    pub id: u64,
+    pub price: f64,
+    pub amount: f64,
+    pub taker: TakerSide,
+    /// When the venue says it happened, if it does
+    pub exchange_time: Option<DateTime<Utc>>,
+    /// When we got it, by the wall clock
+    pub received_time: Option<DateTime<Utc>>,
+}
+
+impl From<binance::model::Trade> for VenueTrade {
+    fn from(input: binance::model::Trade) -> Self {
+        VenueTrade {
+            id: input.id,
+            price: input.price,
+            amount: input.quantity,
+            // The maker's order was resting, so the other side took it
+            taker: match input.buyer_is_maker {
+                true => TakerSide::Sell,
+                false => TakerSide::Buy,
+            },
+            exchange_time: Some(input.timestamp),
+            received_time: None,
+        }
+    }
+}
+
+impl From<bitstamp::model::TradeData> for VenueTrade {
+    fn from(input: bitstamp::model::TradeData) -> Self {
+        VenueTrade {
+            id: input.id,
+            price: input.price,
+            amount: input.amount,
+            taker: match input.side {
+                bitstamp::model::Side::Buy => TakerSide::Buy,
+                bitstamp::model::Side::Sell => TakerSide::Sell,
+            },
+            exchange_time: Some(input.timestamp),
+            received_time: None,
+        }
+    }
+}
+
+/// Takes the order_books from our client libraries and make a new order_book, ready to serve,
+/// with the best `depth` levels on each side. With a `tick`, each venue's levels are grouped by it
+/// first
 pub fn make_merged_market_depth(
-    a: binance::model::Depth,
-    b: bitstamp::model::OrderBookData,
+    books: impl IntoIterator<Item = VenueBook>,
+    depth: usize,
+    tick: Option<Decimal>,
 ) -> crate::api::Summary {
-    // Get the top 10 (highest) bids
-    let mut bids: Vec<Level> = a
-        .bids
-        .into_iter()
-        .map(|price| price.into())
-        .chain(b.bids.into_iter().map(|price| price.into()))
-        .collect();
+    let (mut bids, mut asks): (Vec<Level>, Vec<Level>) = (vec![], vec![]);
+    for book in books {
+        match tick {
+            Some(tick) => {
+                bids.extend(group(book.bids, Side::Bid, tick));
+                asks.extend(group(book.asks, Side::Ask, tick));
+            }
+            None => {
+                bids.extend(book.bids);
+                asks.extend(book.asks);
+            }
+        }
+    }
+    // Get the top (highest) bids
     bids.sort_by(|a, b| b.price.partial_cmp(&a.price).unwrap_or(Ordering::Equal));
-    bids.truncate(10);
-    // Get the 10 best (lowest) asks
-    let mut asks: Vec<Level> = a
-        .asks
-        .into_iter()
-        .map(|price| price.into())
-        .chain(b.asks.into_iter().map(|price| price.into()))
-        .collect();
+    bids.truncate(depth);
+    // Get the best (lowest) asks
     asks.sort_by(|a, b| a.price.partial_cmp(&b.price).unwrap_or(Ordering::Equal));
-    asks.truncate(10);
+    asks.truncate(depth);
 
-    // Get the spread
-    let spread = bids
-        .first()
+    crate::api::Summary {
+        spread: spread(&bids, &asks),
+        bids,
+        asks,
+        ..Default::default()
+    }
+}
+
+/// The best bid less the best ask, or 0 if either side is empty
+fn spread(bids: &[Level], asks: &[Level]) -> f64 {
+    bids.first()
         .zip(asks.first())
         .map(|(bid, ask)| bid.price - ask.price)
-        .unwrap_or(0.0);
+        .unwrap_or(0.0)
+}
+
+/// `price` as the decimal the venue sent. The venues' prices are decimal strings, and any with up
+/// to 15 significant figures parses to a double whose shortest form, as `Display` writes it, is the
+/// same decimal, so for them this is exact. A longer price is off by at most the double's error,
+/// and one a `Decimal` can't hold is None
+fn decimal(price: f64) -> Option<Decimal> {
+    Decimal::from_str_exact(&price.to_string()).ok()
+}
+
+/// `price` as the nearest double, or None if it's out of range
+fn double(price: Decimal) -> Option<f64> {
+    price
+        .to_string()
+        .parse()
+        .ok()
+        .filter(|price: &f64| price.is_finite())
+}
+
+/// The price `price` is combined at: itself, or with a `tick`, rounded to it away from the other
+/// side of the book. Worked out in decimal from the venue's price, so eg. 0.3 is in the 0.1 tick
+/// 0.3, not 0.2
+fn bucket(price: f64, side: Side, tick: Option<Decimal>) -> Option<Decimal> {
+    let price = decimal(price)?;
+    let Some(tick) = tick else {
+        return Some(price);
+    };
+    let ticks = price.checked_div(tick)?;
+    let ticks = match side {
+        Side::Bid => ticks.floor(),
+        Side::Ask => ticks.ceil(),
+    };
+    ticks.checked_mul(tick)
+}
+
+/// Combine each venue's `levels` into one level per `tick`, rounded the same way as consolidated
+/// ones, in no particular order
+fn group(levels: Vec<Level>, side: Side, tick: Decimal) -> Vec<Level> {
+    let mut buckets: HashMap<(Decimal, String), f64> = HashMap::new();
+    for level in levels {
+        match bucket(level.price, side, Some(tick)) {
+            Some(price) => *buckets.entry((price, level.exchange)).or_default() += level.amount,
+            None => log::warn!("Left out a {} level at {}", level.exchange, level.price),
+        }
+    }
+    buckets
+        .into_iter()
+        .filter_map(|((bucket, exchange), amount)| {
+            let Some(price) = double(bucket) else {
+                log::warn!("Left out a {exchange} level at {bucket}");
+                return None;
+            };
+            Some(Level {
+                exchange,
+                price,
+                amount,
+                contributions: vec![],
+            })
+        })
+        .collect()
+}
+
+/// Combine `levels` from every venue into one level per price, or per `tick`, best first. Each
+/// level says how much of it is at each venue, by exchange name
+pub fn consolidate(levels: Vec<Level>, side: Side, tick: Option<Decimal>) -> Vec<Level> {
+    let mut buckets: BTreeMap<Decimal, HashMap<String, f64>> = BTreeMap::new();
+    for level in levels {
+        match bucket(level.price, side, tick) {
+            Some(price) => {
+                let venues = buckets.entry(price).or_default();
+                *venues.entry(level.exchange).or_default() += level.amount;
+            }
+            None => log::warn!("Left out a {} level at {}", level.exchange, level.price),
+        }
+    }
+    let consolidated = buckets.into_iter().filter_map(|(bucket, venues)| {
+        let Some(price) = double(bucket) else {
+            log::warn!("Left out a consolidated level at {bucket}");
+            return None;
+        };
+        let mut contributions: Vec<_> = venues
+            .into_iter()
+            .map(|(exchange, amount)| VenueContribution { exchange, amount })
+            .collect();
+        contributions.sort_by(|a, b| a.exchange.cmp(&b.exchange));
+        Some(Level {
+            exchange: String::new(),
+            price,
+            amount: contributions.iter().map(|venue| venue.amount).sum(),
+            contributions,
+        })
+    });
+    match side {
+        Side::Bid => consolidated.rev().collect(),
+        Side::Ask => consolidated.collect(),
+    }
+}
 
-    crate::api::Summary { spread, bids, asks }
+/// Like `make_merged_market_depth`, but with the venues' levels consolidated by price, or by
+/// `tick`, before taking the best `depth`
+pub fn make_consolidated_market_depth(
+    books: impl IntoIterator<Item = VenueBook>,
+    depth: usize,
+    tick: Option<Decimal>,
+) -> crate::api::Summary {
+    let (mut bids, mut asks): (Vec<Level>, Vec<Level>) = (vec![], vec![]);
+    for book in books {
+        bids.extend(book.bids);
+        asks.extend(book.asks);
+    }
+    let mut bids = consolidate(bids, Side::Bid, tick);
+    bids.truncate(depth);
+    let mut asks = consolidate(asks, Side::Ask, tick);
+    asks.truncate(depth);
+    crate::api::Summary {
+        spread: spread(&bids, &asks),
+        bids,
+        asks,
+        ..Default::default()
+    }
 }
 
 #[cfg(test)]
 mod unit_test {
-    use crate::api::{Level, Summary};
+    use super::VenueBook;
+    use crate::api::{Level, Side, Summary, VenueContribution};
     use binance::model::{Depth, Price};
     use bitstamp::{model::Price as BitPrice, OrderBookData};
     use chrono::Utc;
     use ordered_float::OrderedFloat;
     use pretty_assertions::assert_eq;
+    use rust_decimal::Decimal;
 
     #[test]
     fn test_make_merged_market_depth() {
@@ -145,7 +486,7 @@ mod unit_test {
             .collect(),
         };
 
-        let got = super::make_merged_market_depth(binance, bitstamp);
+        let got = super::make_merged_market_depth([binance.into(), bitstamp.into()], 10, None);
 
         let expected = Summary {
             spread: 4.200000000000037e-5,
@@ -154,51 +495,61 @@ mod unit_test {
                     exchange: "bitstamp".to_string(),
                     price: 0.06712,
                     amount: 1.0,
+                    ..Level::default()
                 },
                 Level {
                     exchange: "bitstamp".to_string(),
                     price: 0.06709444,
                     amount: 1.0,
+                    ..Level::default()
                 },
                 Level {
                     exchange: "bitstamp".to_string(),
                     price: 0.06709423,
                     amount: 1.0,
+                    ..Level::default()
                 },
                 Level {
                     exchange: "bitstamp".to_string(),
                     price: 0.06709148,
                     amount: 1.0,
+                    ..Level::default()
                 },
                 Level {
                     exchange: "bitstamp".to_string(),
                     price: 0.06708276,
                     amount: 1.0,
+                    ..Level::default()
                 },
                 Level {
                     exchange: "binance".to_string(),
                     price: 0.067077,
                     amount: 1.0,
+                    ..Level::default()
                 },
                 Level {
                     exchange: "binance".to_string(),
                     price: 0.067076,
                     amount: 1.0,
+                    ..Level::default()
                 },
                 Level {
                     exchange: "binance".to_string(),
                     price: 0.067075,
                     amount: 1.0,
+                    ..Level::default()
                 },
                 Level {
                     exchange: "binance".to_string(),
                     price: 0.067074,
                     amount: 1.0,
+                    ..Level::default()
                 },
                 Level {
                     exchange: "binance".to_string(),
                     price: 0.067072,
                     amount: 1.0,
+                    ..Level::default()
                 },
             ],
             asks: vec![
@@ -206,65 +557,76 @@ mod unit_test {
                     exchange: "binance".to_string(),
                     price: 0.067078,
                     amount: 1.0,
+                    ..Level::default()
                 },
                 Level {
                     exchange: "binance".to_string(),
                     price: 0.067088,
                     amount: 1.0,
+                    ..Level::default()
                 },
                 Level {
                     exchange: "binance".to_string(),
                     price: 0.06709,
                     amount: 1.0,
+                    ..Level::default()
                 },
                 Level {
                     exchange: "binance".to_string(),
                     price: 0.067091,
                     amount: 1.0,
+                    ..Level::default()
                 },
                 Level {
                     exchange: "binance".to_string(),
                     price: 0.067094,
                     amount: 1.0,
+                    ..Level::default()
                 },
                 Level {
                     exchange: "binance".to_string(),
                     price: 0.067097,
                     amount: 1.0,
+                    ..Level::default()
                 },
                 Level {
                     exchange: "binance".to_string(),
                     price: 0.067098,
                     amount: 1.0,
+                    ..Level::default()
                 },
                 Level {
                     exchange: "binance".to_string(),
                     price: 0.067099,
                     amount: 1.0,
+                    ..Level::default()
                 },
                 Level {
                     exchange: "binance".to_string(),
                     price: 0.0671,
                     amount: 1.0,
+                    ..Level::default()
                 },
                 Level {
                     exchange: "binance".to_string(),
                     price: 0.067101,
                     amount: 1.0,
+                    ..Level::default()
                 },
             ],
+            ..Summary::default()
         };
         assert_eq!(got, expected);
 
         // Also make sure expected is sorted
         // Asks should have the smallest value first
         let mut sorted = expected.asks.clone();
-        sorted.sort_by(|a, b| OrderedFloat(a.price).cmp(&OrderedFloat(b.price)));
+        sorted.sort_by_key(|level| OrderedFloat(level.price));
         assert_eq!(sorted, expected.asks);
 
         // Bids should be sorted with the largest value first
         let mut sorted = expected.bids.clone();
-        sorted.sort_by(|a, b| OrderedFloat(a.price).cmp(&OrderedFloat(b.price)));
+        sorted.sort_by_key(|level| OrderedFloat(level.price));
         sorted.reverse();
         assert_eq!(sorted, expected.bids);
 
@@ -284,4 +646,126 @@ mod unit_test {
         let expected_spread = highest_bid - lowest_ask;
         assert_eq!(got.spread, expected_spread.into_inner());
     }
+
+    #[test]
+    fn test_consolidate() {
+        let level = |exchange: &str, price, amount| Level {
+            exchange: exchange.to_string(),
+            price,
+            amount,
+            ..Level::default()
+        };
+        let contribution = |exchange: &str, amount| VenueContribution {
+            exchange: exchange.to_string(),
+            amount,
+        };
+        let bids = vec![
+            level("bitstamp", 0.3, 1.0),
+            level("binance", 0.3, 2.0),
+            level("binance", 0.31, 4.0),
+            level("binance", 0.29, 8.0),
+        ];
+
+        // Only the same price is combined, best first
+        let got = super::consolidate(bids.clone(), Side::Bid, None);
+        let prices: Vec<f64> = got.iter().map(|level| level.price).collect();
+        assert_eq!(prices, vec![0.31, 0.3, 0.29]);
+        assert_eq!(got[1].exchange, "");
+        assert_eq!(got[1].amount, 3.0);
+        assert_eq!(
+            got[1].contributions,
+            vec![contribution("binance", 2.0), contribution("bitstamp", 1.0)]
+        );
+
+        // Bids are rounded down to the tick, so 0.3 stays in its own 0.1 tick
+        let tick = Some(Decimal::new(1, 1));
+        let got = super::consolidate(bids.clone(), Side::Bid, tick);
+        let prices: Vec<f64> = got.iter().map(|level| level.price).collect();
+        assert_eq!(prices, vec![0.3, 0.2]);
+        assert_eq!(got[0].amount, 7.0);
+        assert_eq!(
+            got[0].contributions,
+            vec![contribution("binance", 6.0), contribution("bitstamp", 1.0)]
+        );
+
+        // and asks up
+        let got = super::consolidate(bids, Side::Ask, tick);
+        let prices: Vec<f64> = got.iter().map(|level| level.price).collect();
+        assert_eq!(prices, vec![0.3, 0.4]);
+        assert_eq!(got[0].amount, 11.0);
+
+        // A price too small for a decimal is left out, rather than put somewhere else
+        let asks = vec![level("binance", 1e-30, 1.0), level("binance", 0.3, 2.0)];
+        let got = super::consolidate(asks, Side::Ask, None);
+        let prices: Vec<f64> = got.iter().map(|level| level.price).collect();
+        assert_eq!(prices, vec![0.3]);
+    }
+
+    #[test]
+    fn test_decimal() {
+        // The venue's decimal, however it came out as a double
+        assert_eq!(super::decimal(0.067071), Some(Decimal::new(67071, 6)));
+        assert_eq!(
+            super::decimal(0.1 + 0.2),
+            Some("0.30000000000000004".parse().unwrap())
+        );
+        assert_eq!(super::decimal(f64::NAN), None);
+        assert_eq!(super::double(Decimal::new(67071, 6)), Some(0.067071));
+    }
+
+    #[test]
+    fn test_grouped_by_venue() {
+        let level = |exchange: &str, price, amount| Level {
+            exchange: exchange.to_string(),
+            price,
+            amount,
+            ..Level::default()
+        };
+        // Binance quotes to 6 places and bitstamp to 8
+        let binance = VenueBook {
+            bids: vec![
+                level("binance", 0.067077, 1.0),
+                level("binance", 0.067071, 2.0),
+            ],
+            asks: vec![
+                level("binance", 0.06708, 1.0),
+                level("binance", 0.067081, 2.0),
+            ],
+            ..VenueBook::default()
+        };
+        let bitstamp = VenueBook {
+            bids: vec![
+                level("bitstamp", 0.06707999, 4.0),
+                level("bitstamp", 0.06706, 8.0),
+            ],
+            asks: vec![level("bitstamp", 0.06708001, 4.0)],
+            ..VenueBook::default()
+        };
+        let got = super::make_merged_market_depth([binance, bitstamp], 2, Some(Decimal::new(1, 5)));
+        let levels = |levels: &[Level]| -> Vec<(String, f64, f64)> {
+            levels
+                .iter()
+                .map(|level| (level.exchange.clone(), level.price, level.amount))
+                .collect()
+        };
+        // Bids down to the tick, and a price already on one stays there
+        let mut bids = levels(&got.bids);
+        bids.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            bids,
+            vec![
+                ("binance".to_string(), 0.06707, 3.0),
+                ("bitstamp".to_string(), 0.06707, 4.0),
+            ]
+        );
+        // asks up to it, and the top `depth` grouped levels are kept
+        assert_eq!(
+            levels(&got.asks),
+            vec![
+                ("binance".to_string(), 0.06708, 1.0),
+                ("binance".to_string(), 0.06709, 2.0),
+            ]
+        );
+        assert_eq!(got.spread, 0.06707 - 0.06708);
+    }
 }
@@ -0,0 +1,320 @@
+//! Records every text and binary frame the venues send us, before we parse it, so a bad merge can
+//! be looked into after the fact. Frames go to zstd-compressed NDJSON capture files, one frame per
+//! line, and a new file is started once the current one is big or old enough. A file is named for
+//! when it was started, and only gets its final name once it's complete
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bitstamp::model::CurrencyPair;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tap::Tap;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::{model::now_us, venue::Venue};
+
+/// What capture files' names end with
+pub const EXTENSION: &str = "ndjson.zst";
+/// Added to a capture file's name while it's being written
+const PARTIAL: &str = "partial";
+/// A balance between speed and size; zstd's own default
+const ZSTD_LEVEL: i32 = 3;
+
+/// Where to record to, and when to start a new file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordSettings {
+    pub dir: PathBuf,
+    /// Uncompressed
+    pub rotate_bytes: u64,
+    pub rotate_after: Duration,
+}
+
+/// One line of a capture file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// When we received it, in Unix microseconds
+    pub received_us: i64,
+    /// Which connection it came in on. Each connection the recorder sees, reconnects included,
+    /// gets the next number, starting from 1
+    pub connection: u64,
+    pub venue: String,
+    pub instrument: String,
+    #[serde(flatten)]
+    pub data: FrameData,
+}
+
+/// A frame's contents
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FrameData {
+    Text(String),
+    /// In base64, eg. HTX's gzipped json
+    Binary(String),
+}
+
+impl FrameData {
+    /// The contents of a text or binary message; None for control frames
+    pub fn from_message(message: &Message) -> Option<FrameData> {
+        match message {
+            Message::Text(text) => Some(FrameData::Text(text.clone())),
+            Message::Binary(data) => Some(FrameData::Binary(BASE64.encode(data))),
+            _ => None,
+        }
+    }
+
+    /// Back to the message it was recorded from
+    pub fn to_message(&self) -> Result<Message, base64::DecodeError> {
+        Ok(match self {
+            FrameData::Text(text) => Message::Text(text.clone()),
+            FrameData::Binary(data) => Message::Binary(BASE64.decode(data)?),
+        })
+    }
+}
+
+/// Hands frames to the thread writing them out. Clones share it
+#[derive(Clone)]
+pub struct Recorder {
+    /// None once we've stopped
+    frames: Arc<Mutex<Option<Sender<Frame>>>>,
+    writer: Arc<Mutex<Option<JoinHandle<()>>>>,
+    connections: Arc<AtomicU64>,
+}
+
+impl Recorder {
+    /// Start writing capture files to `settings.dir`, creating it if need be
+    pub fn start(settings: RecordSettings) -> io::Result<Recorder> {
+        fs::create_dir_all(&settings.dir)?;
+        log::info!("Recording venue frames to {:?}", settings.dir);
+        let (sender, receiver) = channel();
+        let writer = std::thread::Builder::new()
+            .name("recorder".to_string())
+            .spawn(move || write_frames(&settings, receiver))?;
+        Ok(Recorder {
+            frames: Arc::new(Mutex::new(Some(sender))),
+            writer: Arc::new(Mutex::new(Some(writer))),
+            connections: Arc::default(),
+        })
+    }
+
+    /// A tap for a new connection to `venue` for `instrument`
+    pub fn tap(&self, venue: Venue, instrument: CurrencyPair) -> Tap {
+        let connection = self.connections.fetch_add(1, Ordering::Relaxed) + 1;
+        let recorder = self.clone();
+        let (venue, instrument) = (venue.to_string(), instrument.to_string());
+        Arc::new(move |message| {
+            if let Some(data) = FrameData::from_message(message) {
+                recorder.record(Frame {
+                    received_us: now_us(),
+                    connection,
+                    venue: venue.clone(),
+                    instrument: instrument.clone(),
+                    data,
+                });
+            }
+        })
+    }
+
+    fn record(&self, frame: Frame) {
+        if let Some(frames) = self.frames.lock().unwrap().as_ref() {
+            // The writer only goes once we've stopped
+            frames.send(frame).ok();
+        }
+    }
+
+    /// Stop recording, and wait for everything recorded so far to be written. Frames after this
+    /// are dropped. Blocks, so call it from somewhere that can
+    pub fn stop(&self) {
+        self.frames.lock().unwrap().take();
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            if writer.join().is_err() {
+                log::error!("The recorder's writer panicked");
+            }
+        }
+    }
+}
+
+/// The capture file being written
+struct Capture {
+    path: PathBuf,
+    encoder: zstd::Encoder<'static, BufWriter<File>>,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Capture {
+    fn create(dir: &Path) -> io::Result<Capture> {
+        let name = Utc::now().format("capture-%Y%m%dT%H%M%S%.6fZ");
+        let path = dir.join(format!("{name}.{EXTENSION}"));
+        let file = File::create(path.with_extension(format!("zst.{PARTIAL}")))?;
+        log::info!("Recording to {path:?}");
+        Ok(Capture {
+            path,
+            encoder: zstd::Encoder::new(BufWriter::new(file), ZSTD_LEVEL)?,
+            started: Instant::now(),
+            bytes: 0,
+        })
+    }
+
+    fn write(&mut self, frame: &Frame) -> io::Result<()> {
+        let mut line = serde_json::to_vec(frame)?;
+        line.push(b'\n');
+        self.encoder.write_all(&line)?;
+        self.bytes += line.len() as u64;
+        Ok(())
+    }
+
+    /// Finish the file off, and give it its final name
+    fn finish(self) -> io::Result<PathBuf> {
+        let partial = self.path.with_extension(format!("zst.{PARTIAL}"));
+        self.encoder.finish()?.into_inner()?.sync_all()?;
+        fs::rename(partial, &self.path)?;
+        Ok(self.path)
+    }
+}
+
+fn finish(capture: Capture) {
+    match capture.finish() {
+        Ok(path) => log::info!("Finished {path:?}"),
+        Err(err) => log::error!("Unable to finish a capture file: {err}"),
+    }
+}
+
+/// Write frames until the recorder stops, starting a new file whenever the current one is full
+fn write_frames(settings: &RecordSettings, frames: Receiver<Frame>) {
+    let mut current: Option<Capture> = None;
+    for frame in frames {
+        let full = current.as_ref().is_some_and(|capture| {
+            capture.bytes >= settings.rotate_bytes
+                || capture.started.elapsed() >= settings.rotate_after
+        });
+        if let Some(capture) = current.take_if(|_| full) {
+            finish(capture);
+        }
+        let capture = match &mut current {
+            Some(capture) => capture,
+            None => match Capture::create(&settings.dir) {
+                Ok(capture) => current.insert(capture),
+                Err(err) => {
+                    log::error!(
+                        "Unable to start a capture file in {:?}: {err}",
+                        settings.dir
+                    );
+                    continue;
+                }
+            },
+        };
+        if let Err(err) = capture.write(&frame) {
+            log::error!("Unable to record a frame to {:?}: {err}", capture.path);
+        }
+    }
+    if let Some(capture) = current {
+        finish(capture);
+    }
+}
+
+/// The complete capture files in `dir`, oldest first
+pub fn captures(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|name| name.to_str());
+        if name.is_some_and(|name| name.ends_with(&format!(".{EXTENSION}"))) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// The frames in a capture file, in the order they were recorded
+pub fn read_capture(path: &Path) -> io::Result<impl Iterator<Item = io::Result<Frame>>> {
+    let lines = BufReader::new(zstd::Decoder::new(File::open(path)?)?).lines();
+    Ok(lines.map(|line| Ok(serde_json::from_str(&line?)?)))
+}
+
+#[cfg(test)]
+mod unit_test {
+    use std::{path::PathBuf, time::Duration};
+
+    use bitstamp::model::CurrencyPair;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use super::{captures, read_capture, Frame, FrameData, RecordSettings, Recorder};
+    use crate::venue::Venue;
+
+    /// An empty directory of our own under the system's temporary one
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("orderbook-{name}-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    #[test]
+    fn test_record() {
+        let dir = temp_dir("record");
+        let recorder = Recorder::start(RecordSettings {
+            dir: dir.clone(),
+            // Two frames to a file
+            rotate_bytes: 150,
+            rotate_after: Duration::from_secs(3600),
+        })
+        .unwrap();
+        let binance = recorder.tap(Venue::Binance, CurrencyPair::Ethbtc);
+        let htx = recorder.tap(Venue::Htx, CurrencyPair::Ethbtc);
+        binance(&Message::Text("{\"lastUpdateId\":1}".to_string()));
+        binance(&Message::Ping(vec![1]));
+        htx(&Message::Binary(vec![0x1f, 0x8b, 0]));
+        binance(&Message::Text("{\"lastUpdateId\":2}".to_string()));
+        recorder.stop();
+        // Stopped, so this goes nowhere
+        binance(&Message::Text("{\"lastUpdateId\":3}".to_string()));
+
+        let files = captures(&dir).unwrap();
+        assert_eq!(files.len(), 2);
+        let frames: Vec<Frame> = files
+            .iter()
+            .flat_map(|path| read_capture(path).unwrap())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let data: Vec<_> = frames
+            .iter()
+            .map(|frame| (frame.connection, frame.venue.as_str(), frame.data.clone()))
+            .collect();
+        assert_eq!(
+            data,
+            vec![
+                (
+                    1,
+                    "binance",
+                    FrameData::Text("{\"lastUpdateId\":1}".to_string())
+                ),
+                (2, "htx", FrameData::Binary("H4sA".to_string())),
+                (
+                    1,
+                    "binance",
+                    FrameData::Text("{\"lastUpdateId\":2}".to_string())
+                ),
+            ]
+        );
+        assert!(frames.iter().all(|frame| frame.instrument == "ethbtc"));
+        assert!(frames
+            .windows(2)
+            .all(|w| w[0].received_us <= w[1].received_us));
+        assert_eq!(
+            frames[1].data.to_message().unwrap(),
+            Message::Binary(vec![0x1f, 0x8b, 0])
+        );
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
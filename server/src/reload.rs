@@ -0,0 +1,223 @@
+//! Reloads the config when the file changes, or when the server gets SIGHUP. Most settings take
+//! effect straight away; the rest are logged as needing a restart
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{
+    config::{Args, Config, Settings},
+    SummaryServer,
+};
+
+/// How often we look to see if the config file has changed
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The settings that have changed but only take effect when the server is restarted
+pub fn needs_restart(running: &Settings, new: &Settings) -> Vec<&'static str> {
+    let mut changed = vec![];
+    if running.listen != new.listen {
+        changed.push("listen");
+    }
+    if running.metrics_listen != new.metrics_listen {
+        changed.push("metrics_listen");
+    }
+    if running.otlp_endpoint != new.otlp_endpoint {
+        changed.push("otlp_endpoint");
+    }
+    if running.compression != new.compression {
+        changed.push("compression");
+    }
+    if running.log != new.log {
+        changed.push("log");
+    }
+    if running.idle_grace != new.idle_grace {
+        changed.push("idle_grace_ms");
+    }
+    if running.shutdown_timeout != new.shutdown_timeout {
+        changed.push("shutdown_timeout_ms");
+    }
+    if running.tls != new.tls {
+        changed.push("tls");
+    }
+    if running.record != new.record {
+        changed.push("record");
+    }
+    if running.history != new.history {
+        changed.push("history");
+    }
+    if running.export != new.export {
+        changed.push("export");
+    }
+    changed
+}
+
+fn read(args: &Args) -> Option<Vec<u8>> {
+    std::fs::read(args.config.as_ref()?).ok()
+}
+
+/// Load the config again and apply it to `server`, returning the settings now running. If the
+/// config is invalid it's logged, and we carry on with what we had
+async fn reload(args: &Args, server: &SummaryServer, running: Settings) -> Settings {
+    let mut new = match Config::load(args).and_then(|config| config.settings()) {
+        Ok(new) => new,
+        Err(err) => {
+            log::error!("Keeping the running config: {err}");
+            return running;
+        }
+    };
+    for setting in needs_restart(&running, &new) {
+        log::warn!("`{setting}` has changed, but won't take effect until the server restarts");
+    }
+    // These are still what the server is running with
+    new.listen = running.listen;
+    new.metrics_listen = running.metrics_listen;
+    new.otlp_endpoint = running.otlp_endpoint.clone();
+    new.compression = running.compression;
+    new.log = running.log.clone();
+    new.idle_grace = running.idle_grace;
+    new.shutdown_timeout = running.shutdown_timeout;
+    new.tls = running.tls.clone();
+    new.record = running.record.clone();
+    new.history = running.history.clone();
+    new.export = running.export.clone();
+    if new == running {
+        log::info!("Config unchanged");
+        return running;
+    }
+    server.reload(&new).await;
+    log::info!("Config reloaded");
+    new
+}
+
+/// Reload the config into `server` whenever the file changes or we get SIGHUP. `running` is what
+/// the server was started with. Only returns if we can't listen for the signal
+pub async fn watch_config(args: Args, server: SummaryServer, mut running: Settings) -> Result<()> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    let mut polls = tokio::time::interval(POLL_INTERVAL);
+    let mut contents = read(&args);
+    loop {
+        tokio::select! {
+            _ = hangups.recv() => log::info!("Got SIGHUP; reloading the config"),
+            _ = polls.tick() => {
+                let latest = read(&args);
+                if latest == contents {
+                    continue;
+                }
+                contents = latest;
+                log::info!("The config file has changed; reloading it");
+            }
+        }
+        running = reload(&args, &server, running).await;
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use std::{io::Write, path::Path, sync::Arc};
+
+    use bitstamp::model::CurrencyPair;
+
+    use super::{needs_restart, reload};
+    use crate::{
+        config::{Args, Config},
+        venue::Venue,
+        SummaryServer,
+    };
+
+    #[test]
+    fn test_needs_restart() {
+        let running = Config::default().settings().unwrap();
+        let mut new = running.clone();
+        new.defaults.depth = 5;
+        assert!(needs_restart(&running, &new).is_empty());
+        new.listen = ([0, 0, 0, 0], 9000).into();
+        new.log = "debug".to_string();
+        assert_eq!(needs_restart(&running, &new), vec!["listen", "log"]);
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let path =
+            std::env::temp_dir().join(format!("orderbook-reload-{}.toml", std::process::id()));
+        let write = |text: &str| {
+            let mut file = std::fs::File::create(&path).unwrap();
+            file.write_all(text.as_bytes()).unwrap();
+        };
+        write("instruments = [\"ethbtc\"]\n");
+        let args = Args {
+            config: Some(path.clone()),
+            ..Args::default()
+        };
+        let running = Config::load(&args).unwrap().settings().unwrap();
+        let server = SummaryServer::from_settings(&running);
+
+        // A broken config leaves everything as it was
+        write("instruments = [\"nothing\"]\n");
+        let same = reload(&args, &server, running.clone()).await;
+        assert_eq!(same, running);
+
+        // A good one is applied, apart from what needs a restart
+        write("instruments = [\"btcusd\"]\nlisten = \"0.0.0.0:9000\"\n[venues.bybit]\nenabled = false\n");
+        let reloaded = reload(&args, &server, running.clone()).await;
+        assert_eq!(reloaded.defaults.instrument, CurrencyPair::Btcusd);
+        assert!(!reloaded.defaults.venues.contains(&Venue::Bybit));
+        assert_eq!(reloaded.listen, running.listen);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reload_kept() {
+        // Nothing listens on port 1, so the kept merges never get anywhere
+        let settings = |text: &str, disabled: &[Venue]| {
+            let venues: String = Venue::ALL
+                .iter()
+                .map(|venue| {
+                    let enabled = !disabled.contains(venue);
+                    format!(
+                        "[venues.{venue}]\nendpoint = \"ws://127.0.0.1:1\"\nenabled = {enabled}\n"
+                    )
+                })
+                .collect();
+            let text = format!("{text}\n{venues}");
+            Config::parse(Path::new("test.toml"), &text)
+                .unwrap()
+                .settings()
+                .unwrap()
+        };
+        let kept = |server: &SummaryServer| {
+            let kept = server.kept.lock().unwrap();
+            let mut kept: Vec<_> = kept
+                .params
+                .iter()
+                .map(|(instrument, params)| (*instrument, params.borrow().venues.len()))
+                .collect();
+            kept.sort_by_key(|(instrument, _)| instrument.to_string());
+            kept
+        };
+        let server =
+            SummaryServer::from_settings(&settings(r#"instruments = ["ethbtc", "btcusd"]"#, &[]));
+        server.keep(Arc::new(|_, _| {}));
+        let all = Venue::ALL.len();
+        assert_eq!(
+            kept(&server),
+            vec![(CurrencyPair::Btcusd, all), (CurrencyPair::Ethbtc, all)]
+        );
+
+        // Instruments that are no longer served stop being kept, new ones start, and the rest
+        // are kept with the new venues
+        server
+            .reload(&settings(
+                r#"instruments = ["ethbtc", "ltcbtc"]"#,
+                &[Venue::Bybit],
+            ))
+            .await;
+        assert_eq!(
+            kept(&server),
+            vec![
+                (CurrencyPair::Ethbtc, all - 1),
+                (CurrencyPair::Ltcbtc, all - 1)
+            ]
+        );
+    }
+}
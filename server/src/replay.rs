@@ -0,0 +1,578 @@
+//! Plays captured frames back through the exchange clients, so a captured market can be merged
+//! again exactly as it was live. The frames are served from a local websocket that the hub
+//! connects to in place of the venues, and a virtual clock is moved on to each frame's time as
+//! it's sent: at the speed it was captured, some multiple of that, or as fast as the hub can take
+//! them. At max speed each frame is followed by a ping, and the clock's only moved on once the
+//! client's answered it and everything timed by the clock has settled, so nothing's skipped
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt, io,
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::{future::pending, SinkExt, StreamExt};
+use thiserror::Error;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        watch,
+    },
+    task::JoinHandle,
+    time::Instant,
+};
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{
+        handshake::server::{Request, Response},
+        Message,
+    },
+};
+
+use crate::{
+    clock::Clock,
+    recorder::{read_capture, Frame},
+    venue::Venue,
+};
+
+/// How far the clock moves at a time between frames, when we're keeping to a speed, so anything
+/// timed by it, eg. staleness, happens when it should
+const CLOCK_STEP: Duration = Duration::from_millis(100);
+/// How long to wait for a client to reconnect, where the capture has its venue reconnecting
+const RECONNECT_WAIT: Duration = Duration::from_secs(10);
+
+/// How fast to replay
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speed {
+    /// This many times as fast as it was captured
+    Times(f64),
+    /// Without waiting between frames, other than for the hub to have dealt with each one and
+    /// every subscriber to have taken the summaries it made, so a replay always makes the same
+    /// ones
+    Max,
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Speed::Times(times) => write!(f, "{times}x"),
+            Speed::Max => write!(f, "max"),
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("Expected a speed like \"1x\", \"10x\" or \"max\", not {0:?}")]
+pub struct BadSpeed(String);
+
+impl FromStr for Speed {
+    type Err = BadSpeed;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        if input == "max" {
+            return Ok(Speed::Max);
+        }
+        match input.strip_suffix('x').map(str::parse::<f64>) {
+            Some(Ok(times)) if times.is_finite() && times > 0.0 => Ok(Speed::Times(times)),
+            _ => Err(BadSpeed(input.to_string())),
+        }
+    }
+}
+
+/// The venue and instrument a frame came from, as the capture names them
+pub type Feed = (String, String);
+
+/// The client connected for each feed, by the channel to its connection
+type Clients = watch::Sender<HashMap<Feed, UnboundedSender<Message>>>;
+
+/// The payloads of the pongs the clients send back
+type Pongs = UnboundedSender<Vec<u8>>;
+
+/// Captured frames, ready to play back
+#[derive(Debug, Clone, Default)]
+pub struct Replay {
+    frames: Vec<Frame>,
+}
+
+impl Replay {
+    /// `frames` are played in the order they were received, whatever order they're given in
+    pub fn new(mut frames: Vec<Frame>) -> Replay {
+        frames.sort_by_key(|frame| frame.received_us);
+        Replay { frames }
+    }
+
+    /// Every frame in the capture files at `paths`
+    pub fn load(paths: &[PathBuf]) -> io::Result<Replay> {
+        let mut frames = vec![];
+        for path in paths {
+            for frame in read_capture(path)? {
+                frames.push(frame?);
+            }
+        }
+        Ok(Replay::new(frames))
+    }
+
+    /// Only play the frames that `keep` is true of
+    pub fn retain(&mut self, keep: impl Fn(&Frame) -> bool) {
+        self.frames.retain(keep);
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Every venue and instrument in the capture
+    pub fn feeds(&self) -> BTreeSet<Feed> {
+        self.frames
+            .iter()
+            .map(|frame| (frame.venue.clone(), frame.instrument.clone()))
+            .collect()
+    }
+
+    /// Serve the frames to clients connecting on `listener`, starting once there's a client for
+    /// every venue and instrument in the capture. A client asks for a venue by the first part of
+    /// the path, as `Playing::endpoint` has it
+    pub fn play(self, listener: TcpListener, speed: Speed) -> io::Result<Playing> {
+        let addr = listener.local_addr()?;
+        let clock = Clock::virtual_at(self.frames.first().map_or(0, |frame| frame.received_us));
+        let clients = Arc::new(watch::channel(HashMap::new()).0);
+        let (pongs, pong_receiver) = unbounded_channel();
+        let feeds = Arc::new(self.feeds());
+        log::info!(
+            "Replaying {} frames at {speed} on {addr}, once {feeds:?} are connected",
+            self.len()
+        );
+        Ok(Playing {
+            addr,
+            clock: clock.clone(),
+            accept: tokio::spawn(accept(listener, feeds, clients.clone(), pongs)),
+            play: tokio::spawn(play(self.frames, speed, clock, clients, pong_receiver)),
+        })
+    }
+}
+
+/// A replay that's being served. Dropping it stops it
+pub struct Playing {
+    addr: SocketAddr,
+    clock: Clock,
+    accept: JoinHandle<()>,
+    play: JoinHandle<()>,
+}
+
+impl Playing {
+    /// Where to connect to for `venue`'s frames, instead of its default endpoint
+    pub fn endpoint(&self, venue: Venue) -> String {
+        format!("ws://{}/{venue}", self.addr)
+    }
+
+    /// The replay's virtual clock, which is at the time of the frame it last sent
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    /// Resolves once every frame has been sent. The clients stay connected
+    pub async fn finished(&mut self) {
+        if let Err(err) = (&mut self.play).await {
+            log::error!("The replay failed: {err}");
+        }
+    }
+}
+
+impl Drop for Playing {
+    fn drop(&mut self) {
+        self.accept.abort();
+        self.play.abort();
+    }
+}
+
+async fn accept(
+    listener: TcpListener,
+    feeds: Arc<BTreeSet<Feed>>,
+    clients: Arc<Clients>,
+    pongs: Pongs,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let client = serve_client(stream, feeds.clone(), clients.clone(), pongs.clone());
+                tokio::spawn(client);
+            }
+            Err(err) => log::warn!("Unable to accept a replay client: {err}"),
+        }
+    }
+}
+
+/// The feed for `venue` whose instrument `text` mentions, the longest if several do
+fn find_feed(feeds: &BTreeSet<Feed>, venue: &str, text: &str) -> Option<Feed> {
+    let text = text.to_lowercase();
+    feeds
+        .iter()
+        .filter(|(feed_venue, instrument)| feed_venue == venue && text.contains(instrument))
+        .max_by_key(|(_, instrument)| instrument.len())
+        .cloned()
+}
+
+/// Work out which feed a client wants, then send it that feed's frames, and pass on its pongs,
+/// until either of us closes the connection
+async fn serve_client(
+    stream: TcpStream,
+    feeds: Arc<BTreeSet<Feed>>,
+    clients: Arc<Clients>,
+    pongs: Pongs,
+) {
+    let mut path = String::new();
+    // The handshake's own error response, which we never send
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, response: Response| {
+        path = request.uri().path().to_string();
+        Ok(response)
+    };
+    let mut socket = match accept_hdr_async(stream, callback).await {
+        Ok(socket) => socket,
+        Err(err) => {
+            log::warn!("Unable to accept a replay client: {err}");
+            return;
+        }
+    };
+    let venue = path.trim_start_matches('/').split('/').next().unwrap_or("");
+    // Binance's instrument is in the path; the other venues' are in their subscribe messages
+    let mut found = find_feed(&feeds, venue, &path);
+    let feed = loop {
+        if let Some(feed) = found {
+            break feed;
+        }
+        found = match socket.next().await {
+            Some(Ok(Message::Text(text))) => find_feed(&feeds, venue, &text),
+            Some(Ok(_)) => None,
+            _ => return,
+        };
+    };
+    log::info!("Replay client connected for {feed:?}");
+    let (sender, mut frames) = unbounded_channel();
+    clients.send_modify(|clients| {
+        clients.insert(feed.clone(), sender);
+    });
+    loop {
+        tokio::select! {
+            frame = frames.recv() => match frame {
+                Some(frame) => {
+                    if let Err(err) = socket.send(frame).await {
+                        log::warn!("Unable to replay a frame for {feed:?}: {err}");
+                        return;
+                    }
+                }
+                // The capture has the venue disconnecting here
+                None => break,
+            },
+            // Subscriptions need no answer
+            message = socket.next() => match message {
+                Some(Ok(Message::Pong(data))) => {
+                    pongs.send(data).ok();
+                }
+                Some(Ok(_)) => {}
+                _ => return,
+            },
+        }
+    }
+    if let Err(err) = socket.close(None).await {
+        log::warn!("Unable to close the replay socket for {feed:?}: {err}");
+    }
+}
+
+/// Resolves once `feed` has a client
+async fn connected(clients: &Clients, feed: &Feed) {
+    let mut changes = clients.subscribe();
+    while !changes.borrow().contains_key(feed) {
+        if changes.changed().await.is_err() {
+            pending::<()>().await;
+        }
+    }
+}
+
+/// Makes each ping's payload different, so its pong can't be mistaken for another's
+static PINGS: AtomicU64 = AtomicU64::new(0);
+
+/// Ping `client`, and resolve once it's answered, so it's read everything sent before the ping.
+/// False if it didn't answer in time
+async fn pinged(client: &UnboundedSender<Message>, pongs: &mut UnboundedReceiver<Vec<u8>>) -> bool {
+    let payload = PINGS.fetch_add(1, Ordering::Relaxed).to_be_bytes().to_vec();
+    if client.send(Message::Ping(payload.clone())).is_err() {
+        return false;
+    }
+    let answered = async {
+        while let Some(pong) = pongs.recv().await {
+            if pong == payload {
+                return;
+            }
+        }
+        pending::<()>().await
+    };
+    tokio::time::timeout(RECONNECT_WAIT, answered).await.is_ok()
+}
+
+/// Send every frame to its feed's client when it's due, moving the clock on to its time first
+async fn play(
+    frames: Vec<Frame>,
+    speed: Speed,
+    clock: Clock,
+    clients: Arc<Clients>,
+    mut pongs: UnboundedReceiver<Vec<u8>>,
+) {
+    let feeds: BTreeSet<Feed> = frames
+        .iter()
+        .map(|frame| (frame.venue.clone(), frame.instrument.clone()))
+        .collect();
+    for feed in &feeds {
+        connected(&clients, feed).await;
+    }
+    log::info!("Every replay client is connected; starting");
+    // Where we're keeping to the speed from. Waiting for a reconnect moves it
+    let mut started = (Instant::now(), clock.now_us());
+    // The captured connection each feed's client is standing in for
+    let mut connections = HashMap::new();
+    for frame in &frames {
+        match speed {
+            Speed::Max => clock.advance_to(frame.received_us),
+            Speed::Times(times) => {
+                while clock.now_us() < frame.received_us {
+                    let step_us = CLOCK_STEP.as_micros() as i64;
+                    let next_us = frame.received_us.min(clock.now_us() + step_us);
+                    let captured = Duration::from_micros((next_us - started.1) as u64);
+                    tokio::time::sleep_until(started.0 + captured.div_f64(times)).await;
+                    clock.advance_to(next_us);
+                }
+            }
+        }
+        let message = match frame.data.to_message() {
+            Ok(message) => message,
+            Err(err) => {
+                log::warn!("Skipping a frame we can't decode: {err}");
+                continue;
+            }
+        };
+        let feed = (frame.venue.clone(), frame.instrument.clone());
+        let reconnected = connections
+            .insert(feed.clone(), frame.connection)
+            .is_some_and(|connection| connection != frame.connection);
+        if reconnected {
+            log::info!("The capture reconnects {feed:?}, so we do too");
+            clients.send_modify(|clients| {
+                clients.remove(&feed);
+            });
+            let wait = tokio::time::timeout(RECONNECT_WAIT, connected(&clients, &feed));
+            if wait.await.is_err() {
+                log::warn!("{feed:?} hasn't reconnected to the replay");
+            }
+            started = (Instant::now(), clock.now_us());
+        }
+        let client = clients.borrow().get(&feed).cloned();
+        let Some(client) = client.filter(|client| client.send(message).is_ok()) else {
+            log::debug!("No replay client for {feed:?}; skipping a frame");
+            continue;
+        };
+        if speed == Speed::Max {
+            if !pinged(&client, &mut pongs).await {
+                log::warn!("{feed:?} hasn't answered the replay's ping");
+            }
+            clock.settled().await;
+        }
+    }
+    log::info!("Replayed {} frames", frames.len());
+}
+
+#[cfg(test)]
+mod unit_test {
+    use bitstamp::model::CurrencyPair;
+    use pretty_assertions::assert_eq;
+    use tokio::net::TcpListener;
+
+    use super::{Replay, Speed};
+    use crate::{
+        api::{
+            orderbook_aggregator_client::OrderbookAggregatorClient, Level, Summary, SummaryRequest,
+            VenueStatus,
+        },
+        config::Compression,
+        recorder::{Frame, FrameData},
+        serve_on,
+        venue::Venue,
+        SummaryServer,
+    };
+
+    /// When the capture starts, in Unix microseconds
+    const START_US: i64 = 1_700_000_000_000_000;
+
+    fn frame(ms: i64, connection: u64, venue: &str, text: &str) -> Frame {
+        Frame {
+            received_us: START_US + ms * 1000,
+            connection,
+            venue: venue.to_string(),
+            instrument: "ethbtc".to_string(),
+            data: FrameData::Text(text.to_string()),
+        }
+    }
+
+    fn level(exchange: &str, price: f64, amount: f64) -> Level {
+        Level {
+            exchange: exchange.to_string(),
+            price,
+            amount,
+            ..Level::default()
+        }
+    }
+
+    fn venue(exchange: &str, age_ms: u64, exchange_ms: i64, received_ms: i64) -> VenueStatus {
+        let us = |ms| START_US + ms * 1000;
+        VenueStatus {
+            exchange: exchange.to_string(),
+            age_ms,
+            stale: false,
+            exchange_time_us: if exchange_ms < 0 { 0 } else { us(exchange_ms) },
+            received_time_us: us(received_ms),
+        }
+    }
+
+    fn summary(bids: Vec<Level>, asks: Vec<Level>, venues: Vec<VenueStatus>, ms: i64) -> Summary {
+        Summary {
+            spread: bids[0].price - asks[0].price,
+            bids,
+            asks,
+            venues,
+            merged_time_us: START_US + ms * 1000,
+            sent_time_us: START_US + ms * 1000,
+        }
+    }
+
+    #[test]
+    fn test_speed() {
+        assert_eq!("max".parse(), Ok(Speed::Max));
+        assert_eq!("1x".parse(), Ok(Speed::Times(1.0)));
+        assert_eq!("2.5x".parse(), Ok(Speed::Times(2.5)));
+        assert!("0x".parse::<Speed>().is_err());
+        assert!("fast".parse::<Speed>().is_err());
+        assert_eq!(Speed::Times(10.0).to_string(), "10x");
+    }
+
+    /// Replay the test capture at `speed` to a client asking for every venue, returning the
+    /// summaries it gets. Bitstamp reconnects part way through, as it did in the capture
+    async fn replay(speed: Speed) -> Vec<Summary> {
+        let frames = vec![
+            frame(
+                1300,
+                1,
+                "binance",
+                r#"{"lastUpdateId":2,"bids":[["0.052","1.5"]],"asks":[["0.059","1.5"]]}"#,
+            ),
+            frame(
+                0,
+                1,
+                "binance",
+                r#"{"lastUpdateId":1,"bids":[["0.05","1.0"]],"asks":[["0.06","1.0"]]}"#,
+            ),
+            frame(
+                100,
+                2,
+                "bitstamp",
+                r#"{"event":"bts:subscription_succeeded","channel":"detail_order_book_ethbtc","data":{}}"#,
+            ),
+            frame(
+                250,
+                2,
+                "bitstamp",
+                r#"{"event":"data","channel":"detail_order_book_ethbtc","data":{"timestamp":"1700000000","microtimestamp":"1700000000240000","bids":[["0.051","2.0","1"]],"asks":[["0.061","2.0","2"]]}}"#,
+            ),
+            frame(
+                1500,
+                3,
+                "bitstamp",
+                r#"{"event":"data","channel":"detail_order_book_ethbtc","data":{"timestamp":"1700000001","microtimestamp":"1700000001490000","bids":[["0.0515","1.0","3"]],"asks":[["0.0605","1.0","4"]]}}"#,
+            ),
+        ];
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut playing = Replay::new(frames).play(listener, speed).unwrap();
+        let service = SummaryServer::new(CurrencyPair::Ethbtc)
+            .with_clock(playing.clock().clone())
+            .with_endpoint(Venue::Binance, playing.endpoint(Venue::Binance))
+            .with_endpoint(Venue::Bitstamp, playing.endpoint(Venue::Bitstamp));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_on(listener, service, Compression::None));
+
+        let mut client = OrderbookAggregatorClient::connect(format!("http://{addr}"))
+            .await
+            .unwrap();
+        let request = SummaryRequest {
+            venues: vec!["binance".to_string(), "bitstamp".to_string()],
+            ..SummaryRequest::default()
+        };
+        let mut summaries = client.book_summary(request).await.unwrap().into_inner();
+        let mut replayed = vec![];
+        for _ in 0..4 {
+            replayed.push(summaries.message().await.unwrap().unwrap());
+        }
+        playing.finished().await;
+        assert_eq!(playing.clock().now_us(), START_US + 1_500_000);
+        replayed
+    }
+
+    /// Every time is the capture's, however long the replay really took
+    fn expected() -> Vec<Summary> {
+        vec![
+            summary(
+                vec![level("binance", 0.05, 1.0)],
+                vec![level("binance", 0.06, 1.0)],
+                vec![venue("binance", 0, -1, 0)],
+                0,
+            ),
+            summary(
+                vec![level("bitstamp", 0.051, 2.0), level("binance", 0.05, 1.0)],
+                vec![level("binance", 0.06, 1.0), level("bitstamp", 0.061, 2.0)],
+                vec![venue("binance", 250, -1, 0), venue("bitstamp", 0, 240, 250)],
+                250,
+            ),
+            summary(
+                vec![level("binance", 0.052, 1.5), level("bitstamp", 0.051, 2.0)],
+                vec![level("binance", 0.059, 1.5), level("bitstamp", 0.061, 2.0)],
+                vec![
+                    venue("binance", 0, -1, 1300),
+                    venue("bitstamp", 1050, 240, 250),
+                ],
+                1300,
+            ),
+            // From bitstamp's new connection
+            summary(
+                vec![level("binance", 0.052, 1.5), level("bitstamp", 0.0515, 1.0)],
+                vec![level("binance", 0.059, 1.5), level("bitstamp", 0.0605, 1.0)],
+                vec![
+                    venue("binance", 200, -1, 1300),
+                    venue("bitstamp", 0, 1490, 1500),
+                ],
+                1500,
+            ),
+        ]
+    }
+
+    /// At 1x, so there's plenty of time between frames for each summary to reach us before the
+    /// next frame is sent
+    #[tokio::test]
+    async fn test_replay() {
+        assert_eq!(replay(Speed::Times(1.0)).await, expected());
+    }
+
+    /// At max, where we only get them all because the replay waits for each to be taken
+    #[tokio::test]
+    async fn test_replay_max() {
+        assert_eq!(replay(Speed::Max).await, expected());
+    }
+}
@@ -0,0 +1,332 @@
+//! Checks a `SummaryRequest` from a client and fills in the server's defaults
+// `Status` is large, but it's what goes back to the client
+#![allow(clippy::result_large_err)]
+use std::collections::{BTreeSet, HashMap};
+
+use bitstamp::model::CurrencyPair;
+use rust_decimal::Decimal;
+use tonic::Status;
+
+use crate::{
+    api::{Aggregation, SummaryRequest},
+    venue::Venue,
+};
+
+/// Levels per side when the client doesn't ask for a particular depth
+pub const DEFAULT_DEPTH: usize = 10;
+/// The most levels per side a client may ask for
+pub const MAX_DEPTH: usize = 100;
+
+/// What the server will serve, and what a client gets when it doesn't say
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestDefaults {
+    /// The instrument a client gets if it doesn't name one
+    pub instrument: CurrencyPair,
+    /// The only instruments clients may ask for; empty means any
+    pub instruments: Vec<CurrencyPair>,
+    /// The venues that are enabled; a client can only narrow these down
+    pub venues: BTreeSet<Venue>,
+    pub depth: usize,
+    pub max_depth: usize,
+    /// The tick each instrument's levels are combined by when they're consolidated. Instruments
+    /// without one only combine levels at the same price
+    pub ticks: HashMap<CurrencyPair, Decimal>,
+}
+
+impl RequestDefaults {
+    pub fn new(instrument: CurrencyPair) -> RequestDefaults {
+        RequestDefaults {
+            instrument,
+            instruments: vec![],
+            venues: Venue::ALL.into_iter().collect(),
+            depth: DEFAULT_DEPTH,
+            max_depth: MAX_DEPTH,
+            ticks: HashMap::new(),
+        }
+    }
+}
+
+/// A validated `SummaryRequest`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SummaryParams {
+    pub instrument: CurrencyPair,
+    pub depth: usize,
+    pub venues: BTreeSet<Venue>,
+    pub aggregation: Aggregation,
+    /// What levels are grouped by: the request's tick size, or for consolidated levels the
+    /// server's tick for the instrument. None if consolidated levels are only combined at the same
+    /// price, and per venue ones aren't grouped
+    pub tick: Option<Decimal>,
+}
+
+fn parse_venues(names: &[String]) -> Result<BTreeSet<Venue>, Status> {
+    names
+        .iter()
+        .map(|name| {
+            name.to_lowercase()
+                .parse()
+                .map_err(|_| Status::invalid_argument(format!("Unknown venue: \"{name}\"")))
+        })
+        .collect()
+}
+
+/// A tick size, exactly as the client, or the config, wrote it
+pub(crate) fn parse_tick(text: &str) -> Result<Decimal, Status> {
+    Decimal::from_str_exact(text.trim())
+        .ok()
+        .filter(|tick| tick.is_sign_positive() && !tick.is_zero())
+        .ok_or_else(|| {
+            Status::invalid_argument(format!(
+                "Bad tick_size: \"{text}\"; it should be a decimal more than 0, eg. \"0.00001\""
+            ))
+        })
+}
+
+impl SummaryParams {
+    /// Check the request, filling in anything it leaves out from `defaults`
+    pub fn from_request(
+        request: SummaryRequest,
+        defaults: &RequestDefaults,
+    ) -> Result<SummaryParams, Status> {
+        let instrument = if request.instrument.is_empty() {
+            defaults.instrument
+        } else {
+            request.instrument.to_lowercase().parse().map_err(|_| {
+                Status::invalid_argument(format!("Unknown instrument: \"{}\"", request.instrument))
+            })?
+        };
+        if !defaults.instruments.is_empty() && !defaults.instruments.contains(&instrument) {
+            return Err(Status::invalid_argument(format!(
+                "This server doesn't serve {instrument}"
+            )));
+        }
+
+        let max_depth = defaults.max_depth;
+        let depth = match request.depth as usize {
+            0 => defaults.depth,
+            depth if depth > max_depth => {
+                return Err(Status::invalid_argument(format!(
+                    "depth {depth} is more than the maximum of {max_depth}"
+                )))
+            }
+            depth => depth,
+        };
+
+        let included = parse_venues(&request.venues)?;
+        let excluded = parse_venues(&request.exclude_venues)?;
+        if let Some(venue) = included.intersection(&excluded).next() {
+            return Err(Status::invalid_argument(format!(
+                "{venue} is both included and excluded"
+            )));
+        }
+        if let Some(venue) = included.difference(&defaults.venues).next() {
+            return Err(Status::invalid_argument(format!(
+                "{venue} isn't enabled on this server"
+            )));
+        }
+        let venues = if included.is_empty() {
+            defaults.venues.clone()
+        } else {
+            included
+        };
+        let venues: BTreeSet<Venue> = venues.difference(&excluded).copied().collect();
+        if venues.is_empty() {
+            return Err(Status::invalid_argument("No venues left to merge"));
+        }
+
+        let aggregation = Aggregation::from_i32(request.aggregation).ok_or_else(|| {
+            Status::invalid_argument(format!("Unknown aggregation: {}", request.aggregation))
+        })?;
+
+        let tick = if !request.tick_size.is_empty() {
+            Some(parse_tick(&request.tick_size)?)
+        } else {
+            match aggregation {
+                Aggregation::PerVenue => None,
+                Aggregation::Consolidated => defaults.ticks.get(&instrument).copied(),
+            }
+        };
+
+        Ok(SummaryParams {
+            instrument,
+            depth,
+            venues,
+            aggregation,
+            tick,
+        })
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use bitstamp::model::CurrencyPair;
+    use rust_decimal::Decimal;
+    use tonic::Code;
+
+    use super::{RequestDefaults, SummaryParams, DEFAULT_DEPTH};
+    use crate::{
+        api::{Aggregation, SummaryRequest},
+        venue::Venue,
+    };
+
+    fn check(request: SummaryRequest) -> Result<SummaryParams, tonic::Status> {
+        SummaryParams::from_request(request, &RequestDefaults::new(CurrencyPair::Ethbtc))
+    }
+
+    #[test]
+    fn test_defaults() {
+        let params = check(SummaryRequest::default()).unwrap();
+        assert_eq!(
+            params,
+            SummaryParams {
+                instrument: CurrencyPair::Ethbtc,
+                depth: DEFAULT_DEPTH,
+                venues: Venue::ALL.into_iter().collect(),
+                aggregation: Aggregation::PerVenue,
+                tick: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parameters() {
+        let params = check(SummaryRequest {
+            instrument: "BTCUSD".to_string(),
+            depth: 25,
+            venues: vec!["binance".to_string(), "Bitstamp".to_string()],
+            exclude_venues: vec![],
+            aggregation: Aggregation::PerVenue as i32,
+            tick_size: "0.50".to_string(),
+        })
+        .unwrap();
+        assert_eq!(params.instrument, CurrencyPair::Btcusd);
+        assert_eq!(params.depth, 25);
+        assert_eq!(params.tick, Some(Decimal::new(5, 1)));
+        assert_eq!(
+            params.venues.into_iter().collect::<Vec<_>>(),
+            vec![Venue::Binance, Venue::Bitstamp]
+        );
+
+        let params = check(SummaryRequest {
+            exclude_venues: vec!["htx".to_string()],
+            ..SummaryRequest::default()
+        })
+        .unwrap();
+        assert!(!params.venues.contains(&Venue::Htx));
+        assert_eq!(params.venues.len(), Venue::ALL.len() - 1);
+    }
+
+    #[test]
+    fn test_ticks() {
+        let tick = Decimal::new(1, 5);
+        let defaults = RequestDefaults {
+            ticks: [(CurrencyPair::Ethbtc, tick)].into_iter().collect(),
+            ..RequestDefaults::new(CurrencyPair::Ethbtc)
+        };
+        let consolidated = SummaryRequest {
+            aggregation: Aggregation::Consolidated as i32,
+            ..SummaryRequest::default()
+        };
+        let params = SummaryParams::from_request(consolidated.clone(), &defaults).unwrap();
+        assert_eq!(params.tick, Some(tick));
+
+        // The request's own tick size wins
+        let coarser = SummaryRequest {
+            tick_size: "0.0001".to_string(),
+            ..consolidated.clone()
+        };
+        let params = SummaryParams::from_request(coarser, &defaults).unwrap();
+        assert_eq!(params.tick, Some(Decimal::new(1, 4)));
+
+        // Levels listed by venue aren't combined at all
+        let params = SummaryParams::from_request(SummaryRequest::default(), &defaults).unwrap();
+        assert_eq!(params.tick, None);
+
+        let btcusd = SummaryRequest {
+            instrument: "btcusd".to_string(),
+            ..consolidated
+        };
+        let params = SummaryParams::from_request(btcusd, &defaults).unwrap();
+        assert_eq!(params.tick, None);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let invalid = [
+            SummaryRequest {
+                instrument: "dogecoin".to_string(),
+                ..SummaryRequest::default()
+            },
+            SummaryRequest {
+                depth: 1000,
+                ..SummaryRequest::default()
+            },
+            SummaryRequest {
+                venues: vec!["nasdaq".to_string()],
+                ..SummaryRequest::default()
+            },
+            SummaryRequest {
+                venues: vec!["binance".to_string()],
+                exclude_venues: vec!["binance".to_string()],
+                ..SummaryRequest::default()
+            },
+            SummaryRequest {
+                exclude_venues: Venue::ALL.iter().map(Venue::to_string).collect(),
+                ..SummaryRequest::default()
+            },
+            SummaryRequest {
+                aggregation: 42,
+                ..SummaryRequest::default()
+            },
+        ];
+        let ticks = [
+            "0",
+            "-0.001",
+            "tiny",
+            "1e-5",
+            "0.00000000000000000000000000001",
+        ];
+        let invalid = invalid.into_iter().chain(ticks.map(|tick| SummaryRequest {
+            tick_size: tick.to_string(),
+            ..SummaryRequest::default()
+        }));
+        for request in invalid {
+            let err = check(request.clone()).expect_err(&format!("{request:?} should fail"));
+            assert_eq!(err.code(), Code::InvalidArgument);
+        }
+    }
+
+    #[test]
+    fn test_server_limits() {
+        let defaults = RequestDefaults {
+            instruments: vec![CurrencyPair::Ethbtc, CurrencyPair::Btcusd],
+            venues: [Venue::Binance, Venue::Bitstamp].into_iter().collect(),
+            depth: 5,
+            max_depth: 20,
+            ..RequestDefaults::new(CurrencyPair::Ethbtc)
+        };
+        let params = SummaryParams::from_request(SummaryRequest::default(), &defaults).unwrap();
+        assert_eq!(params.depth, 5);
+        assert_eq!(params.venues, defaults.venues);
+
+        let invalid = [
+            SummaryRequest {
+                instrument: "ltcbtc".to_string(),
+                ..SummaryRequest::default()
+            },
+            SummaryRequest {
+                depth: 21,
+                ..SummaryRequest::default()
+            },
+            SummaryRequest {
+                venues: vec!["htx".to_string()],
+                ..SummaryRequest::default()
+            },
+        ];
+        for request in invalid {
+            let err = SummaryParams::from_request(request.clone(), &defaults)
+                .expect_err(&format!("{request:?} should fail"));
+            assert_eq!(err.code(), Code::InvalidArgument);
+        }
+    }
+}
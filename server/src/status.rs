@@ -0,0 +1,114 @@
+//! Machine-readable details on the errors we send clients, in the standard `google.rpc` form, so a
+//! client can tell which venue let it down without picking the message apart
+use bitstamp::model::CurrencyPair;
+use prost::Message;
+use prost_types::Any;
+use tonic::{Code, Status};
+
+use crate::{
+    api::rpc::{self, ErrorInfo},
+    venue::{ErrorKind, Venue, VenueError},
+};
+
+/// Whose reasons ours are
+pub const DOMAIN: &str = "orderbook";
+/// A venue we needed couldn't give us books
+pub const VENUE_FAILED: &str = "VENUE_FAILED";
+const ERROR_INFO_TYPE: &str = "type.googleapis.com/google.rpc.ErrorInfo";
+
+/// A venue we couldn't get books from, and why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VenueFailure {
+    pub venue: Venue,
+    pub instrument: CurrencyPair,
+    pub kind: ErrorKind,
+    pub error: String,
+}
+
+impl VenueFailure {
+    pub fn new(venue: Venue, instrument: CurrencyPair, error: &VenueError) -> Self {
+        VenueFailure {
+            venue,
+            instrument,
+            kind: error.kind(),
+            error: error.to_string(),
+        }
+    }
+
+    fn error_info(&self) -> ErrorInfo {
+        ErrorInfo {
+            reason: VENUE_FAILED.to_string(),
+            domain: DOMAIN.to_string(),
+            metadata: [
+                ("venue", self.venue.to_string()),
+                ("instrument", self.instrument.to_string()),
+                ("kind", self.kind.to_string()),
+                ("error", self.error.clone()),
+            ]
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect(),
+        }
+    }
+}
+
+/// An `Unavailable` status with an `ErrorInfo` for each of the `failures`
+pub fn unavailable(message: &str, failures: &[VenueFailure]) -> Status {
+    let details = rpc::Status {
+        code: Code::Unavailable as i32,
+        message: message.to_string(),
+        details: failures
+            .iter()
+            .map(|failure| Any {
+                type_url: ERROR_INFO_TYPE.to_string(),
+                value: failure.error_info().encode_to_vec(),
+            })
+            .collect(),
+    };
+    Status::with_details(Code::Unavailable, message, details.encode_to_vec().into())
+}
+
+/// The `ErrorInfo`s in `status`' details, if it has any
+pub fn error_infos(status: &Status) -> Vec<ErrorInfo> {
+    let details = match rpc::Status::decode(status.details()) {
+        Ok(details) => details.details,
+        Err(_) => return vec![],
+    };
+    details
+        .iter()
+        .filter(|any| any.type_url == ERROR_INFO_TYPE)
+        .filter_map(|any| ErrorInfo::decode(any.value.as_slice()).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod unit_test {
+    use bitstamp::model::CurrencyPair;
+    use tonic::{Code, Status};
+
+    use super::{error_infos, unavailable, VenueFailure, DOMAIN, VENUE_FAILED};
+    use crate::venue::{Venue, VenueError};
+
+    #[test]
+    fn test_details() {
+        let refused = VenueError::from(bybit::Error::Refused {
+            op: "subscribe".to_string(),
+            reason: "no such symbol".to_string(),
+        });
+        let failure = VenueFailure::new(Venue::Bybit, CurrencyPair::Ethbtc, &refused);
+        let status = unavailable("Every venue has failed", &[failure]);
+        assert_eq!(status.code(), Code::Unavailable);
+        assert_eq!(status.message(), "Every venue has failed");
+
+        let infos = error_infos(&status);
+        assert_eq!(infos.len(), 1);
+        assert_eq!(infos[0].reason, VENUE_FAILED);
+        assert_eq!(infos[0].domain, DOMAIN);
+        assert_eq!(infos[0].metadata["venue"], "bybit");
+        assert_eq!(infos[0].metadata["instrument"], "ethbtc");
+        assert_eq!(infos[0].metadata["kind"], "fatal");
+        assert!(infos[0].metadata["error"].contains("no such symbol"));
+
+        assert!(error_infos(&Status::unavailable("No details")).is_empty());
+    }
+}
@@ -0,0 +1,146 @@
+//! Logs and traces. Everything is logged through `tracing`, including the `log` records from the
+//! exchange clients, so each line carries the spans it happened in. The spans can also be sent to
+//! an OpenTelemetry collector over OTLP/HTTP
+use anyhow::Result;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{ExporterBuildError, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+/// What the server is called in traces
+const SERVICE_NAME: &str = "orderbook-server";
+
+/// Keeps the traces going to the collector. Dropping it sends whatever's left
+pub struct Telemetry {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            if let Err(err) = provider.shutdown() {
+                eprintln!("Unable to send the last traces: {err}");
+            }
+        }
+    }
+}
+
+/// Sends spans in batches to the collector at `endpoint`, eg. "http://localhost:4318"
+pub fn otlp_provider(endpoint: &str) -> Result<SdkTracerProvider, ExporterBuildError> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .build()?;
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+        .build())
+}
+
+/// Log to stderr what `filter` lets through, eg. "info,server=debug", and send the same spans to
+/// `otlp_endpoint` if there is one
+pub fn init(filter: &str, otlp_endpoint: Option<&str>) -> Result<Telemetry> {
+    let provider = otlp_endpoint.map(otlp_provider).transpose()?;
+    let otlp = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer("server")));
+    tracing_subscriber::registry()
+        .with(EnvFilter::try_new(filter)?)
+        .with(tracing_subscriber::fmt::layer())
+        .with(otlp)
+        .try_init()?;
+    Ok(Telemetry { provider })
+}
+
+#[cfg(test)]
+mod unit_test {
+    use std::convert::Infallible;
+
+    use hyper::{
+        body::{to_bytes, Bytes},
+        server::conn::AddrIncoming,
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server,
+    };
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_proto::tonic::{
+        collector::trace::v1::ExportTraceServiceRequest,
+        common::v1::{any_value::Value, AnyValue},
+    };
+    use otlp_prost::Message;
+    use tokio::{net::TcpListener, sync::mpsc::unbounded_channel};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::otlp_provider;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_otlp_export() {
+        // A stand-in for the collector, handing over whatever's posted to it
+        let (posted, mut received) = unbounded_channel::<(String, Bytes)>();
+        let make_service = make_service_fn(move |_| {
+            let posted = posted.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let posted = posted.clone();
+                    async move {
+                        let path = request.uri().path().to_string();
+                        let body = to_bytes(request.into_body()).await.unwrap();
+                        posted.send((path, body)).ok();
+                        Ok::<_, Infallible>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = AddrIncoming::from_listener(listener).unwrap();
+        tokio::spawn(Server::builder(incoming).serve(make_service));
+
+        let provider = otlp_provider(&format!("http://{addr}/")).unwrap();
+        let otlp = tracing_opentelemetry::layer().with_tracer(provider.tracer("test"));
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(otlp), || {
+            let connection = tracing::info_span!("connection", venue = "binance");
+            let _entered = connection.enter();
+            let message = tracing::debug_span!("message", sequence = tracing::field::Empty);
+            message.in_scope(|| tracing::info!("Parsed"));
+            message.record("sequence", 1);
+        });
+        tokio::task::spawn_blocking(move || provider.force_flush())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let (path, body) = received.recv().await.unwrap();
+        assert_eq!(path, "/v1/traces");
+        let request = ExportTraceServiceRequest::decode(body).unwrap();
+        let spans: Vec<_> = request
+            .resource_spans
+            .iter()
+            .flat_map(|resource| &resource.scope_spans)
+            .flat_map(|scope| &scope.spans)
+            .collect();
+        let span = |name: &str| *spans.iter().find(|span| span.name == name).unwrap();
+        let attribute = |name: &str, key: &str| {
+            span(name)
+                .attributes
+                .iter()
+                .find(|attribute| attribute.key == key)
+                .and_then(|attribute| attribute.value.clone())
+        };
+        let (connection, message) = (span("connection"), span("message"));
+        assert_eq!(message.parent_span_id, connection.span_id);
+        assert_eq!(message.trace_id, connection.trace_id);
+        assert_eq!(
+            attribute("connection", "venue"),
+            Some(AnyValue {
+                value: Some(Value::StringValue("binance".to_string()))
+            })
+        );
+        assert_eq!(
+            attribute("message", "sequence"),
+            Some(AnyValue {
+                value: Some(Value::IntValue(1))
+            })
+        );
+    }
+}
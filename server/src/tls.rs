@@ -0,0 +1,207 @@
+//! TLS for the gRPC server, optionally requiring clients to present a certificate (mutual TLS), and
+//! working out who those clients are
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use tonic::{
+    transport::{Certificate, Identity, ServerTlsConfig},
+    Request,
+};
+use x509_parser::{certificate::X509Certificate, prelude::FromDer};
+
+use crate::config::ConfigError;
+
+/// The server's certificate and key, and the CA that client certificates must be signed by if
+/// clients have to present one
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsSettings {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub client_ca: Option<PathBuf>,
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, ConfigError> {
+    std::fs::read(path).map_err(|error| ConfigError::Read {
+        path: path.to_owned(),
+        error,
+    })
+}
+
+impl TlsSettings {
+    /// Read the certificates and key. They're only checked when the server starts
+    pub fn server_config(&self) -> Result<ServerTlsConfig, ConfigError> {
+        let identity = Identity::from_pem(read(&self.cert)?, read(&self.key)?);
+        let config = ServerTlsConfig::new().identity(identity);
+        Ok(match &self.client_ca {
+            Some(ca) => config.client_ca_root(Certificate::from_pem(read(ca)?)),
+            None => config,
+        })
+    }
+}
+
+/// Who a client is, from the certificate it presented
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerIdentity {
+    /// The certificate's subject, eg. "CN=alice, O=Example"
+    pub subject: String,
+    pub common_name: Option<String>,
+}
+
+impl PeerIdentity {
+    /// The client that made `request`, if it presented a certificate
+    pub fn from_request<T>(request: &Request<T>) -> Option<PeerIdentity> {
+        let certs = request.peer_certs()?;
+        PeerIdentity::from_der(certs.first()?.get_ref())
+    }
+
+    /// From a DER encoded certificate
+    pub fn from_der(der: &[u8]) -> Option<PeerIdentity> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+        let subject = cert.subject();
+        let common_name = subject
+            .iter_common_name()
+            .next()
+            .and_then(|name| name.as_str().ok())
+            .map(str::to_string);
+        Some(PeerIdentity {
+            subject: subject.to_string(),
+            common_name,
+        })
+    }
+}
+
+impl fmt::Display for PeerIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.common_name {
+            Some(name) => write!(f, "{name}"),
+            None => write!(f, "{}", self.subject),
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use std::{path::Path, time::Duration};
+
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, DnType, IsCa, KeyPair};
+    use tokio::net::TcpListener;
+    use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity};
+
+    use bitstamp::model::CurrencyPair;
+
+    use super::{PeerIdentity, TlsSettings};
+    use crate::{
+        api::{orderbook_aggregator_client::OrderbookAggregatorClient, VenueStatusRequest},
+        config::Compression,
+        serve_until, SummaryServer,
+    };
+
+    /// A CA, and a certificate and key signed by it for each name, in PEM
+    struct TestPki {
+        ca: String,
+        certs: Vec<(String, String)>,
+    }
+
+    fn test_pki(names: &[&str]) -> TestPki {
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "Test CA");
+        let ca = CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap();
+        let certs = names
+            .iter()
+            .map(|name| {
+                let key = KeyPair::generate().unwrap();
+                let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+                params.distinguished_name.push(DnType::CommonName, *name);
+                let cert = params.signed_by(&key, &ca).unwrap();
+                (cert.pem(), key.serialize_pem())
+            })
+            .collect();
+        TestPki {
+            ca: ca.pem(),
+            certs,
+        }
+    }
+
+    #[test]
+    fn test_peer_identity() {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["alice.example".to_string()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, "alice");
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, "Example");
+        let cert = params.self_signed(&key).unwrap();
+        let identity = PeerIdentity::from_der(cert.der()).unwrap();
+        assert_eq!(identity.common_name.as_deref(), Some("alice"));
+        assert!(identity.subject.contains("O=Example"), "{identity:?}");
+        assert_eq!(identity.to_string(), "alice");
+        assert_eq!(PeerIdentity::from_der(b"not a certificate"), None);
+    }
+
+    fn write(dir: &Path, name: &str, contents: &str) -> std::path::PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        let pki = test_pki(&["localhost", "alice"]);
+        let dir = std::env::temp_dir().join(format!("orderbook-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (server_cert, server_key) = &pki.certs[0];
+        let settings = TlsSettings {
+            cert: write(&dir, "server.pem", server_cert),
+            key: write(&dir, "server.key", server_key),
+            client_ca: Some(write(&dir, "ca.pem", &pki.ca)),
+        };
+        let tls = settings.server_config().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = SummaryServer::new(CurrencyPair::Ethbtc);
+        tokio::spawn(serve_until(
+            listener,
+            service,
+            Compression::None,
+            Some(tls),
+            futures::future::pending(),
+            Duration::from_secs(1),
+        ));
+
+        let connect = |identity: Option<Identity>| {
+            let mut tls = ClientTlsConfig::new()
+                .ca_certificate(Certificate::from_pem(&pki.ca))
+                .domain_name("localhost");
+            if let Some(identity) = identity {
+                tls = tls.identity(identity);
+            }
+            async move {
+                let channel = Channel::from_shared(format!("https://{addr}"))
+                    .unwrap()
+                    .tls_config(tls)
+                    .unwrap()
+                    .connect()
+                    .await?;
+                let reply = OrderbookAggregatorClient::new(channel)
+                    .get_venue_status(VenueStatusRequest::default())
+                    .await?;
+                Ok::<_, Box<dyn std::error::Error>>(reply)
+            }
+        };
+
+        // With a certificate signed by the CA we get in
+        let (alice_cert, alice_key) = &pki.certs[1];
+        let result = connect(Some(Identity::from_pem(alice_cert, alice_key))).await;
+        assert!(result.is_ok(), "{result:?}");
+        // Without one we're turned away
+        assert!(connect(None).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
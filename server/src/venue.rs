@@ -0,0 +1,319 @@
+//! The exchanges we can pull order books from, and how to connect to each of them
+use std::pin::Pin;
+
+use bitstamp::model::CurrencyPair;
+use futures::{Stream, StreamExt};
+use parse_display::{Display, FromStr};
+use tap::Tap;
+use thiserror::Error;
+use tokio_tungstenite::tungstenite::{http::StatusCode, Error as WSError};
+
+use crate::{
+    clock::Clock,
+    model::{VenueBook, VenueTrade},
+};
+
+/// How many levels per side we ask bybit for
+const BYBIT_DEPTH: u16 = 50;
+/// How many levels per side of HTX's incremental feed we follow
+const HTX_LEVELS: u16 = 150;
+/// Aggregated price levels; bitfinex's `R0` would give us individual orders instead
+const BITFINEX_PRECISION: bitfinex::model::Precision = bitfinex::model::Precision::P0;
+
+#[derive(Display, FromStr, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[display(style = "snake_case")]
+pub enum Venue {
+    Binance,
+    Bitfinex,
+    Bitstamp,
+    Bybit,
+    Htx,
+}
+
+/// Any error from any of our exchange client libraries
+#[derive(Error, Debug)]
+pub enum VenueError {
+    #[error("binance: {0}")]
+    Binance(Box<binance::Error>),
+    #[error("bitfinex: {0}")]
+    Bitfinex(#[from] bitfinex::Error),
+    #[error("bitstamp: {0}")]
+    Bitstamp(#[from] bitstamp::Error),
+    #[error("bybit: {0}")]
+    Bybit(#[from] bybit::Error),
+    #[error("htx: {0}")]
+    Htx(#[from] htx::Error),
+}
+
+/// What an error means for the venue's connection
+#[derive(Display, Debug, Clone, Copy, PartialEq, Eq)]
+#[display(style = "snake_case")]
+pub enum ErrorKind {
+    /// The connection failed or dropped, and we'll connect again
+    Transient,
+    /// Connecting again won't help, so we give up on the venue
+    Fatal,
+    /// The venue sent something we couldn't make sense of, and we carry on without it
+    DataQuality,
+}
+
+impl From<binance::ErrorKind> for ErrorKind {
+    fn from(kind: binance::ErrorKind) -> Self {
+        match kind {
+            binance::ErrorKind::Transient => ErrorKind::Transient,
+            binance::ErrorKind::Fatal => ErrorKind::Fatal,
+            binance::ErrorKind::DataQuality => ErrorKind::DataQuality,
+        }
+    }
+}
+
+impl From<bitstamp::ErrorKind> for ErrorKind {
+    fn from(kind: bitstamp::ErrorKind) -> Self {
+        match kind {
+            bitstamp::ErrorKind::Transient => ErrorKind::Transient,
+            bitstamp::ErrorKind::Fatal => ErrorKind::Fatal,
+            bitstamp::ErrorKind::DataQuality => ErrorKind::DataQuality,
+        }
+    }
+}
+
+/// The same as binance's and bitstamp's: a bad url or a refused handshake is fatal, except for
+/// being told to slow down
+fn connect_kind(error: &WSError) -> ErrorKind {
+    match error {
+        WSError::Url(_) => ErrorKind::Fatal,
+        WSError::Http(response)
+            if response.status().is_client_error()
+                && response.status() != StatusCode::TOO_MANY_REQUESTS =>
+        {
+            ErrorKind::Fatal
+        }
+        _ => ErrorKind::Transient,
+    }
+}
+
+impl VenueError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            VenueError::Binance(error) => error.kind().into(),
+            VenueError::Bitstamp(error) => error.kind().into(),
+            VenueError::Bitfinex(error) => match error {
+                bitfinex::Error::Connect { error, .. } => connect_kind(error),
+                bitfinex::Error::MessageError(_) | bitfinex::Error::Send { .. } => {
+                    ErrorKind::Transient
+                }
+                bitfinex::Error::Server { .. } => ErrorKind::Fatal,
+                bitfinex::Error::Json { .. }
+                | bitfinex::Error::UnexpectedFrame { .. }
+                | bitfinex::Error::Number { .. }
+                | bitfinex::Error::Checksum { .. } => ErrorKind::DataQuality,
+            },
+            VenueError::Bybit(error) => match error {
+                bybit::Error::Connect { error, .. } => connect_kind(error),
+                bybit::Error::MessageError(_) | bybit::Error::Send { .. } => ErrorKind::Transient,
+                bybit::Error::Refused { .. } => ErrorKind::Fatal,
+                bybit::Error::Json { .. }
+                | bybit::Error::Number { .. }
+                | bybit::Error::DeltaBeforeSnapshot { .. } => ErrorKind::DataQuality,
+            },
+            VenueError::Htx(error) => match error {
+                htx::Error::Connect { error, .. } => connect_kind(error),
+                htx::Error::MessageError(_) | htx::Error::Send { .. } => ErrorKind::Transient,
+                htx::Error::Server { .. } => ErrorKind::Fatal,
+                htx::Error::Decompress(_)
+                | htx::Error::Json { .. }
+                | htx::Error::SequenceGap { .. } => ErrorKind::DataQuality,
+            },
+        }
+    }
+}
+
+impl From<binance::Error> for VenueError {
+    fn from(error: binance::Error) -> Self {
+        VenueError::Binance(Box::new(error))
+    }
+}
+
+/// A stream of one venue's books or trades
+pub type VenueFeed<T> = Pin<Box<dyn Stream<Item = Result<T, VenueError>> + Send>>;
+/// A stream of one venue's books
+pub type VenueStream = VenueFeed<VenueBook>;
+/// A stream of one venue's trades
+pub type TradeStream = VenueFeed<VenueTrade>;
+
+/// Convert a client library's stream into a `VenueStream`, stamping the books with `clock`'s time
+fn venue_stream<S, T, E>(clock: Clock, stream: S) -> VenueStream
+where
+    S: Stream<Item = Result<T, E>> + Send + 'static,
+    T: Into<VenueBook>,
+    E: Into<VenueError>,
+{
+    // Numbered the same way as the exchange clients number their message spans
+    let mut sequence = 0;
+    Box::pin(stream.map(move |result| {
+        result
+            .map(|book| {
+                sequence += 1;
+                VenueBook {
+                    received: Some(clock.instant()),
+                    received_time: Some(clock.now()),
+                    sequence,
+                    ..book.into()
+                }
+            })
+            .map_err(Into::into)
+    }))
+}
+
+/// Convert a client library's stream into a `TradeStream`, stamping the trades with `clock`'s time
+fn trade_stream<S, T, E>(clock: Clock, stream: S) -> TradeStream
+where
+    S: Stream<Item = Result<T, E>> + Send + 'static,
+    T: Into<VenueTrade>,
+    E: Into<VenueError>,
+{
+    Box::pin(stream.map(move |result| {
+        result
+            .map(|trade| VenueTrade {
+                received_time: Some(clock.now()),
+                ..trade.into()
+            })
+            .map_err(Into::into)
+    }))
+}
+
+impl Venue {
+    pub const ALL: [Venue; 5] = [
+        Venue::Binance,
+        Venue::Bitfinex,
+        Venue::Bitstamp,
+        Venue::Bybit,
+        Venue::Htx,
+    ];
+
+    /// The venue's public websocket, unless we're configured to use another
+    pub fn default_endpoint(self) -> &'static str {
+        match self {
+            Venue::Binance => binance::ENDPOINT,
+            Venue::Bitfinex => bitfinex::ENDPOINT,
+            Venue::Bitstamp => bitstamp::ENDPOINT,
+            Venue::Bybit => bybit::ENDPOINT,
+            Venue::Htx => htx::MBP_ENDPOINT,
+        }
+    }
+
+    /// Connect to the venue at `endpoint` and subscribe to `instrument`'s order book, showing
+    /// `tap` every frame we get, and timing the books by `clock`
+    pub async fn connect(
+        self,
+        instrument: CurrencyPair,
+        endpoint: &str,
+        tap: Option<Tap>,
+        clock: Clock,
+    ) -> Result<VenueStream, VenueError> {
+        // Bitstamp's names are lower case, eg. "ethbtc"
+        let lower = instrument.to_string();
+        let upper = lower.to_uppercase();
+        Ok(match self {
+            Venue::Binance => venue_stream(
+                clock,
+                binance::binance_stream_at(endpoint, &lower, tap).await?,
+            ),
+            Venue::Bitfinex => venue_stream(
+                clock,
+                bitfinex::bitfinex_stream_at(
+                    endpoint,
+                    &format!("t{upper}"),
+                    BITFINEX_PRECISION,
+                    tap,
+                )
+                .await?,
+            ),
+            Venue::Bitstamp => venue_stream(
+                clock,
+                bitstamp::bitstamp_detail_market_depth_stream_at(endpoint, instrument, tap).await?,
+            ),
+            Venue::Bybit => venue_stream(
+                clock,
+                bybit::bybit_stream_at(endpoint, &upper, BYBIT_DEPTH, tap).await?,
+            ),
+            Venue::Htx => venue_stream(
+                clock,
+                htx::htx_mbp_stream_at(endpoint, &lower, HTX_LEVELS, tap).await?,
+            ),
+        })
+    }
+
+    /// Whether we can follow the venue's trades
+    pub fn has_trades(self) -> bool {
+        matches!(self, Venue::Binance | Venue::Bitstamp)
+    }
+
+    /// Connect to the venue at `endpoint` and subscribe to `instrument`'s trades, timing them by
+    /// `clock`. A venue without trades, see `has_trades`, never sends any
+    pub async fn connect_trades(
+        self,
+        instrument: CurrencyPair,
+        endpoint: &str,
+        clock: Clock,
+    ) -> Result<TradeStream, VenueError> {
+        let lower = instrument.to_string();
+        Ok(match self {
+            Venue::Binance => trade_stream(
+                clock,
+                binance::binance_trade_stream_at(endpoint, &lower, None).await?,
+            ),
+            Venue::Bitstamp => trade_stream(
+                clock,
+                bitstamp::bitstamp_live_trades_stream_at(endpoint, instrument, None).await?,
+            ),
+            Venue::Bitfinex | Venue::Bybit | Venue::Htx => Box::pin(futures::stream::pending()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use tokio_tungstenite::tungstenite::{http::Response, Error as WSError};
+
+    use super::{ErrorKind, Venue, VenueError};
+
+    #[test]
+    fn test_names() {
+        for venue in Venue::ALL {
+            assert_eq!(venue.to_string().parse::<Venue>().unwrap(), venue);
+        }
+        assert_eq!(Venue::Htx.to_string(), "htx");
+        assert!("nasdaq".parse::<Venue>().is_err());
+    }
+
+    #[test]
+    fn test_kinds() {
+        let decoding = bitstamp::Error::decoding_general("no data".to_string());
+        assert_eq!(VenueError::from(decoding).kind(), ErrorKind::DataQuality);
+        let number = bitfinex::Error::Number {
+            input: "x".to_string(),
+        };
+        assert_eq!(VenueError::from(number).kind(), ErrorKind::DataQuality);
+        let refused = bybit::Error::Refused {
+            op: "subscribe".to_string(),
+            reason: "no".to_string(),
+        };
+        assert_eq!(VenueError::from(refused).kind(), ErrorKind::Fatal);
+        assert_eq!(
+            VenueError::from(binance::Error::Unknown).kind(),
+            ErrorKind::Fatal
+        );
+        let connect = |status| htx::Error::Connect {
+            url: "wss://api.huobi.pro/ws".to_string(),
+            error: Box::new(WSError::Http(
+                Response::builder().status(status).body(None).unwrap(),
+            )),
+        };
+        assert_eq!(VenueError::from(connect(404)).kind(), ErrorKind::Fatal);
+        assert_eq!(VenueError::from(connect(502)).kind(), ErrorKind::Transient);
+        let dropped = htx::Error::from(WSError::ConnectionClosed);
+        assert_eq!(VenueError::from(dropped).kind(), ErrorKind::Transient);
+        assert_eq!(ErrorKind::DataQuality.to_string(), "data_quality");
+    }
+}
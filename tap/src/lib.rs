@@ -0,0 +1,7 @@
+//! What the exchange clients share with whoever wants their raw feeds, eg. the server's recorder
+use std::sync::Arc;
+
+use tokio_tungstenite::tungstenite::Message;
+
+/// Called with every frame a connection receives, before it's parsed, eg. to record the raw feed
+pub type Tap = Arc<dyn Fn(&Message) + Send + Sync>;